@@ -0,0 +1,361 @@
+//! `chatsafe eval`: score a model on a local benchmark set
+//!
+//! The dataset is JSONL, one `{"prompt", "expected", "match"}` object per
+//! line, where `match` is `exact` (default; trimmed, case and whitespace
+//! insensitive), `regex` (`expected` is a pattern) or `judge` (the same local
+//! server is asked whether the answer matches, by the model named with
+//! `--judge` or else the one under test). Prompts run one at a time at
+//! temperature 0 against a running `chatsafe-server`, so results for two
+//! quantizations are comparable; `--report` writes every answer as JSON.
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// Constants
+const JUDGE_MAX_TOKENS: usize = 4;
+const JUDGE_PROMPT: &str = "You grade answers. Reply with YES if the answer means the same as \
+                            the expected answer, otherwise NO. Reply with one word.";
+
+/// How an answer is compared with the expected one
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MatchMode {
+    #[default]
+    Exact,
+    Regex,
+    Judge,
+}
+
+#[derive(Debug, Deserialize)]
+struct EvalCase {
+    prompt: String,
+    expected: String,
+    #[serde(default, rename = "match")]
+    mode: MatchMode,
+    /// Optional system prompt for this case
+    system: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CaseResult {
+    index: usize,
+    mode: MatchMode,
+    prompt: String,
+    expected: String,
+    output: Option<String>,
+    passed: bool,
+    total_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EvalReport {
+    dataset: PathBuf,
+    model: Option<String>,
+    /// Model that graded `judge` cases, when not the one under test
+    judge: Option<String>,
+    total: usize,
+    passed: usize,
+    accuracy: f64,
+    cases: Vec<CaseResult>,
+}
+
+pub(crate) fn eval_command(args: &[&str], usage: &str) -> Result<()> {
+    let (file, options) = match args {
+        [file, options @ ..] => (PathBuf::from(file), options),
+        [] => bail!("Missing eval dataset\n\n{}", usage),
+    };
+
+    let mut base_url = None;
+    let mut report_path = None;
+    let mut judge = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--url" => base_url = Some(options.next().context("--url needs a value")?.to_string()),
+            "--report" => {
+                report_path = Some(PathBuf::from(
+                    options.next().context("--report needs a value")?,
+                ))
+            }
+            "--judge" => judge = Some(options.next().context("--judge needs a value")?.to_string()),
+            other => bail!("Unknown eval option {}\n\n{}", other, usage),
+        }
+    }
+    let base_url = match base_url {
+        Some(url) => url,
+        None => crate::default_base_url()?,
+    };
+
+    let cases = load_cases(&file)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(run_eval(file, cases, &base_url, judge))?;
+
+    print_report(&report);
+    if let Some(path) = report_path {
+        let json = serde_json::to_string_pretty(&report)?;
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Report written to {}", path.display());
+    }
+    Ok(())
+}
+
+fn load_cases(file: &Path) -> Result<Vec<EvalCase>> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let case: EvalCase = serde_json::from_str(line)
+                .with_context(|| format!("Invalid case on line {}", i + 1))?;
+            if matches!(case.mode, MatchMode::Regex) {
+                Regex::new(&case.expected)
+                    .with_context(|| format!("Invalid pattern on line {}", i + 1))?;
+            }
+            Ok(case)
+        })
+        .collect()
+}
+
+async fn run_eval(
+    dataset: PathBuf,
+    cases: Vec<EvalCase>,
+    base_url: &str,
+    judge: Option<String>,
+) -> Result<EvalReport> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+    let mut model = None;
+    let mut results = Vec::with_capacity(cases.len());
+
+    for (index, case) in cases.into_iter().enumerate() {
+        let mut messages = Vec::new();
+        if let Some(system) = &case.system {
+            messages.push(json!({"role": "system", "content": system}));
+        }
+        messages.push(json!({"role": "user", "content": case.prompt}));
+
+        let started = Instant::now();
+        let answer = complete(&client, &url, None, messages, None).await;
+        let total_ms = started.elapsed().as_millis() as u64;
+
+        let (output, passed, error) = match answer {
+            Ok((output, served_by)) => {
+                model.get_or_insert(served_by);
+                match score(&client, &url, judge.as_deref(), &case, &output).await {
+                    Ok(passed) => (Some(output), passed, None),
+                    Err(e) => (Some(output), false, Some(e.to_string())),
+                }
+            }
+            Err(e) => (None, false, Some(e.to_string())),
+        };
+        results.push(CaseResult {
+            index,
+            mode: case.mode,
+            prompt: case.prompt,
+            expected: case.expected,
+            output,
+            passed,
+            total_ms,
+            error,
+        });
+    }
+
+    let passed = results.iter().filter(|r| r.passed).count();
+    Ok(EvalReport {
+        dataset,
+        model,
+        judge,
+        total: results.len(),
+        passed,
+        accuracy: if results.is_empty() {
+            0.0
+        } else {
+            passed as f64 / results.len() as f64
+        },
+        cases: results,
+    })
+}
+
+/// Non-streaming completion at temperature 0, returning text and model ID;
+/// `model` of `None` leaves the choice to the server
+async fn complete(
+    client: &reqwest::Client,
+    url: &str,
+    model: Option<&str>,
+    messages: Vec<serde_json::Value>,
+    max_tokens: Option<usize>,
+) -> Result<(String, String)> {
+    let mut body = json!({
+        "messages": messages,
+        "stream": false,
+        "temperature": 0.0,
+    });
+    if let Some(model) = model {
+        body["model"] = json!(model);
+    }
+    if let Some(max_tokens) = max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
+
+    let response = client.post(url).json(&body).send().await?;
+    let status = response.status();
+    let response: serde_json::Value = response.json().await?;
+    if !status.is_success() {
+        let message = response
+            .pointer("/error/message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("no error message");
+        bail!("Server returned {}: {}", status, message);
+    }
+
+    let output = response
+        .pointer("/choices/0/message/content")
+        .and_then(|c| c.as_str())
+        .context("Response has no message content")?;
+    let model = response
+        .get("model")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default();
+    Ok((output.to_string(), model.to_string()))
+}
+
+async fn score(
+    client: &reqwest::Client,
+    url: &str,
+    judge: Option<&str>,
+    case: &EvalCase,
+    output: &str,
+) -> Result<bool> {
+    match case.mode {
+        MatchMode::Exact | MatchMode::Regex => compare(case.mode, &case.expected, output),
+        MatchMode::Judge => {
+            let question = format!(
+                "Question: {}\nExpected answer: {}\nAnswer: {}",
+                case.prompt, case.expected, output
+            );
+            let messages = vec![
+                json!({"role": "system", "content": JUDGE_PROMPT}),
+                json!({"role": "user", "content": question}),
+            ];
+            let (verdict, _) =
+                complete(client, url, judge, messages, Some(JUDGE_MAX_TOKENS)).await?;
+            Ok(is_yes(&verdict))
+        }
+    }
+}
+
+/// Score an `exact` or `regex` case without the server
+fn compare(mode: MatchMode, expected: &str, output: &str) -> Result<bool> {
+    match mode {
+        MatchMode::Exact => Ok(normalize(output) == normalize(expected)),
+        MatchMode::Regex => Ok(Regex::new(expected)?.is_match(output)),
+        MatchMode::Judge => bail!("Judge cases are scored by the server"),
+    }
+}
+
+/// Whether a judge's reply is a YES verdict
+fn is_yes(verdict: &str) -> bool {
+    verdict.trim().to_ascii_uppercase().starts_with("YES")
+}
+
+/// Lowercase with whitespace collapsed, for exact matching
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn print_report(report: &EvalReport) {
+    for case in &report.cases {
+        let verdict = match (&case.error, case.passed) {
+            (Some(_), _) => "error",
+            (None, true) => "pass",
+            (None, false) => "fail",
+        };
+        println!(
+            "#{:<4} {:<6} {:<5} {:>6} ms{}",
+            case.index + 1,
+            verdict,
+            format!("{:?}", case.mode).to_lowercase(),
+            case.total_ms,
+            case.error
+                .as_deref()
+                .map_or_else(String::new, |e| format!("  {}", e))
+        );
+    }
+    println!(
+        "{}: {}/{} passed ({:.1}%)",
+        report.model.as_deref().unwrap_or("unknown model"),
+        report.passed,
+        report.total,
+        report.accuracy * 100.0
+    );
+    if let Some(judge) = &report.judge {
+        println!("Judge cases graded by {}", judge);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match_ignores_case_and_whitespace() {
+        assert!(compare(MatchMode::Exact, "Paris", "  paris\n").unwrap());
+        assert!(compare(MatchMode::Exact, "New  York", "new york").unwrap());
+        assert!(!compare(MatchMode::Exact, "Paris", "Paris, France").unwrap());
+    }
+
+    #[test]
+    fn test_regex_match_searches_the_answer() {
+        assert!(compare(MatchMode::Regex, r"\b4\b", "2 + 2 = 4.").unwrap());
+        assert!(!compare(MatchMode::Regex, r"^4$", "It is 4").unwrap());
+        assert!(compare(MatchMode::Regex, "(", "anything").is_err());
+        assert!(compare(MatchMode::Judge, "Paris", "Paris").is_err());
+    }
+
+    #[test]
+    fn test_judge_verdicts() {
+        assert!(is_yes("YES"));
+        assert!(is_yes(" yes."));
+        assert!(!is_yes("NO"));
+        assert!(!is_yes("Maybe yes"));
+    }
+
+    #[test]
+    fn test_load_cases_checks_patterns() {
+        let dir = std::env::temp_dir().join(format!("chatsafe-eval-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("cases.jsonl");
+
+        std::fs::write(
+            &file,
+            "{\"prompt\": \"2+2\", \"expected\": \"4\"}\n\n\
+             {\"prompt\": \"Capital?\", \"expected\": \"Par\", \"match\": \"regex\"}\n",
+        )
+        .unwrap();
+        let cases = load_cases(&file).unwrap();
+        assert_eq!(cases.len(), 2);
+        assert!(matches!(cases[0].mode, MatchMode::Exact));
+        assert!(matches!(cases[1].mode, MatchMode::Regex));
+
+        std::fs::write(
+            &file,
+            "{\"prompt\": \"x\", \"expected\": \"(\", \"match\": \"regex\"}\n",
+        )
+        .unwrap();
+        let error = load_cases(&file).unwrap_err().to_string();
+        assert!(error.contains("line 1"), "{}", error);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
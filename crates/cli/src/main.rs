@@ -0,0 +1,368 @@
+//! `chatsafe` command line tool for local maintenance tasks
+//!
+//! The HTTP server lives in `chatsafe-server`, which `chatsafe serve` runs;
+//! this binary covers offline operations on the local data (model storage
+//! and the like) and client-side tools such as request replay and evals
+//! against a running server.
+
+mod eval;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chatsafe_common::ReplayEnvelope;
+use chatsafe_config::{
+    paths, ConfigLoader, DownloadProgress, ModelDownloader, ModelRegistry, ModelStore,
+};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage:
+  chatsafe serve [--takeover]          Run the server; --takeover replaces a running one
+  chatsafe models list                 List registry models and their storage
+  chatsafe models import <id> <file>   Move a GGUF into the content-addressed store
+  chatsafe models pull <id>            Download a registry model from its Hugging Face source
+  chatsafe models gc [--dry-run]       Remove blobs no manifest entry points at
+  chatsafe data migrate [--dry-run]    Move ~/.local/share/chatsafe into the configured data_dir
+  chatsafe replay <file> [--url <base>] [--no-pacing]
+                                       Re-issue recorded requests and report timings
+  chatsafe eval <dataset.jsonl> [--url <base>] [--report <file>] [--judge <model>]
+                                       Score the served model on prompt/expected pairs";
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+const SERVER_BINARY: &str = "chatsafe-server";
+const PULL_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["serve", rest @ ..] => serve_command(rest),
+        ["models", rest @ ..] => models_command(rest),
+        ["data", rest @ ..] => data_command(rest),
+        ["replay", rest @ ..] => replay_command(rest),
+        ["eval", rest @ ..] => eval::eval_command(rest, USAGE),
+        ["help"] | ["--help"] | ["-h"] | [] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => bail!("Unknown command\n\n{}", USAGE),
+    }
+}
+
+/// Run the server in the foreground with the given options
+fn serve_command(args: &[&str]) -> Result<()> {
+    for arg in args {
+        if *arg != "--takeover" {
+            bail!("Unknown serve option {}\n\n{}", arg, USAGE);
+        }
+    }
+
+    let server = server_binary();
+    let status = std::process::Command::new(&server)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}", server.display()))?;
+    if !status.success() {
+        bail!("{} exited with {}", SERVER_BINARY, status);
+    }
+    Ok(())
+}
+
+/// `chatsafe-server` installed next to this binary, else the one on `PATH`
+fn server_binary() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(SERVER_BINARY)))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(SERVER_BINARY))
+}
+
+/// Local server address from the configuration
+fn default_base_url() -> Result<String> {
+    Ok(format!(
+        "http://127.0.0.1:{}",
+        ConfigLoader::load(None)?.server.port
+    ))
+}
+
+/// Load the registry with the configured model directory applied
+fn load_registry() -> Result<ModelRegistry> {
+    let config = ConfigLoader::load(None)?;
+    let mut registry = ModelRegistry::load_defaults()?;
+    registry.set_model_dir(config.model_dir());
+    Ok(registry)
+}
+
+fn models_command(args: &[&str]) -> Result<()> {
+    let registry = load_registry()?;
+    let store = registry.model_store();
+
+    match args {
+        ["list"] => list_models(&registry, &store),
+        ["import", id, file] => {
+            registry.get_model(id)?;
+            let digest = store.import(id, &PathBuf::from(file))?;
+            println!("Imported {} as sha256:{}", id, digest);
+            Ok(())
+        }
+        ["pull", id] => pull_model(&registry, id),
+        ["gc"] => run_gc(&store, false),
+        ["gc", "--dry-run"] => run_gc(&store, true),
+        _ => Err(anyhow!("Unknown models command\n\n{}", USAGE)),
+    }
+}
+
+fn list_models(registry: &ModelRegistry, store: &ModelStore) -> Result<()> {
+    let manifest = store.load_manifest()?;
+    let mut ids = registry.list_models();
+    ids.sort();
+
+    for id in ids {
+        let path = registry.get_model_path(&id)?;
+        let location = match manifest.models.get(&id) {
+            Some(digest) => format!("sha256:{}", digest),
+            None => path.display().to_string(),
+        };
+        let status = if path.exists() { "present" } else { "missing" };
+        println!("{:<40} {:<8} {}", id, status, location);
+    }
+    Ok(())
+}
+
+/// Download a model, printing progress about once a second
+fn pull_model(registry: &ModelRegistry, id: &str) -> Result<()> {
+    let path = registry.get_model_path(id)?;
+    if path.exists() {
+        println!("{} is already present at {}", id, path.display());
+        return Ok(());
+    }
+
+    let downloader = ModelDownloader::from_env()?;
+    let mut last_report: Option<Instant> = None;
+    let report = |progress: DownloadProgress| {
+        if progress.resumed_from > 0 && last_report.is_none() {
+            println!(
+                "Resuming at {:.1} MB",
+                progress.resumed_from as f64 / BYTES_PER_MB
+            );
+        }
+        let finished = progress.total == Some(progress.downloaded);
+        if finished || last_report.is_none_or(|at| at.elapsed() >= PULL_PROGRESS_INTERVAL) {
+            last_report = Some(Instant::now());
+            let downloaded = progress.downloaded as f64 / BYTES_PER_MB;
+            match progress.total {
+                Some(total) => println!(
+                    "{:.1} / {:.1} MB ({:.0}%)",
+                    downloaded,
+                    total as f64 / BYTES_PER_MB,
+                    progress.downloaded as f64 * 100.0 / total.max(1) as f64
+                ),
+                None => println!("{:.1} MB", downloaded),
+            }
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    let pulled = runtime.block_on(downloader.pull(registry, id, report))?;
+
+    let check = if pulled.verified {
+        "verified"
+    } else {
+        "not verified; the registry has no sha256 for it"
+    };
+    println!("Pulled {} as sha256:{} ({})", id, pulled.digest, check);
+    Ok(())
+}
+
+fn run_gc(store: &ModelStore, dry_run: bool) -> Result<()> {
+    let report = store.gc(dry_run)?;
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+
+    for digest in &report.removed {
+        println!("{} sha256:{}", verb, digest);
+    }
+    println!(
+        "{} {} blob(s), {:.1} MB",
+        verb,
+        report.removed.len(),
+        report.freed_bytes as f64 / BYTES_PER_MB
+    );
+    Ok(())
+}
+
+fn data_command(args: &[&str]) -> Result<()> {
+    let dry_run = match args {
+        ["migrate"] => false,
+        ["migrate", "--dry-run"] => true,
+        _ => bail!("Unknown data command\n\n{}", USAGE),
+    };
+    let config = ConfigLoader::load(None)?;
+    let legacy = paths::legacy_data_dir().context("Cannot determine home directory")?;
+    if !paths::needs_migration(&legacy, &config.data_dir) {
+        println!("Nothing to migrate into {}", config.data_dir.display());
+        return Ok(());
+    }
+
+    let report = paths::migrate(&legacy, &config.data_dir, dry_run)?;
+    let verb = if dry_run { "Would move" } else { "Moved" };
+    for path in &report.moved {
+        println!("{} {}", verb, path.display());
+    }
+    for path in &report.skipped {
+        println!(
+            "Kept {} (already in {})",
+            path.display(),
+            config.data_dir.display()
+        );
+    }
+    println!(
+        "{} {} item(s) from {} to {}",
+        verb,
+        report.moved.len(),
+        legacy.display(),
+        config.data_dir.display()
+    );
+    Ok(())
+}
+
+/// Outcome of one replayed request
+struct ReplayResult {
+    index: usize,
+    status: Option<u16>,
+    first_byte_ms: u64,
+    total_ms: u64,
+}
+
+fn replay_command(args: &[&str]) -> Result<()> {
+    let (file, options) = match args {
+        [file, options @ ..] => (PathBuf::from(file), options),
+        [] => bail!("Missing replay file\n\n{}", USAGE),
+    };
+
+    let mut base_url = None;
+    let mut pacing = true;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--url" => base_url = Some(options.next().context("--url needs a value")?.to_string()),
+            "--no-pacing" => pacing = false,
+            other => bail!("Unknown replay option {}\n\n{}", other, USAGE),
+        }
+    }
+    let base_url = match base_url {
+        Some(url) => url,
+        None => default_base_url()?,
+    };
+
+    let contents = std::fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let envelopes = contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str::<ReplayEnvelope>(line)
+                .with_context(|| format!("Invalid envelope on line {}", i + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let results = runtime.block_on(replay(envelopes, &base_url, pacing));
+    print_replay_report(&results);
+    Ok(())
+}
+
+/// Re-issue envelopes, starting each at its recorded offset when pacing
+async fn replay(envelopes: Vec<ReplayEnvelope>, base_url: &str, pacing: bool) -> Vec<ReplayResult> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+    let first_offset = envelopes.first().map(|e| e.offset_ms).unwrap_or(0);
+    let started = Instant::now();
+
+    let mut tasks = Vec::with_capacity(envelopes.len());
+    for (index, envelope) in envelopes.into_iter().enumerate() {
+        if pacing {
+            let due = Duration::from_millis(envelope.offset_ms.saturating_sub(first_offset));
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let client = client.clone();
+        let url = url.clone();
+        tasks.push(tokio::spawn(async move {
+            replay_one(&client, &url, index, &envelope).await
+        }));
+    }
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        if let Ok(result) = task.await {
+            results.push(result);
+        }
+    }
+    results
+}
+
+async fn replay_one(
+    client: &reqwest::Client,
+    url: &str,
+    index: usize,
+    envelope: &ReplayEnvelope,
+) -> ReplayResult {
+    let started = Instant::now();
+    let mut result = ReplayResult {
+        index,
+        status: None,
+        first_byte_ms: 0,
+        total_ms: 0,
+    };
+
+    if let Ok(mut response) = client
+        .post(url)
+        .json(&envelope.to_request_body())
+        .send()
+        .await
+    {
+        result.status = Some(response.status().as_u16());
+        let mut first_chunk = true;
+        while let Ok(Some(_)) = response.chunk().await {
+            if first_chunk {
+                result.first_byte_ms = started.elapsed().as_millis() as u64;
+                first_chunk = false;
+            }
+        }
+    }
+    result.total_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+fn print_replay_report(results: &[ReplayResult]) {
+    for r in results {
+        let status = r
+            .status
+            .map_or_else(|| "error".to_string(), |s| s.to_string());
+        println!(
+            "#{:<4} {:<6} first byte {:>6} ms  total {:>6} ms",
+            r.index + 1,
+            status,
+            r.first_byte_ms,
+            r.total_ms
+        );
+    }
+
+    let mut totals: Vec<u64> = results.iter().map(|r| r.total_ms).collect();
+    totals.sort_unstable();
+    let failed = results
+        .iter()
+        .filter(|r| !matches!(r.status, Some(200..=299)))
+        .count();
+    let percentile =
+        |p: usize| totals.get((totals.len() * p / 100).min(totals.len().saturating_sub(1)));
+
+    println!(
+        "{} request(s), {} failed, p50 {} ms, p95 {} ms",
+        results.len(),
+        failed,
+        percentile(50).copied().unwrap_or(0),
+        percentile(95).copied().unwrap_or(0)
+    );
+}
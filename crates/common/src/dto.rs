@@ -1,5 +1,8 @@
 use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
 
 // Constants for validation
 const MAX_TOKENS_LIMIT: usize = 4096;
@@ -8,21 +11,48 @@ const TEMPERATURE_MIN: f32 = 0.0;
 const TEMPERATURE_MAX: f32 = 2.0;
 const TOP_P_MIN: f32 = 0.0;
 const TOP_P_MAX: f32 = 1.0;
+const MAX_REQUEST_STOP_SEQUENCES: usize = 4;
+const MAX_STOP_SEQUENCES: usize = 16;
+const MESSAGE_MAX_CHARS: usize = 100_000;
+/// UTC-12:00 to UTC+14:00 covers every real timezone
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+const MAX_LOCALE_TAG_LEN: usize = 35;
+const MAX_TOOLS: usize = 128;
+const MAX_FUNCTION_NAME_LEN: usize = 64;
+const FUNCTION_TOOL_TYPE: &str = "function";
+/// OpenAI's limit on alternatives per token
+const MAX_TOP_LOGPROBS: usize = 20;
+/// OpenAI's limit on inputs per embeddings request
+const MAX_EMBEDDING_INPUTS: usize = 2048;
+const FLOAT_ENCODING: &str = "float";
+/// Largest base64 image accepted in a message, about 15 MB decoded
+pub const MAX_IMAGE_BASE64_LEN: usize = 20 * 1024 * 1024;
+pub const MAX_IMAGES_PER_REQUEST: usize = 8;
+/// Largest GBNF grammar accepted in a request
+const MAX_GRAMMAR_BYTES: usize = 64 * 1024;
+/// Lowest DRY base llama.cpp accepts; the penalty grows as base^length
+const DRY_BASE_MIN: f32 = 1.0;
 
 /// Message role enum for strict validation
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
+    /// Instructions; newer OpenAI SDKs send these as `developer`
+    #[serde(alias = "developer")]
     System,
     User,
     Assistant,
+    /// Result of a tool/function call fed back to the model
+    #[serde(alias = "function", alias = "ipython")]
+    Tool,
 }
 
 impl From<String> for Role {
     fn from(s: String) -> Self {
         match s.to_lowercase().as_str() {
-            "system" => Role::System,
+            "system" | "developer" => Role::System,
             "assistant" => Role::Assistant,
+            "tool" | "function" | "ipython" => Role::Tool,
             _ => Role::User, // Default to user for unknown roles
         }
     }
@@ -34,56 +64,578 @@ impl std::fmt::Display for Role {
             Role::System => write!(f, "system"),
             Role::User => write!(f, "user"),
             Role::Assistant => write!(f, "assistant"),
+            Role::Tool => write!(f, "tool"),
         }
     }
 }
 
 /// Chat message with role and content
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// `content` may also arrive as an array of OpenAI content parts; text parts
+/// are joined into `content` and image parts go to `images`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(try_from = "WireMessage")]
 pub struct Message {
     pub role: Role,
+    /// Empty (sent as `null` by OpenAI clients) on assistant turns that only call tools
+    #[schema(schema_with = message_content_schema)]
     pub content: String,
+    /// Calls made by an assistant turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Call a tool message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Images for a vision model, in the order they were sent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageData>,
+}
+
+impl Message {
+    /// A plain text turn, without tool calls or images
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+            images: Vec::new(),
+        }
+    }
+}
+
+/// `content` as clients may send it: text, or OpenAI content parts
+fn message_content_schema() -> utoipa::openapi::Schema {
+    use utoipa::openapi::schema::{
+        ArrayBuilder, ObjectBuilder, OneOfBuilder, Schema, SchemaType, Type,
+    };
+
+    let string = || ObjectBuilder::new().schema_type(Type::String);
+    let part = |kind: &str| string().enum_values(Some([kind]));
+    let text = ObjectBuilder::new()
+        .property("type", part("text"))
+        .property("text", string())
+        .required("type")
+        .required("text");
+    let url = string().description(Some(
+        "data:image/...;base64,... URL; remote URLs are not fetched",
+    ));
+    let image = ObjectBuilder::new()
+        .property("type", part("image_url"))
+        .property(
+            "image_url",
+            ObjectBuilder::new().property("url", url).required("url"),
+        )
+        .required("type")
+        .required("image_url");
+    let content = OneOfBuilder::new()
+        .item(
+            ObjectBuilder::new()
+                .schema_type(SchemaType::from_iter([Type::String, Type::Null]))
+                .max_length(Some(MESSAGE_MAX_CHARS)),
+        )
+        .item(ArrayBuilder::new().items(OneOfBuilder::new().item(text).item(image)))
+        .description(Some(
+            "Empty or null on assistant turns that only call tools; an array of parts may carry images for vision models",
+        ))
+        .build();
+    Schema::OneOf(content)
+}
+
+/// Image attached to a message, as base64 file contents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ImageData {
+    /// `image/png`, `image/jpeg`, ...
+    pub media_type: String,
+    pub data: String,
+}
+
+impl ImageData {
+    /// Parse a `data:image/png;base64,...` URL
+    pub fn from_data_url(url: &str) -> std::result::Result<Self, String> {
+        let Some(rest) = url.strip_prefix("data:") else {
+            return Err(
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    "Remote image URLs are not fetched; send the image as a data: URL".to_string()
+                } else {
+                    "image_url must be a data:image/...;base64 URL".to_string()
+                },
+            );
+        };
+        let (media_type, data) = rest
+            .split_once(";base64,")
+            .ok_or_else(|| "Image data URLs must be base64 encoded".to_string())?;
+        if !media_type.starts_with("image/") {
+            return Err(format!("Unsupported image type {:?}", media_type));
+        }
+        Ok(Self {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        })
+    }
+}
+
+/// A message as clients send it, before content parts are split up
+#[derive(Deserialize)]
+struct WireMessage {
+    role: Role,
+    #[serde(default)]
+    content: Option<serde_json::Value>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default)]
+    tool_call_id: Option<String>,
+    #[serde(default)]
+    images: Vec<ImageData>,
+}
+
+/// One entry of an array-valued `content`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// `detail` and other hints are accepted and ignored
+#[derive(Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl TryFrom<WireMessage> for Message {
+    type Error = String;
+
+    fn try_from(wire: WireMessage) -> std::result::Result<Self, String> {
+        let mut images = wire.images;
+        let content = match wire.content {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(text)) => text,
+            Some(serde_json::Value::Array(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    let part: ContentPart = serde_json::from_value(part)
+                        .map_err(|e| format!("Unsupported content part: {}", e))?;
+                    match part {
+                        ContentPart::Text { text } => texts.push(text),
+                        ContentPart::ImageUrl { image_url } => {
+                            images.push(ImageData::from_data_url(&image_url.url)?)
+                        }
+                    }
+                }
+                texts.join("\n")
+            }
+            Some(other) => {
+                return Err(format!(
+                    "Message content must be a string or an array of parts, not {}",
+                    other
+                ))
+            }
+        };
+        Ok(Self {
+            role: wire.role,
+            content,
+            tool_calls: wire.tool_calls,
+            tool_call_id: wire.tool_call_id,
+            images,
+        })
+    }
 }
 
 impl Message {
     /// Validate message
     pub fn validate(&self) -> Result<()> {
-        if self.content.is_empty() {
+        let calls_tools = self
+            .tool_calls
+            .as_ref()
+            .is_some_and(|calls| !calls.is_empty());
+        if self.content.is_empty() && !calls_tools && self.images.is_empty() {
             return Err(Error::BadRequest("Message content cannot be empty".into()));
         }
-        if self.content.len() > 100_000 {
-            return Err(Error::BadRequest(
-                "Message content too long (max 100k chars)".into(),
-            ));
+        // Coarse guard against abuse; the prompt's token count is checked
+        // against the model's context by the runtime
+        let chars = self.content.chars().count();
+        if chars > MESSAGE_MAX_CHARS {
+            return Err(Error::BadRequest(format!(
+                "Message content too long: {} chars (max {})",
+                chars, MESSAGE_MAX_CHARS
+            )));
+        }
+        for image in &self.images {
+            if image.data.len() > MAX_IMAGE_BASE64_LEN {
+                return Err(Error::BadRequest(format!(
+                    "Image too large: {} base64 bytes (max {})",
+                    image.data.len(),
+                    MAX_IMAGE_BASE64_LEN
+                )));
+            }
+            let is_base64 = image
+                .data
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='));
+            if image.data.is_empty() || !is_base64 {
+                return Err(Error::BadRequest("Image data is not valid base64".into()));
+            }
         }
         Ok(())
     }
 }
 
+/// `"text"` or `["text", ...]`
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<OneOrMany>::deserialize(deserializer)?.map(|value| match value {
+            OneOrMany::One(text) => vec![text],
+            OneOrMany::Many(texts) => texts,
+        }),
+    )
+}
+
 /// Request for chat completion with validation
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
 pub struct ChatCompletionRequest {
     pub model: Option<String>,
     pub messages: Vec<Message>,
+    #[schema(minimum = 0, maximum = 2)]
     pub temperature: Option<f32>,
+    #[schema(minimum = 1, maximum = 4096)]
     pub max_tokens: Option<usize>,
     pub stream: Option<bool>,
+    #[schema(minimum = 0, maximum = 1)]
     pub top_p: Option<f32>,
     pub top_k: Option<i32>,
     pub repeat_penalty: Option<f32>,
+    /// Repeat window, DRY and XTC; unset ones keep the model's defaults
+    #[serde(flatten)]
+    pub sampler: SamplerSettings,
+    /// Set to false to keep this prompt out of the backend's prompt cache
+    pub cache: Option<bool>,
+    /// Extra stop sequences added to the template and model ones; one
+    /// string or an array, as in OpenAI's API
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schema(max_items = 4)]
+    pub stop: Option<Vec<String>>,
+    /// How to end a response cut off by `max_tokens`
+    #[serde(default)]
+    pub finish: Option<FinishMode>,
+    /// Prompt sent to the backend as is, bypassing the chat template; only
+    /// accepted when the server allows it
+    #[serde(default)]
+    pub prompt_override: Option<RawPrompt>,
+    /// Per-request control of the date/time added to the system prompt
+    #[serde(default)]
+    pub date_context: Option<DateContext>,
+    /// Functions the model may call
+    #[serde(default)]
+    #[schema(max_items = 128)]
+    pub tools: Option<Vec<Tool>>,
+    /// Whether and which tool the model must call; `auto` when tools are given
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// Constrain the output to JSON, optionally matching a schema
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// GBNF grammar the output must follow, passed to llama-server
+    #[serde(default)]
+    #[schema(max_length = 65536)]
+    pub grammar: Option<String>,
+    /// Return the log probability of each generated token
+    #[serde(default)]
+    pub logprobs: Option<bool>,
+    /// Most likely alternatives to return per token, with `logprobs`
+    #[serde(default)]
+    #[schema(maximum = 20)]
+    pub top_logprobs: Option<usize>,
+    /// Content profile from `server.content_profiles` (e.g. `kid-safe`)
+    #[serde(default)]
+    pub content_profile: Option<String>,
+    /// Language the response must be written in
+    #[serde(default)]
+    pub language: Option<ResponseLanguage>,
+}
+
+/// `language` of a chat completion request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ResponseLanguage {
+    /// ISO 639-1 or 639-3 code, or a locale tag such as `de-DE`
+    pub code: String,
+    /// Detect the response's language and regenerate it once on a mismatch
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// `response_format`, as in OpenAI's API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free text, the default
+    Text,
+    /// Any JSON object
+    JsonObject,
+    /// JSON matching `json_schema.schema`
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema the output must match; any JSON object when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub schema: Option<serde_json::Value>,
+    /// Accepted for compatibility; output is always constrained to the schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl ResponseFormat {
+    /// Whether output must be JSON
+    pub fn is_json(&self) -> bool {
+        !matches!(self, ResponseFormat::Text)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let ResponseFormat::JsonSchema { json_schema } = self else {
+            return Ok(());
+        };
+        if !is_function_name(&json_schema.name) {
+            return Err(Error::BadRequest(format!(
+                "Invalid response_format.json_schema.name {:?}; use up to {} letters, digits, '_' or '-'",
+                json_schema.name, MAX_FUNCTION_NAME_LEN
+            )));
+        }
+        if json_schema
+            .schema
+            .as_ref()
+            .is_some_and(|schema| !schema.is_object())
+        {
+            return Err(Error::BadRequest(
+                "response_format.json_schema.schema must be a JSON Schema object".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Tool offered to the model; only functions are supported
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    #[schema(example = "function")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+/// Function the model may call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FunctionDefinition {
+    #[schema(pattern = "^[A-Za-z0-9_-]{1,64}$")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema of the arguments object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub parameters: Option<serde_json::Value>,
+}
+
+impl Tool {
+    pub fn validate(&self) -> Result<()> {
+        if self.kind != FUNCTION_TOOL_TYPE {
+            return Err(Error::BadRequest(format!(
+                "Unsupported tool type {:?}; only \"function\" tools are supported",
+                self.kind
+            )));
+        }
+        let name = &self.function.name;
+        if !is_function_name(name) {
+            return Err(Error::BadRequest(format!(
+                "Invalid function name {:?}; use up to {} letters, digits, '_' or '-'",
+                name, MAX_FUNCTION_NAME_LEN
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Function and schema names: 1-64 letters, digits, `_` or `-`
+fn is_function_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_FUNCTION_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// `tool_choice`: a mode, or `{"type": "function", "function": {"name": ...}}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function {
+        #[serde(rename = "type")]
+        kind: String,
+        function: FunctionName,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    /// Never call a tool
+    None,
+    /// Call tools when the model decides to
+    Auto,
+    /// Call at least one tool
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+impl ToolChoice {
+    /// Function the model is told to call, if one is named
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            ToolChoice::Function { function, .. } => Some(&function.name),
+            ToolChoice::Mode(_) => None,
+        }
+    }
+}
+
+/// Call made by the model, as returned to clients and sent back in history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+impl ToolCall {
+    /// A function call with a fresh ID
+    pub fn function(name: String, arguments: String) -> Self {
+        Self {
+            id: format!("call_{}", uuid::Uuid::new_v4().simple()),
+            kind: FUNCTION_TOOL_TYPE.to_string(),
+            function: FunctionCall { name, arguments },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FunctionCall {
+    pub name: String,
+    /// Arguments object encoded as a JSON string
+    pub arguments: String,
+}
+
+/// Current date, time and locale told to the model in the system prompt
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DateContext {
+    /// Add the context to this request; `server.date_context` when omitted
+    pub enabled: Option<bool>,
+    /// Client's offset from UTC, e.g. 120 for UTC+02:00; the server's when omitted
+    #[schema(minimum = -840, maximum = 840)]
+    pub utc_offset_minutes: Option<i32>,
+    /// Locale tag such as `de-DE`; `server.locale` when omitted
+    #[schema(max_length = 35)]
+    pub locale: Option<String>,
+}
+
+impl DateContext {
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .utc_offset_minutes
+            .is_some_and(|offset| offset.abs() > MAX_UTC_OFFSET_MINUTES)
+        {
+            return Err(Error::BadRequest(format!(
+                "date_context.utc_offset_minutes must be within ±{}",
+                MAX_UTC_OFFSET_MINUTES
+            )));
+        }
+        // The tag goes into the prompt verbatim, so keep it to tag characters
+        if let Some(locale) = &self.locale {
+            let valid = !locale.is_empty()
+                && locale.len() <= MAX_LOCALE_TAG_LEN
+                && locale
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(Error::BadRequest(format!(
+                    "Invalid date_context.locale {:?}; use a tag such as en-US",
+                    locale
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prompt text or token IDs passed straight to llama-server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum RawPrompt {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+impl RawPrompt {
+    /// Token count, estimated for text
+    pub fn estimated_tokens(&self) -> usize {
+        match self {
+            RawPrompt::Text(text) => crate::text::estimate_tokens(text),
+            RawPrompt::Tokens(tokens) => tokens.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            RawPrompt::Text(text) => text.is_empty(),
+            RawPrompt::Tokens(tokens) => tokens.is_empty(),
+        }
+    }
 }
 
 impl ChatCompletionRequest {
     /// Validate the request
     pub fn validate(&self) -> Result<()> {
-        // Validate messages
-        if self.messages.is_empty() {
-            return Err(Error::BadRequest("Messages array cannot be empty".into()));
+        // Validate messages; a prompt override stands in for them
+        match &self.prompt_override {
+            Some(prompt) if prompt.is_empty() => {
+                return Err(Error::BadRequest("prompt_override cannot be empty".into()));
+            }
+            Some(_) => {}
+            None if self.messages.is_empty() => {
+                return Err(Error::BadRequest("Messages array cannot be empty".into()));
+            }
+            None => {}
         }
 
         for msg in &self.messages {
             msg.validate()?;
         }
+        let images: usize = self.messages.iter().map(|msg| msg.images.len()).sum();
+        if images > MAX_IMAGES_PER_REQUEST {
+            return Err(Error::BadRequest(format!(
+                "Too many images: {} (max {})",
+                images, MAX_IMAGES_PER_REQUEST
+            )));
+        }
 
         // Validate temperature
         if let Some(temp) = self.temperature {
@@ -131,12 +683,110 @@ impl ChatCompletionRequest {
             }
         }
 
+        self.sampler.validate()?;
+
+        // Validate stop sequences
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_REQUEST_STOP_SEQUENCES {
+                return Err(Error::BadRequest(format!(
+                    "At most {} stop sequences are allowed",
+                    MAX_REQUEST_STOP_SEQUENCES
+                )));
+            }
+            if stop.iter().any(|s| s.is_empty()) {
+                return Err(Error::BadRequest("Stop sequences cannot be empty".into()));
+            }
+        }
+
+        if let Some(date_context) = &self.date_context {
+            date_context.validate()?;
+        }
+
+        self.validate_tools()?;
+
+        if let Some(format) = &self.response_format {
+            format.validate()?;
+            // A grammar for JSON would keep the model from writing tool calls
+            if format.is_json() && self.tools.as_ref().is_some_and(|tools| !tools.is_empty()) {
+                return Err(Error::BadRequest(
+                    "response_format cannot be combined with tools".into(),
+                ));
+            }
+        }
+
+        if let Some(grammar) = &self.grammar {
+            if grammar.trim().is_empty() {
+                return Err(Error::BadRequest("grammar cannot be empty".into()));
+            }
+            if grammar.len() > MAX_GRAMMAR_BYTES {
+                return Err(Error::BadRequest(format!(
+                    "grammar exceeds {} bytes",
+                    MAX_GRAMMAR_BYTES
+                )));
+            }
+            // Only one grammar can constrain sampling
+            if self.response_format.as_ref().is_some_and(|f| f.is_json()) {
+                return Err(Error::BadRequest(
+                    "grammar cannot be combined with response_format".into(),
+                ));
+            }
+            if self.tools.as_ref().is_some_and(|tools| !tools.is_empty()) {
+                return Err(Error::BadRequest(
+                    "grammar cannot be combined with tools".into(),
+                ));
+            }
+        }
+
+        if let Some(top) = self.top_logprobs {
+            if top > MAX_TOP_LOGPROBS {
+                return Err(Error::BadRequest(format!(
+                    "top_logprobs must be between 0 and {}",
+                    MAX_TOP_LOGPROBS
+                )));
+            }
+            if self.logprobs != Some(true) {
+                return Err(Error::BadRequest(
+                    "top_logprobs requires logprobs to be true".into(),
+                ));
+            }
+        }
+
         Ok(())
     }
+
+    fn validate_tools(&self) -> Result<()> {
+        let tools = self.tools.as_deref().unwrap_or_default();
+        if tools.len() > MAX_TOOLS {
+            return Err(Error::BadRequest(format!(
+                "At most {} tools are allowed",
+                MAX_TOOLS
+            )));
+        }
+        for tool in tools {
+            tool.validate()?;
+        }
+
+        match &self.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::Required)) if tools.is_empty() => Err(
+                Error::BadRequest("tool_choice \"required\" needs tools".into()),
+            ),
+            Some(choice @ ToolChoice::Function { kind, .. }) => {
+                let name = choice.function_name().unwrap_or_default();
+                if kind != FUNCTION_TOOL_TYPE || !tools.iter().any(|t| t.function.name == name) {
+                    return Err(Error::BadRequest(format!(
+                        "tool_choice names function {:?}, which is not in tools",
+                        name
+                    )));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Response for chat completion
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ChatCompletionResponse {
     pub id: String,
     pub object: String,
@@ -144,35 +794,236 @@ pub struct ChatCompletionResponse {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
+    /// Guard model verdicts, when a guardrail checked the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guardrail: Option<GuardrailVerdicts>,
+}
+
+/// Guard model verdicts on a completion's prompt and response
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct GuardrailVerdicts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<GuardVerdict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<GuardVerdict>,
+    /// The first response was flagged and generated again under the
+    /// request's content profile
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub regenerated: bool,
+}
+
+/// One guard model classification
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct GuardVerdict {
+    pub safe: bool,
+    /// Violated categories as the guard model names them, e.g. `S1`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    /// Whether the verdict stopped the prompt or withheld the response
+    pub blocked: bool,
 }
 
 /// Choice in completion response
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Choice {
     pub index: usize,
     pub message: Message,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
     pub finish_reason: Option<FinishReason>,
 }
 
+/// `logprobs` of a choice, as in OpenAI's API
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct ChoiceLogprobs {
+    pub content: Vec<TokenLogprob>,
+}
+
+/// Log probability of one generated token and its most likely alternatives
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    /// UTF-8 bytes of the token, which may be part of a character
+    pub bytes: Vec<u8>,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// An alternative considered for a token
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
 /// Finish reason enum
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     Stop,
     Length,
     ContentFilter,
+    /// The model called one or more tools
+    ToolCalls,
+    /// Output did not match the requested `response_format`
+    InvalidFormat,
     Cancelled,
     Error,
 }
 
 /// Token usage statistics
-#[derive(Debug, Clone, Serialize, Default)]
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
 pub struct Usage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
 }
 
+/// Request for `/v1/embeddings`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingRequest {
+    pub model: Option<String>,
+    pub input: EmbeddingInput,
+    /// Only `float` is supported
+    pub encoding_format: Option<String>,
+}
+
+/// One text or a batch of texts to embed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(text) => vec![text],
+            EmbeddingInput::Many(texts) => texts,
+        }
+    }
+
+    fn texts(&self) -> &[String] {
+        match self {
+            EmbeddingInput::One(text) => std::slice::from_ref(text),
+            EmbeddingInput::Many(texts) => texts,
+        }
+    }
+}
+
+impl EmbeddingRequest {
+    pub fn validate(&self) -> Result<()> {
+        let texts = self.input.texts();
+        if texts.is_empty() {
+            return Err(Error::BadRequest("input cannot be empty".into()));
+        }
+        if texts.len() > MAX_EMBEDDING_INPUTS {
+            return Err(Error::BadRequest(format!(
+                "At most {} inputs per request",
+                MAX_EMBEDDING_INPUTS
+            )));
+        }
+        for (index, text) in texts.iter().enumerate() {
+            if text.is_empty() {
+                return Err(Error::BadRequest(format!("input[{}] is empty", index)));
+            }
+            if text.chars().count() > MESSAGE_MAX_CHARS {
+                return Err(Error::BadRequest(format!(
+                    "input[{}] exceeds {} characters",
+                    index, MESSAGE_MAX_CHARS
+                )));
+            }
+        }
+        match self.encoding_format.as_deref() {
+            None | Some(FLOAT_ENCODING) => Ok(()),
+            Some(other) => Err(Error::BadRequest(format!(
+                "Unsupported encoding_format {:?}; use float",
+                other
+            ))),
+        }
+    }
+}
+
+/// Response for `/v1/embeddings`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+/// Vector for the input at `index`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// Token usage of an embeddings request
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Backend details known only once generation ends, delivered beside the stream
+#[derive(Debug, Clone, Serialize, Default, PartialEq)]
+pub struct GenerationMetadata {
+    /// llama-server slot that served the request
+    pub slot_id: Option<i64>,
+    /// Prompt tokens the backend evaluated, including cached ones
+    pub prompt_tokens: Option<usize>,
+    /// Prompt tokens reused from the backend's prompt cache
+    pub cached_prompt_tokens: Option<usize>,
+    pub completion_tokens: Option<usize>,
+    pub prompt_ms: Option<f64>,
+    pub completion_ms: Option<f64>,
+    pub tokens_per_second: Option<f64>,
+    /// Role-played dialogue replaced the response with a fallback
+    pub role_pollution: bool,
+    /// Sent to the slot holding the conversation's earlier turns
+    pub slot_affinity: bool,
+    /// Oldest messages dropped so the prompt fit the context window
+    pub truncated_messages: usize,
+}
+
+/// Latency breakdown of one response, and any history dropped to fit the
+/// context window, for clients to display
+///
+/// Sent as `x-chatsafe-*` headers on non-streaming responses and as the
+/// `chatsafe` object of the final stream chunk.
+#[derive(Debug, Clone, Serialize, Default, PartialEq, ToSchema)]
+pub struct ResponseTimings {
+    /// Time from arrival until the backend took the request
+    pub queue_ms: Option<u64>,
+    /// Prompt evaluation time reported by llama-server
+    pub prompt_ms: Option<f64>,
+    /// Token generation time reported by llama-server
+    pub gen_ms: Option<f64>,
+    pub tokens_per_sec: Option<f64>,
+    /// Oldest messages left out of the prompt; absent when none were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated_messages: Option<usize>,
+}
+
+impl ResponseTimings {
+    pub fn new(queue_ms: Option<u64>, metadata: Option<&GenerationMetadata>) -> Self {
+        Self {
+            queue_ms,
+            prompt_ms: metadata.and_then(|m| m.prompt_ms),
+            gen_ms: metadata.and_then(|m| m.completion_ms),
+            tokens_per_sec: metadata.and_then(|m| m.tokens_per_second),
+            truncated_messages: metadata
+                .map(|m| m.truncated_messages)
+                .filter(|&dropped| dropped > 0),
+        }
+    }
+}
+
 /// Streaming frame for SSE
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -185,40 +1036,81 @@ pub enum StreamFrame {
     },
     /// Delta content chunk
     Delta { content: String },
+    /// Tool calls parsed from the complete response, before `Done`
+    ToolCalls { tool_calls: Vec<ToolCall> },
+    /// Log probabilities of tokens whose text is in this or a later `Delta`
+    Logprobs { content: Vec<TokenLogprob> },
     /// End of stream with usage stats
     Done {
         finish_reason: FinishReason,
         usage: Usage,
     },
+    /// Waiting for a backend slot; repeated while the position changes
+    Queued {
+        position: usize,
+        eta_ms: Option<u64>,
+    },
     /// Error during streaming
-    Error { message: String },
+    Error {
+        message: String,
+        /// Characters already streamed when generation was interrupted
+        partial_content_length: Option<usize>,
+    },
 }
 
 /// Streaming chunk for OpenAI compatibility
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ChatCompletionChunk {
     pub id: String,
     pub object: String,
     pub created: i64,
     pub model: String,
     pub choices: Vec<StreamChoice>,
+    /// Timings, on the final chunk only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chatsafe: Option<ResponseTimings>,
+    /// Place in line, on chunks with no choices sent while the request waits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<QueueStatus>,
+}
+
+/// Where a waiting request stands
+#[derive(Debug, Clone, Serialize, PartialEq, ToSchema)]
+pub struct QueueStatus {
+    /// 1 when the request is next
+    pub position: usize,
+    /// Rough wait from the token budgets ahead and recent generation speed;
+    /// absent until a generation has been timed
+    pub eta_ms: Option<u64>,
 }
 
 /// Streaming choice
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct StreamChoice {
     pub index: usize,
     pub delta: DeltaContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
     pub finish_reason: Option<FinishReason>,
 }
 
 /// Delta content for streaming
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct DeltaContent {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<Role>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// Tool call in a stream chunk; all calls arrive whole in one chunk
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(flatten)]
+    pub call: ToolCall,
 }
 
 /// Health check response
@@ -228,6 +1120,22 @@ pub struct HealthResponse {
     pub model_loaded: bool,
     pub version: String,
     pub uptime_seconds: u64,
+    /// Unix time of the last successful backend probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
+    /// Each chat model's backend, the default one first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelHealth>,
+}
+
+/// Health of one chat model's backend
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelHealth {
+    pub model: String,
+    pub status: HealthStatus,
+    pub loaded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
 }
 
 /// Health status enum
@@ -239,6 +1147,79 @@ pub enum HealthStatus {
     Unhealthy,
 }
 
+/// Repetition and sampling controls of recent llama.cpp beyond the classic
+/// ones; `None` leaves llama-server's own default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SamplerSettings {
+    /// Tokens the repeat penalty looks back over; -1 for the whole context,
+    /// 0 to disable it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<i32>,
+    /// Strength of the DRY ("don't repeat yourself") penalty; 0 disables it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_multiplier: Option<f32>,
+    /// Growth of the DRY penalty with the length of the repeated sequence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_base: Option<f32>,
+    /// Repeated sequences up to this many tokens go unpenalized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_allowed_length: Option<u32>,
+    /// Tokens DRY scans for repeats; -1 for the whole context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_penalty_last_n: Option<i32>,
+    /// Chance per token of XTC removing the most likely choices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xtc_probability: Option<f32>,
+    /// Probability above which XTC considers a token a top choice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xtc_threshold: Option<f32>,
+}
+
+impl SamplerSettings {
+    /// These settings, with unset ones taken from `defaults`
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            repeat_last_n: self.repeat_last_n.or(defaults.repeat_last_n),
+            dry_multiplier: self.dry_multiplier.or(defaults.dry_multiplier),
+            dry_base: self.dry_base.or(defaults.dry_base),
+            dry_allowed_length: self.dry_allowed_length.or(defaults.dry_allowed_length),
+            dry_penalty_last_n: self.dry_penalty_last_n.or(defaults.dry_penalty_last_n),
+            xtc_probability: self.xtc_probability.or(defaults.xtc_probability),
+            xtc_threshold: self.xtc_threshold.or(defaults.xtc_threshold),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let window = |name: &str, value: Option<i32>| match value {
+            Some(n) if n < -1 => Err(Error::BadRequest(format!(
+                "{} must be -1 (whole context) or more",
+                name
+            ))),
+            _ => Ok(()),
+        };
+        window("repeat_last_n", self.repeat_last_n)?;
+        window("dry_penalty_last_n", self.dry_penalty_last_n)?;
+        if self.dry_multiplier.is_some_and(|m| m < 0.0) {
+            return Err(Error::BadRequest("dry_multiplier must be 0 or more".into()));
+        }
+        if self.dry_base.is_some_and(|b| b < DRY_BASE_MIN) {
+            return Err(Error::BadRequest(format!(
+                "dry_base must be at least {}",
+                DRY_BASE_MIN
+            )));
+        }
+        let probability = |name: &str, value: Option<f32>| match value {
+            Some(p) if !(0.0..=1.0).contains(&p) => Err(Error::BadRequest(format!(
+                "{} must be between 0 and 1",
+                name
+            ))),
+            _ => Ok(()),
+        };
+        probability("xtc_probability", self.xtc_probability)?;
+        probability("xtc_threshold", self.xtc_threshold)
+    }
+}
+
 /// Generation parameters for runtime
 #[derive(Debug, Clone)]
 pub struct GenerationParams {
@@ -248,20 +1229,130 @@ pub struct GenerationParams {
     pub top_p: f32,
     pub top_k: i32,
     pub repeat_penalty: f32,
+    pub sampler: SamplerSettings,
     pub stop_sequences: Vec<String>,
+    /// Point after which the client no longer wants an answer
+    pub deadline: Option<Instant>,
+    /// Reuse and retain the prompt in llama-server's cache
+    pub cache_prompt: bool,
+    /// Where streamed deltas may be split
+    pub stream_boundary: StreamBoundary,
+    /// How to end a response cut off by `max_tokens`
+    pub finish: FinishMode,
+    /// Prompt to send instead of the templated messages
+    pub prompt_override: Option<RawPrompt>,
+    /// Functions described to the model
+    pub tools: Vec<Tool>,
+    pub tool_choice: Option<ToolChoice>,
+    /// Output constraint passed to the backend and checked afterwards
+    pub response_format: Option<ResponseFormat>,
+    /// GBNF grammar passed to the backend as is
+    pub grammar: Option<String>,
+    /// Values for `{{name}}` placeholders in the default system prompt
+    pub prompt_variables: HashMap<String, String>,
+    /// Alternatives per token to return log probabilities for; `None`
+    /// returns no log probabilities
+    pub top_logprobs: Option<usize>,
+    /// Neutralize dangerous markup in the response on top of the model's
+    /// post-processors
+    pub sanitize_markup: bool,
+}
+
+/// What to do with a response that hits `max_tokens` mid-sentence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishMode {
+    /// Return exactly what was generated
+    #[default]
+    Exact,
+    /// Drop the trailing partial sentence, if a complete one precedes it
+    Sentence,
+}
+
+/// Granularity at which streamed text is released to the client
+///
+/// Incomplete UTF-8 sequences are always held back; the coarser settings
+/// also wait for a full grapheme cluster or word.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamBoundary {
+    /// Emit as tokens arrive
+    #[default]
+    Token,
+    /// Never split a grapheme cluster (emoji sequences, combining marks)
+    Grapheme,
+    /// Emit whole words, up to the last whitespace
+    Word,
 }
 
 impl GenerationParams {
     /// Create from request with defaults
     pub fn from_request(req: &ChatCompletionRequest, defaults: GenerationParams) -> Self {
-        Self {
+        let mut params = Self {
             request_id: uuid::Uuid::new_v4().to_string(),
             temperature: req.temperature.unwrap_or(defaults.temperature),
             max_tokens: req.max_tokens.unwrap_or(defaults.max_tokens),
             top_p: req.top_p.unwrap_or(defaults.top_p),
             top_k: req.top_k.unwrap_or(defaults.top_k),
             repeat_penalty: req.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            sampler: req.sampler.or(defaults.sampler),
             stop_sequences: defaults.stop_sequences,
+            deadline: defaults.deadline,
+            cache_prompt: req.cache.unwrap_or(defaults.cache_prompt),
+            stream_boundary: defaults.stream_boundary,
+            finish: req.finish.unwrap_or(defaults.finish),
+            // Left to the server, which decides whether overrides are allowed
+            prompt_override: defaults.prompt_override,
+            tools: req.tools.clone().unwrap_or_default(),
+            tool_choice: req.tool_choice.clone(),
+            response_format: req.response_format.clone(),
+            grammar: req.grammar.clone(),
+            prompt_variables: defaults.prompt_variables,
+            top_logprobs: req
+                .logprobs
+                .unwrap_or(false)
+                .then(|| req.top_logprobs.unwrap_or(0)),
+            sanitize_markup: defaults.sanitize_markup,
+        };
+        if let Some(stop) = &req.stop {
+            params.add_stop_sequences(stop);
+        }
+        params
+    }
+
+    /// Append stop sequences, skipping empty ones and duplicates, up to the overall cap
+    pub fn add_stop_sequences(&mut self, extra: &[String]) {
+        for stop in extra {
+            if self.stop_sequences.len() >= MAX_STOP_SEQUENCES {
+                break;
+            }
+            if !stop.is_empty() && !self.stop_sequences.contains(stop) {
+                self.stop_sequences.push(stop.clone());
+            }
+        }
+    }
+
+    /// Tools the model may call: none when `tool_choice` is "none"
+    pub fn offered_tools(&self) -> &[Tool] {
+        match self.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::None)) => &[],
+            _ => &self.tools,
+        }
+    }
+
+    /// Time left before the deadline, `None` if the request is unbounded
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Fail with `DeadlineExceeded` if the deadline has already passed
+    pub fn check_deadline(&self, stage: &str) -> Result<()> {
+        match self.remaining() {
+            Some(remaining) if remaining.is_zero() => {
+                Err(Error::DeadlineExceeded(format!("no time left {}", stage)))
+            }
+            _ => Ok(()),
         }
     }
 }
@@ -275,11 +1366,24 @@ impl Default for GenerationParams {
             top_p: 0.9,
             top_k: 40,
             repeat_penalty: 1.15,
+            sampler: Default::default(),
             stop_sequences: vec![
                 "<|eot_id|>".to_string(),
                 "<|end_of_text|>".to_string(),
                 "<|start_header_id|>".to_string(),
             ],
+            deadline: None,
+            cache_prompt: true,
+            stream_boundary: StreamBoundary::Token,
+            finish: FinishMode::Exact,
+            prompt_override: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            response_format: None,
+            grammar: None,
+            prompt_variables: HashMap::new(),
+            top_logprobs: None,
+            sanitize_markup: false,
         }
     }
 }
@@ -1,5 +1,6 @@
 use serde::Serialize;
 use thiserror::Error;
+use utoipa::ToSchema;
 
 /// Common error type for ChatSafe with clear taxonomy
 #[derive(Error, Debug)]
@@ -20,6 +21,15 @@ pub enum Error {
     #[error("Rate limit exceeded")]
     RateLimitExceeded,
 
+    #[error("Missing or invalid bearer token")]
+    Unauthorized,
+
+    #[error("No such endpoint: {0}")]
+    RouteNotFound(String),
+
+    #[error("Method not allowed: {0}")]
+    MethodNotAllowed(String),
+
     /// Service availability errors (5xx)
     #[error("Service unavailable: {0}")]
     ServiceUnavailable(String),
@@ -27,6 +37,9 @@ pub enum Error {
     #[error("Model loading failed: {0}")]
     ModelLoadFailed(String),
 
+    #[error("Backend ran out of memory: {0}")]
+    BackendOutOfMemory(String),
+
     #[error("Runtime not ready")]
     RuntimeNotReady,
 
@@ -34,6 +47,9 @@ pub enum Error {
     #[error("Request timeout after {0} seconds")]
     Timeout(u64),
 
+    #[error("Request deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
     #[error("Request cancelled: {0}")]
     Cancelled(String),
 
@@ -72,14 +88,19 @@ impl Error {
             Error::ModelNotFound(_) => 404,
             Error::InvalidModel(_) => 400,
             Error::RateLimitExceeded => 429,
+            Error::Unauthorized => 401,
+            Error::RouteNotFound(_) => 404,
+            Error::MethodNotAllowed(_) => 405,
 
             // 5xx Server Errors
             Error::ServiceUnavailable(_) => 503,
             Error::ModelLoadFailed(_) => 503,
+            Error::BackendOutOfMemory(_) => 503,
             Error::RuntimeNotReady => 503,
 
             // Timeout/Cancellation
             Error::Timeout(_) => 408,
+            Error::DeadlineExceeded(_) => 504,
             Error::Cancelled(_) => 499,
             Error::UserCancelled => 499,
 
@@ -101,10 +122,15 @@ impl Error {
             Error::ModelNotFound(_) => "model_not_found",
             Error::InvalidModel(_) => "invalid_model",
             Error::RateLimitExceeded => "rate_limit",
+            Error::Unauthorized => "unauthorized",
+            Error::RouteNotFound(_) => "route_not_found",
+            Error::MethodNotAllowed(_) => "method_not_allowed",
             Error::ServiceUnavailable(_) => "service_unavailable",
             Error::ModelLoadFailed(_) => "model_load_failed",
+            Error::BackendOutOfMemory(_) => "backend_out_of_memory",
             Error::RuntimeNotReady => "runtime_not_ready",
             Error::Timeout(_) => "timeout",
+            Error::DeadlineExceeded(_) => "deadline_exceeded",
             Error::Cancelled(_) => "cancelled",
             Error::UserCancelled => "user_cancelled",
             Error::Internal(_) => "internal",
@@ -129,14 +155,14 @@ impl Error {
 }
 
 /// Error response for HTTP API
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ErrorResponse {
     pub error: ErrorDetail,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub request_id: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ErrorDetail {
     pub message: String,
     pub r#type: String,
@@ -147,7 +173,7 @@ impl From<&Error> for ErrorResponse {
     fn from(err: &Error) -> Self {
         ErrorResponse {
             error: ErrorDetail {
-                message: err.to_string(),
+                message: crate::i18n::localize(err, crate::i18n::current_locale()),
                 r#type: err.error_type().to_string(),
                 code: err.status_code(),
             },
@@ -156,4 +182,63 @@ impl From<&Error> for ErrorResponse {
     }
 }
 
+/// OpenAI's error object, for SDKs that branch on its exact fields
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct OpenAiErrorResponse {
+    pub error: OpenAiErrorDetail,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct OpenAiErrorDetail {
+    pub message: String,
+    pub r#type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+impl OpenAiErrorResponse {
+    /// For responses that did not come from a ChatSafe `Error`, such as
+    /// axum's rejection of a malformed body
+    pub fn from_status(status: u16, message: String) -> Self {
+        OpenAiErrorResponse {
+            error: OpenAiErrorDetail {
+                message,
+                r#type: openai_error_type(status).to_string(),
+                param: None,
+                code: None,
+            },
+        }
+    }
+}
+
+impl From<&ErrorDetail> for OpenAiErrorResponse {
+    /// `code` carries ChatSafe's error type, renamed where OpenAI has its own
+    fn from(detail: &ErrorDetail) -> Self {
+        let code = match detail.r#type.as_str() {
+            "rate_limit" => "rate_limit_exceeded",
+            "unauthorized" => "invalid_api_key",
+            other => other,
+        };
+        let param = matches!(detail.r#type.as_str(), "model_not_found" | "invalid_model")
+            .then(|| "model".to_string());
+        OpenAiErrorResponse {
+            error: OpenAiErrorDetail {
+                message: detail.message.clone(),
+                r#type: openai_error_type(detail.code).to_string(),
+                param,
+                code: Some(code.to_string()),
+            },
+        }
+    }
+}
+
+/// OpenAI's `type` for a status code
+fn openai_error_type(status: u16) -> &'static str {
+    match status {
+        429 => "requests",
+        500.. => "server_error",
+        _ => "invalid_request_error",
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,204 @@
+//! Localized user-facing error messages
+//!
+//! Error responses are shown to desktop users, so the message prefix for each
+//! error type is looked up in a small built-in catalog for the configured
+//! locale. Variant details (validation text, model IDs) are inserted as-is, and
+//! anything missing from a catalog falls back to English.
+
+use crate::error::Error;
+use std::sync::OnceLock;
+
+// Constants
+const DETAIL_PLACEHOLDER: &str = "{0}";
+
+static LOCALE: OnceLock<Locale> = OnceLock::new();
+
+/// Supported locales
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+    De,
+    Fr,
+}
+
+impl Locale {
+    /// Parse a locale tag such as `es`, `de-DE` or `fr_FR.UTF-8`; unknown tags are `None`
+    pub fn parse(tag: &str) -> Option<Self> {
+        let language = tag
+            .split(['-', '_', '.'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match language.as_str() {
+            "en" => Some(Locale::En),
+            "es" => Some(Locale::Es),
+            "de" => Some(Locale::De),
+            "fr" => Some(Locale::Fr),
+            _ => None,
+        }
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Locale::En => &[],
+            Locale::Es => CATALOG_ES,
+            Locale::De => CATALOG_DE,
+            Locale::Fr => CATALOG_FR,
+        }
+    }
+}
+
+/// Set the process-wide locale for error responses (first call wins)
+pub fn set_locale(locale: Locale) {
+    let _ = LOCALE.set(locale);
+}
+
+/// Locale used for error responses
+pub fn current_locale() -> Locale {
+    LOCALE.get().copied().unwrap_or_default()
+}
+
+/// Render an error message in the given locale, falling back to English
+pub fn localize(error: &Error, locale: Locale) -> String {
+    let key = error.error_type();
+    let Some((_, template)) = locale.catalog().iter().find(|(k, _)| *k == key) else {
+        return error.to_string();
+    };
+
+    match detail(error) {
+        Some(detail) => template.replace(DETAIL_PLACEHOLDER, &detail),
+        None => template.to_string(),
+    }
+}
+
+/// Variant payload inserted into the localized template
+fn detail(error: &Error) -> Option<String> {
+    match error {
+        Error::BadRequest(d)
+        | Error::ModelNotFound(d)
+        | Error::InvalidModel(d)
+        | Error::ValidationFailed(d)
+        | Error::RouteNotFound(d)
+        | Error::MethodNotAllowed(d)
+        | Error::ServiceUnavailable(d)
+        | Error::ModelLoadFailed(d)
+        | Error::BackendOutOfMemory(d)
+        | Error::DeadlineExceeded(d)
+        | Error::Cancelled(d)
+        | Error::Internal(d)
+        | Error::RuntimeError(d)
+        | Error::ConfigError(d) => Some(d.clone()),
+        Error::Timeout(secs) => Some(secs.to_string()),
+        Error::Io(e) => Some(e.to_string()),
+        Error::Serialization(e) => Some(e.to_string()),
+        Error::Anyhow(e) => Some(e.to_string()),
+        Error::RateLimitExceeded
+        | Error::Unauthorized
+        | Error::RuntimeNotReady
+        | Error::UserCancelled => None,
+    }
+}
+
+const CATALOG_ES: &[(&str, &str)] = &[
+    ("bad_request", "Solicitud incorrecta: {0}"),
+    (
+        "validation_failed",
+        "La validación de la solicitud falló: {0}",
+    ),
+    ("model_not_found", "Modelo no encontrado: {0}"),
+    ("invalid_model", "Modelo no válido: {0}"),
+    ("rate_limit", "Se superó el límite de solicitudes"),
+    ("unauthorized", "Falta el token de portador o no es válido"),
+    ("route_not_found", "No existe el endpoint: {0}"),
+    ("method_not_allowed", "Método no permitido: {0}"),
+    ("service_unavailable", "Servicio no disponible: {0}"),
+    ("model_load_failed", "No se pudo cargar el modelo: {0}"),
+    (
+        "backend_out_of_memory",
+        "El motor se quedó sin memoria: {0}",
+    ),
+    ("runtime_not_ready", "El motor aún no está listo"),
+    (
+        "timeout",
+        "La solicitud superó el tiempo de espera de {0} segundos",
+    ),
+    (
+        "deadline_exceeded",
+        "Se agotó el tiempo de la solicitud: {0}",
+    ),
+    ("cancelled", "Solicitud cancelada: {0}"),
+    ("user_cancelled", "Generación cancelada por el usuario"),
+    ("internal", "Error interno: {0}"),
+    ("runtime_error", "Error del motor: {0}"),
+    ("config_error", "Error de configuración: {0}"),
+    ("io_error", "Error de E/S: {0}"),
+    ("serialization_error", "Error de serialización: {0}"),
+];
+
+const CATALOG_DE: &[(&str, &str)] = &[
+    ("bad_request", "Ungültige Anfrage: {0}"),
+    ("validation_failed", "Anfrageprüfung fehlgeschlagen: {0}"),
+    ("model_not_found", "Modell nicht gefunden: {0}"),
+    ("invalid_model", "Ungültiges Modell: {0}"),
+    ("rate_limit", "Anfragelimit überschritten"),
+    ("unauthorized", "Bearer-Token fehlt oder ist ungültig"),
+    ("route_not_found", "Endpunkt nicht vorhanden: {0}"),
+    ("method_not_allowed", "Methode nicht erlaubt: {0}"),
+    ("service_unavailable", "Dienst nicht verfügbar: {0}"),
+    (
+        "model_load_failed",
+        "Modell konnte nicht geladen werden: {0}",
+    ),
+    (
+        "backend_out_of_memory",
+        "Dem Backend ging der Speicher aus: {0}",
+    ),
+    ("runtime_not_ready", "Die Laufzeit ist noch nicht bereit"),
+    (
+        "timeout",
+        "Zeitüberschreitung der Anfrage nach {0} Sekunden",
+    ),
+    (
+        "deadline_exceeded",
+        "Zeitbudget der Anfrage überschritten: {0}",
+    ),
+    ("cancelled", "Anfrage abgebrochen: {0}"),
+    ("user_cancelled", "Generierung vom Benutzer abgebrochen"),
+    ("internal", "Interner Fehler: {0}"),
+    ("runtime_error", "Laufzeitfehler: {0}"),
+    ("config_error", "Konfigurationsfehler: {0}"),
+    ("io_error", "E/A-Fehler: {0}"),
+    ("serialization_error", "Serialisierungsfehler: {0}"),
+];
+
+const CATALOG_FR: &[(&str, &str)] = &[
+    ("bad_request", "Requête invalide : {0}"),
+    (
+        "validation_failed",
+        "La validation de la requête a échoué : {0}",
+    ),
+    ("model_not_found", "Modèle introuvable : {0}"),
+    ("invalid_model", "Modèle invalide : {0}"),
+    ("rate_limit", "Limite de requêtes dépassée"),
+    ("unauthorized", "Jeton d'accès manquant ou invalide"),
+    ("route_not_found", "Point d'accès inexistant : {0}"),
+    ("method_not_allowed", "Méthode non autorisée : {0}"),
+    ("service_unavailable", "Service indisponible : {0}"),
+    ("model_load_failed", "Échec du chargement du modèle : {0}"),
+    (
+        "backend_out_of_memory",
+        "Le moteur a manqué de mémoire : {0}",
+    ),
+    ("runtime_not_ready", "Le moteur n'est pas encore prêt"),
+    ("timeout", "Délai de la requête dépassé après {0} secondes"),
+    ("deadline_exceeded", "Échéance de la requête dépassée : {0}"),
+    ("cancelled", "Requête annulée : {0}"),
+    ("user_cancelled", "Génération annulée par l'utilisateur"),
+    ("internal", "Erreur interne : {0}"),
+    ("runtime_error", "Erreur du moteur : {0}"),
+    ("config_error", "Erreur de configuration : {0}"),
+    ("io_error", "Erreur d'E/S : {0}"),
+    ("serialization_error", "Erreur de sérialisation : {0}"),
+];
@@ -1,14 +1,24 @@
 pub mod dto;
 pub mod error;
+pub mod i18n;
 pub mod metrics;
 pub mod observability;
+pub mod redact;
+pub mod replay;
+pub mod text;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
 pub use dto::*;
-pub use error::{Error, ErrorResponse, Result};
+pub use error::{Error, ErrorResponse, OpenAiErrorResponse, Result};
+pub use i18n::Locale;
 pub use metrics::{Metrics, MetricsSnapshot};
 pub use observability::{
-    ErrorCategory, MetricsSnapshot as ObservableMetricsSnapshot, ObservableMetrics, RequestId,
+    utc_date, BackendInstanceStats, BackendPoolStats, DetectionExample, DetectionSource,
+    ErrorCategory, MetricsSnapshot as ObservableMetricsSnapshot, ObservableMetrics,
+    ProcessResources, PromptCacheSnapshot, RateLimitStats, RequestId, RouteMetrics, SlowRequest,
+    SlowRequestThresholds, TokenUsage, UsageSummary,
 };
+pub use replay::{RecordedMessage, ReplayEnvelope};
@@ -1,11 +1,20 @@
 use serde::Serialize;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
 const MAX_SAMPLES: usize = 10000;
+/// Days of per-day token usage kept in memory
+const USAGE_DAYS_KEPT: usize = 90;
+const SECS_PER_DAY: u64 = 86_400;
+/// Generation speeds averaged for queue ETAs
+const RECENT_TPS_SAMPLES: usize = 10;
+/// Detections kept for `/admin/detections`
+const RECENT_DETECTIONS: usize = 50;
+/// Characters of the offending text kept with each detection
+const DETECTION_EXCERPT_CHARS: usize = 160;
 
 /// Request correlation ID for tracing
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -62,16 +71,20 @@ impl ErrorCategory {
         match error {
             crate::Error::BadRequest(_)
             | crate::Error::ValidationFailed(_)
-            | crate::Error::InvalidModel(_) => ErrorCategory::BadRequest,
+            | crate::Error::InvalidModel(_)
+            | crate::Error::Unauthorized
+            | crate::Error::RouteNotFound(_)
+            | crate::Error::MethodNotAllowed(_) => ErrorCategory::BadRequest,
 
             crate::Error::RateLimitExceeded => ErrorCategory::RateLimited,
 
-            crate::Error::Timeout(_) => ErrorCategory::Timeout,
+            crate::Error::Timeout(_) | crate::Error::DeadlineExceeded(_) => ErrorCategory::Timeout,
 
             crate::Error::Cancelled(_) | crate::Error::UserCancelled => ErrorCategory::Cancelled,
 
             crate::Error::ServiceUnavailable(_)
             | crate::Error::ModelLoadFailed(_)
+            | crate::Error::BackendOutOfMemory(_)
             | crate::Error::RuntimeNotReady
             | crate::Error::ModelNotFound(_) => ErrorCategory::Unavailable,
 
@@ -91,6 +104,38 @@ impl ErrorCategory {
     }
 }
 
+/// What flagged a request as a possible jailbreak or abuse attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionSource {
+    /// The guard model rated a prompt or response unsafe
+    Guard,
+    /// The model role-played a dialogue and the response was replaced
+    Pollution,
+    /// A user message tried to override the instructions or template
+    Injection,
+}
+
+impl DetectionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectionSource::Guard => "guard",
+            DetectionSource::Pollution => "pollution",
+            DetectionSource::Injection => "injection",
+        }
+    }
+}
+
+/// One recent detection, with a redacted excerpt of what triggered it
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionExample {
+    pub age_seconds: u64,
+    pub source: DetectionSource,
+    pub category: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excerpt: Option<String>,
+}
+
 /// Active request tracking
 #[derive(Debug, Clone)]
 pub struct ActiveRequest {
@@ -98,6 +143,139 @@ pub struct ActiveRequest {
     pub started_at: Instant,
     pub model: String,
     pub is_streaming: bool,
+    /// Time between arrival and the backend call starting
+    pub queue_wait_ms: Option<u64>,
+    pub first_token_ms: Option<u64>,
+    pub prompt_tokens: Option<u64>,
+}
+
+/// HTTP-level counters for one route
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RouteMetrics {
+    pub requests: u64,
+    pub total_latency_ms: u64,
+    pub max_latency_ms: u64,
+    /// Response count per HTTP status code
+    pub status_codes: HashMap<u16, u64>,
+}
+
+/// Tokens used by a group of requests
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct TokenUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    fn add(&mut self, prompt: u64, completion: u64) {
+        self.requests += 1;
+        self.prompt_tokens += prompt;
+        self.completion_tokens += completion;
+        self.total_tokens += prompt + completion;
+    }
+}
+
+/// Token usage since startup, for `/v1/usage/summary`
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageSummary {
+    pub total: TokenUsage,
+    pub by_model: HashMap<String, TokenUsage>,
+    /// Keyed by UTC date (`YYYY-MM-DD`), last 90 days
+    pub by_day: BTreeMap<String, TokenUsage>,
+}
+
+/// llama-server prompt cache effectiveness
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PromptCacheSnapshot {
+    /// Completed requests that allowed prompt caching
+    pub lookups: u64,
+    /// Lookups that reused at least one cached prompt token
+    pub hits: u64,
+    pub hit_ratio: f64,
+    /// Prompt tokens the backend did not have to re-evaluate
+    pub tokens_saved: u64,
+    /// Lookups sent to the slot holding the conversation's earlier turns
+    pub affinity_routed: u64,
+    /// Part of `tokens_saved` from those lookups
+    pub affinity_tokens_saved: u64,
+    /// Tokens currently held in each backend slot's cache
+    pub resident_tokens_by_slot: HashMap<i64, u64>,
+}
+
+/// Traffic through the runtime's shared HTTP clients to the backend
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackendPoolStats {
+    pub requests: u64,
+    /// Requests sent but still waiting for response headers
+    pub awaiting_response: u64,
+    /// Requests that failed to open a connection
+    pub connect_errors: u64,
+    pub timeouts: u64,
+    pub max_idle_per_host: usize,
+    pub idle_timeout_secs: u64,
+    /// Routing state of each llama-server instance
+    pub instances: Vec<BackendInstanceStats>,
+}
+
+/// Rate limiter gauges, filled in by the API
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RateLimitStats {
+    pub global_tokens_available: f64,
+    pub global_capacity: u32,
+    /// IPs seen in the last few minutes or with requests in flight
+    pub tracked_ips: usize,
+    pub concurrent_requests: usize,
+    /// IPs using every concurrent slot they are allowed
+    pub ips_at_concurrency_limit: usize,
+    pub rejected_per_ip_rate: u64,
+    pub rejected_per_ip_concurrency: u64,
+    pub rejected_global: u64,
+}
+
+/// Last known state of one llama-server instance
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendInstanceStats {
+    pub url: String,
+    pub healthy: bool,
+    /// Generations currently routed to this instance
+    pub in_flight: usize,
+    /// Times the instance's process was killed for running out of memory
+    pub oom_kills: u64,
+    /// Latest sample of the instance's process, when chatsafe spawned it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ProcessResources>,
+}
+
+/// CPU and memory use of a backend process
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProcessResources {
+    pub pid: u32,
+    /// Share of one core since the previous sample (can exceed 100)
+    pub cpu_percent: Option<f64>,
+    pub rss_bytes: u64,
+    pub threads: Option<u64>,
+    /// Unix time of the sample
+    pub sampled_at: u64,
+}
+
+/// Latency thresholds above which a finished request counts as slow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowRequestThresholds {
+    pub first_token_ms: u64,
+    pub total_ms: u64,
+}
+
+/// A completed request that crossed a slow threshold
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowRequest {
+    pub request_id: RequestId,
+    pub model: String,
+    pub queue_wait_ms: Option<u64>,
+    pub first_token_ms: Option<u64>,
+    pub duration_ms: u64,
+    pub prompt_tokens: Option<u64>,
 }
 
 /// Enhanced metrics with observability
@@ -105,6 +283,7 @@ pub struct ActiveRequest {
 pub struct ObservableMetrics {
     inner: Arc<RwLock<MetricsData>>,
     start_time: Instant,
+    slow_thresholds: Option<SlowRequestThresholds>,
 }
 
 #[derive(Debug)]
@@ -129,6 +308,10 @@ struct MetricsData {
     errors_by_category: HashMap<ErrorCategory, u64>,
     error_messages: VecDeque<(Instant, ErrorCategory, String)>,
 
+    // Guard, pollution and injection detections by category
+    detections: HashMap<DetectionSource, HashMap<String, u64>>,
+    detection_examples: VecDeque<(Instant, DetectionSource, String, Option<String>)>,
+
     // Cancellation and timeout tracking
     cancelled_requests: u64,
     timed_out_requests: u64,
@@ -139,6 +322,9 @@ struct MetricsData {
 
     // Model usage
     requests_by_model: HashMap<String, u64>,
+    usage_total: TokenUsage,
+    usage_by_model: HashMap<String, TokenUsage>,
+    usage_by_day: BTreeMap<String, TokenUsage>,
 
     // Stream metrics
     active_streams: u64,
@@ -147,6 +333,23 @@ struct MetricsData {
 
     // Frame processing metrics
     dropped_frames: u64,
+
+    // Requests over the slow thresholds
+    slow_requests: u64,
+
+    // Requests whose background work panicked
+    panicked_requests: u64,
+
+    // HTTP-level metrics keyed by route pattern
+    http_routes: HashMap<String, RouteMetrics>,
+
+    // Prompt cache tracking
+    prompt_cache_lookups: u64,
+    prompt_cache_hits: u64,
+    prompt_tokens_saved: u64,
+    affinity_routed: u64,
+    affinity_tokens_saved: u64,
+    slot_residency: HashMap<i64, u64>,
 }
 
 impl Default for ObservableMetrics {
@@ -171,20 +374,41 @@ impl ObservableMetrics {
                 total_chunks_sent: 0,
                 errors_by_category: HashMap::new(),
                 error_messages: VecDeque::new(),
+                detections: HashMap::new(),
+                detection_examples: VecDeque::new(),
                 cancelled_requests: 0,
                 timed_out_requests: 0,
                 rate_limit_hits: 0,
                 rate_limit_by_ip: HashMap::new(),
                 requests_by_model: HashMap::new(),
+                usage_total: TokenUsage::default(),
+                usage_by_model: HashMap::new(),
+                usage_by_day: BTreeMap::new(),
                 active_streams: 0,
                 completed_streams: 0,
                 failed_streams: 0,
                 dropped_frames: 0,
+                slow_requests: 0,
+                panicked_requests: 0,
+                http_routes: HashMap::new(),
+                prompt_cache_lookups: 0,
+                prompt_cache_hits: 0,
+                prompt_tokens_saved: 0,
+                affinity_routed: 0,
+                affinity_tokens_saved: 0,
+                slot_residency: HashMap::new(),
             })),
             start_time: Instant::now(),
+            slow_thresholds: None,
         }
     }
 
+    /// Enable slow-request detection with the given thresholds
+    pub fn with_slow_thresholds(mut self, thresholds: SlowRequestThresholds) -> Self {
+        self.slow_thresholds = Some(thresholds);
+        self
+    }
+
     /// Start tracking a request
     pub async fn start_request(
         &self,
@@ -211,29 +435,59 @@ impl ObservableMetrics {
                 started_at: Instant::now(),
                 model,
                 is_streaming,
+                queue_wait_ms: None,
+                first_token_ms: None,
+                prompt_tokens: None,
             },
         );
 
         request_id
     }
 
-    /// Complete a request
-    pub async fn complete_request(&self, request_id: &RequestId) {
+    /// Mark the point where the request stopped waiting and reached the
+    /// backend, returning the wait in milliseconds
+    pub async fn record_generation_started(&self, request_id: &RequestId) -> Option<u64> {
         let mut data = self.inner.write().await;
+        let request = data.active_requests.get_mut(request_id)?;
+        let queue_wait_ms = request.started_at.elapsed().as_millis() as u64;
+        request.queue_wait_ms = Some(queue_wait_ms);
+        Some(queue_wait_ms)
+    }
 
-        if let Some(request) = data.active_requests.remove(request_id) {
-            let duration_ms = request.started_at.elapsed().as_millis() as u64;
+    /// Complete a request, returning its details if it crossed a slow threshold
+    pub async fn complete_request(&self, request_id: &RequestId) -> Option<SlowRequest> {
+        let mut data = self.inner.write().await;
 
-            data.request_durations.push_back(duration_ms);
-            if data.request_durations.len() > MAX_SAMPLES {
-                data.request_durations.pop_front();
-            }
+        let request = data.active_requests.remove(request_id)?;
+        let duration_ms = request.started_at.elapsed().as_millis() as u64;
 
-            if request.is_streaming {
-                data.active_streams = data.active_streams.saturating_sub(1);
-                data.completed_streams += 1;
-            }
+        data.request_durations.push_back(duration_ms);
+        if data.request_durations.len() > MAX_SAMPLES {
+            data.request_durations.pop_front();
+        }
+
+        if request.is_streaming {
+            data.active_streams = data.active_streams.saturating_sub(1);
+            data.completed_streams += 1;
+        }
+
+        let thresholds = self.slow_thresholds?;
+        let slow_first_token = request
+            .first_token_ms
+            .is_some_and(|ms| ms > thresholds.first_token_ms);
+        if !slow_first_token && duration_ms <= thresholds.total_ms {
+            return None;
         }
+
+        data.slow_requests += 1;
+        Some(SlowRequest {
+            request_id: request.request_id,
+            model: request.model,
+            queue_wait_ms: request.queue_wait_ms,
+            first_token_ms: request.first_token_ms,
+            duration_ms,
+            prompt_tokens: request.prompt_tokens,
+        })
     }
 
     /// Record an error with category
@@ -269,6 +523,28 @@ impl ObservableMetrics {
         }
     }
 
+    /// Count a detection and keep a redacted excerpt of the text behind it
+    pub async fn record_detection(
+        &self,
+        source: DetectionSource,
+        category: &str,
+        text: Option<&str>,
+    ) {
+        let excerpt = text.map(|text| crate::redact::excerpt(text, DETECTION_EXCERPT_CHARS));
+        let mut data = self.inner.write().await;
+        *data
+            .detections
+            .entry(source)
+            .or_default()
+            .entry(category.to_string())
+            .or_insert(0) += 1;
+        data.detection_examples
+            .push_back((Instant::now(), source, category.to_string(), excerpt));
+        if data.detection_examples.len() > RECENT_DETECTIONS {
+            data.detection_examples.pop_front();
+        }
+    }
+
     /// Record rate limit hit for an IP
     pub async fn record_rate_limit(&self, ip: String) {
         let mut data = self.inner.write().await;
@@ -277,8 +553,11 @@ impl ObservableMetrics {
     }
 
     /// Record first token latency
-    pub async fn record_first_token_latency(&self, latency_ms: u64) {
+    pub async fn record_first_token_latency(&self, request_id: &RequestId, latency_ms: u64) {
         let mut data = self.inner.write().await;
+        if let Some(request) = data.active_requests.get_mut(request_id) {
+            request.first_token_ms = Some(latency_ms);
+        }
         data.first_token_latencies.push_back(latency_ms);
         if data.first_token_latencies.len() > MAX_SAMPLES {
             data.first_token_latencies.pop_front();
@@ -295,10 +574,43 @@ impl ObservableMetrics {
     }
 
     /// Record token counts
-    pub async fn record_tokens(&self, prompt: u64, completion: u64) {
+    pub async fn record_tokens(&self, request_id: &RequestId, prompt: u64, completion: u64) {
         let mut data = self.inner.write().await;
+        let model = data.active_requests.get_mut(request_id).map(|request| {
+            request.prompt_tokens = Some(prompt);
+            request.model.clone()
+        });
         data.total_prompt_tokens += prompt;
         data.total_completion_tokens += completion;
+
+        data.usage_total.add(prompt, completion);
+        if let Some(model) = model {
+            data.usage_by_model
+                .entry(model)
+                .or_default()
+                .add(prompt, completion);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        data.usage_by_day
+            .entry(utc_date(now))
+            .or_default()
+            .add(prompt, completion);
+        while data.usage_by_day.len() > USAGE_DAYS_KEPT {
+            data.usage_by_day.pop_first();
+        }
+    }
+
+    /// Token usage per model and per day since startup
+    pub async fn usage_summary(&self) -> UsageSummary {
+        let data = self.inner.read().await;
+        UsageSummary {
+            total: data.usage_total.clone(),
+            by_model: data.usage_by_model.clone(),
+            by_day: data.usage_by_day.clone(),
+        }
     }
 
     /// Record chunk sent
@@ -307,6 +619,83 @@ impl ObservableMetrics {
         data.total_chunks_sent += 1;
     }
 
+    /// Record a finished HTTP request against its route
+    pub async fn record_http_request(&self, route: &str, status: u16, latency_ms: u64) {
+        let mut data = self.inner.write().await;
+        let route = data.http_routes.entry(route.to_string()).or_default();
+        route.requests += 1;
+        route.total_latency_ms += latency_ms;
+        route.max_latency_ms = route.max_latency_ms.max(latency_ms);
+        *route.status_codes.entry(status).or_insert(0) += 1;
+    }
+
+    /// Record prompt cache use for a finished generation
+    ///
+    /// `retained` is false for requests that opted out of caching, whose slot
+    /// is erased afterwards.
+    pub async fn record_prompt_cache(&self, metadata: &crate::GenerationMetadata, retained: bool) {
+        let mut data = self.inner.write().await;
+
+        if !retained {
+            if let Some(slot) = metadata.slot_id {
+                data.slot_residency.remove(&slot);
+            }
+            return;
+        }
+
+        let Some(prompt_tokens) = metadata.prompt_tokens else {
+            return;
+        };
+        let cached = metadata.cached_prompt_tokens.unwrap_or(0) as u64;
+
+        data.prompt_cache_lookups += 1;
+        if cached > 0 {
+            data.prompt_cache_hits += 1;
+            data.prompt_tokens_saved += cached;
+        }
+        if metadata.slot_affinity {
+            data.affinity_routed += 1;
+            data.affinity_tokens_saved += cached;
+        }
+        if let Some(slot) = metadata.slot_id {
+            let resident = prompt_tokens + metadata.completion_tokens.unwrap_or(0);
+            data.slot_residency.insert(slot, resident as u64);
+        }
+    }
+
+    /// Forget anything derived from request content (recent error messages,
+    /// detection excerpts, slot residency)
+    pub async fn clear_sensitive(&self) {
+        let mut data = self.inner.write().await;
+        data.error_messages.clear();
+        data.detection_examples.clear();
+        data.slot_residency.clear();
+    }
+
+    /// Average generation speed of the last few requests
+    pub async fn recent_tokens_per_second(&self) -> Option<f64> {
+        let data = self.inner.read().await;
+        let recent: Vec<f64> = data
+            .tokens_per_second
+            .iter()
+            .rev()
+            .take(RECENT_TPS_SAMPLES)
+            .copied()
+            .collect();
+        (!recent.is_empty()).then(|| recent.iter().sum::<f64>() / recent.len() as f64)
+    }
+
+    /// Requests started and not yet completed
+    pub async fn active_requests(&self) -> usize {
+        self.inner.read().await.active_requests.len()
+    }
+
+    /// Record a request whose background work panicked
+    pub async fn record_panicked_request(&self) {
+        let mut data = self.inner.write().await;
+        data.panicked_requests += 1;
+    }
+
     /// Record dropped frames
     pub async fn record_dropped_frames(&self, count: u64) {
         let mut data = self.inner.write().await;
@@ -391,6 +780,30 @@ impl ObservableMetrics {
             // Rate limiting
             rate_limit_hits: data.rate_limit_hits,
 
+            safety_detections: data.detections.clone(),
+
+            slow_requests: data.slow_requests,
+            panicked_requests: data.panicked_requests,
+
+            http_routes: data.http_routes.clone(),
+
+            prompt_cache: PromptCacheSnapshot {
+                lookups: data.prompt_cache_lookups,
+                hits: data.prompt_cache_hits,
+                hit_ratio: if data.prompt_cache_lookups > 0 {
+                    data.prompt_cache_hits as f64 / data.prompt_cache_lookups as f64
+                } else {
+                    0.0
+                },
+                tokens_saved: data.prompt_tokens_saved,
+                affinity_routed: data.affinity_routed,
+                affinity_tokens_saved: data.affinity_tokens_saved,
+                resident_tokens_by_slot: data.slot_residency.clone(),
+            },
+
+            backend_pool: None,
+            rate_limits: None,
+
             // Model usage
             requests_by_model: data.requests_by_model.clone(),
         }
@@ -409,6 +822,42 @@ impl ObservableMetrics {
             })
             .collect()
     }
+
+    /// Recent detections, oldest first
+    pub async fn recent_detections(&self) -> Vec<DetectionExample> {
+        let data = self.inner.read().await;
+        let now = Instant::now();
+
+        data.detection_examples
+            .iter()
+            .map(|(time, source, category, excerpt)| DetectionExample {
+                age_seconds: now.duration_since(*time).as_secs(),
+                source: *source,
+                category: category.clone(),
+                excerpt: excerpt.clone(),
+            })
+            .collect()
+    }
+}
+
+/// UTC calendar date of a Unix timestamp, as `YYYY-MM-DD`
+pub fn utc_date(unix_secs: u64) -> String {
+    // Civil-from-days over 400-year eras (H. Hinnant's algorithm)
+    let days = (unix_secs / SECS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
 }
 
 /// Metrics snapshot for /metrics endpoint
@@ -452,6 +901,29 @@ pub struct MetricsSnapshot {
     // Rate limiting
     pub rate_limit_hits: u64,
 
+    // Possible jailbreak attempts by source and category
+    pub safety_detections: HashMap<DetectionSource, HashMap<String, u64>>,
+
+    // Requests over the first-token or total-duration threshold
+    pub slow_requests: u64,
+
+    // Requests whose handler, streaming or bookkeeping task panicked
+    pub panicked_requests: u64,
+
+    // HTTP metrics per route, including requests that never reach generation
+    pub http_routes: HashMap<String, RouteMetrics>,
+
+    // Prompt cache
+    pub prompt_cache: PromptCacheSnapshot,
+
+    // Backend HTTP pool, filled in by the API from the runtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend_pool: Option<BackendPoolStats>,
+
+    // Rate limiter buckets and rejections by limit, filled in by the API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limits: Option<RateLimitStats>,
+
     // Model usage
     pub requests_by_model: HashMap<String, u64>,
 }
@@ -503,6 +975,135 @@ mod tests {
         assert_eq!(snapshot.rate_limit_hits, 1);
     }
 
+    #[tokio::test]
+    async fn test_slow_request_detection() {
+        let metrics = ObservableMetrics::new().with_slow_thresholds(SlowRequestThresholds {
+            first_token_ms: 100,
+            total_ms: 60_000,
+        });
+
+        let fast = metrics
+            .start_request(RequestId::new(), "test-model".to_string(), true)
+            .await;
+        metrics.record_first_token_latency(&fast, 50).await;
+        assert!(metrics.complete_request(&fast).await.is_none());
+
+        let slow = metrics
+            .start_request(RequestId::new(), "test-model".to_string(), true)
+            .await;
+        metrics.record_generation_started(&slow).await;
+        metrics.record_tokens(&slow, 42, 10).await;
+        metrics.record_first_token_latency(&slow, 250).await;
+        let report = metrics.complete_request(&slow).await.expect("slow request");
+
+        assert_eq!(report.model, "test-model");
+        assert_eq!(report.first_token_ms, Some(250));
+        assert_eq!(report.prompt_tokens, Some(42));
+        assert!(report.queue_wait_ms.is_some());
+        assert_eq!(metrics.snapshot().await.slow_requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_http_route_metrics() {
+        let metrics = ObservableMetrics::new();
+
+        metrics.record_http_request("/models", 200, 5).await;
+        metrics.record_http_request("/models", 200, 15).await;
+        metrics.record_http_request("unmatched", 404, 1).await;
+
+        let snapshot = metrics.snapshot().await;
+        let models = &snapshot.http_routes["/models"];
+        assert_eq!(models.requests, 2);
+        assert_eq!(models.total_latency_ms, 20);
+        assert_eq!(models.max_latency_ms, 15);
+        assert_eq!(models.status_codes.get(&200), Some(&2));
+        assert_eq!(
+            snapshot.http_routes["unmatched"].status_codes.get(&404),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_cache_metrics() {
+        let metrics = ObservableMetrics::new();
+        let mut metadata = crate::GenerationMetadata {
+            slot_id: Some(0),
+            prompt_tokens: Some(100),
+            cached_prompt_tokens: Some(0),
+            completion_tokens: Some(20),
+            ..Default::default()
+        };
+
+        metrics.record_prompt_cache(&metadata, true).await;
+        metadata.cached_prompt_tokens = Some(80);
+        metadata.slot_affinity = true;
+        metrics.record_prompt_cache(&metadata, true).await;
+
+        let cache = metrics.snapshot().await.prompt_cache;
+        assert_eq!(cache.lookups, 2);
+        assert_eq!(cache.hits, 1);
+        assert_eq!(cache.hit_ratio, 0.5);
+        assert_eq!(cache.tokens_saved, 80);
+        assert_eq!(cache.affinity_routed, 1);
+        assert_eq!(cache.affinity_tokens_saved, 80);
+        assert_eq!(cache.resident_tokens_by_slot.get(&0), Some(&120));
+
+        // Opted-out requests erase their slot and do not count as lookups
+        metrics.record_prompt_cache(&metadata, false).await;
+        let cache = metrics.snapshot().await.prompt_cache;
+        assert_eq!(cache.lookups, 2);
+        assert!(cache.resident_tokens_by_slot.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_usage_summary_groups_by_model_and_day() {
+        let metrics = ObservableMetrics::new();
+        for (model, prompt, completion) in [("a", 10, 5), ("a", 20, 5), ("b", 1, 1)] {
+            let id = metrics
+                .start_request(RequestId::new(), model.to_string(), false)
+                .await;
+            metrics.record_tokens(&id, prompt, completion).await;
+        }
+
+        let summary = metrics.usage_summary().await;
+        assert_eq!(summary.total.total_tokens, 42);
+        assert_eq!(summary.by_model["a"].requests, 2);
+        assert_eq!(summary.by_model["a"].prompt_tokens, 30);
+        assert_eq!(summary.by_model["b"].completion_tokens, 1);
+        assert_eq!(summary.by_day.len(), 1);
+
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_790_121_600), "2026-09-23");
+    }
+
+    #[tokio::test]
+    async fn test_clear_sensitive() {
+        let metrics = ObservableMetrics::new();
+        let metadata = crate::GenerationMetadata {
+            slot_id: Some(1),
+            prompt_tokens: Some(10),
+            ..Default::default()
+        };
+        metrics.record_prompt_cache(&metadata, true).await;
+        metrics
+            .record_error(None, &crate::Error::RuntimeError("secret prompt".into()))
+            .await;
+
+        metrics
+            .record_detection(DetectionSource::Injection, "persona", Some("you are DAN"))
+            .await;
+
+        metrics.clear_sensitive().await;
+
+        assert!(metrics.recent_errors().await.is_empty());
+        assert!(metrics.recent_detections().await.is_empty());
+        let snapshot = metrics.snapshot().await;
+        assert!(snapshot.prompt_cache.resident_tokens_by_slot.is_empty());
+        // Aggregate counters are kept
+        assert_eq!(snapshot.prompt_cache.lookups, 1);
+    }
+
     #[test]
     fn test_percentile_calculation() {
         let samples = vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
@@ -0,0 +1,65 @@
+//! PII redaction for text that may end up in logs
+//!
+//! Patterns are deliberately broad: a false positive only hides a bit of a
+//! debug excerpt, a false negative writes private data to disk. Redaction
+//! runs before truncation so a cut can never leave half of a match behind.
+
+use crate::text;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Appended to excerpts that were cut short
+const ELLIPSIS: &str = "…";
+
+/// Patterns in the order they are applied, each with its placeholder
+///
+/// Earlier patterns win: secrets and emails go before the number patterns
+/// that would otherwise eat their digits.
+const PATTERNS: &[(&str, &str)] = &[
+    (
+        r"(?i)\bbearer\s+[a-z0-9._~+/-]+=*|\b(?:sk|pk|rk)-[A-Za-z0-9_-]{16,}|\b(?:ghp|gho|ghs|xox[abp])[_-][A-Za-z0-9_-]{16,}",
+        "[SECRET]",
+    ),
+    (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", "[EMAIL]"),
+    (r"\b(?:\d{1,3}\.){3}\d{1,3}\b", "[IP]"),
+    (r"\b\d{3}-\d{2}-\d{4}\b", "[ID]"),
+    (r"\b(?:\d[ -]?){12,18}\d\b", "[NUMBER]"),
+    (r"\+?\(?\d[\d\s().-]{6,}\d", "[PHONE]"),
+];
+
+fn patterns() -> &'static [(Regex, &'static str)] {
+    static COMPILED: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+    COMPILED.get_or_init(|| {
+        PATTERNS
+            .iter()
+            .map(|(pattern, placeholder)| {
+                (
+                    Regex::new(pattern).expect("redaction pattern is valid"),
+                    *placeholder,
+                )
+            })
+            .collect()
+    })
+}
+
+/// Replace emails, phone numbers, card-like numbers, IPs and API keys
+pub fn redact(text: &str) -> String {
+    let mut redacted = text.to_string();
+    for (regex, placeholder) in patterns() {
+        if regex.is_match(&redacted) {
+            redacted = regex.replace_all(&redacted, *placeholder).into_owned();
+        }
+    }
+    redacted
+}
+
+/// Redacted prefix of at most `max_chars` characters, for debug logs
+pub fn excerpt(text: &str, max_chars: usize) -> String {
+    let redacted = redact(text);
+    let cut = text::truncate_chars(&redacted, max_chars);
+    if cut.len() < redacted.len() {
+        format!("{}{}", cut, ELLIPSIS)
+    } else {
+        redacted
+    }
+}
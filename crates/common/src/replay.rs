@@ -0,0 +1,114 @@
+//! Replay envelopes for reproducing load patterns
+//!
+//! The server can append one envelope per accepted chat request to a JSONL
+//! file. By default message content is dropped and only its length is kept,
+//! so a recording can be shared without leaking conversations; `chatsafe
+//! replay` then substitutes filler text of the same length.
+
+use crate::dto::{ChatCompletionRequest, Role, SamplerSettings};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Filler used to rebuild redacted message content
+const FILLER_WORD: &str = "lorem ";
+
+/// Shape of one recorded message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecordedMessage {
+    pub role: Role,
+    /// Length of the original content in characters
+    pub content_chars: usize,
+    /// Original content, only present when recorded in debug mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Sanitized record of a single chat request
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplayEnvelope {
+    /// Milliseconds since the recorder started, used to reproduce pacing
+    pub offset_ms: u64,
+    pub model: Option<String>,
+    pub messages: Vec<RecordedMessage>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stream: Option<bool>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub repeat_penalty: Option<f32>,
+    #[serde(flatten)]
+    pub sampler: SamplerSettings,
+    pub cache: Option<bool>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl ReplayEnvelope {
+    /// Capture a request, keeping message content only if asked to
+    pub fn capture(request: &ChatCompletionRequest, offset_ms: u64, include_content: bool) -> Self {
+        let messages = request
+            .messages
+            .iter()
+            .map(|m| RecordedMessage {
+                role: m.role.clone(),
+                content_chars: m.content.chars().count(),
+                content: include_content.then(|| m.content.clone()),
+            })
+            .collect();
+
+        Self {
+            offset_ms,
+            model: request.model.clone(),
+            messages,
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            stream: request.stream,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            repeat_penalty: request.repeat_penalty,
+            sampler: request.sampler,
+            cache: request.cache,
+            stop: request.stop.clone(),
+        }
+    }
+
+    /// Build the `/v1/chat/completions` body to re-issue this request
+    pub fn to_request_body(&self) -> serde_json::Value {
+        let messages: Vec<_> = self
+            .messages
+            .iter()
+            .map(|m| {
+                let content = m.content.clone().unwrap_or_else(|| filler(m.content_chars));
+                json!({ "role": m.role, "content": content })
+            })
+            .collect();
+
+        let mut body = json!({ "messages": messages });
+        let fields = [
+            ("model", json!(self.model)),
+            ("temperature", json!(self.temperature)),
+            ("max_tokens", json!(self.max_tokens)),
+            ("stream", json!(self.stream)),
+            ("top_p", json!(self.top_p)),
+            ("top_k", json!(self.top_k)),
+            ("repeat_penalty", json!(self.repeat_penalty)),
+            ("cache", json!(self.cache)),
+            ("stop", json!(self.stop)),
+        ];
+        for (key, value) in fields {
+            if !value.is_null() {
+                body[key] = value;
+            }
+        }
+        if let serde_json::Value::Object(sampler) = json!(self.sampler) {
+            for (key, value) in sampler {
+                body[key] = value;
+            }
+        }
+        body
+    }
+}
+
+/// Deterministic text of exactly `chars` characters
+fn filler(chars: usize) -> String {
+    FILLER_WORD.chars().cycle().take(chars.max(1)).collect()
+}
@@ -6,24 +6,15 @@ mod tests {
     #[test]
     fn test_message_validation() {
         // Valid message
-        let msg = Message {
-            role: Role::User,
-            content: "Hello".to_string(),
-        };
+        let msg = Message::new(Role::User, "Hello");
         assert!(msg.validate().is_ok());
 
         // Empty content
-        let msg = Message {
-            role: Role::User,
-            content: "".to_string(),
-        };
+        let msg = Message::new(Role::User, "");
         assert!(matches!(msg.validate(), Err(Error::BadRequest(_))));
 
         // Too long content
-        let msg = Message {
-            role: Role::User,
-            content: "x".repeat(100_001),
-        };
+        let msg = Message::new(Role::User, "x".repeat(100_001));
         assert!(matches!(msg.validate(), Err(Error::BadRequest(_))));
     }
 
@@ -32,77 +23,45 @@ mod tests {
         // Valid request
         let req = ChatCompletionRequest {
             model: Some("test".to_string()),
-            messages: vec![Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            }],
+            messages: vec![Message::new(Role::User, "Hello")],
             temperature: Some(1.0),
             max_tokens: Some(100),
             stream: Some(false),
             top_p: Some(0.9),
             top_k: Some(40),
             repeat_penalty: Some(1.1),
+            ..Default::default()
         };
         assert!(req.validate().is_ok());
 
         // Empty messages
         let req = ChatCompletionRequest {
-            model: None,
             messages: vec![],
-            temperature: None,
-            max_tokens: None,
-            stream: None,
-            top_p: None,
-            top_k: None,
-            repeat_penalty: None,
+            ..Default::default()
         };
         assert!(matches!(req.validate(), Err(Error::BadRequest(_))));
 
         // Invalid temperature
         let req = ChatCompletionRequest {
-            model: None,
-            messages: vec![Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            }],
+            messages: vec![Message::new(Role::User, "Hello")],
             temperature: Some(3.0), // Too high
-            max_tokens: None,
-            stream: None,
-            top_p: None,
-            top_k: None,
-            repeat_penalty: None,
+            ..Default::default()
         };
         assert!(matches!(req.validate(), Err(Error::BadRequest(_))));
 
         // Invalid max_tokens
         let req = ChatCompletionRequest {
-            model: None,
-            messages: vec![Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            }],
-            temperature: None,
+            messages: vec![Message::new(Role::User, "Hello")],
             max_tokens: Some(5000), // Too high
-            stream: None,
-            top_p: None,
-            top_k: None,
-            repeat_penalty: None,
+            ..Default::default()
         };
         assert!(matches!(req.validate(), Err(Error::BadRequest(_))));
 
         // Invalid top_p
         let req = ChatCompletionRequest {
-            model: None,
-            messages: vec![Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            }],
-            temperature: None,
-            max_tokens: None,
-            stream: None,
+            messages: vec![Message::new(Role::User, "Hello")],
             top_p: Some(1.5), // Too high
-            top_k: None,
-            repeat_penalty: None,
+            ..Default::default()
         };
         assert!(matches!(req.validate(), Err(Error::BadRequest(_))));
     }
@@ -115,6 +74,8 @@ mod tests {
         assert_eq!(Role::from("USER".to_string()), Role::User);
         assert_eq!(Role::from("assistant".to_string()), Role::Assistant);
         assert_eq!(Role::from("ASSISTANT".to_string()), Role::Assistant);
+        assert_eq!(Role::from("tool".to_string()), Role::Tool);
+        assert_eq!(Role::from("ipython".to_string()), Role::Tool);
         assert_eq!(Role::from("unknown".to_string()), Role::User); // Default
     }
 
@@ -125,10 +86,31 @@ mod tests {
         assert_eq!(Error::RateLimitExceeded.status_code(), 429);
         assert_eq!(Error::ServiceUnavailable("test".into()).status_code(), 503);
         assert_eq!(Error::Timeout(30).status_code(), 408);
+        assert_eq!(Error::DeadlineExceeded("test".into()).status_code(), 504);
         assert_eq!(Error::UserCancelled.status_code(), 499);
         assert_eq!(Error::Internal("test".into()).status_code(), 500);
     }
 
+    #[test]
+    fn test_localized_error_messages() {
+        use crate::i18n::{localize, Locale};
+
+        let err = Error::ModelNotFound("phi-3".into());
+        assert_eq!(localize(&err, Locale::En), "Model not found: phi-3");
+        assert_eq!(localize(&err, Locale::Es), "Modelo no encontrado: phi-3");
+        assert_eq!(
+            localize(&Error::RateLimitExceeded, Locale::De),
+            "Anfragelimit überschritten"
+        );
+        assert_eq!(
+            localize(&Error::Timeout(30), Locale::Fr),
+            "Délai de la requête dépassé après 30 secondes"
+        );
+
+        assert_eq!(Locale::parse("de_DE.UTF-8"), Some(Locale::De));
+        assert_eq!(Locale::parse("pt-BR"), None);
+    }
+
     #[test]
     fn test_error_retryable() {
         assert!(Error::ServiceUnavailable("test".into()).is_retryable());
@@ -138,17 +120,65 @@ mod tests {
         assert!(!Error::ModelNotFound("test".into()).is_retryable());
     }
 
+    #[test]
+    fn test_generation_params_deadline() {
+        let mut params = GenerationParams::default();
+        assert!(params.remaining().is_none());
+        assert!(params.check_deadline("queued").is_ok());
+
+        params.deadline = Some(std::time::Instant::now() + std::time::Duration::from_secs(60));
+        assert!(params.remaining().unwrap() > std::time::Duration::from_secs(59));
+        assert!(params.check_deadline("queued").is_ok());
+
+        params.deadline = Some(std::time::Instant::now());
+        let err = params.check_deadline("queued").unwrap_err();
+        assert_eq!(err.error_type(), "deadline_exceeded");
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_add_stop_sequences_dedupes_and_caps() {
+        let mut params = GenerationParams::default();
+        let base = params.stop_sequences.len();
+
+        params.add_stop_sequences(&["<|eot_id|>".to_string(), "END".to_string(), String::new()]);
+        assert_eq!(params.stop_sequences.len(), base + 1);
+        assert_eq!(
+            params.stop_sequences.last().map(String::as_str),
+            Some("END")
+        );
+
+        let many: Vec<String> = (0..40).map(|i| format!("stop{}", i)).collect();
+        params.add_stop_sequences(&many);
+        assert_eq!(params.stop_sequences.len(), 16);
+    }
+
+    #[test]
+    fn test_request_stop_validation() {
+        let mut req = ChatCompletionRequest {
+            messages: vec![Message::new(Role::User, "Hi")],
+            stop: Some(vec!["a".to_string(), "b".to_string()]),
+            ..Default::default()
+        };
+        assert!(req.validate().is_ok());
+
+        req.stop = Some((0..5).map(|i| i.to_string()).collect());
+        assert!(req.validate().is_err());
+
+        req.stop = Some(vec![String::new()]);
+        assert!(req.validate().is_err());
+    }
+
     #[test]
     fn test_generation_params_from_request() {
         let req = ChatCompletionRequest {
-            model: None,
             messages: vec![],
             temperature: Some(0.8),
             max_tokens: Some(200),
-            stream: None,
             top_p: Some(0.95),
             top_k: Some(50),
             repeat_penalty: Some(1.2),
+            ..Default::default()
         };
 
         let defaults = GenerationParams::default();
@@ -161,4 +191,499 @@ mod tests {
         assert_eq!(params.repeat_penalty, 1.2);
         assert!(!params.request_id.is_empty());
     }
+
+    #[test]
+    fn test_replay_envelope_redacts_content() {
+        use crate::replay::ReplayEnvelope;
+
+        let req = ChatCompletionRequest {
+            model: Some("llama-3.2-3b-instruct-q4_k_m".to_string()),
+            messages: vec![Message::new(Role::User, "héllo secret")],
+            temperature: Some(0.5),
+            max_tokens: Some(64),
+            stream: Some(false),
+            ..Default::default()
+        };
+
+        let envelope = ReplayEnvelope::capture(&req, 1500, false);
+        let line = serde_json::to_string(&envelope).unwrap();
+        assert!(!line.contains("secret"));
+        assert_eq!(envelope.messages[0].content_chars, 12);
+
+        let body = envelope.to_request_body();
+        let content = body["messages"][0]["content"].as_str().unwrap();
+        assert_eq!(content.chars().count(), 12);
+        assert_eq!(body["max_tokens"], 64);
+        assert!(body.get("top_p").is_none());
+
+        let debug = ReplayEnvelope::capture(&req, 1500, true);
+        assert_eq!(
+            debug.to_request_body()["messages"][0]["content"],
+            "héllo secret"
+        );
+    }
+
+    #[test]
+    fn test_truncation_respects_char_boundaries() {
+        use crate::text::{truncate_bytes, truncate_chars, truncate_tokens, Tokenizer};
+
+        struct WordTokenizer;
+        impl Tokenizer for WordTokenizer {
+            fn count_tokens(&self, text: &str) -> usize {
+                text.split_whitespace().count()
+            }
+        }
+
+        // "é" is two bytes and "€" is three; no cut may land inside either
+        let text = "héllo wörld €uro";
+        assert_eq!(truncate_bytes(text, 2), "h");
+        assert_eq!(truncate_bytes(text, 3), "hé");
+        assert_eq!(truncate_bytes("€", 2), "");
+        assert_eq!(truncate_bytes(text, 100), text);
+
+        assert_eq!(truncate_chars(text, 2), "hé");
+        assert_eq!(truncate_chars(text, 100), text);
+
+        assert_eq!(
+            truncate_tokens(text, 2, Some(&WordTokenizer)),
+            "héllo wörld "
+        );
+        assert_eq!(truncate_tokens(text, 3, Some(&WordTokenizer)), text);
+        assert_eq!(truncate_tokens("€€€", 0, Some(&WordTokenizer)), "");
+
+        // Without a tokenizer: 4 bytes per token, backed off to a boundary
+        assert_eq!(truncate_tokens("€€€", 1, None), "€");
+    }
+
+    #[test]
+    fn test_redaction_hides_pii_before_truncating() {
+        use crate::redact::{excerpt, redact};
+
+        let text = "Mail jane.doe@example.com or call +1 (555) 123-4567. \
+                    Card 4111 1111 1111 1111, SSN 123-45-6789, host 10.0.0.12, \
+                    key sk-abcdefghijklmnopqrstuv and Bearer abc.def.ghi";
+        let redacted = redact(text);
+        for leaked in [
+            "jane.doe",
+            "555",
+            "4111",
+            "6789",
+            "10.0.0.12",
+            "sk-abc",
+            "abc.def",
+        ] {
+            assert!(
+                !redacted.contains(leaked),
+                "{} leaked: {}",
+                leaked,
+                redacted
+            );
+        }
+        assert!(redacted.starts_with("Mail [EMAIL] or call [PHONE]."));
+        assert_eq!(
+            redact("version 3.2 has 8192 tokens"),
+            "version 3.2 has 8192 tokens"
+        );
+
+        // A cut through the address still hides it
+        assert_eq!(excerpt("jane.doe@example.com says hi", 8), "[EMAIL] …");
+        assert_eq!(excerpt("short", 8), "short");
+    }
+
+    #[test]
+    fn test_last_sentence_end() {
+        use crate::text::last_sentence_end;
+
+        assert_eq!(last_sentence_end("One. Two is cut"), Some(4));
+        assert_eq!(last_sentence_end("He said \"stop!\" and then"), Some(15));
+        assert_eq!(last_sentence_end("Pi is 3.14 roughly"), None);
+        assert_eq!(last_sentence_end("Ends here?"), Some(10));
+        assert_eq!(last_sentence_end("好的。然后"), Some(9));
+        assert_eq!(last_sentence_end("no boundary"), None);
+    }
+
+    #[test]
+    fn test_truncate_middle_tokens_keeps_head_and_tail() {
+        use crate::text::{suffix_tokens, truncate_middle_tokens};
+
+        // The 4-byte cut lands inside "ö" and moves forward
+        assert_eq!(suffix_tokens("héllo wörld", 1, None), "rld");
+
+        let short = "small result";
+        assert!(matches!(
+            truncate_middle_tokens(short, 10, 2, None),
+            std::borrow::Cow::Borrowed(_)
+        ));
+
+        let long = format!("HEAD{}TAIL", "x".repeat(100));
+        let capped = truncate_middle_tokens(&long, 4, 1, None);
+        assert!(capped.starts_with("HEADxxxxxxxx\n"));
+        assert!(capped.ends_with("\nTAIL"));
+        assert!(capped.contains("[... 92 characters omitted ...]"));
+    }
+
+    #[test]
+    fn test_developer_role_maps_to_system() {
+        let msg: Message =
+            serde_json::from_str(r#"{"role": "developer", "content": "Be brief"}"#).unwrap();
+        assert_eq!(msg.role, Role::System);
+        assert_eq!(Role::from("Developer".to_string()), Role::System);
+    }
+
+    #[test]
+    fn test_prompt_override_replaces_messages() {
+        let request: ChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "prompt_override": [128000, 9906]}"#).unwrap();
+        assert_eq!(
+            request.prompt_override,
+            Some(RawPrompt::Tokens(vec![128000, 9906]))
+        );
+        assert!(request.validate().is_ok());
+
+        let request: ChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "prompt_override": ""}"#).unwrap();
+        assert!(request.validate().is_err());
+
+        let request: ChatCompletionRequest = serde_json::from_str(r#"{"messages": []}"#).unwrap();
+        assert!(request.validate().is_err());
+    }
+
+    #[test]
+    fn test_openai_tool_calling_request() {
+        let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "messages": [
+                { "role": "user", "content": "Weather in Paris?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                    }]
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "21C" }
+            ],
+            "tools": [{
+                "type": "function",
+                "function": { "name": "get_weather", "parameters": { "type": "object" } }
+            }],
+            "tool_choice": "auto"
+        }))
+        .unwrap();
+        assert!(request.validate().is_ok());
+        assert_eq!(request.messages[1].content, "");
+        assert_eq!(request.messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(
+            request.tool_choice,
+            Some(ToolChoice::Mode(ToolChoiceMode::Auto))
+        );
+
+        let params = GenerationParams::from_request(&request, GenerationParams::default());
+        assert_eq!(params.offered_tools().len(), 1);
+
+        // "none" keeps the tools from the model
+        let mut off = request.clone();
+        off.tool_choice = Some(ToolChoice::Mode(ToolChoiceMode::None));
+        let params = GenerationParams::from_request(&off, GenerationParams::default());
+        assert!(params.offered_tools().is_empty());
+
+        // A named function must be one of the tools
+        let mut named = request.clone();
+        named.tool_choice = serde_json::from_value(
+            serde_json::json!({ "type": "function", "function": { "name": "get_time" } }),
+        )
+        .unwrap();
+        assert!(named.validate().is_err());
+
+        let mut required = request.clone();
+        required.tools = None;
+        required.tool_choice = Some(ToolChoice::Mode(ToolChoiceMode::Required));
+        assert!(required.validate().is_err());
+
+        let mut bad_name = request.clone();
+        bad_name.tools.as_mut().unwrap()[0].function.name = "get weather".to_string();
+        assert!(bad_name.validate().is_err());
+
+        // Only calls may stand in for content
+        let mut empty = request;
+        empty.messages[1].tool_calls = None;
+        assert!(empty.validate().is_err());
+    }
+
+    #[test]
+    fn test_response_format_validation() {
+        let parse = |body: serde_json::Value| -> ChatCompletionRequest {
+            serde_json::from_value(body).unwrap()
+        };
+        let messages = serde_json::json!([{ "role": "user", "content": "List a city" }]);
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "response_format": { "type": "json_object" }
+        }));
+        assert_eq!(request.response_format, Some(ResponseFormat::JsonObject));
+        assert!(request.validate().is_ok());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": { "name": "city", "strict": true, "schema": { "type": "object" } }
+            }
+        }));
+        assert!(request.response_format.as_ref().unwrap().is_json());
+        assert!(request.validate().is_ok());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "response_format": { "type": "json_schema", "json_schema": { "name": "city", "schema": 5 } }
+        }));
+        assert!(request.validate().is_err());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "response_format": { "type": "json_object" },
+            "tools": [{ "type": "function", "function": { "name": "lookup" } }]
+        }));
+        assert!(request.validate().is_err());
+
+        assert!(
+            serde_json::from_value::<ChatCompletionRequest>(serde_json::json!({
+                "messages": messages,
+                "response_format": { "type": "yaml" }
+            }))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_grammar_validation() {
+        let parse = |body: serde_json::Value| -> ChatCompletionRequest {
+            serde_json::from_value(body).unwrap()
+        };
+        let messages = serde_json::json!([{ "role": "user", "content": "Yes or no?" }]);
+        let grammar = r#"root ::= "yes" | "no""#;
+
+        let request = parse(serde_json::json!({ "messages": messages, "grammar": grammar }));
+        assert!(request.validate().is_ok());
+        assert_eq!(
+            GenerationParams::from_request(&request, GenerationParams::default())
+                .grammar
+                .as_deref(),
+            Some(grammar)
+        );
+
+        let request = parse(serde_json::json!({ "messages": messages, "grammar": "  " }));
+        assert!(request.validate().is_err());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "grammar": "x".repeat(64 * 1024 + 1)
+        }));
+        assert!(request.validate().is_err());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "grammar": grammar,
+            "response_format": { "type": "json_object" }
+        }));
+        assert!(request.validate().is_err());
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "grammar": grammar,
+            "tools": [{ "type": "function", "function": { "name": "lookup" } }]
+        }));
+        assert!(request.validate().is_err());
+    }
+
+    #[test]
+    fn test_logprobs_request() {
+        let parse = |body: serde_json::Value| -> ChatCompletionRequest {
+            serde_json::from_value(body).unwrap()
+        };
+        let messages = serde_json::json!([{ "role": "user", "content": "Hi" }]);
+
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "logprobs": true,
+            "top_logprobs": 5
+        }));
+        assert!(request.validate().is_ok());
+        let params = GenerationParams::from_request(&request, GenerationParams::default());
+        assert_eq!(params.top_logprobs, Some(5));
+
+        let request = parse(serde_json::json!({ "messages": messages, "logprobs": true }));
+        let params = GenerationParams::from_request(&request, GenerationParams::default());
+        assert_eq!(params.top_logprobs, Some(0));
+
+        let request = parse(serde_json::json!({ "messages": messages }));
+        let params = GenerationParams::from_request(&request, GenerationParams::default());
+        assert_eq!(params.top_logprobs, None);
+
+        // Alternatives need logprobs on, and OpenAI allows at most 20
+        let request = parse(serde_json::json!({ "messages": messages, "top_logprobs": 2 }));
+        assert!(request.validate().is_err());
+        let request = parse(serde_json::json!({
+            "messages": messages,
+            "logprobs": true,
+            "top_logprobs": 21
+        }));
+        assert!(request.validate().is_err());
+    }
+
+    #[test]
+    fn test_embedding_request_validation() {
+        let request: EmbeddingRequest =
+            serde_json::from_str(r#"{"model": "nomic-embed", "input": "hello"}"#).unwrap();
+        assert_eq!(request.input, EmbeddingInput::One("hello".to_string()));
+        assert!(request.validate().is_ok());
+
+        let request: EmbeddingRequest =
+            serde_json::from_str(r#"{"input": ["a", "b"], "encoding_format": "float"}"#).unwrap();
+        assert!(request.validate().is_ok());
+        assert_eq!(request.input.into_vec(), vec!["a", "b"]);
+
+        for body in [
+            r#"{"input": []}"#,
+            r#"{"input": ["a", ""]}"#,
+            r#"{"input": "a", "encoding_format": "base64"}"#,
+        ] {
+            let request: EmbeddingRequest = serde_json::from_str(body).unwrap();
+            assert!(request.validate().is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_stop_accepts_string_or_array() {
+        let parse = |stop: &str| -> ChatCompletionRequest {
+            serde_json::from_str(&format!(
+                r#"{{"messages": [{{"role": "user", "content": "hi"}}]{}}}"#,
+                stop
+            ))
+            .unwrap()
+        };
+        assert_eq!(parse("").stop, None);
+        assert_eq!(parse(r#", "stop": null"#).stop, None);
+        assert_eq!(
+            parse(r#", "stop": "END""#).stop,
+            Some(vec!["END".to_string()])
+        );
+        let request = parse(r#", "stop": ["END", "\n\n"]"#);
+        assert_eq!(
+            request.stop,
+            Some(vec!["END".to_string(), "\n\n".to_string()])
+        );
+        assert!(request.validate().is_ok());
+
+        // Merged after the model's own stop sequences
+        let params = GenerationParams::from_request(&request, GenerationParams::default());
+        let defaults = GenerationParams::default().stop_sequences;
+        assert_eq!(params.stop_sequences[..defaults.len()], defaults[..]);
+        assert!(params
+            .stop_sequences
+            .ends_with(&["END".to_string(), "\n\n".to_string()]));
+
+        assert!(parse(r#", "stop": ["END", ""]"#).validate().is_err());
+        assert!(
+            serde_json::from_str::<ChatCompletionRequest>(r#"{"messages": [], "stop": 5}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_message_content_parts() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "Compare" },
+                { "type": "image_url", "image_url": { "url": "data:image/jpeg;base64,/9j/4A==", "detail": "low" } },
+                { "type": "text", "text": "and this" }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(message.content, "Compare\nand this");
+        assert_eq!(
+            message.images,
+            vec![ImageData {
+                media_type: "image/jpeg".to_string(),
+                data: "/9j/4A==".to_string(),
+            }]
+        );
+        assert!(message.validate().is_ok());
+
+        // Images survive a round trip, e.g. through the replay log
+        let round_trip: Message =
+            serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+        assert_eq!(round_trip.images, message.images);
+
+        // An image alone is enough content
+        let image_only: Message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [{ "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }]
+        }))
+        .unwrap();
+        assert!(image_only.validate().is_ok());
+
+        // Remote images are never fetched
+        let remote = serde_json::from_value::<Message>(serde_json::json!({
+            "role": "user",
+            "content": [{ "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } }]
+        }));
+        assert!(remote.unwrap_err().to_string().contains("data: URL"));
+        let audio = serde_json::from_value::<Message>(serde_json::json!({
+            "role": "user",
+            "content": [{ "type": "input_audio", "input_audio": {} }]
+        }));
+        assert!(audio.is_err());
+
+        let mut bad = image_only.clone();
+        bad.images[0].data = "not base64!".to_string();
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_sampler_settings() {
+        let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "repeat_last_n": 256,
+            "dry_multiplier": 0.8,
+            "xtc_probability": 0.5
+        }))
+        .unwrap();
+        assert!(request.validate().is_ok());
+        assert_eq!(request.sampler.repeat_last_n, Some(256));
+
+        // Request values win, the rest come from the model's defaults
+        let mut defaults = GenerationParams::default();
+        defaults.sampler.dry_multiplier = Some(0.3);
+        defaults.sampler.dry_base = Some(1.75);
+        let params = GenerationParams::from_request(&request, defaults);
+        assert_eq!(params.sampler.dry_multiplier, Some(0.8));
+        assert_eq!(params.sampler.dry_base, Some(1.75));
+        assert_eq!(params.sampler.dry_allowed_length, None);
+
+        // Unset ones are left out, so llama-server keeps its own defaults
+        let sent = serde_json::to_value(params.sampler).unwrap();
+        assert_eq!(sent.as_object().unwrap().len(), 4);
+        assert_eq!(sent["xtc_probability"], 0.5);
+
+        for invalid in [
+            SamplerSettings {
+                repeat_last_n: Some(-2),
+                ..Default::default()
+            },
+            SamplerSettings {
+                dry_base: Some(0.5),
+                ..Default::default()
+            },
+            SamplerSettings {
+                xtc_threshold: Some(1.5),
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
 }
@@ -0,0 +1,165 @@
+//! UTF-8 safe text truncation
+//!
+//! All truncation goes through here so no caller can cut a code point in
+//! half. Token limits use a real tokenizer when the caller has one and fall
+//! back to a bytes-per-token estimate cut on a character boundary.
+
+use std::borrow::Cow;
+
+/// Average bytes per token used when no tokenizer is available
+pub const BYTES_PER_TOKEN_ESTIMATE: usize = 4;
+
+/// Counts tokens the way a specific model does
+pub trait Tokenizer {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Rough token count for text when no tokenizer is available
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len() / BYTES_PER_TOKEN_ESTIMATE
+}
+
+/// Longest prefix of at most `max_bytes` bytes that ends on a char boundary
+pub fn truncate_bytes(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Prefix of at most `max_chars` characters
+pub fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Byte offset just past the last complete sentence, if there is one
+///
+/// A sentence ends at `.`, `!`, `?` or `…`, optionally followed by closing
+/// quotes or brackets, then whitespace or the end of the text; CJK full
+/// stops need no whitespace. Decimals like `3.5` are not boundaries.
+pub fn last_sentence_end(text: &str) -> Option<usize> {
+    const TERMINATORS: &[char] = &['.', '!', '?', '…'];
+    const CJK_TERMINATORS: &[char] = &['。', '！', '？'];
+    const CLOSERS: &[char] = &['"', '\'', '”', '’', ')', ']', '»'];
+
+    let mut end = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let cjk = CJK_TERMINATORS.contains(&c);
+        if !cjk && !TERMINATORS.contains(&c) {
+            continue;
+        }
+        let mut candidate = i + c.len_utf8();
+        while let Some(&(j, next)) = chars.peek() {
+            if !CLOSERS.contains(&next) {
+                break;
+            }
+            candidate = j + next.len_utf8();
+            chars.next();
+        }
+        match chars.peek() {
+            None => end = Some(candidate),
+            Some(&(_, next)) if cjk || next.is_whitespace() => end = Some(candidate),
+            _ => {}
+        }
+    }
+    end
+}
+
+/// Longest prefix that fits in `max_tokens`
+///
+/// With a tokenizer this binary-searches character boundaries for the
+/// longest prefix it counts within the limit; without one the limit is
+/// converted to bytes with `BYTES_PER_TOKEN_ESTIMATE`.
+pub fn truncate_tokens<'a>(
+    text: &'a str,
+    max_tokens: usize,
+    tokenizer: Option<&dyn Tokenizer>,
+) -> &'a str {
+    let Some(tokenizer) = tokenizer else {
+        return truncate_bytes(text, max_tokens.saturating_mul(BYTES_PER_TOKEN_ESTIMATE));
+    };
+    if tokenizer.count_tokens(text) <= max_tokens {
+        return text;
+    }
+
+    let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    // The whole text is known not to fit, so at most all but the last char
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if tokenizer.count_tokens(&text[..boundaries[mid]]) <= max_tokens {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    &text[..boundaries[low]]
+}
+
+/// Longest suffix that fits in `max_tokens`, the mirror of `truncate_tokens`
+pub fn suffix_tokens<'a>(
+    text: &'a str,
+    max_tokens: usize,
+    tokenizer: Option<&dyn Tokenizer>,
+) -> &'a str {
+    let Some(tokenizer) = tokenizer else {
+        let max_bytes = max_tokens.saturating_mul(BYTES_PER_TOKEN_ESTIMATE);
+        if text.len() <= max_bytes {
+            return text;
+        }
+        let mut start = text.len() - max_bytes;
+        while !text.is_char_boundary(start) {
+            start += 1;
+        }
+        return &text[start..];
+    };
+    if tokenizer.count_tokens(text) <= max_tokens {
+        return text;
+    }
+
+    let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    // Search for the earliest start whose suffix fits; the whole text doesn't
+    let (mut low, mut high) = (1, boundaries.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if tokenizer.count_tokens(&text[boundaries[mid]..]) <= max_tokens {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    boundaries.get(low).map_or("", |&start| &text[start..])
+}
+
+/// Keep the start and the last `tail_tokens` of text over `max_tokens`,
+/// replacing the middle with a note of how much was cut
+///
+/// The note itself is not counted against `max_tokens`.
+pub fn truncate_middle_tokens<'a>(
+    text: &'a str,
+    max_tokens: usize,
+    tail_tokens: usize,
+    tokenizer: Option<&dyn Tokenizer>,
+) -> Cow<'a, str> {
+    let head = truncate_tokens(text, max_tokens, tokenizer);
+    if head.len() == text.len() {
+        return Cow::Borrowed(text);
+    }
+
+    let tail_tokens = tail_tokens.min(max_tokens);
+    let head = truncate_tokens(text, max_tokens - tail_tokens, tokenizer);
+    let tail = suffix_tokens(&text[head.len()..], tail_tokens, tokenizer);
+    let omitted = text[head.len()..text.len() - tail.len()].chars().count();
+    Cow::Owned(format!(
+        "{}\n[... {} characters omitted ...]\n{}",
+        head, omitted, tail
+    ))
+}
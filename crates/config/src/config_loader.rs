@@ -1,21 +1,391 @@
-use chatsafe_common::Result;
+use crate::{migrations, paths};
+use chatsafe_common::{Error, Result, StreamBoundary};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 
 /// Application configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
+    /// Format version, see `migrations`
+    #[serde(default = "current_config_version")]
+    pub version: u32,
+    /// Root of models, logs and runtime state
+    #[serde(default = "paths::default_data_dir")]
+    pub data_dir: PathBuf,
     pub server: ServerConfig,
     pub runtime: RuntimeConfig,
     pub models: ModelsConfig,
 }
 
+impl AppConfig {
+    /// `models.directory`, or `models/` in the data directory
+    pub fn model_dir(&self) -> PathBuf {
+        self.models
+            .directory
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join(paths::MODELS_DIR))
+    }
+
+    /// Directory for log files
+    pub fn log_dir(&self) -> PathBuf {
+        self.data_dir.join(paths::LOGS_DIR)
+    }
+
+    /// Directory for the running server's pid file and admin socket
+    pub fn state_dir(&self) -> PathBuf {
+        self.data_dir.join(paths::RUN_DIR)
+    }
+
+    /// Directory llama-server may save slots to (`--slot-save-path`)
+    pub fn slot_dir(&self) -> PathBuf {
+        self.data_dir.join(paths::SLOTS_DIR)
+    }
+
+    /// Reject settings that contradict each other
+    pub fn validate(&self) -> Result<()> {
+        let runtime = &self.runtime;
+        let remote = runtime.base_url.is_some()
+            || runtime.instances.iter().any(|i| i.base_url.is_some())
+            || self
+                .models
+                .serve
+                .iter()
+                .any(|s| s.instance.base_url.is_some());
+        if runtime.manage_process && remote {
+            return Err(Error::ConfigError(
+                "A base_url points at a llama-server ChatSafe does not start; \
+                 set runtime.manage_process to false to attach to it"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `server.replay_log`, with a relative path placed in the log directory
+    pub fn replay_log_path(&self) -> Option<PathBuf> {
+        let path = self.server.replay_log.as_ref()?;
+        if path.is_relative() {
+            Some(self.log_dir().join(path))
+        } else {
+            Some(path.clone())
+        }
+    }
+}
+
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
+    /// IP address to listen on (`127.0.0.1`, `::1`, `localhost`)
     pub host: String,
     pub port: u16,
+    /// Listen on these addresses instead of `host:port`
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Open connections allowed at once; more wait until one closes (0 = no limit)
     pub max_connections: usize,
+    /// Reuse connections for several HTTP/1 requests
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: bool,
+    /// Close a connection idle this long between requests (0 = never)
+    #[serde(default = "default_keep_alive_timeout_secs")]
+    pub keep_alive_timeout_secs: u64,
+    /// Send small writes such as streamed tokens without Nagle delay
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Default end-to-end budget for a request; clients may ask for less
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// First-token latency above which a request is logged and counted as slow
+    #[serde(default = "default_slow_first_token_ms")]
+    pub slow_first_token_ms: u64,
+    /// Total duration above which a request is logged and counted as slow
+    #[serde(default = "default_slow_request_ms")]
+    pub slow_request_ms: u64,
+    /// Language for user-facing error messages (`en`, `es`, `de`, `fr`)
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// Append a sanitized envelope of every chat request here for `chatsafe replay`
+    #[serde(default)]
+    pub replay_log: Option<PathBuf>,
+    /// Keep message content in replay envelopes (debugging only)
+    #[serde(default)]
+    pub replay_include_content: bool,
+    /// Granularity of streamed deltas: `token`, `grapheme` or `word`
+    #[serde(default)]
+    pub stream_boundary: StreamBoundary,
+    /// Log redacted prompt/response excerpts of this many characters (0 = off)
+    #[serde(default)]
+    pub debug_excerpt_chars: usize,
+    /// Token cap for each tool result put into the prompt (0 = no cap)
+    #[serde(default = "default_tool_output_max_tokens")]
+    pub tool_output_max_tokens: usize,
+    /// Tokens kept from the end of a capped tool result, where errors and
+    /// summaries usually are; the rest of the cap goes to its start
+    #[serde(default = "default_tool_output_tail_tokens")]
+    pub tool_output_tail_tokens: usize,
+    /// Accept `prompt_override` requests that bypass the chat template
+    #[serde(default)]
+    pub allow_prompt_override: bool,
+    /// Tell the model the current local date, time and `locale` in the
+    /// system prompt; requests can override it with `date_context`
+    #[serde(default)]
+    pub date_context: bool,
+    /// About the user, for `{{profile.<key>}}` in default system prompts
+    #[serde(default)]
+    pub profile: BTreeMap<String, String>,
+    /// Answer `/v1/*` errors with OpenAI's error object instead of ChatSafe's
+    #[serde(default = "default_openai_errors")]
+    pub openai_errors: bool,
+    /// Reverse proxies (addresses or CIDR ranges) whose `Forwarded` /
+    /// `X-Forwarded-For` headers name the real client
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// URLs told about every finished background job (e.g. sweeps)
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// Accept webhook URLs on other hosts; loopback only otherwise
+    #[serde(default)]
+    pub allow_remote_webhooks: bool,
+    /// What to do with the verdicts of a registry `guard` model
+    #[serde(default)]
+    pub guardrail: GuardrailConfig,
+    /// Named content settings (e.g. `kid-safe`) requests pick with
+    /// `content_profile`
+    #[serde(default)]
+    pub content_profiles: BTreeMap<String, ContentProfile>,
+    /// Content profile for requests that name none
+    #[serde(default)]
+    pub content_profile: Option<String>,
+}
+
+/// Content settings for a kind of audience
+///
+/// A profile adds instructions to the system prompt and turns the guard
+/// model on in blocking mode. A flagged response is generated once more with
+/// `retry_instruction` added; if that one is flagged too, `refusal` is sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentProfile {
+    /// Added to the system prompt
+    #[serde(default)]
+    pub instructions: String,
+    /// Allow profanity; otherwise the model is asked to keep language clean
+    #[serde(default)]
+    pub profanity: bool,
+    /// Allow sexual content; otherwise the model is asked to avoid it and
+    /// the guard model's `S12` counts as unsafe
+    #[serde(default)]
+    pub nsfw: bool,
+    /// Guard categories that count as unsafe; empty means all
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Added to the system prompt when regenerating a flagged response
+    #[serde(default = "default_retry_instruction")]
+    pub retry_instruction: String,
+    /// Sent instead of a response that is flagged twice
+    #[serde(default = "default_refusal")]
+    pub refusal: String,
+    /// Neutralize HTML and links that are dangerous when the client renders
+    /// responses as HTML
+    #[serde(default)]
+    pub sanitize_markup: bool,
+}
+
+impl ContentProfile {
+    /// Guard policy while the profile is active: both sides blocked
+    pub fn guardrail(&self) -> GuardrailConfig {
+        let mut categories = self.categories.clone();
+        if self.nsfw {
+            if categories.is_empty() {
+                // Llama Guard 3's categories are S1 to S14
+                categories = (1..=14).map(|n| format!("S{}", n)).collect();
+            }
+            categories.retain(|category| !category.eq_ignore_ascii_case(SEXUAL_CONTENT));
+        } else if !categories.is_empty()
+            && !categories
+                .iter()
+                .any(|category| category.eq_ignore_ascii_case(SEXUAL_CONTENT))
+        {
+            categories.push(SEXUAL_CONTENT.to_string());
+        }
+        GuardrailConfig {
+            prompts: GuardAction::Block,
+            responses: GuardAction::Block,
+            categories,
+        }
+    }
+
+    /// Everything the profile adds to the system prompt
+    pub fn system_prompt(&self) -> String {
+        let mut lines = Vec::new();
+        if !self.instructions.trim().is_empty() {
+            lines.push(self.instructions.trim());
+        }
+        if !self.profanity {
+            lines.push("Do not use profanity or crude language.");
+        }
+        if !self.nsfw {
+            lines.push("Do not produce sexual or explicit content.");
+        }
+        lines.join("\n")
+    }
+}
+
+/// Guard category for sexual content, governed by `ContentProfile::nsfw`
+const SEXUAL_CONTENT: &str = "S12";
+
+fn default_retry_instruction() -> String {
+    "Your previous answer was not appropriate for this audience. Answer again, keeping strictly to the content rules above, or decline politely.".to_string()
+}
+
+fn default_refusal() -> String {
+    "Sorry, I can't help with that.".to_string()
+}
+
+/// Policy for the guard model's verdicts on chat completions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GuardrailConfig {
+    /// Action on prompts (the conversation's last user message)
+    #[serde(default)]
+    pub prompts: GuardAction,
+    /// Action on responses
+    #[serde(default)]
+    pub responses: GuardAction,
+    /// Categories that count as unsafe, e.g. `["S1", "S4"]`; empty means all
+    #[serde(default)]
+    pub categories: Vec<String>,
+}
+
+/// What a guard verdict does to a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuardAction {
+    /// Not checked
+    Off,
+    /// Checked; the verdict is attached to the response
+    #[default]
+    Flag,
+    /// Checked; unsafe prompts are refused and unsafe responses withheld
+    Block,
+}
+
+fn current_config_version() -> u32 {
+    migrations::CONFIG.current()
+}
+
+fn default_tool_output_max_tokens() -> usize {
+    2048
+}
+
+fn default_tool_output_tail_tokens() -> usize {
+    256
+}
+
+/// One address the API listens on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    /// `HOST:PORT` (`[::1]:8081` for IPv6) or `unix:/path/to/socket`
+    pub address: String,
+    /// Require `Authorization: Bearer <token>` on this listener
+    #[serde(default)]
+    pub auth_token: Option<String>,
+}
+
+/// Parsed listener address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// Unix domain socket path
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "http://{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn listen_address(&self) -> Result<ListenAddress> {
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(Error::ConfigError(
+                    "Listener address unix: needs a socket path".into(),
+                ));
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        self.address.parse().map(ListenAddress::Tcp).map_err(|_| {
+            Error::ConfigError(format!(
+                "Invalid listener address {:?}; use HOST:PORT, [IPV6]:PORT or unix:PATH",
+                self.address
+            ))
+        })
+    }
+}
+
+impl ServerConfig {
+    /// Addresses to serve on with their auth tokens; `host:port` without a
+    /// token when no listeners are configured
+    pub fn listen_addresses(&self) -> Result<Vec<(ListenAddress, Option<String>)>> {
+        if self.listeners.is_empty() {
+            let addr = SocketAddr::new(self.host_ip()?, self.port);
+            return Ok(vec![(ListenAddress::Tcp(addr), None)]);
+        }
+        self.listeners
+            .iter()
+            .map(|listener| Ok((listener.listen_address()?, listener.auth_token.clone())))
+            .collect()
+    }
+
+    fn host_ip(&self) -> Result<IpAddr> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        if host == "localhost" {
+            return Ok(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        host.parse().map_err(|_| {
+            Error::ConfigError(format!(
+                "Invalid server host {:?}; use an IP address such as 127.0.0.1 or ::1",
+                self.host
+            ))
+        })
+    }
+}
+
+fn default_openai_errors() -> bool {
+    true
+}
+
+fn default_keep_alive() -> bool {
+    true
+}
+
+fn default_keep_alive_timeout_secs() -> u64 {
+    75
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_request_timeout_secs() -> u64 {
+    300
+}
+
+fn default_slow_first_token_ms() -> u64 {
+    10_000
+}
+
+fn default_slow_request_ms() -> u64 {
+    120_000
+}
+
+fn default_locale() -> String {
+    "en".to_string()
 }
 
 /// Runtime configuration
@@ -24,35 +394,245 @@ pub struct RuntimeConfig {
     pub llama_server_port: u16,
     pub threads: usize,
     pub gpu_layers: Option<i32>,
+    /// Upper bound on a single generation request to llama-server
+    #[serde(default = "default_generation_timeout_secs")]
+    pub generation_timeout_secs: u64,
+    /// Time allowed to open a connection to llama-server
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Reach llama-server here instead of `http://127.0.0.1:<llama_server_port>`
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Spawn and kill llama-server; when false, attach to a user-managed instance
+    #[serde(default = "default_manage_process")]
+    pub manage_process: bool,
+    /// Extra llama-server instances for the same model; empty means one at
+    /// `llama_server_port`/`base_url`
+    #[serde(default)]
+    pub instances: Vec<InstanceConfig>,
+    /// How generations are spread across instances
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    /// Working directory for spawned llama-server processes
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    /// On an out-of-memory load failure, retry with half the context window
+    #[serde(default)]
+    pub adaptive_context: bool,
+    /// Smallest context window an adaptive retry may fall back to
+    #[serde(default = "default_min_ctx_window")]
+    pub min_ctx_window: usize,
+    /// Reject prompts over this many tokens; the loaded context window
+    /// always applies
+    #[serde(default)]
+    pub max_prompt_tokens: Option<usize>,
+    /// Port of the whisper-server spawned for a `transcribe` model
+    #[serde(default = "default_transcription_port")]
+    pub transcription_port: u16,
+    /// Port of the llama-server spawned in embedding mode for an `embed` model
+    #[serde(default = "default_embedding_port")]
+    pub embedding_port: u16,
+    /// Port of the llama-server spawned for a `guard` model
+    #[serde(default = "default_guard_port")]
+    pub guard_port: u16,
+    /// Generations each llama-server instance runs at once (`--parallel`);
+    /// requests beyond every instance's slots wait in ChatSafe
+    #[serde(default = "default_parallel_slots")]
+    pub parallel_slots: usize,
+    /// On Apple Silicon, set llama-server's threads, Metal offload and
+    /// flash attention from the detected chip instead of the registry
+    #[serde(default = "default_hardware_tuning")]
+    pub hardware_tuning: bool,
+    /// Drop a conversation's oldest turns when the prompt leaves no room
+    /// for `max_tokens`, instead of letting llama-server clip it
+    #[serde(default = "default_truncate_history")]
+    pub truncate_history: bool,
+}
+
+/// One llama-server instance serving the configured model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceConfig {
+    pub port: u16,
+    /// Pin the instance to one GPU (`--split-mode none --main-gpu N`)
+    #[serde(default)]
+    pub main_gpu: Option<i32>,
+    /// Reach the instance here instead of `http://127.0.0.1:<port>`
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+impl InstanceConfig {
+    /// Base URL for HTTP calls to this instance, without a trailing slash
+    pub fn server_url(&self) -> String {
+        match &self.base_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://127.0.0.1:{}", self.port),
+        }
+    }
+}
+
+/// Instance selection strategy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    /// Fewest generations in flight, ties broken round-robin
+    LeastBusy,
+}
+
+fn default_generation_timeout_secs() -> u64 {
+    300
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    5
+}
+
+fn default_manage_process() -> bool {
+    true
+}
+
+fn default_transcription_port() -> u16 {
+    8091
+}
+
+fn default_embedding_port() -> u16 {
+    8092
+}
+
+fn default_guard_port() -> u16 {
+    8093
+}
+
+fn default_parallel_slots() -> usize {
+    4
+}
+
+fn default_hardware_tuning() -> bool {
+    true
+}
+
+fn default_truncate_history() -> bool {
+    true
+}
+
+fn default_min_ctx_window() -> usize {
+    2048
+}
+
+impl RuntimeConfig {
+    /// Base URL for HTTP calls to llama-server, without a trailing slash
+    pub fn server_url(&self) -> String {
+        self.primary_instance().server_url()
+    }
+
+    /// Generations the backend runs at once across all instances
+    pub fn total_slots(&self) -> usize {
+        self.parallel_slots.max(1) * self.resolved_instances().len()
+    }
+
+    /// Every instance to run, falling back to the single primary one
+    pub fn resolved_instances(&self) -> Vec<InstanceConfig> {
+        if self.instances.is_empty() {
+            vec![self.primary_instance()]
+        } else {
+            self.instances.clone()
+        }
+    }
+
+    fn primary_instance(&self) -> InstanceConfig {
+        InstanceConfig {
+            port: self.llama_server_port,
+            main_gpu: None,
+            base_url: self.base_url.clone(),
+        }
+    }
 }
 
 /// Models configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelsConfig {
-    pub directory: PathBuf,
+    /// Defaults to `models/` in `data_dir`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
     pub registry_file: Option<PathBuf>,
     pub default_model: String,
+    /// Chat models loaded next to the default one; requests choose by `model`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub serve: Vec<ServedModel>,
+}
+
+/// A chat model served next to the default, by its own llama-server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServedModel {
+    /// Registry ID or alias
+    pub model: String,
+    /// `port`, and optionally `main_gpu` or `base_url`
+    #[serde(flatten)]
+    pub instance: InstanceConfig,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            version: current_config_version(),
+            data_dir: paths::default_data_dir(),
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8081,
+                listeners: Vec::new(),
                 max_connections: 100,
+                keep_alive: default_keep_alive(),
+                keep_alive_timeout_secs: default_keep_alive_timeout_secs(),
+                tcp_nodelay: default_tcp_nodelay(),
+                request_timeout_secs: default_request_timeout_secs(),
+                slow_first_token_ms: default_slow_first_token_ms(),
+                slow_request_ms: default_slow_request_ms(),
+                locale: default_locale(),
+                replay_log: None,
+                replay_include_content: false,
+                stream_boundary: StreamBoundary::Token,
+                debug_excerpt_chars: 0,
+                tool_output_max_tokens: default_tool_output_max_tokens(),
+                tool_output_tail_tokens: default_tool_output_tail_tokens(),
+                allow_prompt_override: false,
+                date_context: false,
+                profile: BTreeMap::new(),
+                openai_errors: default_openai_errors(),
+                trusted_proxies: Vec::new(),
+                webhooks: Vec::new(),
+                allow_remote_webhooks: false,
+                guardrail: GuardrailConfig::default(),
+                content_profiles: BTreeMap::new(),
+                content_profile: None,
             },
             runtime: RuntimeConfig {
                 llama_server_port: 8080,
                 threads: 4,
                 gpu_layers: None, // Auto-detect
+                generation_timeout_secs: default_generation_timeout_secs(),
+                connect_timeout_secs: default_connect_timeout_secs(),
+                base_url: None,
+                manage_process: default_manage_process(),
+                instances: Vec::new(),
+                load_balancing: LoadBalancing::RoundRobin,
+                working_dir: None,
+                adaptive_context: false,
+                min_ctx_window: default_min_ctx_window(),
+                max_prompt_tokens: None,
+                transcription_port: default_transcription_port(),
+                embedding_port: default_embedding_port(),
+                guard_port: default_guard_port(),
+                parallel_slots: default_parallel_slots(),
+                hardware_tuning: default_hardware_tuning(),
+                truncate_history: default_truncate_history(),
             },
             models: ModelsConfig {
-                directory: dirs::home_dir()
-                    .unwrap_or_else(|| PathBuf::from("."))
-                    .join(".local/share/chatsafe/models"),
+                directory: None,
                 registry_file: None,
                 default_model: "llama-3.2-3b-instruct-q4_k_m".to_string(),
+                serve: Vec::new(),
             },
         }
     }
@@ -66,9 +646,7 @@ impl ConfigLoader {
     pub fn load(path: Option<&PathBuf>) -> Result<AppConfig> {
         if let Some(path) = path {
             if path.exists() {
-                let content = std::fs::read_to_string(path)?;
-                let config: AppConfig = serde_json::from_str(&content)?;
-                return Ok(config);
+                return Self::load_file(path);
             }
         }
 
@@ -82,9 +660,7 @@ impl ConfigLoader {
 
         for path in default_paths {
             if path.exists() {
-                let content = std::fs::read_to_string(&path)?;
-                let config: AppConfig = serde_json::from_str(&content)?;
-                return Ok(config);
+                return Self::load_file(&path);
             }
         }
 
@@ -92,6 +668,14 @@ impl ConfigLoader {
         Ok(AppConfig::default())
     }
 
+    /// Parse a config file, upgrading an older format on disk first
+    fn load_file(path: &Path) -> Result<AppConfig> {
+        let value = migrations::load_file(&migrations::CONFIG, path)?;
+        let config: AppConfig = serde_json::from_value(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
     /// Save configuration to file
     pub fn save(config: &AppConfig, path: &PathBuf) -> Result<()> {
         let content = serde_json::to_string_pretty(config)?;
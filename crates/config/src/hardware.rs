@@ -0,0 +1,137 @@
+//! Hardware detection and llama-server tuning
+//!
+//! The registry's `resources` are generic: four threads and whatever GPU
+//! offload the entry names, which underuses M-series Macs. On Apple Silicon
+//! the chip, its performance-core count and the memory size are read with
+//! `sysctl`. With `runtime.hardware_tuning` on, llama-server then gets one
+//! thread per performance core (efficiency cores slow generation down),
+//! every layer on Metal when the model fits the GPU's share of unified
+//! memory, and flash attention only when layers run on Metal. Elsewhere the
+//! registry values are used as written.
+
+use crate::model_registry::ModelResources;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+// Constants
+const BYTES_PER_GB: f32 = 1024.0 * 1024.0 * 1024.0;
+/// Share of unified memory macOS lets the GPU use up to 36 GB of RAM
+const METAL_SHARE_SMALL: f32 = 0.67;
+/// Share on machines with more memory
+const METAL_SHARE_LARGE: f32 = 0.75;
+const METAL_SHARE_THRESHOLD_GB: f32 = 36.0;
+/// `--n-gpu-layers` value offloading every layer
+const ALL_LAYERS: i32 = -1;
+
+/// An Apple Silicon machine
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HardwareProfile {
+    /// e.g. `Apple M2 Pro`
+    pub chip: String,
+    pub performance_cores: usize,
+    pub efficiency_cores: usize,
+    pub memory_gb: f32,
+}
+
+impl HardwareProfile {
+    /// The machine's profile, detected once; `None` off Apple Silicon
+    pub fn detect() -> Option<&'static HardwareProfile> {
+        static PROFILE: OnceLock<Option<HardwareProfile>> = OnceLock::new();
+        PROFILE.get_or_init(detect_apple_silicon).as_ref()
+    }
+
+    /// Build a profile from `sysctl` values: `machdep.cpu.brand_string`,
+    /// `hw.perflevel0.physicalcpu`, `hw.physicalcpu` and `hw.memsize`
+    pub fn from_sysctl(
+        brand: &str,
+        performance_cores: Option<usize>,
+        physical_cores: usize,
+        memory_bytes: u64,
+    ) -> Option<Self> {
+        let chip = brand.trim();
+        if !chip.starts_with("Apple M") {
+            return None;
+        }
+        let performance_cores = performance_cores
+            .filter(|&cores| cores > 0)
+            .unwrap_or(physical_cores)
+            .min(physical_cores)
+            .max(1);
+        Some(Self {
+            chip: chip.to_string(),
+            performance_cores,
+            efficiency_cores: physical_cores.saturating_sub(performance_cores),
+            memory_gb: memory_bytes as f32 / BYTES_PER_GB,
+        })
+    }
+
+    /// Memory the GPU may use: macOS caps Metal's working set below the RAM
+    pub fn metal_budget_gb(&self) -> f32 {
+        let share = if self.memory_gb > METAL_SHARE_THRESHOLD_GB {
+            METAL_SHARE_LARGE
+        } else {
+            METAL_SHARE_SMALL
+        };
+        self.memory_gb * share
+    }
+}
+
+/// Threads, GPU offload and flash attention for one llama-server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerTuning {
+    pub threads: usize,
+    pub gpu_layers: i32,
+    /// `--flash-attn` value: `on`, or `auto` to let llama-server decide
+    pub flash_attn: &'static str,
+}
+
+impl ServerTuning {
+    /// Settings for a model with `resources`, tuned for `hardware` if given
+    pub fn new(resources: &ModelResources, hardware: Option<&HardwareProfile>) -> Self {
+        let Some(hardware) = hardware else {
+            return Self {
+                threads: resources.threads,
+                gpu_layers: resources.gpu_layers,
+                flash_attn: "on",
+            };
+        };
+        // A registry entry asking for CPU only keeps it
+        let gpu_layers =
+            if resources.gpu_layers != 0 && resources.est_disk_gb <= hardware.metal_budget_gb() {
+                ALL_LAYERS
+            } else {
+                resources.gpu_layers
+            };
+        Self {
+            threads: hardware.performance_cores,
+            gpu_layers,
+            // Metal has flash attention kernels; the CPU path may not
+            flash_attn: if gpu_layers != 0 { "on" } else { "auto" },
+        }
+    }
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn detect_apple_silicon() -> Option<HardwareProfile> {
+    use std::process::Command;
+
+    let sysctl = |name: &str| {
+        Command::new("sysctl")
+            .args(["-n", name])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let brand = sysctl("machdep.cpu.brand_string")?;
+    let physical_cores = sysctl("hw.physicalcpu")?.parse().ok()?;
+    let memory_bytes = sysctl("hw.memsize")?.parse().ok()?;
+    // perflevel0 is the performance cluster (macOS 12 and later)
+    let performance_cores = sysctl("hw.perflevel0.physicalcpu").and_then(|v| v.parse().ok());
+    HardwareProfile::from_sysctl(&brand, performance_cores, physical_cores, memory_bytes)
+}
+
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn detect_apple_silicon() -> Option<HardwareProfile> {
+    None
+}
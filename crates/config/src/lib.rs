@@ -1,10 +1,28 @@
 mod config_loader;
+mod hardware;
+pub mod migrations;
+mod model_download;
+mod model_family;
+mod model_metadata;
 mod model_registry;
+mod model_store;
+pub mod paths;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
-pub use config_loader::{AppConfig, ConfigLoader, ModelsConfig, RuntimeConfig, ServerConfig};
+pub use config_loader::{
+    AppConfig, ConfigLoader, ContentProfile, GuardAction, GuardrailConfig, InstanceConfig,
+    ListenAddress, ListenerConfig, LoadBalancing, ModelsConfig, RuntimeConfig, ServedModel,
+    ServerConfig,
+};
+pub use hardware::{HardwareProfile, ServerTuning};
+pub use model_download::{DownloadProgress, ModelDownloader, ModelSource, PulledModel};
+pub use model_family::ModelFamily;
+pub use model_metadata::{read_metadata, MetadataCache, ModelMetadata};
 pub use model_registry::{
-    ModelConfig, ModelDefaults, ModelRegistry, ModelRegistryData, ModelResources, TemplateConfig,
+    Capability, LoraAdapter, ModelConfig, ModelDefaults, ModelRegistry, ModelRegistryData,
+    ModelResources, PostProcessor, TemplateConfig,
 };
+pub use model_store::{GcReport, ModelStore, StoreManifest};
@@ -0,0 +1,186 @@
+//! Versioned upgrades of the files ChatSafe reads on startup
+//!
+//! The config file, user registries and the model store manifest each carry
+//! a format version. A file written by an older build is upgraded in place
+//! when it is loaded: the original is first copied to `<file>.v<old>.bak`,
+//! then each step from its version to the current one runs over the raw
+//! JSON, and the result is written back. A file from a newer build is
+//! rejected instead of being half understood.
+//!
+//! Changing a format means appending a step to its list; the current
+//! version follows from the number of steps.
+
+use crate::paths;
+use chatsafe_common::{Error, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// How a format records its version
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VersionField {
+    /// `"version": 2`, with a missing field meaning version 1
+    Integer,
+    /// `"version": "2.0"`; only the major number is compared
+    MajorMinor,
+}
+
+/// One upgrade, from the version at its position in the list to the next
+struct Step {
+    description: &'static str,
+    apply: fn(&mut Value),
+}
+
+/// A versioned file format and its upgrade steps
+pub struct Format {
+    pub name: &'static str,
+    version_field: VersionField,
+    steps: &'static [Step],
+}
+
+/// `chatsafe.json` / `config.json`
+pub const CONFIG: Format = Format {
+    name: "config",
+    version_field: VersionField::Integer,
+    steps: &[Step {
+        description: "pin data_dir to the directory used before it existed",
+        apply: config_v1_to_v2,
+    }],
+};
+
+/// Registries loaded from `models.registry_file`
+pub const REGISTRY: Format = Format {
+    name: "registry",
+    version_field: VersionField::MajorMinor,
+    steps: &[],
+};
+
+/// `manifest.json` in the model store
+pub const STORE_MANIFEST: Format = Format {
+    name: "model store manifest",
+    version_field: VersionField::MajorMinor,
+    steps: &[],
+};
+
+impl Format {
+    /// Version this build writes
+    pub fn current(&self) -> u32 {
+        self.steps.len() as u32 + 1
+    }
+
+    /// Version of a parsed file
+    pub fn version_of(&self, value: &Value) -> Result<u32> {
+        let field = value.get("version");
+        let version = match (self.version_field, field) {
+            (_, None) => Some(1),
+            (VersionField::Integer, Some(v)) => v.as_u64().map(|v| v as u32),
+            (VersionField::MajorMinor, Some(v)) => v
+                .as_str()
+                .and_then(|s| s.split('.').next())
+                .and_then(|major| major.parse().ok()),
+        };
+        version.filter(|v| *v >= 1).ok_or_else(|| {
+            Error::ConfigError(format!(
+                "Unrecognized {} version {}",
+                self.name,
+                field.cloned().unwrap_or_default()
+            ))
+        })
+    }
+
+    fn set_version(&self, value: &mut Value, version: u32) {
+        if let Some(object) = value.as_object_mut() {
+            let field = match self.version_field {
+                VersionField::Integer => Value::from(version),
+                VersionField::MajorMinor => Value::from(format!("{}.0", version)),
+            };
+            object.insert("version".to_string(), field);
+        }
+    }
+}
+
+/// Bring `value` up to the current version, returning the version it had
+/// when anything changed
+pub fn upgrade(format: &Format, value: &mut Value) -> Result<Option<u32>> {
+    let from = format.version_of(value)?;
+    let current = format.current();
+    if from > current {
+        return Err(Error::ConfigError(format!(
+            "{} format {} is newer than this build understands ({}); upgrade ChatSafe",
+            format.name, from, current
+        )));
+    }
+    if from == current {
+        return Ok(None);
+    }
+    for (version, step) in format.steps.iter().enumerate().skip(from as usize - 1) {
+        info!(
+            "Upgrading {} from format {} to {}: {}",
+            format.name,
+            version + 1,
+            version + 2,
+            step.description
+        );
+        (step.apply)(value);
+    }
+    format.set_version(value, current);
+    Ok(Some(from))
+}
+
+/// Read a JSON file, upgrading it on disk first if it is out of date
+///
+/// The original is kept as `<file>.v<old>.bak`; an existing backup is never
+/// overwritten.
+pub fn load_file(format: &Format, path: &Path) -> Result<Value> {
+    let content = std::fs::read_to_string(path)?;
+    let mut value: Value = serde_json::from_str(&content)?;
+    if let Some(from) = upgrade(format, &mut value)? {
+        let backup = backup_path(path, from);
+        if !backup.exists() {
+            std::fs::copy(path, &backup)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&value)?)?;
+        std::fs::rename(&tmp, path)?;
+        info!(
+            "Upgraded {} {} (previous version saved as {})",
+            format.name,
+            path.display(),
+            backup.display()
+        );
+    }
+    Ok(value)
+}
+
+/// Where `load_file` keeps the pre-upgrade copy of a version `from` file
+pub fn backup_path(path: &Path, from: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{}.bak", from));
+    path.with_file_name(name)
+}
+
+/// Version 1 configs predate `data_dir` and kept everything in the legacy
+/// directory, usually with `models.directory` spelled out. Keep them there
+/// rather than silently switching to the platform default.
+fn config_v1_to_v2(value: &mut Value) {
+    let Some(legacy) = paths::legacy_data_dir() else {
+        return;
+    };
+    let Some(config) = value.as_object_mut() else {
+        return;
+    };
+    if config.contains_key("data_dir") {
+        return;
+    }
+    let legacy_models = legacy.join(paths::MODELS_DIR);
+    if let Some(models) = config.get_mut("models").and_then(Value::as_object_mut) {
+        let directory = models.get("directory").and_then(Value::as_str);
+        if directory.is_some_and(|d| Path::new(d) == legacy_models) {
+            models.remove("directory");
+        }
+    }
+    config.insert(
+        "data_dir".to_string(),
+        Value::from(legacy.to_string_lossy().into_owned()),
+    );
+}
@@ -0,0 +1,224 @@
+//! Downloading registry models from Hugging Face
+//!
+//! A registry entry with a `source` names the Hugging Face repository and
+//! file its GGUF comes from. Pulling it downloads the file into
+//! `downloads/<id>.gguf.part` under the model directory, resuming a partial
+//! file with a `Range` request, checks the SHA256 when the registry gives
+//! one and moves the file into the content-addressed store, where the
+//! registry finds it on the next load.
+//!
+//! This is the only code that talks to the network, and only when asked to.
+
+use crate::model_registry::ModelRegistry;
+use crate::model_store::ModelStore;
+use chatsafe_common::{Error, Result};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+// Constants
+const HUGGING_FACE_ENDPOINT: &str = "https://huggingface.co";
+/// Overrides the endpoint, as in the Hugging Face tools (mirrors, tests)
+const ENDPOINT_ENV: &str = "HF_ENDPOINT";
+const DOWNLOADS_DIR: &str = "downloads";
+const PARTIAL_EXTENSION: &str = "gguf.part";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a registry model's file can be downloaded from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelSource {
+    /// Hugging Face repository, e.g. `bartowski/Llama-3.2-3B-Instruct-GGUF`
+    pub repo: String,
+    /// File within the repository
+    pub file: String,
+    /// Branch, tag or commit
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    /// Expected hex-encoded SHA256 of the file; unverified when absent
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+fn default_revision() -> String {
+    "main".to_string()
+}
+
+impl ModelSource {
+    /// Download URL of the file on `endpoint`
+    pub fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}/{}/resolve/{}/{}",
+            endpoint.trim_end_matches('/'),
+            self.repo,
+            self.revision,
+            self.file
+        )
+    }
+}
+
+/// How far a download has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Bytes on disk, including any resumed from an earlier attempt
+    pub downloaded: u64,
+    /// Size of the file, when the server reports it
+    pub total: Option<u64>,
+    /// Bytes an earlier attempt had already downloaded
+    pub resumed_from: u64,
+}
+
+/// A finished download
+#[derive(Debug, Clone)]
+pub struct PulledModel {
+    /// Hex-encoded SHA256 of the file
+    pub digest: String,
+    /// Where the registry now finds the model
+    pub path: PathBuf,
+    /// Whether the digest matched the registry's `sha256`
+    pub verified: bool,
+}
+
+/// Downloads registry models into the model store
+#[derive(Debug, Clone)]
+pub struct ModelDownloader {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl ModelDownloader {
+    /// Download from `endpoint` instead of huggingface.co
+    pub fn new(endpoint: impl Into<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Internal(format!("Cannot create HTTP client: {}", e)))?;
+        Ok(Self {
+            client,
+            endpoint: endpoint.into(),
+        })
+    }
+
+    /// Download from huggingface.co, or `HF_ENDPOINT` when set
+    pub fn from_env() -> Result<Self> {
+        let endpoint =
+            std::env::var(ENDPOINT_ENV).unwrap_or_else(|_| HUGGING_FACE_ENDPOINT.to_string());
+        Self::new(endpoint)
+    }
+
+    /// Download `model_id`'s file and add it to the model store, reporting
+    /// progress after every chunk
+    pub async fn pull(
+        &self,
+        registry: &ModelRegistry,
+        model_id: &str,
+        mut on_progress: impl FnMut(DownloadProgress),
+    ) -> Result<PulledModel> {
+        let model = registry.get_model(model_id)?;
+        let source = model.source.as_ref().ok_or_else(|| {
+            Error::ConfigError(format!("Model {} has no source to download from", model_id))
+        })?;
+        let store = registry.model_store();
+        let partial = partial_path(&store, model_id);
+        if let Some(parent) = partial.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        self.download(&source.url(&self.endpoint), &partial, &mut on_progress)
+            .await?;
+
+        let digest = ModelStore::hash_file(&partial)?;
+        let verified = match &source.sha256 {
+            Some(expected) if !expected.eq_ignore_ascii_case(&digest) => {
+                // A corrupt file cannot be resumed into a good one
+                tokio::fs::remove_file(&partial).await?;
+                return Err(Error::ModelLoadFailed(format!(
+                    "Download of {} has SHA256 {}, expected {}",
+                    model_id, digest, expected
+                )));
+            }
+            Some(_) => true,
+            None => false,
+        };
+        store.import_hashed(model_id, &partial, &digest)?;
+        Ok(PulledModel {
+            path: store.blob_path(&digest),
+            digest,
+            verified,
+        })
+    }
+
+    /// Fetch `url` into `partial`, continuing from what it already holds
+    async fn download(
+        &self,
+        url: &str,
+        partial: &Path,
+        on_progress: &mut impl FnMut(DownloadProgress),
+    ) -> Result<()> {
+        let existing = match tokio::fs::metadata(partial).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let mut request = self.client.get(url);
+        if existing > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", existing));
+        }
+        let mut response = request.send().await.map_err(download_error)?;
+
+        let resumed_from = match response.status() {
+            StatusCode::PARTIAL_CONTENT => existing,
+            // The partial file already holds everything
+            StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => return Ok(()),
+            status if status.is_success() => 0,
+            status => {
+                return Err(Error::ModelLoadFailed(format!(
+                    "Download from {} failed with {}",
+                    url, status
+                )))
+            }
+        };
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed_from > 0)
+            .truncate(resumed_from == 0)
+            .open(partial)
+            .await?;
+
+        let mut progress = DownloadProgress {
+            downloaded: resumed_from,
+            total: response
+                .content_length()
+                .map(|length| length + resumed_from),
+            resumed_from,
+        };
+        on_progress(progress);
+        while let Some(chunk) = response.chunk().await.map_err(download_error)? {
+            file.write_all(&chunk).await?;
+            progress.downloaded += chunk.len() as u64;
+            on_progress(progress);
+        }
+        file.flush().await?;
+
+        match progress.total {
+            Some(total) if progress.downloaded < total => Err(Error::ModelLoadFailed(format!(
+                "Download stopped at {} of {} bytes; pull again to resume",
+                progress.downloaded, total
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where an unfinished download of `model_id` is kept
+fn partial_path(store: &ModelStore, model_id: &str) -> PathBuf {
+    store
+        .root()
+        .join(DOWNLOADS_DIR)
+        .join(format!("{}.{}", model_id, PARTIAL_EXTENSION))
+}
+
+fn download_error(error: reqwest::Error) -> Error {
+    Error::ModelLoadFailed(format!("Download failed: {}; pull again to resume", error))
+}
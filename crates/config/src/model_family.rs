@@ -0,0 +1,134 @@
+//! Recommended settings per model family
+//!
+//! A registry entry that leaves out `defaults`, or some of its fields, gets
+//! the sampling profile its family's authors recommend instead of one global
+//! default: Gemma wants `temperature` 1.0 and no repeat penalty, Qwen a low
+//! `top_k`, and so on. An entry without `template_id` likewise gets its
+//! family's chat template when the registry defines one. The family comes
+//! from `metadata.family` (a name or GGUF architecture such as `qwen2`), or
+//! else from the model's ID, name or file name.
+
+use crate::model_registry::ModelDefaults;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Model families with their own recommended sampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelFamily {
+    Llama3,
+    Qwen,
+    Gemma,
+    Phi,
+}
+
+impl ModelFamily {
+    /// Recognize the family in a name such as `qwen2.5-7b-instruct`,
+    /// `Llama 3.2 3B` or the GGUF architecture `gemma2`
+    pub fn detect(name: &str) -> Option<Self> {
+        let lower = name.to_lowercase();
+        let words: Vec<&str> = lower
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        words.iter().enumerate().find_map(|(i, word)| {
+            if word.starts_with("qwen") {
+                Some(Self::Qwen)
+            } else if word.starts_with("gemma") {
+                Some(Self::Gemma)
+            } else if word.starts_with("phi") && word[3..].chars().all(|c| c.is_ascii_digit()) {
+                Some(Self::Phi)
+            } else if *word == "llama3"
+                || (*word == "llama" && words.get(i + 1).is_some_and(|next| *next == "3"))
+            {
+                Some(Self::Llama3)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The family of a raw registry entry
+    fn of_entry(model: &Value) -> Option<Self> {
+        ["/metadata/family", "/id", "/name", "/path"]
+            .iter()
+            .filter_map(|pointer| model.pointer(pointer)?.as_str())
+            .find_map(Self::detect)
+    }
+
+    /// Sampling defaults recommended for the family
+    pub fn sampling_defaults(self) -> ModelDefaults {
+        let (temperature, top_p, top_k, repeat_penalty) = match self {
+            Self::Llama3 => (0.6, 0.9, 40, 1.1),
+            Self::Qwen => (0.7, 0.8, 20, 1.05),
+            Self::Gemma => (1.0, 0.95, 64, 1.0),
+            Self::Phi => (0.7, 0.95, 40, 1.0),
+        };
+        ModelDefaults {
+            temperature,
+            top_p,
+            top_k,
+            repeat_penalty,
+            ..ModelDefaults::default()
+        }
+    }
+
+    /// Chat template the family's models are trained on
+    pub fn template_id(self) -> &'static str {
+        match self {
+            Self::Llama3 => "llama3",
+            Self::Qwen => "chatml",
+            Self::Gemma => "gemma",
+            Self::Phi => "phi3",
+        }
+    }
+}
+
+/// Fill each model's missing `defaults` fields, and a missing `template_id`
+/// the registry can satisfy, from its family
+pub(crate) fn fill_family_defaults(registry: &mut Value) {
+    let templates: Vec<String> = registry
+        .get("templates")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|template| template.get("id")?.as_str().map(str::to_string))
+        .collect();
+    let Some(models) = registry.get_mut("models").and_then(Value::as_array_mut) else {
+        return;
+    };
+
+    for model in models.iter_mut().filter(|model| model.is_object()) {
+        let family = ModelFamily::of_entry(model);
+        let profile = family.map_or_else(ModelDefaults::default, ModelFamily::sampling_defaults);
+        let Ok(Value::Object(profile)) = serde_json::to_value(profile) else {
+            continue;
+        };
+
+        let defaults = model.as_object_mut().map(|model| {
+            model
+                .entry("defaults")
+                .or_insert_with(|| Value::Object(Default::default()))
+        });
+        if let Some(Value::Object(defaults)) = defaults {
+            for (key, value) in profile {
+                defaults.entry(key).or_insert(value);
+            }
+        }
+
+        let is_chat = model
+            .get("capability")
+            .and_then(Value::as_str)
+            .is_none_or(|capability| capability == "chat");
+        let Some(template_id) = family.filter(|_| is_chat).map(ModelFamily::template_id) else {
+            continue;
+        };
+        let has_template = model
+            .get("template_id")
+            .and_then(Value::as_str)
+            .is_some_and(|id| !id.is_empty());
+        if !has_template && templates.iter().any(|id| id == template_id) {
+            model["template_id"] = Value::from(template_id);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! GGUF header metadata with an on-disk cache
+//!
+//! Parsing GGUF headers is slow, so results are kept in a small JSON index next
+//! to the models, keyed by file path and invalidated whenever the file's size or
+//! modification time changes. Files are never hashed here: a store blob's digest
+//! is its file name, and other files have none.
+
+use crate::model_store::ModelStore;
+use chatsafe_common::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Constants
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const INDEX_FILE: &str = "metadata-index.json";
+const KEY_ARCHITECTURE: &str = "general.architecture";
+const KEY_NAME: &str = "general.name";
+const KEY_CHAT_TEMPLATE: &str = "tokenizer.chat_template";
+const CONTEXT_LENGTH_SUFFIX: &str = ".context_length";
+const MAX_KEY_LEN: u64 = 64 * 1024;
+const MAX_STRING_LEN: u64 = 16 * 1024 * 1024;
+
+/// Metadata extracted from a GGUF file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub architecture: Option<String>,
+    pub name: Option<String>,
+    /// Training context length declared by the model
+    pub context_length: Option<u64>,
+    /// Jinja chat template embedded in the tokenizer metadata
+    pub chat_template: Option<String>,
+    pub size_bytes: u64,
+    /// Hex-encoded SHA256 of a store blob; `None` for files outside the store
+    pub sha256: Option<String>,
+}
+
+/// Cached metadata for one file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size_bytes: u64,
+    modified_secs: u64,
+    metadata: ModelMetadata,
+}
+
+/// On-disk metadata index keyed by model file path
+#[derive(Debug)]
+pub struct MetadataCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    dirty: bool,
+}
+
+impl MetadataCache {
+    /// Open the index in the given model directory (missing or corrupt indexes start empty)
+    pub fn open(model_dir: &Path) -> Self {
+        let path = model_dir.join(INDEX_FILE);
+        let entries = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// Return cached metadata for a file, re-reading it if it changed
+    pub fn get(&mut self, file: &Path) -> Result<ModelMetadata> {
+        let fs_meta = std::fs::metadata(file)?;
+        let size_bytes = fs_meta.len();
+        let modified_secs = fs_meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let key = file.to_string_lossy().to_string();
+
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.size_bytes == size_bytes && entry.modified_secs == modified_secs {
+                return Ok(entry.metadata.clone());
+            }
+        }
+
+        let metadata = read_metadata(file)?;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                size_bytes,
+                modified_secs,
+                metadata: metadata.clone(),
+            },
+        );
+        self.dirty = true;
+        Ok(metadata)
+    }
+
+    /// Drop entries for files that no longer exist
+    pub fn prune(&mut self) {
+        let before = self.entries.len();
+        self.entries.retain(|path, _| Path::new(path).exists());
+        self.dirty |= self.entries.len() != before;
+    }
+
+    /// Persist the index if anything changed
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string(&self.entries)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Parse the GGUF header; the digest comes from the store blob's name
+pub fn read_metadata(file: &Path) -> Result<ModelMetadata> {
+    let size_bytes = std::fs::metadata(file)?.len();
+    let mut metadata = parse_gguf_header(file)?;
+    metadata.size_bytes = size_bytes;
+    metadata.sha256 = ModelStore::blob_digest(file);
+    Ok(metadata)
+}
+
+/// Parse the key/value section of a GGUF header
+fn parse_gguf_header(file: &Path) -> Result<ModelMetadata> {
+    let mut reader = GgufReader {
+        inner: BufReader::new(File::open(file)?),
+        version: 0,
+    };
+
+    let mut magic = [0u8; 4];
+    reader.inner.read_exact(&mut magic)?;
+    if &magic != GGUF_MAGIC {
+        return Err(Error::InvalidModel(format!(
+            "{} is not a GGUF file",
+            file.display()
+        )));
+    }
+
+    reader.version = reader.u32()?;
+    let _tensor_count = reader.length()?;
+    let kv_count = reader.length()?;
+
+    let mut metadata = ModelMetadata {
+        architecture: None,
+        name: None,
+        context_length: None,
+        chat_template: None,
+        size_bytes: 0,
+        sha256: None,
+    };
+
+    for _ in 0..kv_count {
+        let key = reader.string(MAX_KEY_LEN)?;
+        let value_type = reader.u32()?;
+
+        match key.as_str() {
+            KEY_ARCHITECTURE | KEY_NAME | KEY_CHAT_TEMPLATE if value_type == GgufType::STRING => {
+                let value = reader.string(MAX_STRING_LEN)?;
+                match key.as_str() {
+                    KEY_ARCHITECTURE => metadata.architecture = Some(value),
+                    KEY_NAME => metadata.name = Some(value),
+                    _ => metadata.chat_template = Some(value),
+                }
+            }
+            _ if key.ends_with(CONTEXT_LENGTH_SUFFIX) => {
+                metadata.context_length = reader.integer(value_type)?;
+            }
+            _ => reader.skip_value(value_type)?,
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// GGUF value type tags
+struct GgufType;
+
+impl GgufType {
+    const UINT8: u32 = 0;
+    const INT8: u32 = 1;
+    const UINT16: u32 = 2;
+    const INT16: u32 = 3;
+    const UINT32: u32 = 4;
+    const INT32: u32 = 5;
+    const FLOAT32: u32 = 6;
+    const BOOL: u32 = 7;
+    const STRING: u32 = 8;
+    const ARRAY: u32 = 9;
+    const UINT64: u32 = 10;
+    const INT64: u32 = 11;
+    const FLOAT64: u32 = 12;
+
+    /// Size in bytes of fixed-width types
+    fn fixed_size(value_type: u32) -> Option<u64> {
+        match value_type {
+            Self::UINT8 | Self::INT8 | Self::BOOL => Some(1),
+            Self::UINT16 | Self::INT16 => Some(2),
+            Self::UINT32 | Self::INT32 | Self::FLOAT32 => Some(4),
+            Self::UINT64 | Self::INT64 | Self::FLOAT64 => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// Little-endian GGUF primitive reader
+struct GgufReader {
+    inner: BufReader<File>,
+    /// Format version from the header; version 1 counts with 32 bits
+    version: u32,
+}
+
+impl GgufReader {
+    fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// A count or string length, whose width depends on the version
+    fn length(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            Ok(self.u32()?.into())
+        } else {
+            self.u64()
+        }
+    }
+
+    fn string(&mut self, max_len: u64) -> Result<String> {
+        let len = self.length()?;
+        if len > max_len {
+            return Err(Error::InvalidModel(format!(
+                "GGUF string length {} exceeds limit",
+                len
+            )));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Read an unsigned/signed integer value of any width
+    fn integer(&mut self, value_type: u32) -> Result<Option<u64>> {
+        let Some(size) = GgufType::fixed_size(value_type) else {
+            self.skip_value(value_type)?;
+            return Ok(None);
+        };
+        if matches!(
+            value_type,
+            GgufType::FLOAT32 | GgufType::FLOAT64 | GgufType::BOOL
+        ) {
+            self.skip(size)?;
+            return Ok(None);
+        }
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf[..size as usize])?;
+        Ok(Some(u64::from_le_bytes(buf)))
+    }
+
+    fn skip(&mut self, bytes: u64) -> Result<()> {
+        self.inner.seek(SeekFrom::Current(bytes as i64))?;
+        Ok(())
+    }
+
+    fn skip_value(&mut self, value_type: u32) -> Result<()> {
+        if let Some(size) = GgufType::fixed_size(value_type) {
+            return self.skip(size);
+        }
+        match value_type {
+            GgufType::STRING => {
+                let len = self.length()?;
+                self.skip(len)
+            }
+            GgufType::ARRAY => {
+                let item_type = self.u32()?;
+                let count = self.length()?;
+                if let Some(size) = GgufType::fixed_size(item_type) {
+                    return self.skip(size.saturating_mul(count));
+                }
+                for _ in 0..count {
+                    self.skip_value(item_type)?;
+                }
+                Ok(())
+            }
+            other => Err(Error::InvalidModel(format!(
+                "Unknown GGUF value type {}",
+                other
+            ))),
+        }
+    }
+}
@@ -1,7 +1,14 @@
-use chatsafe_common::{Error, GenerationParams, Result};
+use crate::migrations;
+use crate::model_download::ModelSource;
+use crate::model_family::fill_family_defaults;
+use crate::model_metadata::{MetadataCache, ModelMetadata};
+use crate::model_store::{ModelStore, StoreManifest};
+use chatsafe_common::{Error, GenerationParams, Result, SamplerSettings};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 /// Complete model configuration from registry
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,15 +19,43 @@ pub struct ModelConfig {
     pub name: String,
     /// Model file path/name
     pub path: String,
+    /// Where `chatsafe models pull` downloads the file from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<ModelSource>,
     /// Context window size
     pub ctx_window: usize,
-    /// Template identifier
+    /// What the model is served for; only `chat` models can be loaded
+    /// by the chat runtime
+    #[serde(default)]
+    pub capability: Capability,
+    /// Template identifier (chat models)
+    #[serde(default)]
     pub template_id: String,
-    /// Stop sequences
+    /// Stop sequences added to the template's stop tokens
+    #[serde(default)]
     pub stop_sequences: Vec<String>,
-    /// End of sequence token
+    /// End of sequence token (chat models)
+    #[serde(default)]
     pub eos_token: String,
-    /// Default generation parameters
+    /// Accepts images in chat messages (LLaVA-style models); needs `mmproj`
+    #[serde(default)]
+    pub vision: bool,
+    /// Multimodal projector file passed as `--mmproj`, relative to the
+    /// model directory
+    #[serde(default)]
+    pub mmproj: Option<String>,
+    /// LoRA adapters applied on top of the model (chat models)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub loras: Vec<LoraAdapter>,
+    /// Environment variables set on this model's llama-server process
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Processors run over each final response, in order
+    #[serde(default)]
+    pub postprocess: Vec<PostProcessor>,
+    /// Default generation parameters; fields left out of the registry come
+    /// from the model family's profile (see `ModelFamily`)
+    #[serde(default)]
     pub defaults: ModelDefaults,
     /// Resource requirements
     pub resources: ModelResources,
@@ -31,6 +66,53 @@ pub struct ModelConfig {
     pub metadata: HashMap<String, serde_json::Value>,
 }
 
+/// A LoRA adapter passed to llama-server as `--lora-scaled`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapter {
+    /// GGUF adapter file, relative to the model directory
+    pub path: String,
+    /// Strength the adapter is applied with; 0 loads it disabled
+    #[serde(default = "default_lora_scale")]
+    pub scale: f32,
+}
+
+fn default_lora_scale() -> f32 {
+    1.0
+}
+
+/// Kind of backend a registry model runs on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Chat completions through llama-server
+    #[default]
+    Chat,
+    /// Speech to text through whisper.cpp's whisper-server
+    Transcribe,
+    /// Text to speech; each entry is one piper voice
+    Speech,
+    /// Image generation through stable-diffusion.cpp (`images` feature)
+    Image,
+    /// Text embeddings through llama-server in embedding mode
+    Embed,
+    /// Safety classification of prompts and responses (Llama Guard style)
+    /// through llama-server
+    Guard,
+}
+
+/// Final-response processor, see `chatsafe_runtime::postprocess`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessor {
+    /// Close a code fence left open at the end of the response
+    CloseCodeFences,
+    /// Renumber ordered lists sequentially
+    NormalizeLists,
+    /// Escape dangerous HTML and defuse `javascript:`/`data:` links, for
+    /// clients that render output as HTML
+    SanitizeMarkup,
+}
+
 /// Default generation parameters
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelDefaults {
@@ -39,6 +121,22 @@ pub struct ModelDefaults {
     pub top_k: i32,
     pub repeat_penalty: f32,
     pub max_tokens: usize,
+    /// Repeat window, DRY and XTC, given next to the fields above
+    #[serde(flatten)]
+    pub sampler: SamplerSettings,
+}
+
+impl Default for ModelDefaults {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            top_p: 0.9,
+            top_k: 40,
+            repeat_penalty: 1.1,
+            sampler: Default::default(),
+            max_tokens: 2000,
+        }
+    }
 }
 
 /// Resource requirements
@@ -55,7 +153,7 @@ pub struct ModelResources {
 }
 
 /// Template configuration for different model families
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TemplateConfig {
     pub id: String,
     pub name: String,
@@ -65,6 +163,14 @@ pub struct TemplateConfig {
     pub user_suffix: String,
     pub assistant_prefix: String,
     pub assistant_suffix: String,
+    /// Wraps tool results; templates without one fall back to the user turn
+    #[serde(default)]
+    pub tool_prefix: String,
+    #[serde(default)]
+    pub tool_suffix: String,
+    /// Canonical stop tokens for this format, inherited by every model using it
+    #[serde(default)]
+    pub stop_tokens: Vec<String>,
     pub default_system_prompt: String,
 }
 
@@ -74,6 +180,9 @@ pub struct ModelRegistryData {
     pub version: String,
     pub templates: Vec<TemplateConfig>,
     pub models: Vec<ModelConfig>,
+    /// Stable names for models, e.g. `"default-chat": "llama-3.2-3b-instruct-q4_k_m"`
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
 }
 
 /// Model registry manager
@@ -83,34 +192,47 @@ pub struct ModelRegistry {
     templates: HashMap<String, TemplateConfig>,
     model_dir: PathBuf,
     default_model_id: Option<String>,
+    aliases: HashMap<String, String>,
+    /// Store manifest as last read, so model paths don't re-read it
+    manifest: Arc<Mutex<Option<CachedManifest>>>,
+}
+
+/// A manifest with the directory and file stamp it was read under
+#[derive(Debug)]
+struct CachedManifest {
+    model_dir: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+    manifest: StoreManifest,
 }
 
 impl ModelRegistry {
     /// Create an empty registry
     pub fn new() -> Result<Self> {
-        let model_dir = dirs::home_dir()
-            .ok_or_else(|| Error::ConfigError("Cannot determine home directory".into()))?
-            .join(".local/share/chatsafe/models");
+        let model_dir = crate::paths::default_data_dir().join(crate::paths::MODELS_DIR);
 
         Ok(Self {
             models: HashMap::new(),
             templates: HashMap::new(),
             model_dir,
             default_model_id: None,
+            aliases: HashMap::new(),
+            manifest: Arc::default(),
         })
     }
 
     /// Load registry from JSON file
-    pub fn load_from_file(path: &PathBuf) -> Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let data: ModelRegistryData = serde_json::from_str(&content)?;
-        Self::from_data(data)
+    pub fn load_from_file(path: &Path) -> Result<Self> {
+        let mut value = migrations::load_file(&migrations::REGISTRY, path)?;
+        fill_family_defaults(&mut value);
+        Self::from_data(serde_json::from_value(value)?)
     }
 
     /// Load registry from JSON string
     pub fn load_from_json(json: &str) -> Result<Self> {
-        let data: ModelRegistryData = serde_json::from_str(json)?;
-        Self::from_data(data)
+        let mut value: serde_json::Value = serde_json::from_str(json)?;
+        migrations::upgrade(&migrations::REGISTRY, &mut value)?;
+        fill_family_defaults(&mut value);
+        Self::from_data(serde_json::from_value(value)?)
     }
 
     /// Create registry from data
@@ -125,6 +247,35 @@ impl ModelRegistry {
         // Load models and find default
         let mut default_found = false;
         for model in data.models {
+            if model.default && model.capability != Capability::Chat {
+                return Err(Error::ConfigError(format!(
+                    "Default model {} must be a chat model",
+                    model.id
+                )));
+            }
+            if model.vision && model.mmproj.is_none() {
+                return Err(Error::ConfigError(format!(
+                    "Vision model {} needs an mmproj file",
+                    model.id
+                )));
+            }
+            if !model.loras.is_empty() && model.capability != Capability::Chat {
+                return Err(Error::ConfigError(format!(
+                    "LoRA adapters are only supported on chat models, not {}",
+                    model.id
+                )));
+            }
+            if let Some(lora) = model.loras.iter().find(|lora| !lora.scale.is_finite()) {
+                return Err(Error::ConfigError(format!(
+                    "Model {} LoRA {} has an invalid scale",
+                    model.id, lora.path
+                )));
+            }
+            model
+                .defaults
+                .sampler
+                .validate()
+                .map_err(|e| Error::ConfigError(format!("Model {} defaults: {}", model.id, e)))?;
             if model.default {
                 if default_found {
                     return Err(Error::ConfigError(
@@ -138,8 +289,13 @@ impl ModelRegistry {
         }
 
         if !default_found && !registry.models.is_empty() {
-            // If no default specified, use first model
-            let first_id = registry.models.keys().next().cloned();
+            // If no default specified, use the first chat model
+            let first_id = registry
+                .models
+                .values()
+                .filter(|model| model.capability == Capability::Chat)
+                .map(|model| model.id.clone())
+                .min();
             if let Some(id) = first_id {
                 registry.default_model_id = Some(id.clone());
                 if let Some(model) = registry.models.get_mut(&id) {
@@ -148,6 +304,11 @@ impl ModelRegistry {
             }
         }
 
+        for (alias, target) in data.aliases {
+            let model_id = registry.check_alias(&alias, &target)?;
+            registry.aliases.insert(alias, model_id);
+        }
+
         Ok(registry)
     }
 
@@ -164,6 +325,50 @@ impl ModelRegistry {
             .ok_or_else(|| Error::ModelNotFound(id.to_string()))
     }
 
+    /// Models with the given capability, sorted by ID
+    pub fn models_with_capability(&self, capability: Capability) -> Vec<&ModelConfig> {
+        let mut models: Vec<_> = self
+            .models
+            .values()
+            .filter(|model| model.capability == capability)
+            .collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        models
+    }
+
+    /// Model serving speech-to-text, if the registry has one
+    pub fn get_transcription_model(&self) -> Option<&ModelConfig> {
+        self.models_with_capability(Capability::Transcribe)
+            .first()
+            .copied()
+    }
+
+    /// Model generating images, if the registry has one
+    pub fn get_image_model(&self) -> Option<&ModelConfig> {
+        self.models_with_capability(Capability::Image)
+            .first()
+            .copied()
+    }
+
+    /// Model serving embeddings, if the registry has one
+    pub fn get_embedding_model(&self) -> Option<&ModelConfig> {
+        self.models_with_capability(Capability::Embed)
+            .first()
+            .copied()
+    }
+
+    /// Model classifying prompts and responses for the guardrail, if any
+    pub fn get_guard_model(&self) -> Option<&ModelConfig> {
+        self.models_with_capability(Capability::Guard)
+            .first()
+            .copied()
+    }
+
+    /// Voices for text-to-speech
+    pub fn get_speech_models(&self) -> Vec<&ModelConfig> {
+        self.models_with_capability(Capability::Speech)
+    }
+
     /// Get the default model
     pub fn get_default_model(&self) -> Result<&ModelConfig> {
         let id = self
@@ -187,28 +392,125 @@ impl ModelRegistry {
     }
 
     /// Get the full path to a model file
+    ///
+    /// Models managed by the content-addressed store resolve to their blob;
+    /// everything else is looked up by file name in the model directory.
     pub fn get_model_path(&self, model_id: &str) -> Result<PathBuf> {
         let model = self.get_model(model_id)?;
+        if let Some(blob) = self.store_blob(model_id)? {
+            return Ok(blob);
+        }
         Ok(self.model_dir.join(&model.path))
     }
 
+    /// Blob the store manifest maps `model_id` to, re-reading the manifest
+    /// only when it was rewritten since the last call
+    fn store_blob(&self, model_id: &str) -> Result<Option<PathBuf>> {
+        let store = self.model_store();
+        let stamp = store.manifest_stamp()?;
+        let mut cached = self.manifest.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = cached
+            .as_ref()
+            .is_some_and(|c| c.model_dir == self.model_dir && c.stamp == stamp);
+        if !fresh {
+            *cached = Some(CachedManifest {
+                model_dir: self.model_dir.clone(),
+                stamp,
+                manifest: store.load_manifest()?,
+            });
+        }
+        Ok(cached
+            .as_ref()
+            .and_then(|c| c.manifest.models.get(model_id))
+            .map(|digest| store.blob_path(digest)))
+    }
+
+    /// Path of a vision model's multimodal projector
+    pub fn get_mmproj_path(&self, model_id: &str) -> Result<Option<PathBuf>> {
+        let model = self.get_model(model_id)?;
+        Ok(model
+            .mmproj
+            .as_ref()
+            .map(|mmproj| self.model_dir.join(mmproj)))
+    }
+
+    /// Paths of a model's LoRA adapters, in registry order
+    pub fn get_lora_paths(&self, model_id: &str) -> Result<Vec<PathBuf>> {
+        let model = self.get_model(model_id)?;
+        Ok(model
+            .loras
+            .iter()
+            .map(|lora| self.model_dir.join(&lora.path))
+            .collect())
+    }
+
+    /// Content-addressed store backing the model directory
+    pub fn model_store(&self) -> ModelStore {
+        ModelStore::new(self.model_dir.clone())
+    }
+
+    /// Read GGUF metadata for every installed model, using the on-disk cache
+    ///
+    /// Models whose file is missing are skipped. Unchanged files are served
+    /// from the index without re-reading them.
+    pub fn load_metadata(&self) -> Result<HashMap<String, ModelMetadata>> {
+        let mut cache = MetadataCache::open(&self.model_dir);
+        let mut metadata = HashMap::new();
+
+        // Only chat models are GGUF; whisper uses its own ggml format
+        let chat_models = self
+            .models
+            .values()
+            .filter(|model| model.capability == Capability::Chat);
+        for model in chat_models {
+            let id = &model.id;
+            let path = self.get_model_path(id)?;
+            if path.exists() {
+                metadata.insert(id.clone(), cache.get(&path)?);
+            }
+        }
+
+        cache.prune();
+        cache.save()?;
+        Ok(metadata)
+    }
+
     /// Set model directory
     pub fn set_model_dir(&mut self, dir: PathBuf) {
         self.model_dir = dir;
     }
 
     /// Create generation params from model defaults
+    ///
+    /// Stop sequences are the template's stop tokens extended by the model's own.
     pub fn get_generation_params(&self, model_id: &str) -> Result<GenerationParams> {
         let model = self.get_model(model_id)?;
-        Ok(GenerationParams {
+        let template = self.get_template(&model.template_id)?;
+        let mut params = GenerationParams {
             request_id: uuid::Uuid::new_v4().to_string(),
             temperature: model.defaults.temperature,
             max_tokens: model.defaults.max_tokens,
             top_p: model.defaults.top_p,
             top_k: model.defaults.top_k,
             repeat_penalty: model.defaults.repeat_penalty,
-            stop_sequences: model.stop_sequences.clone(),
-        })
+            sampler: model.defaults.sampler,
+            stop_sequences: Vec::new(),
+            deadline: None,
+            cache_prompt: true,
+            stream_boundary: Default::default(),
+            finish: Default::default(),
+            prompt_override: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            response_format: None,
+            grammar: None,
+            prompt_variables: Default::default(),
+            top_logprobs: None,
+            sanitize_markup: false,
+        };
+        params.add_stop_sequences(&template.stop_tokens);
+        params.add_stop_sequences(&model.stop_sequences);
+        Ok(params)
     }
 
     /// Apply request overrides to generation params
@@ -242,6 +544,64 @@ impl ModelRegistry {
         Ok(params)
     }
 
+    /// Check that `alias` can point at `target`, a model ID or `name@quant`,
+    /// and return the model ID: the name must not shadow a model and the
+    /// target must be a chat model
+    pub fn check_alias(&self, alias: &str, target: &str) -> Result<String> {
+        if self.models.contains_key(alias) {
+            return Err(Error::ConfigError(format!(
+                "Alias {} has the same name as a model",
+                alias
+            )));
+        }
+        let model = self.find_version(target).map_err(|e| {
+            Error::ConfigError(format!("Alias {} points at {}: {}", alias, target, e))
+        })?;
+        if model.capability != Capability::Chat {
+            return Err(Error::ConfigError(format!(
+                "Alias {} must point at a chat model, not {}",
+                alias, target
+            )));
+        }
+        Ok(model.id.clone())
+    }
+
+    /// The model `target` names: an ID, or `name@quant` for the one model
+    /// whose ID is `name` or starts with `name-`, and whose
+    /// `metadata.quantization` is `quant`
+    pub fn find_version(&self, target: &str) -> Result<&ModelConfig> {
+        let Some((name, quant)) = target.split_once('@') else {
+            return self.get_model(target);
+        };
+        let prefix = format!("{}-", name);
+        let mut matches = self.models.values().filter(|model| {
+            let quantization = model.metadata.get("quantization").and_then(|q| q.as_str());
+            (model.id == name || model.id.starts_with(&prefix))
+                && quantization.is_some_and(|q| q.eq_ignore_ascii_case(quant))
+        });
+        match (matches.next(), matches.next()) {
+            (Some(model), None) => Ok(model),
+            (None, _) => Err(Error::ModelNotFound(format!(
+                "no {} model with quantization {}",
+                name, quant
+            ))),
+            (Some(_), Some(_)) => Err(Error::ConfigError(format!(
+                "{} matches several models; name one by ID",
+                target
+            ))),
+        }
+    }
+
+    /// Aliases from the registry file, alias to model ID
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    /// Model ID behind `name`, which may be an alias or already an ID
+    pub fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
     /// List all available model IDs
     pub fn list_models(&self) -> Vec<String> {
         self.models.keys().cloned().collect()
@@ -267,6 +627,7 @@ impl ModelRegistry {
             version: "1.0".to_string(),
             templates: self.templates.values().cloned().collect(),
             models: self.models.values().cloned().collect(),
+            aliases: self.aliases.clone(),
         };
 
         serde_json::to_string_pretty(&data).map_err(Error::Serialization)
@@ -275,7 +636,9 @@ impl ModelRegistry {
 
 // For the sys_info functionality
 mod sys_info {
-    use chatsafe_common::{Error, Result};
+    #[cfg(target_os = "macos")]
+    use chatsafe_common::Error;
+    use chatsafe_common::Result;
 
     pub struct MemInfo {
         pub _total: u64,
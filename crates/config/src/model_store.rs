@@ -0,0 +1,235 @@
+//! Content-addressed model storage
+//!
+//! GGUF files are stored under `blobs/sha256-<digest>` inside the model directory,
+//! and a manifest maps registry IDs to digests. Several registry entries can point
+//! at the same blob, and blobs no longer referenced by the manifest can be
+//! garbage collected.
+
+use crate::migrations;
+use chatsafe_common::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Constants
+const BLOBS_DIR: &str = "blobs";
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_VERSION: &str = "1.0";
+const DIGEST_PREFIX: &str = "sha256-";
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Manifest mapping registry model IDs to blob digests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreManifest {
+    pub version: String,
+    /// Model ID -> hex-encoded SHA256 digest
+    pub models: BTreeMap<String, String>,
+}
+
+impl Default for StoreManifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION.to_string(),
+            models: BTreeMap::new(),
+        }
+    }
+}
+
+/// Result of a garbage collection pass
+#[derive(Debug, Clone, Default)]
+pub struct GcReport {
+    /// Digests of blobs that were (or would be, on a dry run) removed
+    pub removed: Vec<String>,
+    /// Total size of the removed blobs in bytes
+    pub freed_bytes: u64,
+}
+
+/// Content-addressed store rooted at the model directory
+#[derive(Debug, Clone)]
+pub struct ModelStore {
+    root: PathBuf,
+}
+
+impl ModelStore {
+    /// Create a store rooted at the given model directory
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The model directory the store is rooted at
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Directory holding the blobs
+    pub fn blobs_dir(&self) -> PathBuf {
+        self.root.join(BLOBS_DIR)
+    }
+
+    /// Path of the blob for a digest
+    pub fn blob_path(&self, digest: &str) -> PathBuf {
+        self.blobs_dir()
+            .join(format!("{}{}", DIGEST_PREFIX, digest))
+    }
+
+    /// Digest of a blob, read from its file name; `None` outside a store
+    pub fn blob_digest(path: &Path) -> Option<String> {
+        let digest = path.file_name()?.to_str()?.strip_prefix(DIGEST_PREFIX)?;
+        let in_blobs = path.parent()?.file_name()? == BLOBS_DIR;
+        (in_blobs && !digest.is_empty()).then(|| digest.to_string())
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.root.join(MANIFEST_FILE)
+    }
+
+    /// Modification time and size of the manifest, `None` if there is none;
+    /// a change in either means it was rewritten
+    pub fn manifest_stamp(&self) -> Result<Option<(SystemTime, u64)>> {
+        match std::fs::metadata(self.manifest_path()) {
+            Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load the manifest, returning an empty one if none exists yet
+    pub fn load_manifest(&self) -> Result<StoreManifest> {
+        let path = self.manifest_path();
+        if !path.exists() {
+            return Ok(StoreManifest::default());
+        }
+        migrations::load_file(&migrations::STORE_MANIFEST, &path)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+            .map_err(|e| match e {
+                // Version problems, such as a manifest from a newer build,
+                // already say what is wrong and are not corruption
+                Error::ConfigError(_) => e,
+                e => {
+                    Error::ConfigError(format!("Corrupt model manifest {}: {}", path.display(), e))
+                }
+            })
+    }
+
+    /// Write the manifest atomically (temp file + rename)
+    pub fn save_manifest(&self, manifest: &StoreManifest) -> Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let path = self.manifest_path();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(manifest)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Compute the hex-encoded SHA256 digest of a file
+    pub fn hash_file(path: &Path) -> Result<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Move a model file into the store and register it under `model_id`
+    ///
+    /// If a blob with the same content already exists the source file is
+    /// removed instead, so identical downloads are stored once.
+    pub fn import(&self, model_id: &str, source: &Path) -> Result<String> {
+        let digest = Self::hash_file(source)?;
+        self.import_hashed(model_id, source, &digest)?;
+        Ok(digest)
+    }
+
+    /// Like `import`, for a file whose SHA256 the caller already computed
+    pub fn import_hashed(&self, model_id: &str, source: &Path, digest: &str) -> Result<()> {
+        let blob = self.blob_path(digest);
+
+        if blob.exists() {
+            std::fs::remove_file(source)?;
+        } else {
+            std::fs::create_dir_all(self.blobs_dir())?;
+            if std::fs::rename(source, &blob).is_err() {
+                // Cross-device move: copy then remove
+                std::fs::copy(source, &blob)?;
+                std::fs::remove_file(source)?;
+            }
+        }
+
+        self.link(model_id, digest)
+    }
+
+    /// Point `model_id` at an existing blob
+    pub fn link(&self, model_id: &str, digest: &str) -> Result<()> {
+        if !self.blob_path(digest).exists() {
+            return Err(Error::ConfigError(format!(
+                "No blob with digest {} in model store",
+                digest
+            )));
+        }
+        let mut manifest = self.load_manifest()?;
+        manifest
+            .models
+            .insert(model_id.to_string(), digest.to_string());
+        self.save_manifest(&manifest)
+    }
+
+    /// Remove the manifest entry for `model_id`; the blob stays until GC
+    pub fn unlink(&self, model_id: &str) -> Result<bool> {
+        let mut manifest = self.load_manifest()?;
+        let removed = manifest.models.remove(model_id).is_some();
+        if removed {
+            self.save_manifest(&manifest)?;
+        }
+        Ok(removed)
+    }
+
+    /// Resolve a model ID to its blob path, if it is managed by the store
+    pub fn resolve(&self, model_id: &str) -> Result<Option<PathBuf>> {
+        let manifest = self.load_manifest()?;
+        Ok(manifest
+            .models
+            .get(model_id)
+            .map(|digest| self.blob_path(digest)))
+    }
+
+    /// Remove blobs that no manifest entry references
+    pub fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let manifest = self.load_manifest()?;
+        let referenced: HashSet<&str> = manifest.models.values().map(String::as_str).collect();
+        let mut report = GcReport::default();
+
+        let blobs_dir = self.blobs_dir();
+        if !blobs_dir.exists() {
+            return Ok(report);
+        }
+
+        for entry in std::fs::read_dir(&blobs_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(digest) = name.to_str().and_then(|n| n.strip_prefix(DIGEST_PREFIX)) else {
+                continue;
+            };
+            if referenced.contains(digest) {
+                continue;
+            }
+
+            report.freed_bytes += entry.metadata()?.len();
+            report.removed.push(digest.to_string());
+            if !dry_run {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,132 @@
+//! Where ChatSafe keeps its files
+//!
+//! Everything the server writes hangs off one `data_dir`: models and their
+//! content-addressed store under `models/`, logs under `logs/`, the pid
+//! file and admin socket under `run/`, and llama-server's slot files under
+//! `slots/`. The default follows each platform's
+//! convention: `$XDG_DATA_HOME/chatsafe` (usually `~/.local/share/chatsafe`)
+//! on Linux, `~/Library/Application Support/ChatSafe` on macOS and
+//! `%LOCALAPPDATA%\ChatSafe` on Windows.
+//!
+//! Older versions always used `~/.local/share/chatsafe`; `migrate` moves that
+//! content into a different `data_dir`.
+
+use chatsafe_common::Result;
+use std::path::{Path, PathBuf};
+
+// Constants
+pub const MODELS_DIR: &str = "models";
+pub const LOGS_DIR: &str = "logs";
+pub const RUN_DIR: &str = "run";
+pub const SLOTS_DIR: &str = "slots";
+const LEGACY_DATA_DIR: &str = ".local/share/chatsafe";
+#[cfg(any(target_os = "macos", windows))]
+const APP_DIR: &str = "ChatSafe";
+#[cfg(not(any(target_os = "macos", windows)))]
+const APP_DIR: &str = "chatsafe";
+
+/// Platform default for `data_dir`
+pub fn default_data_dir() -> PathBuf {
+    // Roaming AppData would sync gigabytes of models between machines
+    let base = if cfg!(windows) {
+        dirs::data_local_dir()
+    } else {
+        dirs::data_dir()
+    };
+    base.unwrap_or_else(|| PathBuf::from(".")).join(APP_DIR)
+}
+
+/// The fixed directory used before `data_dir` existed
+pub fn legacy_data_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(LEGACY_DATA_DIR))
+}
+
+/// Whether `legacy` still holds files that belong in `data_dir`
+pub fn needs_migration(legacy: &Path, data_dir: &Path) -> bool {
+    let has_entries = std::fs::read_dir(legacy).is_ok_and(|mut entries| entries.next().is_some());
+    has_entries && !same_dir(legacy, data_dir)
+}
+
+/// What a migration moved, and what it left because the target existed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MigrationReport {
+    /// Paths relative to the old directory
+    pub moved: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Move the contents of `from` into `to`, merging directories that exist
+/// in both and never overwriting a file
+///
+/// Files are renamed when both directories are on one filesystem and
+/// copied otherwise. Emptied directories under `from` are removed.
+pub fn migrate(from: &Path, to: &Path, dry_run: bool) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    if !from.is_dir() || same_dir(from, to) {
+        return Ok(report);
+    }
+    merge_dir(from, to, Path::new(""), dry_run, &mut report)?;
+    Ok(report)
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn merge_dir(
+    from: &Path,
+    to: &Path,
+    relative: &Path,
+    dry_run: bool,
+    report: &mut MigrationReport,
+) -> Result<()> {
+    if !dry_run {
+        std::fs::create_dir_all(to)?;
+    }
+    let mut entries = std::fs::read_dir(from)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        let relative = relative.join(entry.file_name());
+        if source.is_dir() && target.is_dir() {
+            merge_dir(&source, &target, &relative, dry_run, report)?;
+        } else if target.exists() {
+            report.skipped.push(relative);
+        } else {
+            if !dry_run {
+                move_path(&source, &target)?;
+            }
+            report.moved.push(relative);
+        }
+    }
+
+    if !dry_run {
+        // Fails while skipped files remain, which is what we want
+        let _ = std::fs::remove_dir(from);
+    }
+    Ok(())
+}
+
+fn move_path(source: &Path, target: &Path) -> Result<()> {
+    if std::fs::rename(source, target).is_ok() {
+        return Ok(());
+    }
+    // Different filesystems
+    if source.is_dir() {
+        std::fs::create_dir_all(target)?;
+        for entry in std::fs::read_dir(source)? {
+            let entry = entry?;
+            move_path(&entry.path(), &target.join(entry.file_name()))?;
+        }
+        std::fs::remove_dir(source)?;
+    } else {
+        std::fs::copy(source, target)?;
+        std::fs::remove_file(source)?;
+    }
+    Ok(())
+}
@@ -1,7 +1,70 @@
 #[cfg(test)]
 mod tests {
+    use crate::model_metadata::MetadataCache;
     use crate::model_registry::*;
+    use crate::model_store::ModelStore;
     use chatsafe_common::Result;
+    use std::path::PathBuf;
+
+    /// Build a minimal GGUF v3 header with the given key/value pairs
+    fn write_gguf(path: &std::path::Path, arch: &str, ctx: u32, template: &str) {
+        write_gguf_version(path, 3, arch, ctx, template);
+    }
+
+    /// GGUF fixture in format `version`; version 1 has 32-bit counts and lengths
+    fn write_gguf_version(
+        path: &std::path::Path,
+        version: u32,
+        arch: &str,
+        ctx: u32,
+        template: &str,
+    ) {
+        let length = |buf: &mut Vec<u8>, n: usize| {
+            if version == 1 {
+                buf.extend_from_slice(&(n as u32).to_le_bytes());
+            } else {
+                buf.extend_from_slice(&(n as u64).to_le_bytes());
+            }
+        };
+        let gguf_string = |buf: &mut Vec<u8>, s: &str| {
+            length(buf, s.len());
+            buf.extend_from_slice(s.as_bytes());
+        };
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"GGUF");
+        buf.extend_from_slice(&version.to_le_bytes());
+        length(&mut buf, 0); // tensors
+        length(&mut buf, 4); // kv pairs
+
+        gguf_string(&mut buf, "general.architecture");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        gguf_string(&mut buf, arch);
+
+        // An array that must be skipped
+        gguf_string(&mut buf, "tokenizer.ggml.tokens");
+        buf.extend_from_slice(&9u32.to_le_bytes());
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        length(&mut buf, 2);
+        gguf_string(&mut buf, "<s>");
+        gguf_string(&mut buf, "</s>");
+
+        gguf_string(&mut buf, &format!("{}.context_length", arch));
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&ctx.to_le_bytes());
+
+        gguf_string(&mut buf, "tokenizer.chat_template");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        gguf_string(&mut buf, template);
+
+        std::fs::write(path, buf).expect("Failed to write GGUF fixture");
+    }
+
+    fn temp_model_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chatsafe-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("Failed to create temp dir");
+        dir
+    }
 
     #[test]
     fn test_load_default_registry() -> Result<()> {
@@ -76,6 +139,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_stop_sequences_inherit_template_tokens() -> Result<()> {
+        let mut registry = ModelRegistry::load_defaults()?;
+        let model_id = "llama-3.2-3b-instruct-q4_k_m";
+
+        // Template tokens come first; model sequences extend without duplicates
+        let params = registry.get_generation_params(model_id)?;
+        assert_eq!(params.stop_sequences[0], "<|eot_id|>");
+        assert!(params
+            .stop_sequences
+            .contains(&"<|start_header_id|>".to_string()));
+
+        // A model without its own sequences still gets the template's
+        let mut data: serde_json::Value =
+            serde_json::from_str(include_str!("default_registry.json"))?;
+        data["models"][0]
+            .as_object_mut()
+            .expect("model entry")
+            .remove("stop_sequences");
+        registry = ModelRegistry::load_from_json(&data.to_string())?;
+        let params = registry.get_generation_params(model_id)?;
+        assert_eq!(params.stop_sequences, vec!["<|eot_id|>", "<|end_of_text|>"]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_apply_overrides() -> Result<()> {
         let registry = ModelRegistry::load_defaults()?;
@@ -104,7 +193,7 @@ mod tests {
         let registry = ModelRegistry::load_defaults()?;
 
         let models = registry.list_models();
-        assert!(models.len() >= 1); // We have at least 1 model in default registry
+        assert!(!models.is_empty()); // We have at least 1 model in default registry
 
         // Check each model has required fields
         for model_id in &models {
@@ -164,4 +253,598 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_model_store_dedup_and_gc() -> Result<()> {
+        let dir = temp_model_dir();
+        let store = ModelStore::new(dir.clone());
+
+        // Two identical downloads share one blob
+        std::fs::write(dir.join("a.gguf"), b"same weights")?;
+        std::fs::write(dir.join("b.gguf"), b"same weights")?;
+        let digest_a = store.import("model-a", &dir.join("a.gguf"))?;
+        let digest_b = store.import("model-b", &dir.join("b.gguf"))?;
+        assert_eq!(digest_a, digest_b);
+        assert!(!dir.join("b.gguf").exists());
+        assert_eq!(std::fs::read_dir(store.blobs_dir())?.count(), 1);
+
+        // Blob survives while any entry references it
+        store.unlink("model-a")?;
+        assert!(store.gc(false)?.removed.is_empty());
+
+        store.unlink("model-b")?;
+        let dry_run = store.gc(true)?;
+        assert_eq!(dry_run.removed, vec![digest_a.clone()]);
+        assert!(store.blob_path(&digest_a).exists());
+
+        let report = store.gc(false)?;
+        assert_eq!(report.removed, vec![digest_a.clone()]);
+        assert_eq!(report.freed_bytes, b"same weights".len() as u64);
+        assert!(!store.blob_path(&digest_a).exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_path_prefers_store_blob() -> Result<()> {
+        let dir = temp_model_dir();
+        let mut registry = ModelRegistry::load_defaults()?;
+        registry.set_model_dir(dir.clone());
+
+        let plain = registry.get_model_path("llama-3.2-3b-instruct-q4_k_m")?;
+        assert!(plain.starts_with(&dir));
+
+        // The cached manifest is re-read once the store rewrites it
+        let source = dir.join("download.gguf");
+        std::fs::write(&source, b"weights")?;
+        let digest = registry
+            .model_store()
+            .import("llama-3.2-3b-instruct-q4_k_m", &source)?;
+
+        let path = registry.get_model_path("llama-3.2-3b-instruct-q4_k_m")?;
+        assert_eq!(path, registry.model_store().blob_path(&digest));
+
+        registry
+            .model_store()
+            .unlink("llama-3.2-3b-instruct-q4_k_m")?;
+        assert_eq!(
+            registry.get_model_path("llama-3.2-3b-instruct-q4_k_m")?,
+            plain
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_store_link_requires_blob() {
+        let dir = temp_model_dir();
+        let store = ModelStore::new(dir.clone());
+
+        assert!(store.link("model", "deadbeef").is_err());
+
+        std::fs::remove_dir_all(&dir).expect("Failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_model_store_manifest_errors() {
+        let dir = temp_model_dir();
+        let store = ModelStore::new(dir.clone());
+        let manifest = dir.join("manifest.json");
+
+        std::fs::write(&manifest, "{ not json").unwrap();
+        let err = store.load_manifest().unwrap_err().to_string();
+        assert!(err.contains("Corrupt model manifest"), "{}", err);
+
+        // A newer format is reported as such, not as corruption
+        std::fs::write(&manifest, r#"{"version": "9.0", "models": {}}"#).unwrap();
+        let err = store.load_manifest().unwrap_err().to_string();
+        assert!(err.contains("newer than this build"), "{}", err);
+        assert!(!err.contains("Corrupt"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).expect("Failed to remove temp dir");
+    }
+
+    #[test]
+    fn test_gguf_metadata_parsing() -> Result<()> {
+        let dir = temp_model_dir();
+        let file = dir.join("model.gguf");
+        write_gguf(&file, "llama", 131072, "{{ messages }}");
+
+        let metadata = crate::model_metadata::read_metadata(&file)?;
+        assert_eq!(metadata.architecture.as_deref(), Some("llama"));
+        assert_eq!(metadata.context_length, Some(131072));
+        assert_eq!(metadata.chat_template.as_deref(), Some("{{ messages }}"));
+        // Only store blobs have a digest, taken from their name
+        assert_eq!(metadata.sha256, None);
+        let store = ModelStore::new(dir.clone());
+        let digest = store.import("model", &file)?;
+        let metadata = crate::model_metadata::read_metadata(&store.blob_path(&digest))?;
+        assert_eq!(metadata.sha256, Some(digest));
+
+        // Version 1 headers use 32-bit counts and string lengths
+        let v1 = dir.join("v1.gguf");
+        write_gguf_version(&v1, 1, "llama", 2048, "{{ v1 }}");
+        let metadata = crate::model_metadata::read_metadata(&v1)?;
+        assert_eq!(metadata.architecture.as_deref(), Some("llama"));
+        assert_eq!(metadata.context_length, Some(2048));
+        assert_eq!(metadata.chat_template.as_deref(), Some("{{ v1 }}"));
+
+        // Non-GGUF files are rejected
+        std::fs::write(dir.join("bad.gguf"), b"not a model")?;
+        assert!(crate::model_metadata::read_metadata(&dir.join("bad.gguf")).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_cache_invalidation() -> Result<()> {
+        let dir = temp_model_dir();
+        let file = dir.join("model.gguf");
+        write_gguf(&file, "llama", 8192, "");
+
+        let mut cache = MetadataCache::open(&dir);
+        assert_eq!(cache.get(&file)?.context_length, Some(8192));
+        cache.save()?;
+
+        // Reopened index serves the cached entry
+        let mut cache = MetadataCache::open(&dir);
+        assert_eq!(cache.get(&file)?.context_length, Some(8192));
+
+        // A changed file (different size) is re-read
+        write_gguf(&file, "qwen2", 32768, "chatml");
+        let metadata = cache.get(&file)?;
+        assert_eq!(metadata.architecture.as_deref(), Some("qwen2"));
+        assert_eq!(metadata.context_length, Some(32768));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_config_server_url() -> Result<()> {
+        let runtime: crate::RuntimeConfig = serde_json::from_str(
+            r#"{"llama_server_port": 9090, "threads": 2, "gpu_layers": null}"#,
+        )?;
+        assert_eq!(runtime.generation_timeout_secs, 300);
+        assert_eq!(runtime.connect_timeout_secs, 5);
+        assert_eq!(runtime.server_url(), "http://127.0.0.1:9090");
+
+        let runtime = crate::RuntimeConfig {
+            base_url: Some("http://gpu-box:8080/".to_string()),
+            ..runtime
+        };
+        assert_eq!(runtime.server_url(), "http://gpu-box:8080");
+
+        // A remote server can only be attached to, not managed
+        let mut config = crate::AppConfig {
+            runtime,
+            ..Default::default()
+        };
+        config.runtime.manage_process = true;
+        assert!(config.validate().is_err());
+        config.runtime.manage_process = false;
+        config.validate()?;
+        config.runtime.base_url = None;
+        config.runtime.manage_process = true;
+        config.validate()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcription_model_capability() -> Result<()> {
+        let mut data: serde_json::Value =
+            serde_json::from_str(include_str!("default_registry.json"))?;
+        let registry = ModelRegistry::load_from_json(&data.to_string())?;
+        assert!(registry.get_transcription_model().is_none());
+
+        // Chat-only fields can be left out of a whisper entry
+        let whisper = serde_json::json!({
+            "id": "whisper-base-en",
+            "name": "Whisper base.en",
+            "path": "ggml-base.en.bin",
+            "ctx_window": 0,
+            "capability": "transcribe",
+            "resources": data["models"][0]["resources"].clone(),
+            "default": false
+        });
+        data["models"].as_array_mut().expect("models").push(whisper);
+        let registry = ModelRegistry::load_from_json(&data.to_string())?;
+        let model = registry
+            .get_transcription_model()
+            .expect("transcribe model");
+        assert_eq!(model.id, "whisper-base-en");
+        assert_eq!(model.capability, Capability::Transcribe);
+        assert_eq!(registry.get_default_model()?.capability, Capability::Chat);
+
+        // A transcription model cannot serve chat as the default
+        for model in data["models"].as_array_mut().expect("models") {
+            let is_whisper = model["id"] == "whisper-base-en";
+            model["default"] = serde_json::json!(is_whisper);
+        }
+        assert!(ModelRegistry::load_from_json(&data.to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_aliases() -> Result<()> {
+        let model_id = "llama-3.2-3b-instruct-q4_k_m";
+        let mut data: serde_json::Value =
+            serde_json::from_str(include_str!("default_registry.json"))?;
+        data["aliases"] = serde_json::json!({ "default-chat": model_id });
+        let registry = ModelRegistry::load_from_json(&data.to_string())?;
+        assert_eq!(registry.resolve_alias("default-chat"), model_id);
+        assert_eq!(registry.resolve_alias(model_id), model_id);
+        assert_eq!(registry.resolve_alias("gpt-4"), "gpt-4");
+
+        // Aliases survive an export round trip
+        let reloaded = ModelRegistry::load_from_json(&registry.export()?)?;
+        assert_eq!(reloaded.resolve_alias("default-chat"), model_id);
+
+        // `name@quant` picks the model by family prefix and quantization
+        data["aliases"] = serde_json::json!({ "default-chat": "llama-3.2-3b@Q4_K_M" });
+        let registry = ModelRegistry::load_from_json(&data.to_string())?;
+        assert_eq!(registry.resolve_alias("default-chat"), model_id);
+        assert_eq!(registry.find_version("llama-3.2-3b@q4_k_m")?.id, model_id);
+        assert!(registry.find_version("llama-3.2-3b@Q8_0").is_err());
+        assert!(registry.find_version("llama-3.2-1b@Q4_K_M").is_err());
+
+        // Dangling targets and names shadowing a model are rejected
+        data["aliases"] = serde_json::json!({ "default-chat": "missing" });
+        assert!(ModelRegistry::load_from_json(&data.to_string()).is_err());
+        data["aliases"] = serde_json::json!({ model_id: model_id });
+        assert!(ModelRegistry::load_from_json(&data.to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_listen_addresses() -> Result<()> {
+        use crate::{ListenAddress, ListenerConfig};
+
+        let mut server = crate::AppConfig::default().server;
+        server.host = "::1".to_string();
+        assert_eq!(
+            server.listen_addresses()?,
+            vec![(ListenAddress::Tcp("[::1]:8081".parse().unwrap()), None)]
+        );
+        server.host = "localhost".to_string();
+        assert_eq!(
+            server.listen_addresses()?[0].0,
+            ListenAddress::Tcp("127.0.0.1:8081".parse().unwrap())
+        );
+        server.host = "example.com".to_string();
+        assert!(server.listen_addresses().is_err());
+
+        // Explicit listeners replace host:port
+        server.listeners = vec![
+            ListenerConfig {
+                address: "127.0.0.1:8081".to_string(),
+                auth_token: None,
+            },
+            ListenerConfig {
+                address: "unix:/tmp/chatsafe.sock".to_string(),
+                auth_token: Some("secret".to_string()),
+            },
+        ];
+        let addresses = server.listen_addresses()?;
+        assert_eq!(
+            addresses[1],
+            (
+                ListenAddress::Unix("/tmp/chatsafe.sock".into()),
+                Some("secret".to_string())
+            )
+        );
+
+        server.listeners[0].address = "::1:8081".to_string();
+        assert!(server.listen_addresses().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_hang_off_data_dir() {
+        let mut config = crate::AppConfig {
+            data_dir: PathBuf::from("/data/chatsafe"),
+            ..crate::AppConfig::default()
+        };
+        assert_eq!(config.model_dir(), PathBuf::from("/data/chatsafe/models"));
+        assert_eq!(config.state_dir(), PathBuf::from("/data/chatsafe/run"));
+        assert_eq!(config.slot_dir(), PathBuf::from("/data/chatsafe/slots"));
+        assert_eq!(config.replay_log_path(), None);
+
+        config.models.directory = Some(PathBuf::from("/mnt/models"));
+        assert_eq!(config.model_dir(), PathBuf::from("/mnt/models"));
+        config.server.replay_log = Some(PathBuf::from("replay.jsonl"));
+        assert_eq!(
+            config.replay_log_path(),
+            Some(PathBuf::from("/data/chatsafe/logs/replay.jsonl"))
+        );
+        config.server.replay_log = Some(PathBuf::from("/var/log/replay.jsonl"));
+        assert_eq!(
+            config.replay_log_path(),
+            Some(PathBuf::from("/var/log/replay.jsonl"))
+        );
+
+        // Older configs name no data_dir and always set models.directory
+        let mut value = serde_json::to_value(crate::AppConfig::default()).unwrap();
+        value.as_object_mut().unwrap().remove("data_dir");
+        value["models"]["directory"] = serde_json::json!("/old/models");
+        let config: crate::AppConfig = serde_json::from_value(value).unwrap();
+        assert_eq!(config.data_dir, crate::paths::default_data_dir());
+        assert_eq!(config.model_dir(), PathBuf::from("/old/models"));
+    }
+
+    #[test]
+    fn test_legacy_data_migration() -> Result<()> {
+        use crate::paths::{migrate, needs_migration};
+
+        let root = std::env::temp_dir().join(format!("chatsafe-migrate-{}", uuid::Uuid::new_v4()));
+        let legacy = root.join("legacy");
+        let data_dir = root.join("data");
+        std::fs::create_dir_all(legacy.join("models/blobs"))?;
+        std::fs::write(legacy.join("models/blobs/sha256-abc"), "weights")?;
+        std::fs::write(legacy.join("models/manifest.json"), "old")?;
+        std::fs::create_dir_all(data_dir.join("models"))?;
+        std::fs::write(data_dir.join("models/manifest.json"), "new")?;
+        assert!(needs_migration(&legacy, &data_dir));
+        assert!(!needs_migration(&legacy, &legacy));
+
+        // A dry run reports without touching anything
+        let report = migrate(&legacy, &data_dir, true)?;
+        assert_eq!(report.moved, vec![PathBuf::from("models/blobs")]);
+        assert!(legacy.join("models/blobs/sha256-abc").exists());
+
+        // Directories merge; existing files win
+        let report = migrate(&legacy, &data_dir, false)?;
+        assert_eq!(report.moved, vec![PathBuf::from("models/blobs")]);
+        assert_eq!(report.skipped, vec![PathBuf::from("models/manifest.json")]);
+        assert_eq!(
+            std::fs::read_to_string(data_dir.join("models/blobs/sha256-abc"))?,
+            "weights"
+        );
+        assert_eq!(
+            std::fs::read_to_string(data_dir.join("models/manifest.json"))?,
+            "new"
+        );
+        assert!(legacy.join("models/manifest.json").exists());
+        assert!(!legacy.join("models/blobs").exists());
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_format_upgrade() -> Result<()> {
+        use crate::{migrations, paths, ConfigLoader};
+
+        let root = std::env::temp_dir().join(format!("chatsafe-upgrade-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root)?;
+        let path = root.join("chatsafe.json");
+
+        // A version 1 file: no version, no data_dir, models.directory spelled out
+        let legacy = paths::legacy_data_dir().expect("home directory");
+        let mut old = serde_json::to_value(crate::AppConfig::default())?;
+        old.as_object_mut().unwrap().remove("version");
+        old.as_object_mut().unwrap().remove("data_dir");
+        old["models"]["directory"] = serde_json::json!(legacy.join("models"));
+        let original = serde_json::to_string_pretty(&old)?;
+        std::fs::write(&path, &original)?;
+
+        let config = ConfigLoader::load(Some(&path))?;
+        assert_eq!(config.version, migrations::CONFIG.current());
+        assert_eq!(config.data_dir, legacy);
+        assert_eq!(config.models.directory, None);
+        assert_eq!(config.model_dir(), legacy.join("models"));
+
+        // The original is backed up and the file rewritten in the new format
+        let backup = migrations::backup_path(&path, 1);
+        assert_eq!(std::fs::read_to_string(&backup)?, original);
+        let upgraded: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(upgraded["version"], 2);
+        assert_eq!(ConfigLoader::load(Some(&path))?.data_dir, legacy);
+
+        // Files from a newer build are refused
+        std::fs::write(&path, r#"{"version": 99}"#)?;
+        let err = ConfigLoader::load(Some(&path)).unwrap_err();
+        assert!(err.to_string().contains("newer than this build"));
+
+        let mut registry = serde_json::json!({ "version": "7.1", "models": [] });
+        assert!(migrations::upgrade(&migrations::REGISTRY, &mut registry).is_err());
+        let mut registry = serde_json::json!({ "version": "1.0", "models": [] });
+        assert_eq!(
+            migrations::upgrade(&migrations::REGISTRY, &mut registry)?,
+            None
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_family_sampling_profiles() -> Result<()> {
+        use crate::ModelFamily;
+
+        assert_eq!(
+            ModelFamily::detect("qwen2.5-7b-instruct-q4_k_m"),
+            Some(ModelFamily::Qwen)
+        );
+        assert_eq!(
+            ModelFamily::detect("Llama 3.2 3B Instruct"),
+            Some(ModelFamily::Llama3)
+        );
+        assert_eq!(ModelFamily::detect("gemma2"), Some(ModelFamily::Gemma));
+        assert_eq!(ModelFamily::detect("phi-3-mini"), Some(ModelFamily::Phi));
+        assert_eq!(ModelFamily::detect("dolphin-2.9-llama-2"), None);
+
+        // Entries without defaults or a template get their family's
+        let mut data: serde_json::Value =
+            serde_json::from_str(include_str!("default_registry.json"))?;
+        let mut qwen = data["models"][0].clone();
+        let entry = qwen.as_object_mut().expect("model entry");
+        entry.remove("defaults");
+        entry.remove("template_id");
+        entry.insert("id".into(), "qwen2.5-7b-instruct".into());
+        entry.insert("default".into(), false.into());
+        entry.insert("metadata".into(), serde_json::json!({}));
+        let mut gemma = qwen.clone();
+        gemma["id"] = "gemma-2-9b-it".into();
+        gemma["defaults"] = serde_json::json!({ "max_tokens": 512 });
+        let models = data["models"].as_array_mut().expect("models");
+        models.push(qwen);
+        models.push(gemma);
+        let registry = ModelRegistry::load_from_json(&data.to_string())?;
+
+        let qwen = registry.get_model("qwen2.5-7b-instruct")?;
+        assert_eq!(qwen.template_id, "chatml");
+        assert_eq!(qwen.defaults.top_k, 20);
+        assert_eq!(qwen.defaults.repeat_penalty, 1.05);
+
+        // Given fields win; the rest come from the profile. No gemma template
+        // is defined, so none is set
+        let gemma = registry.get_model("gemma-2-9b-it")?;
+        assert_eq!(gemma.defaults.max_tokens, 512);
+        assert_eq!(gemma.defaults.temperature, 1.0);
+        assert_eq!(gemma.defaults.top_k, 64);
+        assert!(gemma.template_id.is_empty());
+
+        // Complete defaults are left alone
+        let llama = registry.get_model("llama-3.2-3b-instruct-q4_k_m")?;
+        assert_eq!(llama.defaults.repeat_penalty, 1.15);
+
+        Ok(())
+    }
+
+    /// Serve `body` over HTTP, honouring `Range: bytes=N-`; records each
+    /// request's range start
+    async fn range_server(
+        body: &'static [u8],
+    ) -> (String, std::sync::Arc<std::sync::Mutex<Vec<Option<usize>>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let ranges = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = ranges.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let read = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..read]).to_lowercase();
+                let start = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok());
+                seen.lock().unwrap().push(start);
+                let (status, part) = match start {
+                    Some(start) => ("206 Partial Content", &body[start..]),
+                    None => ("200 OK", body),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    part.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(part).await;
+            }
+        });
+        (endpoint, ranges)
+    }
+
+    #[tokio::test]
+    async fn test_pull_resumes_and_verifies() -> Result<()> {
+        use crate::ModelDownloader;
+
+        const MODEL: &str = "llama-3.2-3b-instruct-q4_k_m";
+        const WEIGHTS: &[u8] = b"GGUF pretend weights for the download test";
+        let dir = temp_model_dir();
+        let (endpoint, ranges) = range_server(WEIGHTS).await;
+        let downloader = ModelDownloader::new(endpoint)?;
+
+        // A partial file from an interrupted pull is continued, not restarted
+        let mut registry = ModelRegistry::load_defaults()?;
+        registry.set_model_dir(dir.clone());
+        std::fs::create_dir_all(dir.join("downloads"))?;
+        std::fs::write(
+            dir.join("downloads").join(format!("{}.gguf.part", MODEL)),
+            &WEIGHTS[..10],
+        )?;
+        let mut reports = Vec::new();
+        let pulled = downloader
+            .pull(&registry, MODEL, |progress| reports.push(progress))
+            .await?;
+        assert_eq!(*ranges.lock().unwrap(), vec![Some(10)]);
+        assert_eq!(reports.first().map(|p| p.resumed_from), Some(10));
+        assert_eq!(
+            reports.last().map(|p| p.downloaded),
+            Some(WEIGHTS.len() as u64)
+        );
+        assert!(!pulled.verified);
+        assert_eq!(registry.get_model_path(MODEL)?, pulled.path);
+        assert_eq!(std::fs::read(&pulled.path)?, WEIGHTS);
+
+        // A registry digest that does not match fails the pull and drops the file
+        let json = include_str!("default_registry.json").replace(
+            "\"file\": \"Llama-3.2-3B-Instruct-Q4_K_M.gguf\"",
+            "\"file\": \"Llama-3.2-3B-Instruct-Q4_K_M.gguf\", \"sha256\": \"00ff\"",
+        );
+        let mut registry = ModelRegistry::load_from_json(&json)?;
+        let other_dir = temp_model_dir();
+        registry.set_model_dir(other_dir.clone());
+        let err = downloader.pull(&registry, MODEL, |_| {}).await.unwrap_err();
+        assert!(err.to_string().contains("expected 00ff"));
+        assert!(!other_dir
+            .join("downloads")
+            .join(format!("{}.gguf.part", MODEL))
+            .exists());
+        assert!(registry.model_store().resolve(MODEL)?.is_none());
+
+        std::fs::remove_dir_all(&dir)?;
+        std::fs::remove_dir_all(&other_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_apple_silicon_tuning() {
+        use crate::{HardwareProfile, ServerTuning};
+        const GB: u64 = 1024 * 1024 * 1024;
+
+        assert!(HardwareProfile::from_sysctl("Intel(R) Core(TM) i9", None, 8, 16 * GB).is_none());
+        let m2_pro = HardwareProfile::from_sysctl("Apple M2 Pro\n", Some(8), 12, 16 * GB).unwrap();
+        assert_eq!(m2_pro.chip, "Apple M2 Pro");
+        assert_eq!((m2_pro.performance_cores, m2_pro.efficiency_cores), (8, 4));
+        // Without perflevel sysctls every core counts as a performance core
+        let m1 = HardwareProfile::from_sysctl("Apple M1", None, 8, 8 * GB).unwrap();
+        assert_eq!(m1.performance_cores, 8);
+
+        let mut resources = ModelResources {
+            min_ram_gb: 4.0,
+            est_disk_gb: 2.0,
+            gpu_layers: 20,
+            threads: 4,
+        };
+        let generic = ServerTuning::new(&resources, None);
+        assert_eq!(
+            (generic.threads, generic.gpu_layers, generic.flash_attn),
+            (4, 20, "on")
+        );
+
+        let tuned = ServerTuning::new(&resources, Some(&m2_pro));
+        assert_eq!(
+            (tuned.threads, tuned.gpu_layers, tuned.flash_attn),
+            (8, -1, "on")
+        );
+
+        // Too big for Metal's share of 8 GB: the registry's offload stays
+        resources.est_disk_gb = 6.0;
+        assert_eq!(ServerTuning::new(&resources, Some(&m1)).gpu_layers, 20);
+
+        // CPU-only entries stay on the CPU, where flash attention is left to llama-server
+        resources.gpu_layers = 0;
+        let cpu = ServerTuning::new(&resources, Some(&m2_pro));
+        assert_eq!((cpu.gpu_layers, cpu.flash_attn), (0, "auto"));
+    }
 }
@@ -0,0 +1,92 @@
+//! Embed build environment details for the `/version` endpoint
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Constants
+const UNKNOWN: &str = "unknown";
+const SECONDS_PER_DAY: u64 = 86_400;
+
+fn main() {
+    let git_commit = command_output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let build_date = format_utc(build_time());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+
+    // Cargo exposes each enabled feature as CARGO_FEATURE_<NAME>
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .map(|name| name.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=CHATSAFE_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=CHATSAFE_BUILD_DATE={}", build_date);
+    println!("cargo:rustc-env=CHATSAFE_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=CHATSAFE_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/refs");
+}
+
+/// Seconds since the epoch: `SOURCE_DATE_EPOCH` for reproducible builds,
+/// otherwise now
+fn build_time() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        })
+}
+
+/// RFC 3339 UTC timestamp, e.g. `2024-05-01T12:00:00Z`
+fn format_utc(seconds: u64) -> String {
+    let (days, time) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Gregorian date of a day count since 1970-01-01 (Howard Hinnant's
+/// `civil_from_days`)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Run a command and return its trimmed stdout, or "unknown" on any failure
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_string())
+        .filter(|stdout| !stdout.is_empty())
+        .unwrap_or_else(|| UNKNOWN.to_string())
+}
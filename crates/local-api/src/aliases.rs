@@ -0,0 +1,130 @@
+//! Model aliases that can be repointed while the server runs
+//!
+//! Aliases start from the registry's `aliases` map. `PUT /admin/aliases/{name}`
+//! swaps the target in one step, so clients configured with a stable name
+//! like `default-chat` follow a model upgrade without editing their config.
+//! The same request can give the alias a canary: a second model that gets a
+//! percentage of the alias's chat requests, so the new version can be
+//! compared before the alias is repointed at it. Changes last until restart;
+//! the registry file stays as it is.
+
+use chatsafe_common::{Error, Result};
+use chatsafe_config::ModelRegistry;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// A second model taking a share of an alias's requests
+#[derive(Debug, Serialize)]
+pub(crate) struct Canary {
+    pub(crate) model: String,
+    /// Share of requests, 0 to 100
+    pub(crate) percent: u8,
+    #[serde(skip)]
+    requests: AtomicU64,
+}
+
+impl Canary {
+    /// Whether the next request goes to the canary, spread evenly so any
+    /// run of 100 requests sends exactly `percent` of them
+    fn take_turn(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        let percent = u64::from(self.percent);
+        (n + 1) * percent / 100 > n * percent / 100
+    }
+}
+
+/// Live alias to model ID table
+pub(crate) struct AliasTable {
+    aliases: RwLock<HashMap<String, String>>,
+    canaries: RwLock<HashMap<String, Canary>>,
+}
+
+impl AliasTable {
+    pub(crate) fn from_registry(registry: &ModelRegistry) -> Self {
+        Self {
+            aliases: RwLock::new(registry.aliases().clone()),
+            canaries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Model ID behind `name`, or `name` itself when it is not an alias
+    pub(crate) fn resolve(&self, name: &str) -> String {
+        self.read()
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Model to serve a request for `name`: the canary's turn or `resolve`
+    pub(crate) fn route(&self, name: &str) -> String {
+        let canaries = self.canaries.read().unwrap_or_else(|e| e.into_inner());
+        match canaries.get(name) {
+            Some(canary) if canary.take_turn() => canary.model.clone(),
+            _ => self.resolve(name),
+        }
+    }
+
+    /// Send `percent` of `alias`'s requests to `model`, or stop with `None`
+    pub(crate) fn set_canary(
+        &self,
+        registry: &ModelRegistry,
+        alias: &str,
+        canary: Option<(&str, u8)>,
+    ) -> Result<()> {
+        let mut canaries = self.canaries.write().unwrap_or_else(|e| e.into_inner());
+        let Some((model, percent)) = canary else {
+            canaries.remove(alias);
+            return Ok(());
+        };
+        let model = registry.check_alias(alias, model)?;
+        if percent > 100 {
+            return Err(Error::ConfigError(format!(
+                "Canary percent must be 0 to 100, not {}",
+                percent
+            )));
+        }
+        canaries.insert(
+            alias.to_string(),
+            Canary {
+                model,
+                percent,
+                requests: AtomicU64::new(0),
+            },
+        );
+        Ok(())
+    }
+
+    /// Point `alias` at `target`, a model ID or `name@quant`, creating it if
+    /// needed; returns the old target
+    pub(crate) fn repoint(
+        &self,
+        registry: &ModelRegistry,
+        alias: &str,
+        target: &str,
+    ) -> Result<Option<String>> {
+        let model_id = registry.check_alias(alias, target)?;
+        let mut aliases = self.aliases.write().unwrap_or_else(|e| e.into_inner());
+        Ok(aliases.insert(alias.to_string(), model_id))
+    }
+
+    /// All aliases, sorted by name
+    pub(crate) fn snapshot(&self) -> BTreeMap<String, String> {
+        self.read()
+            .iter()
+            .map(|(alias, target)| (alias.clone(), target.clone()))
+            .collect()
+    }
+
+    /// All canaries, by alias
+    pub(crate) fn canaries(&self) -> serde_json::Value {
+        let canaries = self.canaries.read().unwrap_or_else(|e| e.into_inner());
+        let sorted: BTreeMap<_, _> = canaries.iter().collect();
+        serde_json::json!(sorted)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, String>> {
+        self.aliases.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,177 @@
+//! Real client addresses behind a reverse proxy
+//!
+//! Behind a local TLS proxy every request comes from 127.0.0.1, so all
+//! clients would share one rate-limit bucket. When the connecting peer is in
+//! `server.trusted_proxies`, the client is taken from `Forwarded` (or
+//! `X-Forwarded-For` without it): the last address in the chain that is not
+//! itself a trusted proxy. The connection info the handlers, rate limiter
+//! and metrics read is replaced with that address.
+//!
+//! Headers from untrusted peers are ignored, since anyone can send them.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use chatsafe_common::{Error, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const FORWARDED: &str = "forwarded";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// One `server.trusted_proxies` entry: an address or a CIDR range
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    fn parse(entry: &str) -> Option<Self> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+            None => (entry, None),
+        };
+        let network: IpAddr = addr.trim().parse().ok()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Peers whose forwarding headers are believed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrustedProxies {
+    ranges: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+    pub(crate) fn parse(entries: &[String]) -> Result<Self> {
+        let ranges = entries
+            .iter()
+            .map(|entry| {
+                IpRange::parse(entry).ok_or_else(|| {
+                    Error::ConfigError(format!(
+                        "Invalid trusted proxy {:?}; use an IP address or CIDR range such as 10.0.0.0/8",
+                        entry
+                    ))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { ranges })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    /// The client `peer` is forwarding for, or `peer` itself
+    pub(crate) fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let chain = forwarded_for(headers).unwrap_or_else(|| x_forwarded_for(headers));
+        // Only the hops appended by trusted proxies can be believed, so walk
+        // back from the nearest one; unparseable entries end the walk
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            let Some(ip) = hop else { break };
+            client = *ip;
+            if !self.trusts(*ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// `for=` addresses of every `Forwarded` element, or `None` without the header
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let values: Vec<_> = headers.get_all(FORWARDED).iter().collect();
+    if values.is_empty() {
+        return None;
+    }
+    let hops = values
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect();
+    Some(hops)
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `[2001:db8::1]:4711` or `2001:db8::1`;
+/// `unknown` and obfuscated identifiers give `None`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+    node.strip_prefix('[')?
+        .split(']')
+        .next()?
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// Replace the peer address with the forwarded client's
+pub(crate) async fn resolve_client_ip(
+    State(trusted): State<Arc<TrustedProxies>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let client = trusted.client_ip(peer.ip(), request.headers());
+        if client != peer.ip() {
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(client, 0)));
+        }
+    }
+    next.run(request).await
+}
@@ -0,0 +1,274 @@
+//! Caps on work in flight to the backends
+//!
+//! The rate limiter decides whether a client may send a request at all; this
+//! decides when an admitted request reaches llama-server. Chat completions
+//! (including sweep runs) and embeddings each hold a permit from their own
+//! semaphore for as long as the backend works on them; every chat model has
+//! a semaphore of its own, so a busy model does not hold up the others. Each
+//! cap is the backend's slot count (`runtime.parallel_slots` per instance),
+//! so even generous rate limits never queue more work inside llama-server
+//! than it can run. Requests over the cap wait in arrival order until a permit frees
+//! up or their deadline passes.
+//!
+//! Each waiter holds a `Ticket` that knows its place in line and the token
+//! budgets (`max_tokens`) of the requests ahead of it, which together with
+//! the recent generation speed gives a rough ETA.
+
+use chatsafe_common::{Error as CommonError, QueueStatus};
+use chatsafe_config::{AppConfig, ModelRegistry};
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use chatsafe_runtime::FrameStream;
+
+/// Requests one endpoint may have in flight to its backend
+#[derive(Debug, Clone)]
+pub(crate) struct ConcurrencyLimit {
+    endpoint: &'static str,
+    semaphore: Arc<Semaphore>,
+    capacity: usize,
+    queue: Arc<QueueState>,
+}
+
+/// One `ConcurrencyLimit` per chat model, keyed by model ID
+#[derive(Debug, Clone)]
+pub(crate) struct ChatLimits {
+    /// Slots of the default model's instances, for any model loaded on them
+    default_capacity: usize,
+    limits: Arc<Mutex<HashMap<String, ConcurrencyLimit>>>,
+}
+
+/// Who is waiting, and how much work is ahead of them
+#[derive(Debug, Default)]
+struct QueueState {
+    next_ticket: AtomicU64,
+    /// Token budget of each waiting ticket, in arrival order
+    waiting: Mutex<BTreeMap<u64, usize>>,
+    /// Token budgets of the requests holding a slot
+    in_flight_tokens: AtomicUsize,
+}
+
+impl QueueState {
+    fn waiting(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, usize>> {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A held slot; frees it, and its share of the pending tokens, on drop
+#[derive(Debug)]
+pub(crate) struct SlotPermit {
+    _permit: OwnedSemaphorePermit,
+    queue: Arc<QueueState>,
+    budget: usize,
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        self.queue
+            .in_flight_tokens
+            .fetch_sub(self.budget, Ordering::Relaxed);
+    }
+}
+
+/// A place in line for a slot; leaves the line on drop
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    place: QueuePlace,
+    budget: usize,
+}
+
+/// Where a ticket stands, readable by others while its holder waits
+#[derive(Debug, Clone)]
+pub(crate) struct QueuePlace {
+    limit: ConcurrencyLimit,
+    id: u64,
+}
+
+/// Usage of one limit, for `/admin/diagnostics`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ConcurrencyStats {
+    pub(crate) capacity: usize,
+    pub(crate) in_flight: usize,
+    pub(crate) waiting: usize,
+}
+
+impl ConcurrencyLimit {
+    pub(crate) fn new(endpoint: &'static str, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            endpoint,
+            semaphore: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            queue: Arc::default(),
+        }
+    }
+
+    /// Wait for a free slot for up to `budget` tokens, giving up at `deadline`
+    pub(crate) async fn acquire(
+        &self,
+        deadline: Instant,
+        budget: usize,
+    ) -> Result<SlotPermit, CommonError> {
+        match self.try_acquire(budget) {
+            Ok(permit) => Ok(permit),
+            Err(ticket) => ticket.wait(deadline).await,
+        }
+    }
+
+    /// Take a free slot at once, or get in line for one
+    pub(crate) fn try_acquire(&self, budget: usize) -> Result<SlotPermit, Ticket> {
+        // A free permit means nobody is waiting, since the semaphore is fair
+        match Arc::clone(&self.semaphore).try_acquire_owned() {
+            Ok(permit) => Ok(self.hold(permit, budget)),
+            Err(_) => {
+                let id = self.queue.next_ticket.fetch_add(1, Ordering::Relaxed);
+                self.queue.waiting().insert(id, budget);
+                debug!(
+                    "All {} {} slots busy, waiting for one",
+                    self.capacity, self.endpoint
+                );
+                Err(Ticket {
+                    place: QueuePlace {
+                        limit: self.clone(),
+                        id,
+                    },
+                    budget,
+                })
+            }
+        }
+    }
+
+    fn hold(&self, permit: OwnedSemaphorePermit, budget: usize) -> SlotPermit {
+        self.queue
+            .in_flight_tokens
+            .fetch_add(budget, Ordering::Relaxed);
+        SlotPermit {
+            _permit: permit,
+            queue: Arc::clone(&self.queue),
+            budget,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> ConcurrencyStats {
+        ConcurrencyStats {
+            capacity: self.capacity,
+            in_flight: self.capacity - self.semaphore.available_permits(),
+            waiting: self.queue.waiting().len(),
+        }
+    }
+}
+
+impl ChatLimits {
+    /// Limits for the configured models: the default model's instances, and
+    /// one llama-server of `parallel_slots` per `models.serve` entry
+    pub(crate) fn new(config: &AppConfig, registry: &ModelRegistry) -> Self {
+        let served_capacity = config.runtime.parallel_slots;
+        let limits = config
+            .models
+            .serve
+            .iter()
+            .map(|served| {
+                let model_id = registry.resolve_alias(&served.model).to_string();
+                (model_id, ConcurrencyLimit::new("chat", served_capacity))
+            })
+            .collect();
+        Self {
+            default_capacity: config.runtime.total_slots(),
+            limits: Arc::new(Mutex::new(limits)),
+        }
+    }
+
+    /// The limit for `model_id`; a model that is not served next to the
+    /// default one runs on the default model's instances
+    pub(crate) fn for_model(&self, model_id: &str) -> ConcurrencyLimit {
+        let mut limits = self.limits.lock().unwrap_or_else(|e| e.into_inner());
+        limits
+            .entry(model_id.to_string())
+            .or_insert_with(|| ConcurrencyLimit::new("chat", self.default_capacity))
+            .clone()
+    }
+
+    /// Usage per model
+    pub(crate) fn stats(&self) -> BTreeMap<String, ConcurrencyStats> {
+        let limits = self.limits.lock().unwrap_or_else(|e| e.into_inner());
+        limits
+            .iter()
+            .map(|(model_id, limit)| (model_id.clone(), limit.stats()))
+            .collect()
+    }
+}
+
+impl QueuePlace {
+    /// Place in line (1 = next) and a rough wait, given the recent
+    /// generation speed of one request; `None` once it has left the line
+    pub(crate) fn status(&self, tokens_per_second: Option<f64>) -> Option<QueueStatus> {
+        let queue = &self.limit.queue;
+        let (ahead, waiting_tokens) = {
+            let waiting = queue.waiting();
+            if !waiting.contains_key(&self.id) {
+                return None;
+            }
+            let ahead = waiting.range(..self.id);
+            (
+                ahead.clone().count(),
+                ahead.map(|(_, tokens)| tokens).sum::<usize>(),
+            )
+        };
+        // Every slot generates at about the recent speed, in parallel
+        let tokens_ahead = waiting_tokens + queue.in_flight_tokens.load(Ordering::Relaxed);
+        let eta_ms = tokens_per_second.filter(|tps| *tps > 0.0).map(|tps| {
+            let slots = self.limit.capacity as f64;
+            (tokens_ahead as f64 / (tps * slots) * 1000.0) as u64
+        });
+        Some(QueueStatus {
+            position: ahead + 1,
+            eta_ms,
+        })
+    }
+}
+
+impl Ticket {
+    pub(crate) fn place(&self) -> &QueuePlace {
+        &self.place
+    }
+
+    /// Wait for the slot, giving up at `deadline`
+    pub(crate) async fn wait(&self, deadline: Instant) -> Result<SlotPermit, CommonError> {
+        let QueuePlace { limit, id } = &self.place;
+        let acquire = Arc::clone(&limit.semaphore).acquire_owned();
+        match tokio::time::timeout_at(deadline.into(), acquire).await {
+            Ok(Ok(permit)) => {
+                limit.queue.waiting().remove(id);
+                Ok(limit.hold(permit, self.budget))
+            }
+            Ok(Err(_)) => Err(CommonError::ServiceUnavailable(format!(
+                "{} is shutting down",
+                limit.endpoint
+            ))),
+            Err(_) => Err(CommonError::DeadlineExceeded(format!(
+                "still waiting for one of {} busy {} slots",
+                limit.capacity, limit.endpoint
+            ))),
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.place.limit.queue.waiting().remove(&self.place.id);
+    }
+}
+
+/// Keep `permit` until the stream is finished or dropped
+pub(crate) fn hold_until_done(stream: FrameStream, permit: SlotPermit) -> FrameStream {
+    Box::pin(stream.map(move |frame| {
+        let _held = &permit;
+        frame
+    }))
+}
@@ -0,0 +1,61 @@
+//! Opt-in, redacted prompt and response excerpts in the logs
+//!
+//! Off by default. When enabled through `debug_excerpt_chars` or
+//! `PUT /admin/log-level`, prompts and responses are logged under the
+//! `chatsafe::content` target after PII redaction, cut to the configured
+//! length, so users can share logs without sharing whole conversations.
+
+use chatsafe_common::{redact, text};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::info;
+
+// Constants
+const CONTENT_TARGET: &str = "chatsafe::content";
+
+/// Excerpt length in characters; 0 disables content logging
+static EXCERPT_CHARS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn excerpt_chars() -> usize {
+    EXCERPT_CHARS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_excerpt_chars(chars: usize) {
+    EXCERPT_CHARS.store(chars, Ordering::Relaxed);
+}
+
+/// Log a redacted excerpt of `text` if content logging is on
+pub(crate) fn log_excerpt(kind: &'static str, text: &str) {
+    let chars = excerpt_chars();
+    if chars == 0 {
+        return;
+    }
+    info!(target: CONTENT_TARGET, kind, excerpt = %redact::excerpt(text, chars));
+}
+
+/// Collects the start of a streamed response for `log_excerpt`
+///
+/// Keeps twice the excerpt length so PII straddling the cut is still
+/// recognised by the redactor.
+#[derive(Default)]
+pub(crate) struct ExcerptBuffer {
+    text: String,
+    chars: usize,
+}
+
+impl ExcerptBuffer {
+    pub(crate) fn push(&mut self, delta: &str) {
+        let limit = excerpt_chars() * 2;
+        if self.chars >= limit {
+            return;
+        }
+        let kept = text::truncate_chars(delta, limit - self.chars);
+        self.text.push_str(kept);
+        self.chars += kept.chars().count();
+    }
+
+    pub(crate) fn log(&self, kind: &'static str) {
+        if !self.text.is_empty() {
+            log_excerpt(kind, &self.text);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Content profiles
+//!
+//! `server.content_profiles` names content settings such as `kid-safe`. A
+//! request picks one with `content_profile`, or gets `server.content_profile`.
+//! The profile's rules are added to the system prompt and the guard model
+//! checks the request in blocking mode: an unsafe prompt is refused, and a
+//! flagged response is generated once more with a stricter instruction before
+//! the profile's refusal is sent instead. Streamed responses are held back
+//! until checked, so this works for them too. Without a guard model only the
+//! system prompt changes.
+
+use crate::system_prompt;
+use chatsafe_common::{Error as CommonError, Message};
+use chatsafe_config::{ContentProfile, ServerConfig};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// The configured profiles and the default one
+#[derive(Default)]
+pub(crate) struct ContentProfiles {
+    profiles: BTreeMap<String, Arc<ContentProfile>>,
+    default: Option<Arc<ContentProfile>>,
+}
+
+impl ContentProfiles {
+    pub(crate) fn new(config: &ServerConfig) -> Result<Self, CommonError> {
+        let profiles: BTreeMap<_, _> = config
+            .content_profiles
+            .iter()
+            .map(|(name, profile)| (name.clone(), Arc::new(profile.clone())))
+            .collect();
+        let default = match &config.content_profile {
+            Some(name) => Some(profiles.get(name).cloned().ok_or_else(|| {
+                CommonError::ConfigError(format!(
+                    "server.content_profile {:?} is not in server.content_profiles",
+                    name
+                ))
+            })?),
+            None => None,
+        };
+        Ok(Self { profiles, default })
+    }
+
+    /// The profile a request asked for, or the default one
+    pub(crate) fn select(
+        &self,
+        requested: Option<&str>,
+    ) -> Result<Option<Arc<ContentProfile>>, CommonError> {
+        match requested {
+            Some(name) => self.profiles.get(name).cloned().map(Some).ok_or_else(|| {
+                CommonError::BadRequest(format!("Unknown content_profile {:?}", name))
+            }),
+            None => Ok(self.default.clone()),
+        }
+    }
+}
+
+/// Add the profile's rules to the system prompt
+pub(crate) fn apply(messages: &mut Vec<Message>, profile: &ContentProfile, default_prompt: &str) {
+    let rules = profile.system_prompt();
+    if !rules.is_empty() {
+        system_prompt::append(messages, &rules, default_prompt);
+    }
+}
+
+/// `messages` with the profile's retry instruction added, for regenerating
+/// a flagged response
+pub(crate) fn stricter(
+    messages: &[Message],
+    profile: &ContentProfile,
+    default_prompt: &str,
+) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    system_prompt::append(&mut messages, &profile.retry_instruction, default_prompt);
+    messages
+}
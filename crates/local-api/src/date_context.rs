@@ -0,0 +1,99 @@
+//! Current date and time in the system prompt
+//!
+//! Local models answer "what's today's date" from their training data. With
+//! `server.date_context` on, or `date_context.enabled` in a request, a line
+//! with the local date, time, UTC offset and locale is added to the system
+//! prompt. Clients in another timezone can send their own offset and locale.
+//!
+//! The line changes every minute, so the prompt cache is only reused for
+//! requests within the same minute.
+
+use crate::system_prompt;
+use chatsafe_common::{utc_date, DateContext, Message};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Constants
+const SECS_PER_DAY: i64 = 86_400;
+/// 1970-01-01 was a Thursday
+const WEEKDAYS: [&str; 7] = [
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+];
+
+/// Add the date line to the system prompt when enabled for this request
+pub(crate) fn apply(
+    messages: &mut Vec<Message>,
+    request: Option<&DateContext>,
+    enabled_by_default: bool,
+    default_locale: &str,
+    default_prompt: &str,
+) {
+    let request = request.cloned().unwrap_or_default();
+    if !request.enabled.unwrap_or(enabled_by_default) {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let offset = request
+        .utc_offset_minutes
+        .unwrap_or_else(|| local_utc_offset_minutes(now));
+    let locale = request.locale.as_deref().unwrap_or(default_locale);
+    system_prompt::append(messages, &describe(now, offset, locale), default_prompt);
+}
+
+/// Today's date on this machine, as YYYY-MM-DD
+pub(crate) fn local_date() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let local = now + i64::from(local_utc_offset_minutes(now)) * 60;
+    utc_date(local.max(0) as u64)
+}
+
+/// The line told to the model, e.g. `Current date and time: Friday,
+/// 2026-10-16 14:03 (UTC+02:00). User locale: de-DE.`
+pub(crate) fn describe(unix_secs: i64, utc_offset_minutes: i32, locale: &str) -> String {
+    let local = unix_secs + i64::from(utc_offset_minutes) * 60;
+    let days = local.div_euclid(SECS_PER_DAY);
+    let secs_of_day = local.rem_euclid(SECS_PER_DAY);
+    let weekday = WEEKDAYS[days.rem_euclid(7) as usize];
+    let sign = if utc_offset_minutes < 0 { '-' } else { '+' };
+    let offset = utc_offset_minutes.unsigned_abs();
+    format!(
+        "Current date and time: {}, {} {:02}:{:02} (UTC{}{:02}:{:02}). User locale: {}.",
+        weekday,
+        utc_date(local.max(0) as u64),
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        sign,
+        offset / 60,
+        offset % 60,
+        locale
+    )
+}
+
+/// This machine's UTC offset at `unix_secs`, from the OS timezone settings
+#[cfg(unix)]
+fn local_utc_offset_minutes(unix_secs: i64) -> i32 {
+    let time = unix_secs as libc::time_t;
+    // SAFETY: localtime_r only writes to the tm we pass and is thread-safe
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::localtime_r(&time, &mut tm) };
+    if result.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i32
+}
+
+#[cfg(not(unix))]
+fn local_utc_offset_minutes(_unix_secs: i64) -> i32 {
+    0
+}
@@ -0,0 +1,261 @@
+//! Non-streaming completions answered later
+//!
+//! A streaming request that has to wait for a chat slot learns its place in
+//! line from `queue` chunks. A non-streaming request has no such channel, so
+//! one sent with `Prefer: respond-async` gets `202 Accepted` instead of
+//! waiting: the body carries its place in line and a rough ETA, and
+//! `Location` points at `/v1/chat/completions/{id}`. Polling that URL returns
+//! `202` with the current status until the completion is done, then the
+//! response the request would have got. Finished responses are kept for
+//! `RESULT_TTL`, and are also POSTed to the webhooks as a `chat.completion`
+//! job (see `webhooks`).
+//!
+//! Without the header, or with a free slot, requests are answered as before.
+
+use crate::concurrency::QueuePlace;
+use crate::webhooks::JobEvent;
+use crate::{create_error_response, AppState};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, ErrorResponse, QueueStatus, RequestId};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use utoipa::ToSchema;
+
+// Constants
+const PREFER_ASYNC: &str = "respond-async";
+const STATUS_PATH: &str = "/v1/chat/completions";
+const STATUS_OBJECT: &str = "chat.completion.deferred";
+const JOB_KIND: &str = "chat.completion";
+/// How long a finished response waits to be fetched
+const RESULT_TTL: Duration = Duration::from_secs(600);
+/// Largest response body kept; completions are far smaller
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Whether the client asked not to wait (`Prefer: respond-async`)
+pub(crate) fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case(PREFER_ASYNC))
+}
+
+/// Completions answered with `202`, by request ID
+#[derive(Debug, Default)]
+pub(crate) struct DeferredResponses {
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+#[derive(Debug)]
+enum Job {
+    /// Waiting for a slot, then generating
+    Pending(QueuePlace),
+    Done {
+        response: StoredResponse,
+        finished: Instant,
+    },
+}
+
+/// A finished response, replayed on every poll until it expires
+#[derive(Debug)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Lets the `/v1/*` middleware render errors in OpenAI's format
+    error: Option<ErrorResponse>,
+}
+
+impl StoredResponse {
+    fn to_response(&self) -> Response {
+        let mut response = (self.status, self.headers.clone(), self.body.clone()).into_response();
+        if let Some(error) = &self.error {
+            response.extensions_mut().insert(error.clone());
+        }
+        response
+    }
+}
+
+impl DeferredResponses {
+    fn jobs(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record a request waiting at `place` and build its `202`
+    pub(crate) fn accept(
+        &self,
+        request_id: &RequestId,
+        place: QueuePlace,
+        tokens_per_second: Option<f64>,
+    ) -> Response {
+        let id = request_id.to_string();
+        let queue = place.status(tokens_per_second);
+        let mut jobs = self.jobs();
+        jobs.retain(|_, job| match job {
+            Job::Pending(_) => true,
+            Job::Done { finished, .. } => finished.elapsed() < RESULT_TTL,
+        });
+        jobs.insert(id.clone(), Job::Pending(place));
+        drop(jobs);
+        status_response(&id, queue)
+    }
+
+    /// Keep the finished response for polling, and describe it for webhooks
+    pub(crate) async fn finish(
+        &self,
+        request_id: &RequestId,
+        response: Response,
+        duration: Duration,
+    ) -> JobEvent {
+        let (mut parts, body) = response.into_parts();
+        let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Dropping deferred response for {}: {}", request_id, e);
+                let e = CommonError::Internal(format!("response could not be kept: {}", e));
+                let (error_parts, error_body) =
+                    create_error_response(&e, request_id, StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_parts();
+                parts = error_parts;
+                axum::body::to_bytes(error_body, MAX_BODY_BYTES)
+                    .await
+                    .unwrap_or_default()
+            }
+        };
+        let response = StoredResponse {
+            status: parts.status,
+            error: parts.extensions.remove::<ErrorResponse>(),
+            headers: parts.headers,
+            body,
+        };
+        let event = job_event(request_id, &response, duration);
+        self.jobs().insert(
+            request_id.to_string(),
+            Job::Done {
+                response,
+                finished: Instant::now(),
+            },
+        );
+        event
+    }
+
+    /// Drop every finished response, for `/admin/flush`; pending requests
+    /// keep their place and are stored again when they finish
+    pub(crate) fn clear(&self) -> usize {
+        let mut jobs = self.jobs();
+        let before = jobs.len();
+        jobs.retain(|_, job| matches!(job, Job::Pending(_)));
+        before - jobs.len()
+    }
+
+    /// The finished response, or the status of a pending one
+    pub(crate) fn poll(&self, id: &str, tokens_per_second: Option<f64>) -> Option<Response> {
+        match self.jobs().get(id)? {
+            Job::Pending(place) => Some(status_response(id, place.status(tokens_per_second))),
+            Job::Done { response, finished } => {
+                (finished.elapsed() < RESULT_TTL).then(|| response.to_response())
+            }
+        }
+    }
+}
+
+/// Webhook event for a finished completion, carrying the response body
+fn job_event(request_id: &RequestId, response: &StoredResponse, duration: Duration) -> JobEvent {
+    let job_id = request_id.to_string();
+    if !response.status.is_success() {
+        let error = match &response.error {
+            Some(error) => error.error.message.clone(),
+            None => response.status.to_string(),
+        };
+        return JobEvent::failed(job_id, JOB_KIND, duration, error);
+    }
+    let result: serde_json::Value = serde_json::from_slice(&response.body).unwrap_or_default();
+    let summary = json!({
+        "prompt_tokens": result["usage"]["prompt_tokens"],
+        "completion_tokens": result["usage"]["completion_tokens"],
+        "finish_reason": result["choices"][0]["finish_reason"]
+    });
+    JobEvent::completed(job_id, JOB_KIND, duration, summary, result)
+}
+
+/// Body of a `202` for a completion that is not done yet
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct DeferredCompletion {
+    id: String,
+    #[schema(example = "chat.completion.deferred")]
+    object: &'static str,
+    status: DeferredStatus,
+    /// Place in line; null once generating
+    queue: Option<QueueStatus>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DeferredStatus {
+    Queued,
+    Running,
+}
+
+/// `202` describing a pending completion; `queue` is `None` once it is
+/// generating
+fn status_response(id: &str, queue: Option<QueueStatus>) -> Response {
+    let location = format!("{}/{}", STATUS_PATH, id);
+    let status = if queue.is_some() {
+        DeferredStatus::Queued
+    } else {
+        DeferredStatus::Running
+    };
+    let body = DeferredCompletion {
+        id: id.to_string(),
+        object: STATUS_OBJECT,
+        status,
+        queue,
+    };
+    let mut response = (StatusCode::ACCEPTED, Json(body)).into_response();
+    if let Ok(value) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(header::LOCATION, value);
+    }
+    response
+}
+
+/// `GET /v1/chat/completions/{id}`
+#[utoipa::path(
+    get,
+    path = "/v1/chat/completions/{id}",
+    tag = "chat",
+    summary = "Status, then result, of a completion answered with 202",
+    params(("id" = String, Path, description = "ID from the 202's `Location`")),
+    responses(
+        (status = 200, description = "The finished completion, as the original request would have returned it", body = chatsafe_common::ChatCompletionResponse),
+        (status = 202, description = "Still queued or generating", body = DeferredCompletion, headers(("Location" = String))),
+        (status = "default", description = "Error", body = chatsafe_common::OpenAiErrorResponse)
+    )
+)]
+pub(crate) async fn completion_status(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<String>,
+) -> Response {
+    let tokens_per_second = state.metrics.recent_tokens_per_second().await;
+    state
+        .deferred
+        .poll(&id, tokens_per_second)
+        .unwrap_or_else(|| {
+            let e = CommonError::RouteNotFound(format!(
+                "GET {}/{} (unknown or expired completion)",
+                STATUS_PATH, id
+            ));
+            create_error_response(&e, &request_id, StatusCode::NOT_FOUND)
+        })
+}
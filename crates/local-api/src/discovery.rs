@@ -0,0 +1,224 @@
+//! API discovery for first-time callers
+//!
+//! `GET /` describes the API and lists every endpoint, and requests that
+//! miss a route or use the wrong method get the usual JSON error plus the
+//! endpoints they could have meant, instead of axum's empty 404/405.
+
+use crate::API_VERSION;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, ErrorResponse, RequestId};
+use serde::Serialize;
+use serde_json::json;
+
+/// One route served by the API
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Endpoint {
+    pub(crate) method: &'static str,
+    pub(crate) path: &'static str,
+    pub(crate) description: &'static str,
+}
+
+const fn endpoint(method: &'static str, path: &'static str, description: &'static str) -> Endpoint {
+    Endpoint {
+        method,
+        path,
+        description,
+    }
+}
+
+const ENDPOINTS: &[Endpoint] = &[
+    endpoint("GET", "/", "This index"),
+    endpoint(
+        "GET",
+        "/openapi.json",
+        "OpenAPI 3.1 description of this API",
+    ),
+    endpoint("GET", "/docs", "Swagger UI for the OpenAPI description"),
+    endpoint(
+        "POST",
+        "/v1/chat/completions",
+        "OpenAI-compatible chat completions, streamed as SSE with \"stream\": true",
+    ),
+    endpoint(
+        "GET",
+        "/v1/chat/completions/{id}",
+        "Status, then result, of a completion answered with 202",
+    ),
+    endpoint(
+        "POST",
+        "/v1/audio/transcriptions",
+        "OpenAI-compatible speech-to-text (multipart upload)",
+    ),
+    endpoint(
+        "POST",
+        "/v1/audio/speech",
+        "OpenAI-compatible text-to-speech",
+    ),
+    endpoint(
+        "POST",
+        "/v1/embeddings",
+        "OpenAI-compatible text embeddings",
+    ),
+    endpoint(
+        "POST",
+        "/v1/experiments/sweep",
+        "Run one conversation across a grid of sampling settings",
+    ),
+    endpoint(
+        "GET",
+        "/v1/usage/summary",
+        "Token usage totals per model and day",
+    ),
+    endpoint(
+        "GET",
+        "/v1/models",
+        "OpenAI-compatible model list with registry details",
+    ),
+    endpoint("GET", "/v1/models/{id}", "One model by ID or alias"),
+    endpoint("GET", "/healthz", "Health check"),
+    endpoint("GET", "/health", "Health check (alias of /healthz)"),
+    endpoint(
+        "GET",
+        "/readyz",
+        "Readiness: 503 with parse errors while in safe mode",
+    ),
+    endpoint(
+        "GET",
+        "/version",
+        "API version, build info and loaded models",
+    ),
+    endpoint("GET", "/metrics", "Privacy-preserving metrics"),
+    endpoint("GET", "/models", "Available models and aliases"),
+    endpoint(
+        "POST",
+        "/admin/flush",
+        "Wipe backend caches and in-memory request data",
+    ),
+    endpoint(
+        "GET",
+        "/admin/diagnostics",
+        "Backend state and recent errors",
+    ),
+    endpoint(
+        "GET",
+        "/admin/rate-limits",
+        "Rate limit buckets, in-flight requests per IP and recent rejections",
+    ),
+    endpoint(
+        "GET",
+        "/admin/detections",
+        "Jailbreak detections by category and recent redacted examples",
+    ),
+    endpoint(
+        "POST",
+        "/admin/reload",
+        "Re-read config and registry and restart out of safe mode",
+    ),
+    endpoint("GET", "/admin/log-level", "Current logging settings"),
+    endpoint("PUT", "/admin/log-level", "Change logging settings"),
+    endpoint("GET", "/admin/aliases", "Model aliases"),
+    endpoint("PUT", "/admin/aliases/{alias}", "Repoint a model alias"),
+    endpoint(
+        "POST",
+        "/admin/models/{id}/load",
+        "Load a chat model, streaming progress",
+    ),
+    endpoint(
+        "POST",
+        "/admin/models/{id}/unload",
+        "Unload a model and stop its server",
+    ),
+    endpoint("GET", "/admin/models/{id}/loras", "A model's LoRA adapters"),
+    endpoint(
+        "PUT",
+        "/admin/models/{id}/loras/{index}",
+        "Enable, disable or rescale a LoRA adapter",
+    ),
+];
+
+#[cfg(feature = "images")]
+const IMAGE_ENDPOINTS: &[Endpoint] = &[endpoint(
+    "POST",
+    "/v1/images/generations",
+    "OpenAI-compatible image generation",
+)];
+#[cfg(not(feature = "images"))]
+const IMAGE_ENDPOINTS: &[Endpoint] = &[];
+
+#[cfg(feature = "pprof")]
+const PPROF_ENDPOINTS: &[Endpoint] = &[endpoint(
+    "GET",
+    "/admin/pprof",
+    "CPU profile as a flamegraph",
+)];
+#[cfg(not(feature = "pprof"))]
+const PPROF_ENDPOINTS: &[Endpoint] = &[];
+
+/// Every endpoint in this build
+pub(crate) fn endpoints() -> impl Iterator<Item = &'static Endpoint> {
+    ENDPOINTS
+        .iter()
+        .chain(IMAGE_ENDPOINTS)
+        .chain(PPROF_ENDPOINTS)
+}
+
+/// `GET /`: what this server is and what it serves
+pub(crate) async fn index() -> Json<serde_json::Value> {
+    let endpoints: Vec<_> = endpoints().collect();
+    Json(json!({
+        "api": "ChatSafe Local API",
+        "version": API_VERSION,
+        "model_api": "OpenAI Compatible",
+        "endpoints": endpoints
+    }))
+}
+
+/// Fallback for paths no route matches
+pub(crate) async fn not_found(request: Request) -> Response {
+    let error =
+        CommonError::RouteNotFound(format!("{} {}", request.method(), request.uri().path()));
+    let endpoints: Vec<_> = endpoints().collect();
+    discovery_error(&error, &request, StatusCode::NOT_FOUND, endpoints)
+}
+
+/// Fallback for a known path called with a method it does not accept
+pub(crate) async fn method_not_allowed(request: Request) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let error = CommonError::MethodNotAllowed(format!("{} {}", request.method(), path));
+    let endpoints: Vec<_> = endpoints().filter(|e| e.path == path).collect();
+    discovery_error(&error, &request, StatusCode::METHOD_NOT_ALLOWED, endpoints)
+}
+
+fn discovery_error(
+    error: &CommonError,
+    request: &Request,
+    status: StatusCode,
+    endpoints: Vec<&Endpoint>,
+) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .cloned()
+        .unwrap_or_default();
+    let mut error = ErrorResponse::from(error);
+    error.request_id = Some(request_id.to_string());
+    let mut body = serde_json::to_value(&error).unwrap_or_default();
+    body["available_endpoints"] = json!(endpoints);
+
+    let mut response = (status, Extension(error), Json(body)).into_response();
+    if let Ok(value) = HeaderValue::from_str(&request_id.to_string()) {
+        response
+            .headers_mut()
+            .insert(crate::REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,120 @@
+//! OpenAI-compatible embeddings
+//!
+//! `POST /v1/embeddings` runs the registry's `embed` model on a second
+//! llama-server started with `--embedding` on `runtime.embedding_port`. The
+//! server is loaded on the first request and returns one float vector per
+//! input.
+
+use crate::on_demand::ensure_loaded;
+use crate::{create_error_response, AppState, RateLimitGuard};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chatsafe_common::{
+    text, Embedding, EmbeddingRequest, EmbeddingResponse, EmbeddingUsage, Error as CommonError,
+    RequestId,
+};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::info;
+
+/// Embed the request's inputs
+#[utoipa::path(
+    post,
+    path = "/v1/embeddings",
+    tag = "embeddings",
+    summary = "OpenAI-compatible text embeddings",
+    request_body = EmbeddingRequest,
+    responses(
+        (status = 200, description = "One vector per input", body = chatsafe_common::EmbeddingResponse),
+        (status = "default", description = "Error", body = chatsafe_common::OpenAiErrorResponse)
+    )
+)]
+pub(crate) async fn create_embeddings(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Response, Response> {
+    let ip = addr.ip();
+    let fail = |e: CommonError| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        create_error_response(&e, &request_id, status)
+    };
+
+    if let Err(e) = state.rate_limiter.check_rate_limit(ip).await {
+        state.metrics.record_error(Some(&request_id), &e).await;
+        state.metrics.record_rate_limit(ip.to_string()).await;
+        return Err(fail(e));
+    }
+    let _rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
+
+    let result = match state
+        .embedding_slots
+        // No budget: embedding requests are never shown an ETA
+        .acquire(Instant::now() + state.request_timeout, 0)
+        .await
+    {
+        Ok(_permit) => embed(&state, request).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        state.metrics.record_error(Some(&request_id), e).await;
+    }
+    result
+        .map(|response| Json(response).into_response())
+        .map_err(fail)
+}
+
+async fn embed(
+    state: &AppState,
+    request: EmbeddingRequest,
+) -> Result<EmbeddingResponse, CommonError> {
+    let embedder = state.embedder.as_ref().ok_or_else(|| {
+        CommonError::ServiceUnavailable(
+            "No embedding model configured; add a registry model with capability: embed".into(),
+        )
+    })?;
+    request.validate()?;
+
+    let model_id = ensure_loaded(embedder, "embedding").await?;
+    if let Some(model) = &request.model {
+        if state.aliases.resolve(model) != model_id {
+            return Err(CommonError::ModelNotFound(model.clone()));
+        }
+    }
+
+    let inputs = request.input.into_vec();
+    info!("Embedding {} inputs with {}", inputs.len(), model_id);
+    let embeddings = embedder.read().await.embed(&inputs).await?;
+    let prompt_tokens = match embeddings.prompt_tokens {
+        0 => inputs
+            .iter()
+            .map(|input| text::estimate_tokens(input))
+            .sum(),
+        tokens => tokens,
+    };
+
+    Ok(EmbeddingResponse {
+        object: "list".to_string(),
+        data: embeddings
+            .vectors
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| Embedding {
+                object: "embedding".to_string(),
+                index,
+                embedding,
+            })
+            .collect(),
+        model: model_id,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
@@ -0,0 +1,181 @@
+//! Local moderation with a guard model
+//!
+//! With a registry model of `capability: guard` (e.g. Llama Guard 3), chat
+//! completions are checked by it on a llama-server of its own on
+//! `runtime.guard_port`, started on first use. `server.guardrail` sets what
+//! happens to prompts and responses: `off`, `flag` (the default; the verdict
+//! is attached to the response) or `block` (an unsafe prompt is refused with
+//! a 400, an unsafe response withheld with `finish_reason: content_filter`).
+//! `categories` narrows which of the model's categories count as unsafe.
+//!
+//! Streamed responses are held back until the whole response has been
+//! checked. When the guard model fails, `flag` lets the request go ahead
+//! unchecked and `block` fails it with a 503. A request's content profile
+//! replaces the policy with its own (see `content_profile`). Flagged verdicts
+//! are counted in the metrics as `guard` detections.
+
+use crate::on_demand::ensure_loaded;
+use axum::http::{HeaderName, HeaderValue};
+use axum::response::Response;
+use chatsafe_common::{
+    DetectionSource, Error as CommonError, GuardVerdict, Message, ObservableMetrics, Role,
+};
+use chatsafe_config::{ContentProfile, GuardAction, GuardrailConfig};
+use chatsafe_runtime::{GuardTarget, LlamaAdapter, Runtime};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+// Constants
+const GUARDRAIL_HEADER: &str = "x-chatsafe-guardrail";
+
+/// The guard model and the policy applied to its verdicts
+pub(crate) struct Guardrail {
+    server: RwLock<LlamaAdapter>,
+    policy: GuardrailConfig,
+    metrics: Arc<ObservableMetrics>,
+}
+
+impl Guardrail {
+    pub(crate) fn new(
+        server: LlamaAdapter,
+        policy: GuardrailConfig,
+        metrics: Arc<ObservableMetrics>,
+    ) -> Self {
+        Self {
+            server: RwLock::new(server),
+            policy,
+            metrics,
+        }
+    }
+
+    pub(crate) async fn shutdown(&self) -> Result<(), CommonError> {
+        self.server.write().await.shutdown().await
+    }
+
+    /// The content profile's policy, or else `server.guardrail`
+    pub(crate) fn policy_for(&self, profile: Option<&ContentProfile>) -> GuardrailConfig {
+        profile.map_or_else(|| self.policy.clone(), ContentProfile::guardrail)
+    }
+
+    /// Verdict on the last user message; an error if `policy` blocks it
+    pub(crate) async fn check_prompt(
+        &self,
+        messages: &[Message],
+        policy: &GuardrailConfig,
+    ) -> Result<Option<GuardVerdict>, CommonError> {
+        let verdict = self
+            .check(messages, GuardTarget::Prompt, policy.prompts, policy)
+            .await?;
+        match verdict {
+            Some(verdict) if verdict.blocked => Err(CommonError::BadRequest(format!(
+                "The prompt was blocked by the guardrail ({})",
+                verdict.categories.join(", ")
+            ))),
+            verdict => Ok(verdict),
+        }
+    }
+
+    /// Verdict on `content` as the reply to `messages`; an error if the
+    /// guard model failed and `policy` blocks responses
+    pub(crate) async fn check_response(
+        &self,
+        messages: &[Message],
+        content: &str,
+        policy: &GuardrailConfig,
+    ) -> Result<Option<GuardVerdict>, CommonError> {
+        let mut conversation = messages.to_vec();
+        conversation.push(Message::new(Role::Assistant, content.to_string()));
+        self.check(
+            &conversation,
+            GuardTarget::Response,
+            policy.responses,
+            policy,
+        )
+        .await
+    }
+
+    /// Classify the `target` turn; a failing guard model is an error only
+    /// when `action` blocks
+    async fn check(
+        &self,
+        messages: &[Message],
+        target: GuardTarget,
+        action: GuardAction,
+        policy: &GuardrailConfig,
+    ) -> Result<Option<GuardVerdict>, CommonError> {
+        if action == GuardAction::Off {
+            return Ok(None);
+        }
+        let classification = match ensure_loaded(&self.server, "guard").await {
+            Ok(_) => self.server.read().await.classify(messages, target).await,
+            Err(e) => Err(e),
+        };
+        match classification {
+            Ok(classification) => {
+                let flagged = !classification.safe && counts(policy, &classification.categories);
+                if flagged {
+                    self.record(messages, &classification.categories).await;
+                }
+                Ok(Some(GuardVerdict {
+                    safe: classification.safe,
+                    categories: classification.categories,
+                    blocked: flagged && action == GuardAction::Block,
+                }))
+            }
+            Err(e) if action == GuardAction::Block => {
+                warn!("Guardrail check of the {:?} failed: {}", target, e);
+                Err(CommonError::ServiceUnavailable(format!(
+                    "The guardrail could not check the {}: {}",
+                    target.noun(),
+                    e
+                )))
+            }
+            Err(e) => {
+                warn!("Guardrail check of the {:?} failed: {}", target, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Count a flagged verdict once per category, with the checked message
+    async fn record(&self, messages: &[Message], categories: &[String]) {
+        let text = messages.last().map(|message| message.content.as_str());
+        if categories.is_empty() {
+            self.metrics
+                .record_detection(DetectionSource::Guard, "unsafe", text)
+                .await;
+        }
+        for category in categories {
+            self.metrics
+                .record_detection(DetectionSource::Guard, category, text)
+                .await;
+        }
+    }
+}
+
+/// Whether any of `categories` is one `policy` cares about
+fn counts(policy: &GuardrailConfig, categories: &[String]) -> bool {
+    policy.categories.is_empty()
+        || categories.iter().any(|category| {
+            policy
+                .categories
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(category))
+        })
+}
+
+/// Report a prompt verdict in `x-chatsafe-guardrail`: `safe`, or `unsafe`
+/// with the categories, e.g. `unsafe; categories=S1,S10`
+pub(crate) fn add_verdict_header(response: &mut Response, verdict: &GuardVerdict) {
+    let value = if verdict.safe {
+        "safe".to_string()
+    } else {
+        format!("unsafe; categories={}", verdict.categories.join(","))
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(GUARDRAIL_HEADER), value);
+    }
+}
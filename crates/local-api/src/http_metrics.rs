@@ -0,0 +1,44 @@
+//! HTTP-level metrics middleware
+//!
+//! Records count, latency and status code for every request by route pattern,
+//! so failures that never reach generation (404s, 422s from bad JSON) show up
+//! in `/metrics` alongside the generation metrics.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chatsafe_common::ObservableMetrics;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Constants
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Middleware recording per-route request metrics
+pub(crate) async fn track_http_metrics(
+    State(metrics): State<Arc<ObservableMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Use the route pattern, not the raw path, to keep cardinality bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    metrics
+        .record_http_request(
+            &route,
+            response.status().as_u16(),
+            started.elapsed().as_millis() as u64,
+        )
+        .await;
+
+    response
+}
@@ -0,0 +1,164 @@
+//! OpenAI-compatible image generation (`images` feature)
+//!
+//! `POST /v1/images/generations` runs the registry's `image` model through
+//! stable-diffusion.cpp and returns the PNGs inline as `b64_json`; there is
+//! no URL hosting. Generations run one at a time.
+
+use crate::{create_error_response, AppState, RateLimitGuard};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::Response,
+    Extension, Json,
+};
+use base64::Engine;
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_runtime::ImageRequest;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Constants
+const MAX_IMAGES: usize = 4;
+const DEFAULT_SIZE: (u32, u32) = (512, 512);
+const MAX_SIDE: u32 = 2048;
+/// stable-diffusion latents are 1/8 of the image; sizes must divide evenly
+const SIZE_STEP: u32 = 64;
+const MAX_STEPS: u32 = 150;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImageGenerationRequest {
+    prompt: String,
+    /// Must name the image model when given
+    model: Option<String>,
+    n: Option<usize>,
+    /// `WIDTHxHEIGHT`, e.g. `512x768`
+    size: Option<String>,
+    /// Only `b64_json` is supported
+    response_format: Option<String>,
+    negative_prompt: Option<String>,
+    steps: Option<u32>,
+    seed: Option<i64>,
+}
+
+/// Generate images for a prompt
+pub(crate) async fn create_image(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    Json(request): Json<ImageGenerationRequest>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let ip = addr.ip();
+    let fail = |e: CommonError| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        create_error_response(&e, &request_id, status)
+    };
+
+    if let Err(e) = state.rate_limiter.check_rate_limit(ip).await {
+        state.metrics.record_error(Some(&request_id), &e).await;
+        state.metrics.record_rate_limit(ip.to_string()).await;
+        return Err(fail(e));
+    }
+    let _rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
+
+    let result = generate(&state, request).await;
+    if let Err(e) = &result {
+        state.metrics.record_error(Some(&request_id), e).await;
+    }
+    result.map(Json).map_err(fail)
+}
+
+async fn generate(
+    state: &AppState,
+    request: ImageGenerationRequest,
+) -> Result<serde_json::Value, CommonError> {
+    let generator = state.image_generator.as_ref().ok_or_else(|| {
+        CommonError::ServiceUnavailable(
+            "No image model configured; add a registry model with capability: image".into(),
+        )
+    })?;
+
+    if request.prompt.trim().is_empty() {
+        return Err(CommonError::BadRequest("Prompt is empty".into()));
+    }
+    let count = request.n.unwrap_or(1);
+    if !(1..=MAX_IMAGES).contains(&count) {
+        return Err(CommonError::BadRequest(format!(
+            "n must be between 1 and {}",
+            MAX_IMAGES
+        )));
+    }
+    let (width, height) = match request.size.as_deref() {
+        Some(size) => parse_size(size)?,
+        None => DEFAULT_SIZE,
+    };
+    if request
+        .steps
+        .is_some_and(|steps| !(1..=MAX_STEPS).contains(&steps))
+    {
+        return Err(CommonError::BadRequest(format!(
+            "steps must be between 1 and {}",
+            MAX_STEPS
+        )));
+    }
+    if let Some(format) = request
+        .response_format
+        .as_deref()
+        .filter(|f| *f != "b64_json")
+    {
+        return Err(CommonError::BadRequest(format!(
+            "Unsupported response_format {:?}; only b64_json is available locally",
+            format
+        )));
+    }
+
+    let mut generator = generator.lock().await;
+    if let Some(model) = &request.model {
+        if model != generator.model_id() {
+            return Err(CommonError::ModelNotFound(model.clone()));
+        }
+    }
+    let images = generator
+        .generate(&ImageRequest {
+            prompt: request.prompt,
+            negative_prompt: request.negative_prompt,
+            count,
+            width,
+            height,
+            steps: request.steps,
+            seed: request.seed,
+        })
+        .await?;
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let data: Vec<_> = images
+        .iter()
+        .map(|png| json!({ "b64_json": engine.encode(png) }))
+        .collect();
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(json!({ "created": created, "data": data }))
+}
+
+/// `WIDTHxHEIGHT` with both sides multiples of 64, at most 2048
+pub(crate) fn parse_size(size: &str) -> Result<(u32, u32), CommonError> {
+    let invalid = || {
+        CommonError::BadRequest(format!(
+            "Invalid size {:?}; use WIDTHxHEIGHT with multiples of {} up to {}",
+            size, SIZE_STEP, MAX_SIDE
+        ))
+    };
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let side = |s: &str| {
+        s.trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0 && *n <= MAX_SIDE && n % SIZE_STEP == 0)
+            .ok_or_else(invalid)
+    };
+    Ok((side(width)?, side(height)?))
+}
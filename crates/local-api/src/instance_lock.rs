@@ -0,0 +1,230 @@
+//! One server per machine
+//!
+//! A second `chatsafe-server` would kill the first one's llama-server when it
+//! claims the backend port. On startup the server looks for a live instance,
+//! through the pid in `server.json` in the state directory or by probing its
+//! own listen ports, and refuses to start with a message naming it.
+//!
+//! A running server also listens on `admin.sock` next to `server.json`, a
+//! Unix socket only its user can open. `--takeover` sends `shutdown` there,
+//! waits for the old instance to exit and then starts as usual.
+
+use anyhow::{bail, Context, Result};
+use chatsafe_config::ListenAddress;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+// Constants
+const STATE_FILE: &str = "server.json";
+const ADMIN_SOCKET: &str = "admin.sock";
+const SHUTDOWN_COMMAND: &str = "shutdown";
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long the old instance gets to drain its requests, stop its backends
+/// and exit; longer than `server::DRAIN_TIMEOUT`
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(45);
+const TAKEOVER_POLL: Duration = Duration::from_millis(100);
+
+/// Contents of `server.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct InstanceState {
+    pid: u32,
+    addresses: Vec<String>,
+}
+
+/// Held for the life of the server; removes the state file when dropped
+pub(crate) struct InstanceLock {
+    dir: PathBuf,
+    shutdown: Arc<Notify>,
+}
+
+impl InstanceLock {
+    /// Claim `dir` for this process, first shutting down the running
+    /// instance when `takeover` is set
+    pub(crate) async fn acquire(
+        dir: &Path,
+        addresses: &[ListenAddress],
+        takeover: bool,
+    ) -> Result<Self> {
+        if let Some(running) = find_running(dir, addresses) {
+            if !takeover {
+                bail!(
+                    "{}. Stop it first, or start with --takeover to shut it down and replace it",
+                    running
+                );
+            }
+            warn!("{}; asking it to shut down", running);
+            request_shutdown(dir).await?;
+            wait_until_stopped(dir, addresses).await?;
+            info!("Previous instance stopped");
+        }
+
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let state = InstanceState {
+            pid: std::process::id(),
+            addresses: addresses.iter().map(ToString::to_string).collect(),
+        };
+        std::fs::write(dir.join(STATE_FILE), serde_json::to_vec_pretty(&state)?)
+            .with_context(|| format!("Failed to write {}", dir.join(STATE_FILE).display()))?;
+
+        let shutdown = Arc::new(Notify::new());
+        listen_for_shutdown(dir, Arc::clone(&shutdown))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            shutdown,
+        })
+    }
+
+    /// Completes when another instance asks this one to shut down
+    pub(crate) async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // A takeover may already have replaced the file with its own
+        if read_state(&self.dir).is_some_and(|state| state.pid == std::process::id()) {
+            let _ = std::fs::remove_file(self.dir.join(STATE_FILE));
+            let _ = std::fs::remove_file(self.dir.join(ADMIN_SOCKET));
+        }
+    }
+}
+
+/// Describe the instance already serving, if there is one
+pub(crate) fn find_running(dir: &Path, addresses: &[ListenAddress]) -> Option<String> {
+    if let Some(state) = read_state(dir) {
+        // A server restarting itself with exec keeps its pid
+        if state.pid != std::process::id() && process_alive(state.pid) {
+            return Some(format!(
+                "ChatSafe is already running (pid {}, serving {})",
+                state.pid,
+                state.addresses.join(", ")
+            ));
+        }
+    }
+
+    addresses.iter().find_map(|address| match address {
+        ListenAddress::Tcp(addr) if port_in_use(*addr) => Some(format!(
+            "Something is already listening on {}; is ChatSafe running without {}?",
+            addr,
+            dir.join(STATE_FILE).display()
+        )),
+        _ => None,
+    })
+}
+
+fn read_state(dir: &Path) -> Option<InstanceState> {
+    let content = std::fs::read(dir.join(STATE_FILE)).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Whether a connection to `addr` succeeds; wildcard addresses are probed on loopback
+fn port_in_use(addr: SocketAddr) -> bool {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    TcpStream::connect_timeout(&SocketAddr::new(ip, addr.port()), PORT_PROBE_TIMEOUT).is_ok()
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a cheap check, trust the port probe instead
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+async fn wait_until_stopped(dir: &Path, addresses: &[ListenAddress]) -> Result<()> {
+    let started = Instant::now();
+    while let Some(running) = find_running(dir, addresses) {
+        if started.elapsed() > TAKEOVER_TIMEOUT {
+            bail!(
+                "{} and did not stop within {} s",
+                running,
+                TAKEOVER_TIMEOUT.as_secs()
+            );
+        }
+        tokio::time::sleep(TAKEOVER_POLL).await;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn request_shutdown(dir: &Path) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = dir.join(ADMIN_SOCKET);
+    let mut stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .with_context(|| {
+            format!(
+                "Cannot reach the running instance's admin socket {}; stop it by hand",
+                path.display()
+            )
+        })?;
+    stream
+        .write_all(format!("{}\n", SHUTDOWN_COMMAND).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    if reply.trim() != "ok" {
+        bail!("Running instance refused to shut down: {}", reply.trim());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn request_shutdown(_dir: &Path) -> Result<()> {
+    bail!("--takeover needs a Unix admin socket; stop the running instance by hand")
+}
+
+/// Serve `shutdown` on the admin socket, readable and writable by this user only
+#[cfg(unix)]
+fn listen_for_shutdown(dir: &Path, shutdown: Arc<Notify>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let path = dir.join(ADMIN_SOCKET);
+    // Left behind by an instance that crashed or restarted itself
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind {}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (read, mut write) = stream.into_split();
+            let mut line = String::new();
+            if BufReader::new(read).read_line(&mut line).await.is_err() {
+                continue;
+            }
+            let reply = if line.trim() == SHUTDOWN_COMMAND {
+                warn!("Shutdown requested through the admin socket");
+                shutdown.notify_one();
+                "ok\n".to_string()
+            } else {
+                format!("unknown command {:?}\n", line.trim())
+            };
+            let _ = write.write_all(reply.as_bytes()).await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_for_shutdown(_dir: &Path, _shutdown: Arc<Notify>) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! Log filter changes at runtime
+//!
+//! The fmt layer's `EnvFilter` sits behind a reload handle, so
+//! `PUT /admin/log-level` can turn on e.g. `chatsafe_runtime=debug` while a
+//! problem is reproduced, without a restart losing the broken state.
+
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Reloadable filter for the log output layer
+pub(crate) type ReloadableFilter = reload::Layer<EnvFilter, Registry>;
+
+/// Handle for reading and replacing the active log filter
+#[derive(Clone)]
+pub(crate) struct LogLevelControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: Arc<Mutex<String>>,
+}
+
+impl LogLevelControl {
+    /// Filter from `RUST_LOG` with an `info` default, and its control handle
+    pub(crate) fn from_env() -> (ReloadableFilter, Self) {
+        let filter = EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into());
+        let directives = filter.to_string();
+        let (layer, handle) = reload::Layer::new(filter);
+        let control = Self {
+            handle,
+            directives: Arc::new(Mutex::new(directives)),
+        };
+        (layer, control)
+    }
+
+    /// Active filter directives, e.g. `info,chatsafe_runtime=debug`
+    pub(crate) fn directives(&self) -> String {
+        self.directives
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace the filter; a bare level like `debug` applies everywhere
+    pub(crate) fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| format!("Invalid log directives {:?}: {}", directives, e))?;
+        let normalized = filter.to_string();
+        self.handle
+            .reload(filter)
+            .map_err(|e| format!("Failed to apply log directives: {}", e))?;
+        *self.directives.lock().unwrap_or_else(|e| e.into_inner()) = normalized;
+        Ok(())
+    }
+}
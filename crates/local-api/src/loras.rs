@@ -0,0 +1,94 @@
+//! Turning a served model's LoRA adapters on and off
+//!
+//! Registry `loras` are loaded with the model at their configured scale.
+//! `GET /admin/models/{id}/loras` lists them with their current scale, and
+//! `PUT /admin/models/{id}/loras/{index}` changes one: `{"enabled": false}`
+//! sets its scale to 0, `{"enabled": true}` restores the registry scale and
+//! `{"scale": 0.5}` sets any other. A running server applies the change to
+//! the next generation; a stopped one when it starts. Changes last until the
+//! server restarts.
+
+use crate::model_switch::{chat_model, error_response};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    response::Response,
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_runtime::LoraState;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Body of `PUT /admin/models/{id}/loras/{index}`
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoraUpdate {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    scale: Option<f32>,
+}
+
+/// `GET /admin/models/{id}/loras`
+pub(crate) async fn get_loras(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let model_id = chat_model(&state, &id).map_err(|e| error_response(&e, &request_id))?;
+    let loras = state
+        .runtime
+        .lora_adapters(&model_id)
+        .await
+        .map_err(|e| error_response(&e, &request_id))?;
+    Ok(Json(listing(&model_id, loras)))
+}
+
+/// `PUT /admin/models/{id}/loras/{index}`
+pub(crate) async fn put_lora(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path((id, index)): Path<(String, usize)>,
+    Json(update): Json<LoraUpdate>,
+) -> Result<Json<Value>, Response> {
+    let model_id = chat_model(&state, &id).map_err(|e| error_response(&e, &request_id))?;
+    let scale = requested_scale(&state, &model_id, index, &update)
+        .map_err(|e| error_response(&e, &request_id))?;
+    let loras = state
+        .runtime
+        .set_lora_scale(&model_id, index, scale)
+        .await
+        .map_err(|e| error_response(&e, &request_id))?;
+    Ok(Json(listing(&model_id, loras)))
+}
+
+/// The scale `update` asks for
+fn requested_scale(
+    state: &AppState,
+    model_id: &str,
+    index: usize,
+    update: &LoraUpdate,
+) -> Result<f32, CommonError> {
+    match (update.enabled, update.scale) {
+        (Some(false), _) => Ok(0.0),
+        (_, Some(scale)) => Ok(scale),
+        (Some(true), None) => {
+            let model = state.registry.get_model(model_id)?;
+            let lora = model.loras.get(index).ok_or_else(|| {
+                CommonError::BadRequest(format!("Model {} has no LoRA adapter {}", model_id, index))
+            })?;
+            // An adapter configured off is turned on at full strength
+            Ok(if lora.scale == 0.0 { 1.0 } else { lora.scale })
+        }
+        (None, None) => Err(CommonError::BadRequest(
+            "Send \"enabled\" or \"scale\"".into(),
+        )),
+    }
+}
+
+fn listing(model_id: &str, loras: Vec<LoraState>) -> Value {
+    json!({
+        "model": model_id,
+        "loras": loras
+    })
+}
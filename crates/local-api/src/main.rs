@@ -1,42 +1,111 @@
 use anyhow::Result;
 
+mod aliases;
+mod client_ip;
+mod concurrency;
+use concurrency::{SlotPermit, Ticket};
+mod content_log;
+mod content_profile;
+mod date_context;
+mod deferred;
+mod discovery;
+mod embeddings;
+mod guardrail;
+mod http_metrics;
+#[cfg(feature = "images")]
+mod images;
+mod instance_lock;
+mod log_level;
+mod loras;
+mod model_switch;
+mod models;
+mod on_demand;
+mod openai_errors;
+mod openai_headers;
+mod openapi;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod prompt_injection;
 mod rate_limiter;
+mod replay_recorder;
+mod response_language;
+mod safe_mode;
+mod server;
+mod speech;
 mod streaming;
+mod supervisor;
+mod sweep;
+mod system_prompt;
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
+mod transcription;
+mod webhooks;
 use axum::{
-    extract::{ConnectInfo, State},
-    http::{HeaderValue, StatusCode},
+    extract::{ConnectInfo, DefaultBodyLimit, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{get, post, MethodRouter},
+    Extension, Json, Router,
 };
 use chatsafe_common::{
-    ChatCompletionRequest, ChatCompletionResponse, Choice, Error as CommonError, ErrorResponse,
-    FinishReason, GenerationParams, HealthResponse, HealthStatus, Message, ObservableMetrics,
-    ObservableMetricsSnapshot, RequestId, Role, StreamFrame, Usage,
+    text, ChatCompletionRequest, ChatCompletionResponse, Choice, ChoiceLogprobs, DetectionSource,
+    Error as CommonError, ErrorResponse, FinishReason, GenerationMetadata, GenerationParams,
+    GuardVerdict, GuardrailVerdicts, HealthResponse, HealthStatus, Locale, Message, ModelHealth,
+    ObservableMetrics, ObservableMetricsSnapshot, QueueStatus, RequestId, ResponseTimings, Role,
+    SlowRequest, SlowRequestThresholds, StreamBoundary, StreamFrame, ToolCall, Usage,
+    MAX_IMAGES_PER_REQUEST, MAX_IMAGE_BASE64_LEN,
+};
+use chatsafe_config::{
+    paths, ContentProfile, GuardAction, GuardrailConfig, HardwareProfile, ListenAddress,
+    ModelRegistry,
+};
+use chatsafe_runtime::{
+    FrameStream, Generation, ModelHandle, ModelRuntime, PiperAdapter, Runtime, RuntimeHandle,
+    WhisperAdapter,
 };
-use chatsafe_config::{ConfigLoader, ModelRegistry};
-use chatsafe_runtime::{ModelHandle, ModelRuntime, RuntimeHandle};
 use futures::StreamExt;
 use rate_limiter::{RateLimiter, RateLimiterConfig};
+use replay_recorder::ReplayRecorder;
+use response_language::TargetLanguage;
 use serde_json::json;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, Mutex, RwLock};
 use tower_http::trace::TraceLayer;
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{debug, info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 // Constants
 const API_VERSION: &str = "0.1.0";
 const HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
 const REQUEST_ID_HEADER: &str = "x-request-id";
+const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout-ms";
+const PROMPT_CACHED_HEADER: &str = "x-chatsafe-prompt-cached";
+const QUEUE_MS_HEADER: &str = "x-chatsafe-queue-ms";
+const PROMPT_MS_HEADER: &str = "x-chatsafe-prompt-ms";
+const GEN_MS_HEADER: &str = "x-chatsafe-gen-ms";
+const TOKENS_PER_SEC_HEADER: &str = "x-chatsafe-tokens-per-sec";
+const TRUNCATED_MESSAGES_HEADER: &str = "x-chatsafe-truncated-messages";
 const DEFAULT_MODEL_NAME: &str = "unknown";
 const CHAT_COMPLETION_OBJECT: &str = "chat.completion";
+/// How often a queued stream checks its place in line
+const QUEUE_POLL: Duration = Duration::from_millis(500);
+/// Longest a queued stream goes without a queue chunk
+const QUEUE_HEARTBEAT: Duration = Duration::from_secs(10);
+/// Shut down the running instance before starting
+const TAKEOVER_FLAG: &str = "--takeover";
+/// Room for a chat request's text and JSON next to its images
+const CHAT_BODY_HEADROOM: usize = 4 * 1024 * 1024;
+/// Largest chat request body: as many images as a request may carry
+const MAX_CHAT_BODY_BYTES: usize =
+    MAX_IMAGES_PER_REQUEST * MAX_IMAGE_BASE64_LEN + CHAT_BODY_HEADROOM;
 
 /// Ensures rate limit slots are released on all early exits.
 pub(crate) struct RateLimitGuard {
@@ -84,10 +153,47 @@ impl Drop for RateLimitGuard {
 struct AppState {
     runtime: RuntimeHandle,
     registry: Arc<ModelRegistry>,
+    aliases: Arc<aliases::AliasTable>,
     model_handle: Arc<RwLock<Option<ModelHandle>>>,
     start_time: SystemTime,
     metrics: Arc<ObservableMetrics>,
     rate_limiter: RateLimiter,
+    /// Chat generations in flight to llama-server, capped at its slots
+    chat_slots: concurrency::ChatLimits,
+    /// Embedding requests in flight to the embedding server
+    embedding_slots: concurrency::ConcurrencyLimit,
+    request_timeout: Duration,
+    replay_recorder: Option<Arc<ReplayRecorder>>,
+    stream_boundary: StreamBoundary,
+    tool_output_max_tokens: usize,
+    tool_output_tail_tokens: usize,
+    allow_prompt_override: bool,
+    /// Add the date line to system prompts unless a request says otherwise
+    date_context: bool,
+    /// `server.locale`, as told to the model in the date line
+    locale: String,
+    /// `server.profile`, for `{{profile.<key>}}` in system prompts
+    profile: Arc<BTreeMap<String, String>>,
+    /// Speech-to-text backend, when the registry has a `transcribe` model
+    transcriber: Option<Arc<Mutex<WhisperAdapter>>>,
+    /// Embedding-mode llama-server, when the registry has an `embed` model
+    embedder: Option<Arc<RwLock<chatsafe_runtime::LlamaAdapter>>>,
+    /// Prompt and response checks, when the registry has a `guard` model
+    guardrail: Option<Arc<guardrail::Guardrail>>,
+    /// `server.content_profiles`
+    content_profiles: Arc<content_profile::ContentProfiles>,
+    /// Text-to-speech voices, when the registry has `speech` models
+    synthesizer: Option<Arc<PiperAdapter>>,
+    /// Image generator, when built with `images` and the registry has an `image` model
+    #[cfg(feature = "images")]
+    image_generator: Option<Arc<Mutex<chatsafe_runtime::SdAdapter>>>,
+    log_level: log_level::LogLevelControl,
+    /// Job notifications (`server.webhooks`)
+    webhooks: webhooks::Notifier,
+    /// Non-streaming completions answered with `202`
+    deferred: Arc<deferred::DeferredResponses>,
+    /// Config and registry parse errors; non-empty means safe mode
+    startup_errors: Arc<Vec<String>>,
 }
 
 // Helper function to create error response with request ID
@@ -112,11 +218,83 @@ fn create_error_response(
                     .unwrap_or_else(|_| HeaderValue::from_static(DEFAULT_MODEL_NAME)),
             ),
         ],
+        // Lets the `/v1/*` middleware re-render it in OpenAI's format
+        Extension(error_response.clone()),
         Json(error_response),
     )
         .into_response()
 }
 
+/// Log a structured warning for requests that crossed a slow threshold
+pub(crate) fn warn_if_slow(slow: Option<SlowRequest>) {
+    if let Some(slow) = slow {
+        warn!(
+            request_id = %slow.request_id,
+            model = %slow.model,
+            queue_wait_ms = ?slow.queue_wait_ms,
+            first_token_ms = ?slow.first_token_ms,
+            duration_ms = slow.duration_ms,
+            prompt_tokens = ?slow.prompt_tokens,
+            "Slow request; a lighter quantization may help"
+        );
+    }
+}
+
+/// Feed backend timings and prompt cache use into the metrics once generation finishes
+pub(crate) async fn record_generation_metadata(
+    metrics: &ObservableMetrics,
+    metadata: &GenerationMetadata,
+    cache_prompt: bool,
+) {
+    if let Some(tps) = metadata.tokens_per_second {
+        metrics.record_tokens_per_second(tps).await;
+    }
+    metrics.record_prompt_cache(metadata, cache_prompt).await;
+    if metadata.role_pollution {
+        metrics
+            .record_detection(DetectionSource::Pollution, "role_play", None)
+            .await;
+    }
+}
+
+/// Expose prompt-cache reuse as a response header; throughput goes out
+/// with the other timings in `add_timing_headers`
+pub(crate) fn add_generation_headers(response: &mut Response, metadata: &GenerationMetadata) {
+    if let Some(cached) = metadata.cached_prompt_tokens {
+        response.headers_mut().insert(
+            axum::http::HeaderName::from_static(PROMPT_CACHED_HEADER),
+            HeaderValue::from(cached),
+        );
+    }
+}
+
+/// Expose the latency breakdown and dropped history as `x-chatsafe-*` headers
+pub(crate) fn add_timing_headers(response: &mut Response, timings: &ResponseTimings) {
+    let headers = response.headers_mut();
+    if let Some(dropped) = timings.truncated_messages {
+        headers.insert(
+            axum::http::HeaderName::from_static(TRUNCATED_MESSAGES_HEADER),
+            HeaderValue::from(dropped),
+        );
+    }
+    if let Some(queue_ms) = timings.queue_ms {
+        headers.insert(
+            axum::http::HeaderName::from_static(QUEUE_MS_HEADER),
+            HeaderValue::from(queue_ms),
+        );
+    }
+    let millis = [
+        (PROMPT_MS_HEADER, timings.prompt_ms),
+        (GEN_MS_HEADER, timings.gen_ms),
+        (TOKENS_PER_SEC_HEADER, timings.tokens_per_sec),
+    ];
+    for (name, value) in millis {
+        if let Some(Ok(value)) = value.map(|v| HeaderValue::from_str(&format!("{:.1}", v))) {
+            headers.insert(axum::http::HeaderName::from_static(name), value);
+        }
+    }
+}
+
 // Helper to add request ID header to response
 fn add_request_id_header(response: &mut Response, request_id: &RequestId) {
     response.headers_mut().insert(
@@ -126,20 +304,35 @@ fn add_request_id_header(response: &mut Response, request_id: &RequestId) {
     );
 }
 
+/// Budget for a request: the client's timeout header, capped at the configured default
+pub(crate) fn request_budget(headers: &HeaderMap, default: Duration) -> Duration {
+    headers
+        .get(REQUEST_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|ms| Duration::from_millis(ms).min(default))
+        .unwrap_or(default)
+}
+
 async fn health_check(State(state): State<AppState>) -> Json<HealthResponse> {
-    // Apply timeout to health check
-    let health_future = state.runtime.health();
+    // Apply timeout to health check; the default model comes first
+    let health_future = state.runtime.model_health();
     let timeout_duration = Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS);
+    let models = tokio::time::timeout(timeout_duration, health_future)
+        .await
+        .unwrap_or_default();
 
-    let health = match tokio::time::timeout(timeout_duration, health_future).await {
-        Ok(Ok(health)) => health,
-        Ok(Err(_)) | Err(_) => {
+    let health = match models.first() {
+        Some((_, health)) => health.clone(),
+        None => {
             // Either runtime error or timeout - treat as unhealthy
             chatsafe_runtime::RuntimeHealth {
                 is_healthy: false,
                 model_loaded: None,
                 active_requests: 0,
                 uptime_seconds: 0,
+                last_success: None,
+                resources: Vec::new(),
             }
         }
     };
@@ -147,51 +340,392 @@ async fn health_check(State(state): State<AppState>) -> Json<HealthResponse> {
     let uptime = state.start_time.elapsed().unwrap_or_default().as_secs();
 
     Json(HealthResponse {
-        status: if health.is_healthy {
-            HealthStatus::Healthy
-        } else {
-            HealthStatus::Unhealthy
-        },
+        status: health_status(&health),
         model_loaded: health.model_loaded.is_some(),
         version: API_VERSION.to_string(),
         uptime_seconds: uptime,
+        last_success: unix_seconds(health.last_success),
+        models: models
+            .iter()
+            .map(|(model, health)| ModelHealth {
+                model: model.clone(),
+                status: health_status(health),
+                loaded: health.model_loaded.is_some(),
+                last_success: unix_seconds(health.last_success),
+            })
+            .collect(),
     })
 }
 
+fn health_status(health: &chatsafe_runtime::RuntimeHealth) -> HealthStatus {
+    if health.is_healthy {
+        HealthStatus::Healthy
+    } else {
+        HealthStatus::Unhealthy
+    }
+}
+
+fn unix_seconds(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+/// Wait for the backend slot `slot` holds or is in line for, then start a
+/// generation that holds it
+///
+/// Time spent waiting counts as queue time and against the request deadline.
+async fn generate_in_slot(
+    state: &AppState,
+    handle: &ModelHandle,
+    messages: Vec<Message>,
+    params: GenerationParams,
+    slot: Result<SlotPermit, Ticket>,
+) -> Result<(Generation, SlotPermit), CommonError> {
+    let permit = match slot {
+        Ok(permit) => permit,
+        Err(ticket) => {
+            let deadline = params
+                .deadline
+                .unwrap_or_else(|| Instant::now() + state.request_timeout);
+            ticket.wait(deadline).await?
+        }
+    };
+    let generation = state.runtime.generate(handle, messages, params).await?;
+    Ok((generation, permit))
+}
+
+/// Record that generation started and forward its timings once known,
+/// returning its frames with the slot held until they end
+async fn start_stream(
+    state: &AppState,
+    tracked_request_id: &RequestId,
+    generation: Generation,
+    permit: SlotPermit,
+    cache_prompt: bool,
+    timings_tx: oneshot::Sender<ResponseTimings>,
+) -> FrameStream {
+    let queue_ms = state
+        .metrics
+        .record_generation_started(tracked_request_id)
+        .await;
+
+    // SSE headers are already sent by the time timings exist, so they feed
+    // the metrics and the final chunk's timings
+    let metrics = Arc::clone(&state.metrics);
+    supervisor::spawn(
+        Arc::clone(&metrics),
+        tracked_request_id.clone(),
+        "generation metadata",
+        async move {
+            let metadata = generation.metadata.await.ok();
+            if let Some(metadata) = &metadata {
+                record_generation_metadata(&metrics, metadata, cache_prompt).await;
+            }
+            let _ = timings_tx.send(ResponseTimings::new(queue_ms, metadata.as_ref()));
+        },
+    );
+    concurrency::hold_until_done(generation.stream, permit)
+}
+
+/// Frames of a request that has to wait for a slot: its place in line
+/// whenever that changes (and at least every `QUEUE_HEARTBEAT`), then the
+/// generation
+///
+/// The response has started by then, so errors become error events.
+fn queued_stream(
+    state: AppState,
+    handle: ModelHandle,
+    messages: Vec<Message>,
+    params: GenerationParams,
+    tracked_request_id: RequestId,
+    ticket: Ticket,
+    timings_tx: oneshot::Sender<ResponseTimings>,
+) -> FrameStream {
+    Box::pin(async_stream::stream! {
+        let deadline = params
+            .deadline
+            .unwrap_or_else(|| Instant::now() + state.request_timeout);
+        let cache_prompt = params.cache_prompt;
+        let wait = ticket.wait(deadline);
+        tokio::pin!(wait);
+        let mut last_sent: Option<(QueueStatus, Instant)> = None;
+        let slot = loop {
+            let tokens_per_second = state.metrics.recent_tokens_per_second().await;
+            if let Some(status) = ticket.place().status(tokens_per_second) {
+                let due = last_sent.as_ref().is_none_or(|(last, at)| {
+                    *last != status || at.elapsed() >= QUEUE_HEARTBEAT
+                });
+                if due {
+                    yield Ok(StreamFrame::Queued {
+                        position: status.position,
+                        eta_ms: status.eta_ms,
+                    });
+                    last_sent = Some((status, Instant::now()));
+                }
+            }
+            tokio::select! {
+                slot = &mut wait => break slot,
+                _ = tokio::time::sleep(QUEUE_POLL) => {}
+            }
+        };
+
+        let started = match slot {
+            Ok(permit) => state
+                .runtime
+                .generate(&handle, messages, params)
+                .await
+                .map(|generation| (generation, permit)),
+            Err(e) => Err(e),
+        };
+        match started {
+            Ok((generation, permit)) => {
+                let mut frames = start_stream(
+                    &state,
+                    &tracked_request_id,
+                    generation,
+                    permit,
+                    cache_prompt,
+                    timings_tx,
+                )
+                .await;
+                while let Some(frame) = frames.next().await {
+                    yield frame;
+                }
+            }
+            Err(e) => {
+                state.metrics.record_error(Some(&tracked_request_id), &e).await;
+                yield Err(e);
+            }
+        }
+    })
+}
+
+/// A generation's frames, held back until its response has been checked
+#[derive(Default)]
+struct HeldFrames {
+    frames: Vec<Result<StreamFrame, CommonError>>,
+    content: String,
+    failed: bool,
+}
+
+impl HeldFrames {
+    fn push(&mut self, frame: Result<StreamFrame, CommonError>) {
+        match &frame {
+            Ok(StreamFrame::Delta { content }) => self.content.push_str(content),
+            Ok(StreamFrame::Error { .. }) | Err(_) => self.failed = true,
+            Ok(_) => {}
+        }
+        self.frames.push(frame);
+    }
+}
+
+/// A streamed response held back until the guardrail has checked all of it,
+/// then sent, or withheld with `finish_reason: content_filter` when blocked
+///
+/// With a content profile, a blocked response is generated once more with
+/// the profile's stricter instruction, in the slot `frames` still holds,
+/// before the profile's refusal is sent instead.
+#[allow(clippy::too_many_arguments)]
+fn checked_stream(
+    state: AppState,
+    handle: ModelHandle,
+    params: GenerationParams,
+    profile: Option<Arc<ContentProfile>>,
+    guardrail: Arc<guardrail::Guardrail>,
+    policy: GuardrailConfig,
+    conversation: Vec<Message>,
+    mut frames: FrameStream,
+) -> FrameStream {
+    Box::pin(async_stream::stream! {
+        let request_id = params.request_id.clone();
+        let mut response = HeldFrames::default();
+        while let Some(frame) = frames.next().await {
+            match frame {
+                // Not part of the response, and keep the client informed
+                Ok(StreamFrame::Start { .. } | StreamFrame::Queued { .. }) => yield frame,
+                frame => response.push(frame),
+            }
+        }
+
+        let blocked = |verdict: Result<Option<GuardVerdict>, CommonError>| {
+            verdict.map(|verdict| verdict.is_some_and(|verdict| verdict.blocked))
+        };
+        let mut verdict = if response.failed {
+            Ok(false)
+        } else {
+            blocked(guardrail.check_response(&conversation, &response.content, &policy).await)
+        };
+        if let (Ok(true), Some(profile)) = (&verdict, &profile) {
+            // One more try with a stricter instruction, then a refusal
+            info!("Regenerating the response to {} flagged by the guardrail", request_id);
+            let default_prompt = system_prompt::template_default(
+                &state,
+                &handle.model_id,
+                &params.prompt_variables,
+            );
+            let retry = content_profile::stricter(&conversation, profile, &default_prompt);
+            let mut retried = HeldFrames::default();
+            match state.runtime.generate(&handle, retry, params.clone()).await {
+                Ok(mut generation) => {
+                    while let Some(frame) = generation.stream.next().await {
+                        if !matches!(frame, Ok(StreamFrame::Start { .. })) {
+                            retried.push(frame);
+                        }
+                    }
+                }
+                Err(e) => retried.push(Err(e)),
+            }
+            if retried.failed {
+                warn!("Regenerating the response to {} failed", request_id);
+            } else {
+                verdict = blocked(
+                    guardrail.check_response(&conversation, &retried.content, &policy).await,
+                );
+                response = retried;
+            }
+        }
+
+        match verdict {
+            Ok(false) => {
+                for frame in response.frames {
+                    yield frame;
+                }
+            }
+            Ok(true) => {
+                match &profile {
+                    Some(_) => warn!("Response to {} refused by content profile", request_id),
+                    None => warn!("Response to {} withheld by the guardrail", request_id),
+                }
+                for frame in response.frames {
+                    match frame {
+                        Ok(StreamFrame::Done { usage, .. }) => {
+                            if let Some(profile) = &profile {
+                                yield Ok(StreamFrame::Delta {
+                                    content: profile.refusal.clone(),
+                                });
+                            }
+                            yield Ok(StreamFrame::Done {
+                                finish_reason: FinishReason::ContentFilter,
+                                usage,
+                            });
+                        }
+                        Ok(
+                            StreamFrame::Delta { .. }
+                            | StreamFrame::ToolCalls { .. }
+                            | StreamFrame::Logprobs { .. },
+                        ) => {}
+                        frame => yield frame,
+                    }
+                }
+            }
+            Err(e) => yield Err(e),
+        }
+    })
+}
+
+/// `POST /v1/chat/completions`, with a body limit that fits its images
+fn chat_completion_route() -> MethodRouter<AppState> {
+    post(chat_completion).layer(DefaultBodyLimit::max(MAX_CHAT_BODY_BYTES))
+}
+
 // Handle streaming response
+#[allow(clippy::too_many_arguments)]
 async fn handle_streaming(
     state: &AppState,
     handle: &ModelHandle,
     messages: Vec<Message>,
     params: GenerationParams,
+    profile: Option<Arc<ContentProfile>>,
     request_id: &RequestId,
     tracked_request_id: &RequestId,
     ip: std::net::IpAddr,
 ) -> Result<Response, Response> {
     let model_id = handle.model_id.to_string();
-
-    let stream = state
-        .runtime
-        .generate(handle, messages, params)
-        .await
-        .map_err(|e| {
-            let response = create_error_response(&e, request_id, StatusCode::INTERNAL_SERVER_ERROR);
-
-            // Complete request tracking on error
-            let metrics = Arc::clone(&state.metrics);
-            let req_id = request_id.clone();
-            let tracked_id = tracked_request_id.clone();
-            tokio::spawn(async move {
-                metrics.record_error(Some(&req_id), &e).await;
-                metrics.complete_request(&tracked_id).await;
-            });
-
-            response
-        })?;
+    let cache_prompt = params.cache_prompt;
+    let (timings_tx, timings_rx) = tokio::sync::oneshot::channel();
+    let response_check = state.guardrail.as_ref().and_then(|guardrail| {
+        let policy = guardrail.policy_for(profile.as_deref());
+        (policy.responses != GuardAction::Off).then(|| {
+            (
+                Arc::clone(guardrail),
+                policy,
+                messages.clone(),
+                params.clone(),
+            )
+        })
+    });
+
+    let frames = match state
+        .chat_slots
+        .for_model(&handle.model_id)
+        .try_acquire(params.max_tokens)
+    {
+        // Wait for the backend before answering, so its errors keep their status
+        Ok(permit) => {
+            let generation = state
+                .runtime
+                .generate(handle, messages, params)
+                .await
+                .map_err(|e| {
+                    let status = StatusCode::from_u16(e.status_code())
+                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    let response = create_error_response(&e, request_id, status);
+
+                    // Complete request tracking on error
+                    let metrics = Arc::clone(&state.metrics);
+                    let req_id = request_id.clone();
+                    let tracked_id = tracked_request_id.clone();
+                    supervisor::spawn(
+                        Arc::clone(&metrics),
+                        tracked_id.clone(),
+                        "error bookkeeping",
+                        async move {
+                            metrics.record_error(Some(&req_id), &e).await;
+                            metrics.complete_request(&tracked_id).await;
+                        },
+                    );
+
+                    response
+                })?;
+            start_stream(
+                state,
+                tracked_request_id,
+                generation,
+                permit,
+                cache_prompt,
+                timings_tx,
+            )
+            .await
+        }
+        // Answer at once and report the place in line until a slot frees up
+        Err(ticket) => queued_stream(
+            state.clone(),
+            handle.clone(),
+            messages,
+            params,
+            tracked_request_id.clone(),
+            ticket,
+            timings_tx,
+        ),
+    };
+    let frames = match response_check {
+        Some((guardrail, policy, conversation, params)) => checked_stream(
+            state.clone(),
+            handle.clone(),
+            params,
+            profile,
+            guardrail,
+            policy,
+            conversation,
+            frames,
+        ),
+        None => frames,
+    };
 
     // Request completion is handled by streaming module's CleanupGuard
     let mut response = streaming::streaming_response_with_observability(
-        stream,
+        frames,
+        timings_rx,
         model_id,
         Arc::clone(&state.metrics),
         state.rate_limiter.clone(),
@@ -204,71 +738,282 @@ async fn handle_streaming(
     Ok(response)
 }
 
+/// A finished generation's frames, put together
+struct Collected {
+    content: String,
+    usage: Usage,
+    finish_reason: FinishReason,
+    tool_calls: Option<Vec<ToolCall>>,
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+impl Collected {
+    /// Replace the response with `content`, marked as filtered
+    fn refuse(&mut self, content: String) {
+        self.content = content;
+        self.tool_calls = None;
+        self.logprobs = None;
+        self.finish_reason = FinishReason::ContentFilter;
+    }
+}
+
+/// Read a generation to its end, counting its tokens
+async fn collect_frames(
+    state: &AppState,
+    generation: &mut Generation,
+    tracked_request_id: &RequestId,
+    with_logprobs: bool,
+) -> Result<Collected, CommonError> {
+    let mut collected = Collected {
+        content: String::new(),
+        usage: Usage::default(),
+        finish_reason: FinishReason::Stop,
+        tool_calls: None,
+        logprobs: with_logprobs.then(ChoiceLogprobs::default),
+    };
+    while let Some(frame) = generation.stream.next().await {
+        match frame {
+            Ok(StreamFrame::Delta { content: delta }) => {
+                collected.content.push_str(&delta);
+            }
+            Ok(StreamFrame::Logprobs { content: tokens }) => {
+                if let Some(logprobs) = &mut collected.logprobs {
+                    logprobs.content.extend(tokens);
+                }
+            }
+            Ok(StreamFrame::ToolCalls { tool_calls: calls }) => {
+                collected.tool_calls = Some(calls);
+            }
+            Ok(StreamFrame::Done {
+                finish_reason: reason,
+                usage: u,
+            }) => {
+                state
+                    .metrics
+                    .record_tokens(
+                        tracked_request_id,
+                        u.prompt_tokens as u64,
+                        u.completion_tokens as u64,
+                    )
+                    .await;
+                collected.finish_reason = reason;
+                collected.usage = u;
+            }
+            Ok(StreamFrame::Error { message, .. }) => {
+                return Err(CommonError::RuntimeError(message));
+            }
+            Err(e) => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(collected)
+}
+
+/// Generate a response again in the slot the request already holds
+async fn regenerate(
+    state: &AppState,
+    handle: &ModelHandle,
+    messages: Vec<Message>,
+    params: &GenerationParams,
+    tracked_request_id: &RequestId,
+    with_logprobs: bool,
+) -> Result<(Generation, Collected), CommonError> {
+    let mut generation = state
+        .runtime
+        .generate(handle, messages, params.clone())
+        .await?;
+    let collected =
+        collect_frames(state, &mut generation, tracked_request_id, with_logprobs).await?;
+    Ok((generation, collected))
+}
+
+/// End a non-streamed request whose generation failed after it started
+async fn fail_generation(
+    state: &AppState,
+    err: CommonError,
+    request_id: &RequestId,
+    tracked_request_id: &RequestId,
+    ip: std::net::IpAddr,
+) -> Response {
+    // Complete request tracking on error
+    state.rate_limiter.release_request(ip).await;
+    state.metrics.record_error(Some(request_id), &err).await;
+    state.metrics.complete_request(tracked_request_id).await;
+
+    let status =
+        StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    create_error_response(&err, request_id, status)
+}
+
 // Handle non-streaming response
+#[allow(clippy::too_many_arguments)]
 async fn handle_non_streaming(
     state: &AppState,
     handle: &ModelHandle,
     messages: Vec<Message>,
     params: GenerationParams,
+    slot: Result<SlotPermit, Ticket>,
+    prompt_verdict: Option<GuardVerdict>,
+    profile: Option<Arc<ContentProfile>>,
+    language: Option<TargetLanguage>,
     request_id: &RequestId,
     tracked_request_id: &RequestId,
     ip: std::net::IpAddr,
 ) -> Result<Response, Response> {
     let model_id = handle.model_id.to_string();
-
-    let mut stream = state
-        .runtime
-        .generate(handle, messages, params.clone())
+    let response_check = state.guardrail.as_ref().and_then(|guardrail| {
+        let policy = guardrail.policy_for(profile.as_deref());
+        (policy.responses != GuardAction::Off).then_some((guardrail, policy))
+    });
+    let language = language.filter(TargetLanguage::verify);
+    let conversation = (response_check.is_some() || language.is_some()).then(|| messages.clone());
+
+    // Regenerations reuse the slot, which is held until the response is done
+    let (mut generation, _permit) = generate_in_slot(state, handle, messages, params.clone(), slot)
         .await
         .map_err(|e| {
-            let response = create_error_response(&e, request_id, StatusCode::INTERNAL_SERVER_ERROR);
+            let status =
+                StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            let response = create_error_response(&e, request_id, status);
 
             // Complete request tracking on error
             let metrics = Arc::clone(&state.metrics);
             let req_id = request_id.clone();
             let tracked_id = tracked_request_id.clone();
-            tokio::spawn(async move {
-                metrics.record_error(Some(&req_id), &e).await;
-                metrics.complete_request(&tracked_id).await;
-            });
+            supervisor::spawn(
+                Arc::clone(&metrics),
+                tracked_id.clone(),
+                "error bookkeeping",
+                async move {
+                    metrics.record_error(Some(&req_id), &e).await;
+                    metrics.complete_request(&tracked_id).await;
+                },
+            );
 
             response
         })?;
 
-    // Collect all frames
-    let mut content = String::new();
-    let mut usage = Usage::default();
-    let mut finish_reason = FinishReason::Stop;
+    let queue_ms = state
+        .metrics
+        .record_generation_started(tracked_request_id)
+        .await;
 
-    while let Some(frame) = stream.next().await {
-        match frame {
-            Ok(StreamFrame::Delta { content: delta }) => {
-                content.push_str(&delta);
+    let with_logprobs = params.top_logprobs.is_some();
+    let mut collected =
+        match collect_frames(state, &mut generation, tracked_request_id, with_logprobs).await {
+            Ok(collected) => collected,
+            Err(err) => {
+                return Err(fail_generation(state, err, request_id, tracked_request_id, ip).await)
             }
-            Ok(StreamFrame::Done {
-                finish_reason: reason,
-                usage: u,
-            }) => {
-                finish_reason = reason;
-                usage = u;
+        };
+    content_log::log_excerpt("response", &collected.content);
+
+    if let (Some(language), Some(conversation)) = (language, &conversation) {
+        if !language.matches(&collected.content) {
+            info!(
+                "Regenerating the response to {}, which is not in {}",
+                request_id,
+                language.code()
+            );
+            let default_prompt =
+                system_prompt::template_default(state, &handle.model_id, &params.prompt_variables);
+            let retry = language.stricter(conversation, &default_prompt);
+            match regenerate(
+                state,
+                handle,
+                retry,
+                &params,
+                tracked_request_id,
+                with_logprobs,
+            )
+            .await
+            {
+                Ok((retried, recollected)) => {
+                    generation = retried;
+                    collected = recollected;
+                }
+                Err(e) => warn!("Regenerating the response to {} failed: {}", request_id, e),
             }
-            Ok(StreamFrame::Error { message }) => {
-                // Complete request tracking on error
-                state.rate_limiter.release_request(ip).await;
-
-                let err = CommonError::RuntimeError(message);
-                state.metrics.record_error(Some(request_id), &err).await;
-                state.metrics.complete_request(tracked_request_id).await;
+        }
+    }
 
-                return Err(create_error_response(
-                    &err,
-                    request_id,
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ));
+    let mut response_verdict = None;
+    let mut regenerated = false;
+    if let (Some((guardrail, policy)), Some(conversation)) = (&response_check, &conversation) {
+        let blocked = |verdict: &Option<GuardVerdict>| verdict.as_ref().is_some_and(|v| v.blocked);
+        response_verdict = match guardrail
+            .check_response(conversation, &collected.content, policy)
+            .await
+        {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                return Err(fail_generation(state, err, request_id, tracked_request_id, ip).await)
             }
-            _ => {}
+        };
+        if let Some(profile) = profile.as_deref().filter(|_| blocked(&response_verdict)) {
+            // One more try with a stricter instruction, then a refusal
+            info!(
+                "Regenerating the response to {} flagged by the guardrail",
+                request_id
+            );
+            let default_prompt =
+                system_prompt::template_default(state, &handle.model_id, &params.prompt_variables);
+            let retry = content_profile::stricter(conversation, profile, &default_prompt);
+            match regenerate(
+                state,
+                handle,
+                retry,
+                &params,
+                tracked_request_id,
+                with_logprobs,
+            )
+            .await
+            {
+                Ok((retried, recollected)) => {
+                    regenerated = true;
+                    generation = retried;
+                    collected = recollected;
+                    response_verdict = match guardrail
+                        .check_response(conversation, &collected.content, policy)
+                        .await
+                    {
+                        Ok(verdict) => verdict,
+                        Err(err) => {
+                            return Err(fail_generation(
+                                state,
+                                err,
+                                request_id,
+                                tracked_request_id,
+                                ip,
+                            )
+                            .await)
+                        }
+                    };
+                }
+                Err(e) => warn!("Regenerating the response to {} failed: {}", request_id, e),
+            }
+            if !regenerated || blocked(&response_verdict) {
+                warn!("Response to {} refused by content profile", request_id);
+                collected.refuse(profile.refusal.clone());
+            }
+        } else if blocked(&response_verdict) {
+            warn!("Response to {} withheld by the guardrail", request_id);
+            collected.refuse(String::new());
         }
     }
+    let guardrail =
+        (prompt_verdict.is_some() || response_verdict.is_some()).then_some(GuardrailVerdicts {
+            prompt: prompt_verdict,
+            response: response_verdict,
+            regenerated,
+        });
+    let Collected {
+        content,
+        usage,
+        finish_reason,
+        tool_calls,
+        logprobs,
+    } = collected;
 
     // Create response
     let response = ChatCompletionResponse {
@@ -282,36 +1027,64 @@ async fn handle_non_streaming(
         choices: vec![Choice {
             index: 0,
             message: Message {
-                role: Role::Assistant,
-                content,
+                tool_calls,
+                ..Message::new(Role::Assistant, content)
             },
+            logprobs,
             finish_reason: Some(finish_reason),
         }],
         usage,
+        guardrail,
     };
 
     // Release rate limit for non-streaming requests
     state.rate_limiter.release_request(ip).await;
 
     // Complete request tracking
-    state.metrics.complete_request(tracked_request_id).await;
+    warn_if_slow(state.metrics.complete_request(tracked_request_id).await);
 
     // Create response with headers
     let mut http_response = Json(response).into_response();
     add_request_id_header(&mut http_response, request_id);
+    let metadata = generation.metadata.try_recv().ok();
+    if let Some(metadata) = &metadata {
+        record_generation_metadata(&state.metrics, metadata, params.cache_prompt).await;
+        add_generation_headers(&mut http_response, metadata);
+    }
+    add_timing_headers(
+        &mut http_response,
+        &ResponseTimings::new(queue_ms, metadata.as_ref()),
+    );
 
     Ok(http_response)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions",
+    tag = "chat",
+    summary = "OpenAI-compatible chat completions, streamed as SSE with \"stream\": true",
+    request_body = ChatCompletionRequest,
+    responses(
+        (status = 200, description = "Completion, or an SSE stream of chunks ending with `data: [DONE]` when `stream` is true", content(
+            (chatsafe_common::ChatCompletionResponse = "application/json"),
+            (chatsafe_common::ChatCompletionChunk = "text/event-stream")
+        )),
+        (status = 202, description = "Non-streaming request sent with `Prefer: respond-async` that has to wait for a slot; poll `Location` for the result", body = deferred::DeferredCompletion, headers(("Location" = String))),
+        (status = "default", description = "Error", body = chatsafe_common::OpenAiErrorResponse)
+    )
+)]
 async fn chat_completion(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
     Json(request): Json<ChatCompletionRequest>,
 ) -> Result<Response, Response> {
     let ip = addr.ip();
 
-    // Generate request ID for tracing
-    let request_id = RequestId::new();
+    // The deadline starts at the edge so every later stage shares one budget
+    let deadline = Instant::now() + request_budget(&headers, state.request_timeout);
 
     // Start tracking this request early for all paths
     let is_streaming = request.stream.unwrap_or(true);
@@ -319,6 +1092,9 @@ async fn chat_completion(
         .model
         .clone()
         .unwrap_or_else(|| String::from(DEFAULT_MODEL_NAME));
+    let span = tracing::Span::current();
+    span.record("model", model_name.as_str());
+    span.record("stream", is_streaming);
     let tracked_request_id = state
         .metrics
         .start_request(request_id.clone(), model_name.clone(), is_streaming)
@@ -340,33 +1116,64 @@ async fn chat_completion(
     let mut rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
 
     // Validate request
-    if let Err(e) = request.validate() {
-        state.metrics.record_error(Some(&request_id), &e).await;
-        state.metrics.complete_request(&tracked_request_id).await;
+    let validation = request.validate().and_then(|()| {
+        if request.prompt_override.is_some() && !state.allow_prompt_override {
+            return Err(CommonError::BadRequest(
+                "prompt_override is disabled; set server.allow_prompt_override to use it".into(),
+            ));
+        }
+        let profile = state
+            .content_profiles
+            .select(request.content_profile.as_deref())?;
+        let language = request
+            .language
+            .as_ref()
+            .map(TargetLanguage::new)
+            .transpose()?;
+        Ok((profile, language))
+    });
+    let (profile, language) = match validation {
+        Ok(checks) => checks,
+        Err(e) => {
+            state.metrics.record_error(Some(&request_id), &e).await;
+            state.metrics.complete_request(&tracked_request_id).await;
+
+            return Err(create_error_response(
+                &e,
+                &request_id,
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
 
-        return Err(create_error_response(
-            &e,
-            &request_id,
-            StatusCode::BAD_REQUEST,
-        ));
+    if let Some(recorder) = &state.replay_recorder {
+        recorder.record(&request);
     }
 
-    // Get model handle
-    let handle = state.model_handle.read().await.clone().ok_or_else(|| {
-        let err = CommonError::RuntimeNotReady;
-        let response = create_error_response(&err, &request_id, StatusCode::SERVICE_UNAVAILABLE);
+    // Get the handle of the model the request asked for
+    let handle = serving_handle(&state, request.model.as_deref())
+        .await
+        .map_err(|err| {
+            let status =
+                StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            let response = create_error_response(&err, &request_id, status);
 
-        // Record error and complete request tracking
-        let metrics = Arc::clone(&state.metrics);
-        let req_id = request_id.clone();
-        let tracked_id = tracked_request_id.clone();
-        tokio::spawn(async move {
-            metrics.record_error(Some(&req_id), &err).await;
-            metrics.complete_request(&tracked_id).await;
-        });
+            // Record error and complete request tracking
+            let metrics = Arc::clone(&state.metrics);
+            let req_id = request_id.clone();
+            let tracked_id = tracked_request_id.clone();
+            supervisor::spawn(
+                Arc::clone(&metrics),
+                tracked_id.clone(),
+                "error bookkeeping",
+                async move {
+                    metrics.record_error(Some(&req_id), &err).await;
+                    metrics.complete_request(&tracked_id).await;
+                },
+            );
 
-        response
-    })?;
+            response
+        })?;
 
     // Get model config and create params
     let model_id = &handle.model_id;
@@ -388,19 +1195,97 @@ async fn chat_completion(
             let metrics = Arc::clone(&state.metrics);
             let req_id = request_id.clone();
             let tracked_id = tracked_request_id.clone();
-            tokio::spawn(async move {
-                metrics.record_error(Some(&req_id), &e).await;
-                metrics.complete_request(&tracked_id).await;
-            });
+            supervisor::spawn(
+                Arc::clone(&metrics),
+                tracked_id.clone(),
+                "error bookkeeping",
+                async move {
+                    metrics.record_error(Some(&req_id), &e).await;
+                    metrics.complete_request(&tracked_id).await;
+                },
+            );
 
             response
         })?;
 
     // Add request ID to params for tracing
     params.request_id = request_id.to_string();
+    params.deadline = Some(deadline);
+    params.sampler = request.sampler.or(params.sampler);
+    params.cache_prompt = request.cache.unwrap_or(true);
+    params.stream_boundary = state.stream_boundary;
+    params.finish = request.finish.unwrap_or_default();
+    params.prompt_override = request.prompt_override;
+    params.tools = request.tools.unwrap_or_default();
+    params.tool_choice = request.tool_choice;
+    params.response_format = request.response_format;
+    params.grammar = request.grammar;
+    params.top_logprobs = request
+        .logprobs
+        .unwrap_or(false)
+        .then(|| request.top_logprobs.unwrap_or(0));
+    params.prompt_variables = prompt_variables(&state, model_id);
+    params.sanitize_markup = profile.as_ref().is_some_and(|p| p.sanitize_markup);
+    if let Some(stop) = &request.stop {
+        params.add_stop_sequences(stop);
+    }
 
     // Convert messages
-    let messages: Vec<Message> = request.messages;
+    let mut messages: Vec<Message> = request.messages;
+    if state.tool_output_max_tokens > 0 {
+        cap_tool_outputs(
+            &mut messages,
+            state.tool_output_max_tokens,
+            state.tool_output_tail_tokens,
+        );
+    }
+    let default_prompt =
+        system_prompt::template_default(&state, model_id, &params.prompt_variables);
+    date_context::apply(
+        &mut messages,
+        request.date_context.as_ref(),
+        state.date_context,
+        &state.locale,
+        &default_prompt,
+    );
+    if let Some(profile) = &profile {
+        content_profile::apply(&mut messages, profile, &default_prompt);
+    }
+    if let Some(language) = &language {
+        language.apply(&mut messages, &default_prompt);
+    }
+    if let Some(last) = messages.last() {
+        content_log::log_excerpt("prompt", &last.content);
+    }
+    prompt_injection::record(&state.metrics, &messages).await;
+
+    let prompt_verdict = match &state.guardrail {
+        Some(guardrail) => {
+            let policy = guardrail.policy_for(profile.as_deref());
+            guardrail.check_prompt(&messages, &policy).await
+        }
+        None => Ok(None),
+    };
+    let prompt_verdict = prompt_verdict.map_err(|e| {
+        let status = StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::BAD_REQUEST);
+        let response = create_error_response(&e, &request_id, status);
+
+        // Record error and complete request
+        let metrics = Arc::clone(&state.metrics);
+        let req_id = request_id.clone();
+        let tracked_id = tracked_request_id.clone();
+        supervisor::spawn(
+            Arc::clone(&metrics),
+            tracked_id.clone(),
+            "error bookkeeping",
+            async move {
+                metrics.record_error(Some(&req_id), &e).await;
+                metrics.complete_request(&tracked_id).await;
+            },
+        );
+
+        response
+    })?;
 
     if is_streaming {
         let result = handle_streaming(
@@ -408,6 +1293,7 @@ async fn chat_completion(
             &handle,
             messages,
             params,
+            profile,
             &request_id,
             &tracked_request_id,
             ip,
@@ -416,13 +1302,73 @@ async fn chat_completion(
         if result.is_ok() {
             rate_guard.disarm();
         }
-        result
+        result.map(|mut response| {
+            if let Some(verdict) = &prompt_verdict {
+                guardrail::add_verdict_header(&mut response, verdict);
+            }
+            response
+        })
     } else {
+        let slot = state
+            .chat_slots
+            .for_model(&handle.model_id)
+            .try_acquire(params.max_tokens);
+        if let Err(ticket) = &slot {
+            if deferred::prefers_async(&headers) {
+                // Answer now and finish in the background, holding the
+                // rate-limit slot until the completion is done
+                let accepted = state.deferred.accept(
+                    &request_id,
+                    ticket.place().clone(),
+                    state.metrics.recent_tokens_per_second().await,
+                );
+                let task_state = state.clone();
+                let started = Instant::now();
+                supervisor::spawn(
+                    Arc::clone(&state.metrics),
+                    tracked_request_id.clone(),
+                    "deferred completion",
+                    async move {
+                        let state = task_state;
+                        let result = handle_non_streaming(
+                            &state,
+                            &handle,
+                            messages,
+                            params,
+                            slot,
+                            prompt_verdict,
+                            profile,
+                            language,
+                            &request_id,
+                            &tracked_request_id,
+                            ip,
+                        )
+                        .await;
+                        if result.is_ok() {
+                            rate_guard.disarm();
+                        } else {
+                            rate_guard.release_now().await;
+                        }
+                        let response = result.unwrap_or_else(|response| response);
+                        let event = state
+                            .deferred
+                            .finish(&request_id, response, started.elapsed())
+                            .await;
+                        state.webhooks.notify(event, None);
+                    },
+                );
+                return Ok(accepted);
+            }
+        }
         let result = handle_non_streaming(
             &state,
             &handle,
             messages,
             params,
+            slot,
+            prompt_verdict,
+            profile,
+            language,
             &request_id,
             &tracked_request_id,
             ip,
@@ -437,26 +1383,136 @@ async fn chat_completion(
     }
 }
 
-async fn version() -> Json<serde_json::Value> {
+/// The loaded model a request names, directly or through an alias
+///
+/// Requests without `model`, or naming one outside the registry (e.g. an
+/// OpenAI model ID), get the default model; a registry model that is not
+/// served is rejected.
+async fn serving_handle(
+    state: &AppState,
+    requested: Option<&str>,
+) -> Result<ModelHandle, CommonError> {
+    let default = state
+        .model_handle
+        .read()
+        .await
+        .clone()
+        .ok_or(CommonError::RuntimeNotReady)?;
+    let Some(requested) = requested else {
+        return Ok(default);
+    };
+    let model_id = state.aliases.resolve(requested);
+    let routed = state.aliases.route(requested);
+    if *default.model_id == *routed {
+        return Ok(default);
+    }
+    let served = state.runtime.handles().await;
+    // A canary that is not served leaves its share on the alias's model
+    for model_id in [&routed, &model_id] {
+        if *default.model_id == **model_id {
+            return Ok(default);
+        }
+        if let Some(handle) = served.iter().find(|h| *h.model_id == **model_id) {
+            return Ok(handle.clone());
+        }
+    }
+    // Names outside the registry, such as OpenAI's, get the default model
+    if state.registry.get_model(&model_id).is_err() {
+        return Ok(default);
+    }
+    let serving: Vec<&str> = served.iter().map(|h| &*h.model_id).collect();
+    Err(CommonError::BadRequest(format!(
+        "Model {} is not loaded; add it to models.serve or use one of: {}",
+        model_id,
+        serving.join(", ")
+    )))
+}
+
+/// Values for `{{date}}`, `{{model_name}}` and `{{profile.<key>}}` in the
+/// template's default system prompt
+fn prompt_variables(state: &AppState, model_id: &str) -> HashMap<String, String> {
+    let model_name = state
+        .registry
+        .get_model(model_id)
+        .map(|model| model.name.clone())
+        .unwrap_or_else(|_| model_id.to_string());
+    let mut variables = HashMap::from([
+        ("date".to_string(), date_context::local_date()),
+        ("model_name".to_string(), model_name),
+    ]);
+    for (key, value) in state.profile.iter() {
+        variables.insert(format!("profile.{}", key), value.clone());
+    }
+    variables
+}
+
+/// Cut oversized tool results down to their start and end, so one huge
+/// result can't crowd the conversation out of the context window
+fn cap_tool_outputs(messages: &mut [Message], max_tokens: usize, tail_tokens: usize) {
+    for message in messages.iter_mut().filter(|m| m.role == Role::Tool) {
+        let capped =
+            match text::truncate_middle_tokens(&message.content, max_tokens, tail_tokens, None) {
+                Cow::Borrowed(_) => continue,
+                Cow::Owned(capped) => capped,
+            };
+        debug!(
+            "Capped tool output from {} to {} bytes",
+            message.content.len(),
+            capped.len()
+        );
+        message.content = capped;
+    }
+}
+
+async fn version(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let features: Vec<&str> = env!("CHATSAFE_FEATURES")
+        .split(',')
+        .filter(|f| !f.is_empty())
+        .collect();
+    let models_loaded: Vec<String> = state
+        .runtime
+        .handles()
+        .await
+        .iter()
+        .map(|handle| handle.model_id.to_string())
+        .collect();
+
     Json(json!({
         "version": API_VERSION,
         "api": "ChatSafe Local API",
-        "model_api": "OpenAI Compatible"
+        "model_api": "OpenAI Compatible",
+        "build": {
+            "git_commit": env!("CHATSAFE_GIT_COMMIT"),
+            "build_date": env!("CHATSAFE_BUILD_DATE"),
+            "rustc": env!("CHATSAFE_RUSTC_VERSION"),
+            "features": features
+        },
+        "backend": {
+            "llama_server": state.runtime.backend_version().await
+        },
+        "models_loaded": models_loaded
     }))
 }
 
 async fn get_models(State(state): State<AppState>) -> Json<serde_json::Value> {
     let models = state.registry.list_models();
+    let loaded = state.runtime.handles().await;
     let model_info: Vec<serde_json::Value> = models
         .iter()
         .map(|id| {
             if let Ok(model) = state.registry.get_model(id) {
-                json!({
+                let mut info = json!({
                     "id": model.id,
                     "name": model.name,
                     "context_window": model.ctx_window,
+                    "capability": model.capability,
                     "default": model.default
-                })
+                });
+                // An adaptive retry may have loaded less context than configured
+                if let Some(handle) = loaded.iter().find(|h| *h.model_id == *model.id) {
+                    info["loaded_context_window"] = json!(handle.context_size);
+                }
+                info
             } else {
                 json!({"id": id})
             }
@@ -464,78 +1520,619 @@ async fn get_models(State(state): State<AppState>) -> Json<serde_json::Value> {
         .collect();
 
     Json(json!({
-        "models": model_info
+        "models": model_info,
+        "aliases": state.aliases.snapshot()
+    }))
+}
+
+/// Token usage per model and per UTC day since the server started
+async fn usage_summary(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let since = state
+        .start_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Json(json!({
+        "since": since,
+        "usage": state.metrics.usage_summary().await,
+    }))
+}
+
+/// Wipe backend caches and in-memory request-derived data
+async fn admin_flush(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let slots_erased = state.runtime.flush_caches().await.map_err(|e| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        create_error_response(&e, &request_id, status)
+    })?;
+    state.metrics.clear_sensitive().await;
+    let deferred_cleared = state.deferred.clear();
+
+    warn!(
+        "Flushed backend caches ({} slots) and {} deferred responses",
+        slots_erased, deferred_cleared
+    );
+    Ok(Json(json!({
+        "flushed": true,
+        "slots_erased": slots_erased,
+        "deferred_responses_cleared": deferred_cleared
+    })))
+}
+
+#[derive(serde::Deserialize)]
+struct LogLevelUpdate {
+    /// `EnvFilter` directives replacing the current ones, e.g.
+    /// `info,chatsafe_runtime=debug`
+    directives: Option<String>,
+    /// Characters of redacted prompt/response to log; 0 turns it off
+    content_excerpt_chars: Option<usize>,
+}
+
+/// Current logging settings
+async fn get_log_level(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "directives": state.log_level.directives(),
+        "content_excerpt_chars": content_log::excerpt_chars()
     }))
 }
 
+/// Change logging settings without a restart
+async fn put_log_level(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Json(update): Json<LogLevelUpdate>,
+) -> Result<Json<serde_json::Value>, Response> {
+    if let Some(directives) = &update.directives {
+        state.log_level.set(directives).map_err(|message| {
+            create_error_response(
+                &CommonError::BadRequest(message),
+                &request_id,
+                StatusCode::BAD_REQUEST,
+            )
+        })?;
+        warn!("Log filter set to {}", state.log_level.directives());
+    }
+    if let Some(chars) = update.content_excerpt_chars {
+        content_log::set_excerpt_chars(chars);
+        warn!("Redacted content excerpts set to {} characters", chars);
+    }
+    Ok(get_log_level(State(state)).await)
+}
+
+/// Current alias targets
+async fn get_aliases(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "aliases": state.aliases.snapshot(),
+        "canaries": state.aliases.canaries()
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct AliasUpdate {
+    /// Model ID the alias should point at
+    model: String,
+    /// Second model taking a share of the alias's requests; none stops it
+    #[serde(default)]
+    canary: Option<CanaryUpdate>,
+}
+
+#[derive(serde::Deserialize)]
+struct CanaryUpdate {
+    model: String,
+    percent: u8,
+}
+
+/// Repoint (or create) an alias in one step
+///
+/// The new target must already be loaded, so the alias keeps answering on
+/// its old model until the new one can take over.
+async fn put_alias(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(alias): Path<String>,
+    Json(update): Json<AliasUpdate>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let bad_request = |e: CommonError| {
+        create_error_response(
+            &CommonError::BadRequest(e.to_string()),
+            &request_id,
+            StatusCode::BAD_REQUEST,
+        )
+    };
+    let canary = update
+        .canary
+        .as_ref()
+        .map(|canary| (canary.model.as_str(), canary.percent));
+    // Nothing changes unless both the target and the canary are valid
+    let model_id = state
+        .registry
+        .check_alias(&alias, &update.model)
+        .map_err(bad_request)?;
+    let served = state.runtime.handles().await;
+    if !served.iter().any(|handle| *handle.model_id == *model_id) {
+        let e = CommonError::BadRequest(format!(
+            "Model {} is not loaded; load it with POST /admin/models/{}/load before pointing {} at it",
+            model_id, model_id, alias
+        ));
+        return Err(create_error_response(
+            &e,
+            &request_id,
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    state
+        .aliases
+        .set_canary(&state.registry, &alias, canary)
+        .map_err(bad_request)?;
+    let previous = state
+        .aliases
+        .repoint(&state.registry, &alias, &update.model)
+        .map_err(bad_request)?;
+    warn!(
+        "Alias {} now points at {} (was {:?})",
+        alias, model_id, previous
+    );
+    if let Some((model, percent)) = canary {
+        warn!(
+            "Alias {} sends {}% of requests to {}",
+            alias, percent, model
+        );
+    }
+    Ok(Json(json!({
+        "alias": alias,
+        "model": model_id,
+        "previous": previous,
+        "canary": state.aliases.canaries().get(&alias)
+    })))
+}
+
+/// Backend state, recent llama-server output and recent errors for troubleshooting
+async fn admin_diagnostics(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let runtime = state.runtime.diagnostics().await;
+    let recent_errors: Vec<_> = state
+        .metrics
+        .recent_errors()
+        .await
+        .into_iter()
+        .map(|(age_seconds, category, message)| {
+            json!({
+                "age_seconds": age_seconds,
+                "category": category,
+                "message": message
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "runtime": runtime,
+        "concurrency": {
+            "chat": state.chat_slots.stats(),
+            "embeddings": state.embedding_slots.stats()
+        },
+        "recent_errors": recent_errors
+    }))
+}
+
+/// `GET /admin/detections`: recent guard, pollution and injection detections
+async fn get_detections(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let snapshot = state.metrics.snapshot().await;
+    Json(json!({
+        "by_source": snapshot.safety_detections,
+        "recent": state.metrics.recent_detections().await
+    }))
+}
+
+/// `GET /admin/rate-limits`: which limit is turning requests away
+async fn get_rate_limits(State(state): State<AppState>) -> Json<rate_limiter::RateLimitReport> {
+    Json(state.rate_limiter.report().await)
+}
+
 async fn get_metrics(State(state): State<AppState>) -> Json<ObservableMetricsSnapshot> {
-    Json(state.metrics.snapshot().await)
+    let mut snapshot = state.metrics.snapshot().await;
+    snapshot.backend_pool = state.runtime.pool_stats().await;
+    snapshot.rate_limits = Some(state.rate_limiter.stats().await);
+    Json(snapshot)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .init();
+    // Initialize tracing; the filter applies to log output only so the
+    // console layer still sees tokio's task instrumentation
+    let (log_filter, log_level) = log_level::LogLevelControl::from_env();
+    let fmt_layer = tracing_subscriber::fmt::layer().with_filter(log_filter);
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
 
     info!("Starting ChatSafe local API server");
 
-    // Load configuration
-    let config = ConfigLoader::load(None)?;
+    // Load configuration and model registry; parse errors start safe mode
+    let safe_mode::Startup {
+        config,
+        registry,
+        errors: startup_errors,
+    } = safe_mode::load(None)?;
+
+    content_log::set_excerpt_chars(config.server.debug_excerpt_chars);
+    if config.server.debug_excerpt_chars > 0 {
+        warn!(
+            "Logging redacted prompt/response excerpts of {} characters",
+            config.server.debug_excerpt_chars
+        );
+    }
+
+    match Locale::parse(&config.server.locale) {
+        Some(locale) => chatsafe_common::i18n::set_locale(locale),
+        None => warn!(
+            "Unsupported locale {:?}, using English error messages",
+            config.server.locale
+        ),
+    }
+
+    // Refuse to run beside another instance, or replace it with --takeover
+    let takeover = std::env::args().skip(1).any(|arg| arg == TAKEOVER_FLAG);
+    let listen_addresses = config.server.listen_addresses()?;
+    let addresses: Vec<_> = listen_addresses
+        .iter()
+        .map(|(address, _)| address.clone())
+        .collect();
+    let instance =
+        instance_lock::InstanceLock::acquire(&config.state_dir(), &addresses, takeover).await?;
+
+    if let Some(legacy) = paths::legacy_data_dir() {
+        if paths::needs_migration(&legacy, &config.data_dir) {
+            warn!(
+                "Found data from an older version in {}; run `chatsafe data migrate` to move it to {}",
+                legacy.display(),
+                config.data_dir.display()
+            );
+        }
+    }
+
+    // Check installed models against their GGUF metadata (cached between runs)
+    match registry.load_metadata() {
+        Ok(metadata) => {
+            for (id, meta) in &metadata {
+                let Ok(model) = registry.get_model(id) else {
+                    continue;
+                };
+                if let Some(trained_ctx) = meta.context_length {
+                    if model.ctx_window as u64 > trained_ctx {
+                        warn!(
+                            "Model {} configures ctx_window {} but was trained with {}",
+                            id, model.ctx_window, trained_ctx
+                        );
+                    }
+                }
+            }
+        }
+        Err(e) => warn!("Failed to read model metadata: {}", e),
+    }
 
-    // Load model registry
-    let registry = ModelRegistry::load_defaults()?;
+    if let Some(hardware) = HardwareProfile::detect() {
+        info!(
+            "Detected {} ({} performance + {} efficiency cores, {:.0} GB); hardware tuning {}",
+            hardware.chip,
+            hardware.performance_cores,
+            hardware.efficiency_cores,
+            hardware.memory_gb,
+            if config.runtime.hardware_tuning {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
 
     // Create runtime
     let runtime = ModelRuntime::create(&config, &registry).await?;
 
-    // Load default model
-    let default_model = registry.get_default_model()?;
-    info!("Loading default model: {}", default_model.id);
+    // Load default model; the configured one may be pinned through an alias.
+    // Safe mode starts without one so a fallback config never picks a model.
+    let model_handle = if startup_errors.is_empty() {
+        let default_model =
+            registry.get_model(registry.resolve_alias(&config.models.default_model))?;
+        info!("Loading default model: {}", default_model.id);
+        let handle = runtime.load(&default_model.id).await?;
+        for served in &config.models.serve {
+            let model_id = registry.resolve_alias(&served.model);
+            info!("Loading {} on port {}", model_id, served.instance.port);
+            runtime.load(model_id).await?;
+        }
+        Some(handle)
+    } else {
+        warn!("Safe mode: no model loaded until POST /admin/reload succeeds");
+        None
+    };
 
-    let model_handle = runtime.load(&default_model.id).await?;
+    let transcriber = ModelRuntime::create_transcriber(&config, &registry)?;
+    if let Some(transcriber) = &transcriber {
+        info!(
+            "Transcription model: {} (starts on first use)",
+            transcriber.model_id()
+        );
+    }
+    let embedder = ModelRuntime::create_embedder(&config, &registry)?;
+    if let Some(embedder) = &embedder {
+        info!(
+            "Embedding model: {} (starts on first use)",
+            embedder.model_id()
+        );
+    }
+    let guard = ModelRuntime::create_guard(&config, &registry)?;
+    if let Some(guard) = &guard {
+        info!("Guard model: {} (starts on first use)", guard.model_id());
+    }
+    let content_profiles = content_profile::ContentProfiles::new(&config.server)?;
+    if guard.is_none() && !config.server.content_profiles.is_empty() {
+        warn!("Content profiles only change the system prompt without a guard model");
+    }
+    #[cfg(feature = "images")]
+    let image_generator = ModelRuntime::create_image_generator(&config, &registry)?;
+    #[cfg(feature = "images")]
+    if let Some(generator) = &image_generator {
+        info!("Image model: {}", generator.model_id());
+    }
+    let synthesizer = PiperAdapter::from_registry(&registry, &config.runtime)?;
+    if let Some(synthesizer) = &synthesizer {
+        info!(
+            "Speech voices: {}",
+            synthesizer.voices().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let trusted_proxies = Arc::new(client_ip::TrustedProxies::parse(
+        &config.server.trusted_proxies,
+    )?);
+    if !trusted_proxies.is_empty() {
+        info!(
+            "Taking client addresses from forwarding headers sent by {}",
+            config.server.trusted_proxies.join(", ")
+        );
+    }
+
+    let webhooks =
+        webhooks::Notifier::new(&config.server.webhooks, config.server.allow_remote_webhooks)?;
 
     // Create rate limiter
     let rate_limiter = RateLimiter::new(RateLimiterConfig::default());
 
+    let metrics = Arc::new(
+        ObservableMetrics::new().with_slow_thresholds(SlowRequestThresholds {
+            first_token_ms: config.server.slow_first_token_ms,
+            total_ms: config.server.slow_request_ms,
+        }),
+    );
+
+    let replay_recorder = match config.replay_log_path() {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let recorder = ReplayRecorder::open(&path, config.server.replay_include_content)?;
+            if config.server.replay_include_content {
+                warn!(
+                    "Recording requests WITH message content to {}",
+                    path.display()
+                );
+            } else {
+                info!("Recording sanitized requests to {}", path.display());
+            }
+            Some(Arc::new(recorder))
+        }
+        None => None,
+    };
+
     // Create app state
+    let chat_slots = concurrency::ChatLimits::new(&config, &registry);
     let state = AppState {
         runtime,
+        aliases: Arc::new(aliases::AliasTable::from_registry(&registry)),
         registry: Arc::new(registry),
-        model_handle: Arc::new(RwLock::new(Some(model_handle))),
+        model_handle: Arc::new(RwLock::new(model_handle)),
         start_time: SystemTime::now(),
-        metrics: Arc::new(ObservableMetrics::new()),
-        rate_limiter,
+        metrics: Arc::clone(&metrics),
+        rate_limiter: rate_limiter.clone(),
+        chat_slots,
+        embedding_slots: concurrency::ConcurrencyLimit::new(
+            "embeddings",
+            config.runtime.parallel_slots,
+        ),
+        request_timeout: Duration::from_secs(config.server.request_timeout_secs),
+        replay_recorder,
+        stream_boundary: config.server.stream_boundary,
+        tool_output_max_tokens: config.server.tool_output_max_tokens,
+        tool_output_tail_tokens: config.server.tool_output_tail_tokens,
+        allow_prompt_override: config.server.allow_prompt_override,
+        date_context: config.server.date_context,
+        locale: config.server.locale.clone(),
+        profile: Arc::new(config.server.profile.clone()),
+        transcriber: transcriber.map(|t| Arc::new(Mutex::new(t))),
+        embedder: embedder.map(|e| Arc::new(RwLock::new(e))),
+        content_profiles: Arc::new(content_profiles),
+        guardrail: guard.map(|guard| {
+            Arc::new(guardrail::Guardrail::new(
+                guard,
+                config.server.guardrail.clone(),
+                Arc::clone(&metrics),
+            ))
+        }),
+        synthesizer: synthesizer.map(Arc::new),
+        #[cfg(feature = "images")]
+        image_generator: image_generator.map(|g| Arc::new(Mutex::new(g))),
+        log_level,
+        webhooks,
+        deferred: Arc::default(),
+        startup_errors: Arc::new(startup_errors),
     };
 
+    let runtime_handle = state.runtime.clone();
+    let embedder = state.embedder.clone();
+    let guardrail = state.guardrail.clone();
+
     // Build router with tracing layer
     let app = Router::new()
-        .route("/v1/chat/completions", post(chat_completion))
+        .route("/", get(discovery::index))
+        .merge(openapi::swagger_ui())
+        .route("/v1/chat/completions", chat_completion_route())
+        .route(
+            "/v1/chat/completions/{id}",
+            get(deferred::completion_status),
+        )
+        .route(
+            "/v1/audio/transcriptions",
+            post(transcription::create_transcription)
+                .layer(DefaultBodyLimit::max(transcription::MAX_AUDIO_BYTES)),
+        )
+        .route("/v1/audio/speech", post(speech::create_speech))
+        .route("/v1/embeddings", post(embeddings::create_embeddings))
+        .route("/v1/experiments/sweep", post(sweep::run_sweep))
+        .route("/v1/usage/summary", get(usage_summary))
+        .route("/v1/models", get(models::list_models))
+        .route("/v1/models/{id}", get(models::get_model))
         .route("/healthz", get(health_check))
         .route("/health", get(health_check))
+        .route("/readyz", get(safe_mode::readyz))
         .route("/version", get(version))
         .route("/metrics", get(get_metrics))
         .route("/models", get(get_models))
+        .route("/admin/flush", post(admin_flush))
+        .route("/admin/diagnostics", get(admin_diagnostics))
+        .route("/admin/reload", post(safe_mode::reload))
+        .route("/admin/rate-limits", get(get_rate_limits))
+        .route("/admin/detections", get(get_detections))
+        .route("/admin/log-level", get(get_log_level).put(put_log_level))
+        .route("/admin/aliases", get(get_aliases))
+        .route("/admin/aliases/{alias}", axum::routing::put(put_alias))
+        .route("/admin/models/{id}/load", post(model_switch::load_model))
+        .route(
+            "/admin/models/{id}/unload",
+            post(model_switch::unload_model),
+        )
+        .route("/admin/models/{id}/loras", get(loras::get_loras))
+        .route(
+            "/admin/models/{id}/loras/{index}",
+            axum::routing::put(loras::put_lora),
+        );
+    #[cfg(feature = "pprof")]
+    let app = app.route("/admin/pprof", get(profiling::pprof_profile));
+    #[cfg(feature = "images")]
+    let app = app.route("/v1/images/generations", post(images::create_image));
+    let app = app
+        .method_not_allowed_fallback(discovery::method_not_allowed)
+        .fallback(discovery::not_found)
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&metrics),
+            supervisor::supervise_request,
+        ))
+        .layer(middleware::from_fn_with_state(
+            metrics,
+            http_metrics::track_http_metrics,
+        ))
+        .layer(middleware::from_fn_with_state(
+            rate_limiter,
+            openai_headers::openai_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            trusted_proxies,
+            client_ip::resolve_client_ip,
+        ))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
     // Start server
-    let addr = SocketAddr::from(([127, 0, 0, 1], config.server.port));
-    info!("Listening on http://{} (localhost only)", addr);
-
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-
-    // Use into_make_service_with_connect_info to get client IP addresses
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await?;
+    let settings = server::ConnectionSettings::from_config(&config.server);
+    info!(
+        "Connections: max {:?}, keep-alive {} (idle timeout {:?}), TCP_NODELAY {}",
+        settings.max_connections,
+        settings.keep_alive,
+        settings.keep_alive_timeout,
+        settings.tcp_nodelay
+    );
+    let slots = settings.connection_slots();
+    let (stop, stopping) = tokio::sync::watch::channel(false);
+    let stop_signal = || {
+        let mut stopping = stopping.clone();
+        async move {
+            let _ = stopping.wait_for(|stop| *stop).await;
+        }
+    };
+    let mut servers = Vec::new();
+    for (address, token) in listen_addresses {
+        let auth = if token.is_some() {
+            "token required"
+        } else {
+            "no auth"
+        };
+        let mut listener_app = server::require_token(app.clone(), token.clone());
+        // Outermost, so auth failures and handler panics are converted too
+        if config.server.openai_errors {
+            listener_app =
+                listener_app.layer(middleware::from_fn(openai_errors::openai_error_format));
+        }
+        match &address {
+            ListenAddress::Tcp(addr) => {
+                if !addr.ip().is_loopback() && token.is_none() {
+                    warn!(
+                        "Listening on non-loopback {} without an auth_token; other machines can use the API",
+                        addr
+                    );
+                }
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                servers.push(tokio::spawn(server::serve(
+                    listener,
+                    listener_app,
+                    settings.clone(),
+                    slots.clone(),
+                    stop_signal(),
+                )));
+            }
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                let listener = server::bind_unix(path)?;
+                servers.push(tokio::spawn(server::serve(
+                    listener,
+                    listener_app,
+                    settings.clone(),
+                    slots.clone(),
+                    stop_signal(),
+                )));
+            }
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => {
+                anyhow::bail!("Unix socket listeners are not supported on this platform")
+            }
+        }
+        info!("Listening on {} ({})", address, auth);
+    }
 
+    let servers = futures::future::join_all(servers);
+    tokio::pin!(servers);
+    tokio::select! {
+        _ = &mut servers => {}
+        _ = instance.shutdown_requested() => {
+            info!("Shutting down for the instance taking over");
+            // Finish in-flight requests before their backends go away
+            let _ = stop.send(true);
+            servers.await;
+            if let Err(e) = runtime_handle.shutdown().await {
+                warn!("Backend shutdown failed: {}", e);
+            }
+            if let Some(embedder) = embedder {
+                if let Err(e) = embedder.write().await.shutdown().await {
+                    warn!("Embedding backend shutdown failed: {}", e);
+                }
+            }
+            if let Some(guardrail) = guardrail {
+                if let Err(e) = guardrail.shutdown().await {
+                    warn!("Guard backend shutdown failed: {}", e);
+                }
+            }
+        }
+    }
     Ok(())
 }
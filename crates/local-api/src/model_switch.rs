@@ -0,0 +1,178 @@
+//! Loading and unloading models without a restart
+//!
+//! `POST /admin/models/{id}/load` loads a registry chat model. A model in
+//! `models.serve` (or the default) is started on its own port again; any
+//! other model replaces the default one, whose server is stopped first, so
+//! generations still running on it end with an error. The response is an
+//! event stream reporting progress about once a second until the model has
+//! loaded or failed; the load finishes even if the client goes away.
+//!
+//! `POST /admin/models/{id}/unload` stops a model's server to free its
+//! memory. Without the default model, chat completions answer 503 until one
+//! is loaded.
+
+use crate::{create_error_response, supervisor, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, ErrorResponse, RequestId};
+use chatsafe_config::Capability;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+// Constants
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `POST /admin/models/{id}/load`
+pub(crate) async fn load_model(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<String>,
+) -> Response {
+    let model_id = match chat_model(&state, &id) {
+        Ok(model_id) => model_id,
+        Err(e) => return error_response(&e, &request_id),
+    };
+
+    // The load runs on its own so a dropped connection cannot interrupt a switch
+    let (done_tx, mut done_rx) = oneshot::channel();
+    let task_state = state.clone();
+    let task_model = model_id.clone();
+    supervisor::spawn(
+        Arc::clone(&state.metrics),
+        request_id.clone(),
+        "model load",
+        async move {
+            let _ = done_tx.send(load(&task_state, &task_model).await);
+        },
+    );
+
+    let started = Instant::now();
+    let events = async_stream::stream! {
+        let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+        let result = loop {
+            tokio::select! {
+                result = &mut done_rx => break result.unwrap_or_else(|_| {
+                    Err(CommonError::Internal("model load stopped unexpectedly".into()))
+                }),
+                _ = progress.tick() => yield event(json!({
+                    "status": "loading",
+                    "model": model_id,
+                    "elapsed_ms": started.elapsed().as_millis() as u64
+                })),
+            }
+        };
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        yield event(match result {
+            Ok(loaded) => json!({
+                "status": "loaded",
+                "model": model_id,
+                "elapsed_ms": elapsed_ms,
+                "context_window": loaded.context_window,
+                "default": loaded.default
+            }),
+            Err(e) => {
+                let mut error = ErrorResponse::from(&e);
+                error.request_id = Some(request_id.to_string());
+                json!({
+                    "status": "failed",
+                    "model": model_id,
+                    "elapsed_ms": elapsed_ms,
+                    "error": error
+                })
+            }
+        });
+    };
+    Sse::new(events).into_response()
+}
+
+/// `POST /admin/models/{id}/unload`
+pub(crate) async fn unload_model(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let model_id = chat_model(&state, &id).map_err(|e| error_response(&e, &request_id))?;
+    state
+        .runtime
+        .unload_model(&model_id)
+        .await
+        .map_err(|e| error_response(&e, &request_id))?;
+
+    let mut default = state.model_handle.write().await;
+    let was_default = default
+        .as_ref()
+        .is_some_and(|handle| *handle.model_id == *model_id);
+    if was_default {
+        *default = None;
+    }
+    warn!("Unloaded model {}", model_id);
+    Ok(Json(json!({
+        "model": model_id,
+        "unloaded": true,
+        "default": was_default
+    })))
+}
+
+/// What a finished load reports
+struct Loaded {
+    context_window: usize,
+    /// Whether the model now answers requests without a `model`
+    default: bool,
+}
+
+async fn load(state: &AppState, model_id: &str) -> Result<Loaded, CommonError> {
+    info!("Loading model {} on request", model_id);
+    let handle = state.runtime.load(model_id).await?;
+    let is_default = state
+        .runtime
+        .get_handle()
+        .await
+        .is_some_and(|default| default == handle);
+    if is_default {
+        *state.model_handle.write().await = Some(handle.clone());
+    }
+    info!("Model {} loaded", model_id);
+    Ok(Loaded {
+        context_window: handle.context_size,
+        default: is_default,
+    })
+}
+
+/// Resolve `id` to a registry chat model, refusing in safe mode
+pub(crate) fn chat_model(state: &AppState, id: &str) -> Result<String, CommonError> {
+    if !state.startup_errors.is_empty() {
+        return Err(CommonError::ServiceUnavailable(
+            "In safe mode; fix the config and POST /admin/reload first".into(),
+        ));
+    }
+    let model_id = state.aliases.resolve(id);
+    let model = state.registry.get_model(&model_id)?;
+    if model.capability != Capability::Chat {
+        return Err(CommonError::BadRequest(format!(
+            "{} is not a chat model; it is loaded on first use",
+            model_id
+        )));
+    }
+    Ok(model_id)
+}
+
+fn event(data: Value) -> Result<Event, Infallible> {
+    Ok(Event::default().data(data.to_string()))
+}
+
+pub(crate) fn error_response(error: &CommonError, request_id: &RequestId) -> Response {
+    let status =
+        StatusCode::from_u16(error.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    create_error_response(error, request_id, status)
+}
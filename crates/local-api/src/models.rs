@@ -0,0 +1,93 @@
+//! OpenAI-compatible model listing
+//!
+//! `GET /v1/models` and `GET /v1/models/{id}` return OpenAI's model objects
+//! so SDKs can discover models, extended with what the registry knows:
+//! capability, context window, quantization, template and resources. A
+//! model alias resolves to the model it points at.
+
+use crate::{create_error_response, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Response,
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_config::ModelConfig;
+use chatsafe_runtime::ModelHandle;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Constants
+const OWNED_BY: &str = "chatsafe";
+
+/// `GET /v1/models`
+pub(crate) async fn list_models(State(state): State<AppState>) -> Json<Value> {
+    let loaded = state.runtime.handles().await;
+    let data: Vec<Value> = state
+        .registry
+        .list_models()
+        .iter()
+        .filter_map(|id| state.registry.get_model(id).ok())
+        .map(|model| model_object(model, loaded_handle(&loaded, model), state.start_time))
+        .collect();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+/// `GET /v1/models/{id}`
+pub(crate) async fn get_model(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let model_id = state.aliases.resolve(&id);
+    let model = state.registry.get_model(&model_id).map_err(|_| {
+        let e = CommonError::ModelNotFound(id);
+        create_error_response(&e, &request_id, StatusCode::NOT_FOUND)
+    })?;
+    let loaded = state.runtime.handles().await;
+    Ok(Json(model_object(
+        model,
+        loaded_handle(&loaded, model),
+        state.start_time,
+    )))
+}
+
+fn loaded_handle<'a>(loaded: &'a [ModelHandle], model: &ModelConfig) -> Option<&'a ModelHandle> {
+    loaded.iter().find(|handle| *handle.model_id == *model.id)
+}
+
+/// OpenAI model object plus registry details
+pub(crate) fn model_object(
+    model: &ModelConfig,
+    loaded: Option<&ModelHandle>,
+    start_time: SystemTime,
+) -> Value {
+    let loaded = loaded.filter(|handle| *handle.model_id == *model.id);
+    let created = loaded.map_or(start_time, |handle| handle.loaded_at);
+    let mut object = json!({
+        "id": model.id,
+        "object": "model",
+        "created": created
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        "owned_by": OWNED_BY,
+        "name": model.name,
+        "capability": model.capability,
+        "vision": model.vision,
+        "context_window": model.ctx_window,
+        "quantization": model.metadata.get("quantization"),
+        "resources": model.resources,
+        "default": model.default,
+        "loaded": loaded.is_some()
+    });
+    if !model.template_id.is_empty() {
+        object["template_id"] = json!(model.template_id);
+    }
+    // An adaptive retry may have loaded less context than configured
+    if let Some(handle) = loaded {
+        object["loaded_context_window"] = json!(handle.context_size);
+    }
+    object
+}
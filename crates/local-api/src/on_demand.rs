@@ -0,0 +1,30 @@
+//! Backends started on first use
+//!
+//! The embedding and guard models each run on a llama-server of their own
+//! that is only started when a request first needs it.
+
+use chatsafe_common::Error as CommonError;
+use chatsafe_runtime::{LlamaAdapter, Runtime};
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// Start a first-use llama-server (embedding, guard) unless it is up,
+/// returning its model ID
+pub(crate) async fn ensure_loaded(
+    server: &RwLock<LlamaAdapter>,
+    kind: &str,
+) -> Result<String, CommonError> {
+    {
+        let adapter = server.read().await;
+        if adapter.get_handle().await.is_some() {
+            return Ok(adapter.model_id().to_string());
+        }
+    }
+    let mut adapter = server.write().await;
+    let model_id = adapter.model_id().to_string();
+    if adapter.get_handle().await.is_none() {
+        info!("Starting {} model {}", kind, model_id);
+        adapter.load(&model_id).await?;
+    }
+    Ok(model_id)
+}
@@ -0,0 +1,61 @@
+//! OpenAI-shaped error bodies on `/v1/*`
+//!
+//! OpenAI SDKs read `error.type`, `error.param` and a string `error.code`,
+//! where ChatSafe sends a numeric `code` and a `request_id`. With
+//! `server.openai_errors` on (the default), error responses under `/v1/`
+//! are rewritten to OpenAI's object. Other routes keep ChatSafe's format,
+//! and `x-request-id` stays on every response either way.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chatsafe_common::{ErrorResponse, OpenAiErrorResponse};
+
+// Constants
+pub(crate) const OPENAI_PREFIX: &str = "/v1/";
+/// Largest non-ChatSafe error body read to use as the message
+const MAX_REJECTION_BYTES: usize = 64 * 1024;
+
+/// Middleware rewriting `/v1/*` error responses to OpenAI's format
+pub(crate) async fn openai_error_format(request: Request, next: Next) -> Response {
+    if !request.uri().path().starts_with(OPENAI_PREFIX) {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let error = match parts.extensions.remove::<ErrorResponse>() {
+        Some(error) => OpenAiErrorResponse::from(&error.error),
+        // axum rejections (bad JSON, wrong content type) are plain text
+        None => {
+            let text = axum::body::to_bytes(body, MAX_REJECTION_BYTES)
+                .await
+                .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+                .unwrap_or_default();
+            let message = if text.is_empty() {
+                status.canonical_reason().unwrap_or("Error").to_string()
+            } else {
+                text
+            };
+            OpenAiErrorResponse::from_status(status.as_u16(), message)
+        }
+    };
+
+    let Ok(body) = serde_json::to_vec(&error) else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,99 @@
+//! OpenAI-style rate-limit and timing headers on `/v1/*`
+//!
+//! OpenAI SDKs and the retry helpers around them read `x-ratelimit-*` to
+//! pace requests, `retry-after` / `retry-after-ms` to back off after a 429
+//! and `openai-processing-ms` for timing. Every `/v1/*` response gets them,
+//! filled from the client's bucket in the rate limiter (or the global one,
+//! when that is tighter) and the time spent in the handler. ChatSafe limits
+//! requests, not tokens, so the `-tokens` variants are not sent.
+
+use crate::openai_errors::OPENAI_PREFIX;
+use crate::rate_limiter::{Quota, RateLimiter};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Constants
+const LIMIT_HEADER: &str = "x-ratelimit-limit-requests";
+const REMAINING_HEADER: &str = "x-ratelimit-remaining-requests";
+const RESET_HEADER: &str = "x-ratelimit-reset-requests";
+const RETRY_AFTER_MS_HEADER: &str = "retry-after-ms";
+const PROCESSING_MS_HEADER: &str = "openai-processing-ms";
+/// Shortest back-off suggested; a concurrency rejection has no refill time
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Middleware adding rate-limit and processing-time headers to `/v1/*`
+pub(crate) async fn openai_headers(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.uri().path().starts_with(OPENAI_PREFIX) {
+        return next.run(request).await;
+    }
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let started = Instant::now();
+
+    let mut response = next.run(request).await;
+
+    let status = response.status();
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static(PROCESSING_MS_HEADER),
+        HeaderValue::from(started.elapsed().as_millis() as u64),
+    );
+    if let Some(ip) = ip {
+        insert_quota_headers(headers, &limiter.quota(ip).await, status);
+    }
+    response
+}
+
+/// Add `x-ratelimit-*-requests`, and the back-off headers on a 429
+pub(crate) fn insert_quota_headers(headers: &mut HeaderMap, quota: &Quota, status: StatusCode) {
+    headers.insert(
+        HeaderName::from_static(LIMIT_HEADER),
+        HeaderValue::from(quota.limit),
+    );
+    headers.insert(
+        HeaderName::from_static(REMAINING_HEADER),
+        HeaderValue::from(quota.remaining),
+    );
+    if let Ok(value) = HeaderValue::from_str(&format_duration(quota.reset)) {
+        headers.insert(HeaderName::from_static(RESET_HEADER), value);
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = quota.retry_after.max(MIN_RETRY_AFTER);
+        headers.insert(
+            axum::http::header::RETRY_AFTER,
+            HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+        );
+        headers.insert(
+            HeaderName::from_static(RETRY_AFTER_MS_HEADER),
+            HeaderValue::from(retry_after.as_millis() as u64),
+        );
+    }
+}
+
+/// A duration the way OpenAI writes resets: `20ms`, `1.5s`, `6m0s`
+fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis() as u64;
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+    let (minutes, millis) = (millis / 60_000, millis % 60_000);
+    let seconds = format!("{:.3}", millis as f64 / 1000.0);
+    let seconds = seconds.trim_end_matches('0').trim_end_matches('.');
+    if minutes > 0 {
+        format!("{}m{}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,115 @@
+//! OpenAPI 3.1 description of the local API
+//!
+//! `GET /openapi.json` lists every endpoint from the discovery table. The
+//! schemas are derived from the DTOs with utoipa, and the chat completion,
+//! deferred completion and embedding handlers carry `#[utoipa::path]`
+//! annotations; the other endpoints are described but untyped. `GET /docs`
+//! serves Swagger UI from assets built into the binary, so the page loads
+//! nothing from a CDN.
+
+use crate::deferred::{DeferredCompletion, DeferredStatus};
+use crate::discovery::{endpoints, Endpoint};
+use crate::API_VERSION;
+use chatsafe_common::{ChatCompletionChunk, ErrorResponse, OpenAiErrorResponse};
+use utoipa::openapi::path::{HttpMethod, OperationBuilder, ParameterBuilder, ParameterIn};
+use utoipa::openapi::{ContentBuilder, OpenApi as Document, Ref, ResponseBuilder};
+use utoipa::{OpenApi, PartialSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+// Constants
+const DOCUMENT_PATH: &str = "/openapi.json";
+const SWAGGER_UI_PATH: &str = "/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "ChatSafe Local API",
+        description = "Local, OpenAI-compatible LLM server"
+    ),
+    servers((url = "http://127.0.0.1:8081")),
+    paths(
+        crate::chat_completion,
+        crate::deferred::completion_status,
+        crate::embeddings::create_embeddings
+    ),
+    components(schemas(
+        ChatCompletionChunk,
+        DeferredCompletion,
+        DeferredStatus,
+        ErrorResponse,
+        OpenAiErrorResponse
+    ))
+)]
+struct ApiDoc;
+
+/// Swagger UI at `/docs`, which also serves `/openapi.json`
+pub(crate) fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new(SWAGGER_UI_PATH).url(DOCUMENT_PATH, document())
+}
+
+/// The full OpenAPI document for this build
+pub(crate) fn document() -> Document {
+    let mut doc = ApiDoc::openapi();
+    doc.info.version = API_VERSION.to_string();
+    for endpoint in endpoints() {
+        let method = http_method(endpoint.method);
+        if doc
+            .paths
+            .get_path_operation(endpoint.path, method.clone())
+            .is_none()
+        {
+            doc.paths
+                .add_path_operation(endpoint.path, vec![method], operation(endpoint));
+        }
+    }
+    doc
+}
+
+/// An untyped operation for an endpoint without annotations
+fn operation(endpoint: &Endpoint) -> OperationBuilder {
+    let mut operation = OperationBuilder::new()
+        .summary(Some(endpoint.description))
+        .response("200", ResponseBuilder::new().description("Success"))
+        .response("default", error_response(endpoint.path));
+    for name in path_params(endpoint.path) {
+        operation = operation.parameter(
+            ParameterBuilder::new()
+                .name(name)
+                .parameter_in(ParameterIn::Path)
+                .required(utoipa::openapi::Required::True)
+                .schema(Some(String::schema())),
+        );
+    }
+    operation
+}
+
+fn http_method(method: &str) -> HttpMethod {
+    match method {
+        "POST" => HttpMethod::Post,
+        "PUT" => HttpMethod::Put,
+        "DELETE" => HttpMethod::Delete,
+        "PATCH" => HttpMethod::Patch,
+        _ => HttpMethod::Get,
+    }
+}
+
+/// Names of `{param}` segments in a route path
+fn path_params(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+}
+
+/// `/v1/*` errors use OpenAI's object when `server.openai_errors` is on (the default)
+fn error_response(path: &str) -> ResponseBuilder {
+    let schema = if path.starts_with("/v1/") {
+        "OpenAiErrorResponse"
+    } else {
+        "ErrorResponse"
+    };
+    ResponseBuilder::new().description("Error").content(
+        "application/json",
+        ContentBuilder::new()
+            .schema(Some(Ref::from_schema_name(schema)))
+            .build(),
+    )
+}
@@ -0,0 +1,68 @@
+//! On-demand CPU profiling (`pprof` feature)
+//!
+//! `GET /admin/pprof?seconds=10` samples every thread for the requested
+//! window and returns an SVG flamegraph. Only one profile can run at a time.
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use serde::Deserialize;
+use std::time::Duration;
+
+// Constants
+const DEFAULT_PROFILE_SECONDS: u64 = 10;
+const MAX_PROFILE_SECONDS: u64 = 120;
+const SAMPLE_FREQUENCY_HZ: i32 = 99;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ProfileParams {
+    seconds: Option<u64>,
+}
+
+/// Capture a CPU profile and render it as a flamegraph
+pub(crate) async fn pprof_profile(Query(params): Query<ProfileParams>) -> Response {
+    let seconds = params
+        .seconds
+        .unwrap_or(DEFAULT_PROFILE_SECONDS)
+        .clamp(1, MAX_PROFILE_SECONDS);
+
+    // The profiler guard is not Send, so sample on a blocking thread
+    let result = tokio::task::spawn_blocking(move || capture_flamegraph(seconds)).await;
+
+    match result {
+        Ok(Ok(svg)) => ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response(),
+        Ok(Err(e)) => {
+            let status =
+                StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            crate::create_error_response(&e, &RequestId::new(), status)
+        }
+        Err(e) => crate::create_error_response(
+            &CommonError::Internal(format!("Profiler task failed: {}", e)),
+            &RequestId::new(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+fn capture_flamegraph(seconds: u64) -> Result<Vec<u8>, CommonError> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY_HZ)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| CommonError::ServiceUnavailable(format!("Profiler unavailable: {}", e)))?;
+
+    std::thread::sleep(Duration::from_secs(seconds));
+
+    let report = guard
+        .report()
+        .build()
+        .map_err(|e| CommonError::Internal(format!("Failed to build profile: {}", e)))?;
+    let mut svg = Vec::new();
+    report
+        .flamegraph(&mut svg)
+        .map_err(|e| CommonError::Internal(format!("Failed to render flamegraph: {}", e)))?;
+    Ok(svg)
+}
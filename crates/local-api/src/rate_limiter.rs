@@ -1,5 +1,6 @@
-use chatsafe_common::{Error, Result};
-use std::collections::HashMap;
+use chatsafe_common::{Error, RateLimitStats, Result};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -9,6 +10,8 @@ use tokio::task::JoinHandle;
 // Constants
 const CLEANUP_RETENTION_SECS: u64 = 300; // 5 minutes
 const TOKENS_PER_MINUTE_TO_PER_SECOND: f64 = 60.0;
+/// Rejections kept for `/admin/rate-limits`
+const RECENT_REJECTIONS: usize = 50;
 
 /// Token bucket implementation for rate limiting
 #[derive(Debug, Clone)]
@@ -55,6 +58,30 @@ impl TokenBucket {
         self.last_refill = now;
     }
 
+    /// Tokens available now, without updating the bucket
+    fn available(&self) -> f64 {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        (self.tokens + elapsed * self.refill_rate).min(self.capacity as f64)
+    }
+
+    /// Requests left and how long until more are allowed
+    fn quota(&self) -> Quota {
+        let available = self.available();
+        let wait = |tokens: f64| {
+            if self.refill_rate > 0.0 {
+                Duration::from_secs_f64(tokens.max(0.0) / self.refill_rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        Quota {
+            limit: self.capacity,
+            remaining: available.floor() as u32,
+            reset: wait(self.capacity as f64 - available),
+            retry_after: wait(1.0 - available),
+        }
+    }
+
     /// Return a consumed token (for rollback scenarios)
     fn return_token(&mut self) {
         self.tokens = (self.tokens + 1.0).min(self.capacity as f64);
@@ -96,6 +123,83 @@ struct IpState {
     last_seen: Instant,
 }
 
+/// Which limit turned a request away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    /// `per_ip_per_minute`
+    PerIpRate,
+    /// `max_concurrent_per_ip`
+    PerIpConcurrency,
+    /// `global_per_minute`
+    Global,
+}
+
+/// A client's request quota under the tighter of its own and the global
+/// bucket, for `x-ratelimit-*` headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again
+    pub reset: Duration,
+    /// Until the bucket holds a whole request again
+    pub retry_after: Duration,
+}
+
+/// Rejection counts and the latest rejections
+#[derive(Debug, Default)]
+struct RejectionLog {
+    recent: VecDeque<(Instant, IpAddr, LimitKind)>,
+    per_ip_rate: u64,
+    per_ip_concurrency: u64,
+    global: u64,
+}
+
+impl RejectionLog {
+    fn record(&mut self, ip: IpAddr, limit: LimitKind) {
+        match limit {
+            LimitKind::PerIpRate => self.per_ip_rate += 1,
+            LimitKind::PerIpConcurrency => self.per_ip_concurrency += 1,
+            LimitKind::Global => self.global += 1,
+        }
+        if self.recent.len() == RECENT_REJECTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((Instant::now(), ip, limit));
+    }
+}
+
+/// Everything `/admin/rate-limits` shows
+#[derive(Debug, Serialize)]
+pub struct RateLimitReport {
+    pub per_ip_per_minute: u32,
+    pub max_concurrent_per_ip: usize,
+    pub global_per_minute: u32,
+    pub stats: RateLimitStats,
+    /// Busiest first
+    pub ips: Vec<IpLimitState>,
+    /// Newest first
+    pub recent_rejections: Vec<RejectionRecord>,
+}
+
+/// Bucket level and in-flight requests of one client IP
+#[derive(Debug, Serialize)]
+pub struct IpLimitState {
+    pub ip: String,
+    pub tokens_available: f64,
+    pub concurrent_requests: usize,
+    pub last_seen_secs_ago: u64,
+}
+
+/// One request turned away
+#[derive(Debug, Serialize)]
+pub struct RejectionRecord {
+    pub age_seconds: u64,
+    pub ip: String,
+    pub limit: LimitKind,
+}
+
 /// Rate limiter for the API
 ///
 /// Implements both per-IP and global rate limiting using token bucket algorithm.
@@ -104,6 +208,7 @@ pub struct RateLimiter {
     config: RateLimiterConfig,
     ip_states: Arc<RwLock<HashMap<IpAddr, IpState>>>,
     global_bucket: Arc<RwLock<TokenBucket>>,
+    rejections: Arc<RwLock<RejectionLog>>,
     cleanup_handle: Arc<RwLock<Option<JoinHandle<()>>>>,
 }
 
@@ -113,6 +218,7 @@ impl Clone for RateLimiter {
             config: self.config.clone(),
             ip_states: self.ip_states.clone(),
             global_bucket: self.global_bucket.clone(),
+            rejections: self.rejections.clone(),
             cleanup_handle: self.cleanup_handle.clone(),
         }
     }
@@ -130,6 +236,7 @@ impl RateLimiter {
             config,
             ip_states: Arc::new(RwLock::new(HashMap::new())),
             global_bucket: Arc::new(RwLock::new(global_bucket)),
+            rejections: Arc::new(RwLock::new(RejectionLog::default())),
             cleanup_handle: Arc::new(RwLock::new(None)),
         };
 
@@ -158,9 +265,18 @@ impl RateLimiter {
     /// Check if a request from the given IP is allowed
     ///
     /// This checks both global and per-IP rate limits, as well as
-    /// concurrent request limits. Returns an error if any limit is exceeded.
-    /// Optimized to avoid double-locking when global check fails.
+    /// concurrent request limits. Returns an error if any limit is exceeded,
+    /// and records which one for `report`.
     pub async fn check_rate_limit(&self, ip: IpAddr) -> Result<()> {
+        let result = self.try_acquire(ip).await;
+        if let Err(limit) = result {
+            self.rejections.write().await.record(ip, limit);
+        }
+        result.map_err(|_| Error::RateLimitExceeded)
+    }
+
+    /// Optimized to avoid double-locking when global check fails
+    async fn try_acquire(&self, ip: IpAddr) -> std::result::Result<(), LimitKind> {
         // Structure to hold rollback info if needed
         struct RollbackInfo {
             ip: IpAddr,
@@ -189,12 +305,12 @@ impl RateLimiter {
 
             // Check concurrent request limit
             if state.concurrent_requests >= self.config.max_concurrent_per_ip {
-                return Err(Error::RateLimitExceeded);
+                return Err(LimitKind::PerIpConcurrency);
             }
 
             // Check token bucket
             if !state.bucket.try_consume(1) {
-                return Err(Error::RateLimitExceeded);
+                return Err(LimitKind::PerIpRate);
             }
 
             // Update state - will need rollback if global check fails
@@ -217,7 +333,7 @@ impl RateLimiter {
                     state.bucket.return_token();
                 }
             }
-            return Err(Error::RateLimitExceeded);
+            return Err(LimitKind::Global);
         }
 
         Ok(())
@@ -231,6 +347,93 @@ impl RateLimiter {
         }
     }
 
+    /// Requests `ip` has left; an IP not seen yet has its full bucket
+    pub async fn quota(&self, ip: IpAddr) -> Quota {
+        let own = match self.ip_states.read().await.get(&ip) {
+            Some(state) => state.bucket.quota(),
+            None => Quota {
+                limit: self.config.per_ip_per_minute,
+                remaining: self.config.per_ip_per_minute,
+                reset: Duration::ZERO,
+                retry_after: Duration::ZERO,
+            },
+        };
+        let global = self.global_bucket.read().await.quota();
+        let tighter = if global.remaining < own.remaining {
+            global
+        } else {
+            own
+        };
+        Quota {
+            retry_after: own.retry_after.max(global.retry_after),
+            ..tighter
+        }
+    }
+
+    /// Gauges for `/metrics`
+    pub async fn stats(&self) -> RateLimitStats {
+        let states = self.ip_states.read().await;
+        let global = self.global_bucket.read().await;
+        let rejections = self.rejections.read().await;
+        RateLimitStats {
+            global_tokens_available: global.available(),
+            global_capacity: global.capacity,
+            tracked_ips: states.len(),
+            concurrent_requests: states.values().map(|s| s.concurrent_requests).sum(),
+            ips_at_concurrency_limit: states
+                .values()
+                .filter(|s| s.concurrent_requests >= self.config.max_concurrent_per_ip)
+                .count(),
+            rejected_per_ip_rate: rejections.per_ip_rate,
+            rejected_per_ip_concurrency: rejections.per_ip_concurrency,
+            rejected_global: rejections.global,
+        }
+    }
+
+    /// Limits, every tracked IP and the latest rejections
+    pub async fn report(&self) -> RateLimitReport {
+        let stats = self.stats().await;
+        let mut ips: Vec<_> = self
+            .ip_states
+            .read()
+            .await
+            .iter()
+            .map(|(ip, state)| IpLimitState {
+                ip: ip.to_string(),
+                tokens_available: state.bucket.available(),
+                concurrent_requests: state.concurrent_requests,
+                last_seen_secs_ago: state.last_seen.elapsed().as_secs(),
+            })
+            .collect();
+        ips.sort_by(|a, b| {
+            b.concurrent_requests
+                .cmp(&a.concurrent_requests)
+                .then(a.tokens_available.total_cmp(&b.tokens_available))
+        });
+        let recent_rejections = self
+            .rejections
+            .read()
+            .await
+            .recent
+            .iter()
+            .rev()
+            .map(|(at, ip, limit)| RejectionRecord {
+                age_seconds: at.elapsed().as_secs(),
+                ip: ip.to_string(),
+                limit: *limit,
+            })
+            .collect();
+
+        RateLimitReport {
+            per_ip_per_minute: self.config.per_ip_per_minute,
+            max_concurrent_per_ip: self.config.max_concurrent_per_ip,
+            global_per_minute: self.config.global_per_minute,
+            stats,
+            ips,
+            recent_rejections,
+        }
+    }
+
     /// Background cleanup loop to remove old IP entries
     async fn cleanup_loop(
         ip_states: Arc<RwLock<HashMap<IpAddr, IpState>>>,
@@ -354,4 +557,42 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_report_names_tripped_limit() {
+        let config = RateLimiterConfig {
+            per_ip_per_minute: 2,
+            max_concurrent_per_ip: 1,
+            global_per_minute: 100,
+            cleanup_interval: Duration::from_secs(60),
+        };
+
+        let limiter = RateLimiter::new(config);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        assert!(limiter.check_rate_limit(ip).await.is_ok());
+        assert!(limiter.check_rate_limit(ip).await.is_err());
+        limiter.release_request(ip).await;
+        assert!(limiter.check_rate_limit(ip).await.is_ok());
+        limiter.release_request(ip).await;
+        assert!(limiter.check_rate_limit(ip).await.is_err());
+
+        let stats = limiter.stats().await;
+        assert_eq!(stats.tracked_ips, 1);
+        assert_eq!(stats.concurrent_requests, 0);
+        assert_eq!(stats.rejected_per_ip_concurrency, 1);
+        assert_eq!(stats.rejected_per_ip_rate, 1);
+        assert_eq!(stats.rejected_global, 0);
+        assert_eq!(stats.global_capacity, 100);
+
+        let report = limiter.report().await;
+        assert_eq!(report.ips.len(), 1);
+        assert!(report.ips[0].tokens_available < 1.0);
+        let limits: Vec<_> = report.recent_rejections.iter().map(|r| r.limit).collect();
+        assert_eq!(
+            limits,
+            vec![LimitKind::PerIpRate, LimitKind::PerIpConcurrency]
+        );
+        assert_eq!(report.recent_rejections[0].ip, "127.0.0.1");
+    }
 }
@@ -0,0 +1,49 @@
+//! Opt-in recorder for `chatsafe replay`
+//!
+//! Appends one `ReplayEnvelope` per accepted chat request to a JSONL file.
+//! Message content is only kept when `server.replay_include_content` is set.
+
+use chatsafe_common::{ChatCompletionRequest, ReplayEnvelope};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::warn;
+
+/// Writes replay envelopes with offsets relative to server start
+pub(crate) struct ReplayRecorder {
+    file: Mutex<File>,
+    started: Instant,
+    include_content: bool,
+}
+
+impl ReplayRecorder {
+    pub(crate) fn open(path: &Path, include_content: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            started: Instant::now(),
+            include_content,
+        })
+    }
+
+    /// Record a request; failures are logged and never affect the request
+    pub(crate) fn record(&self, request: &ChatCompletionRequest) {
+        let offset_ms = self.started.elapsed().as_millis() as u64;
+        let envelope = ReplayEnvelope::capture(request, offset_ms, self.include_content);
+
+        let line = match serde_json::to_string(&envelope) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize replay envelope: {}", e);
+                return;
+            }
+        };
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Failed to write replay envelope: {}", e);
+        }
+    }
+}
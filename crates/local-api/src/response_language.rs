@@ -0,0 +1,176 @@
+//! Answering in the language the client asked for
+//!
+//! Small models drift into English whatever language the prompt is in. A
+//! request's `language` adds a directive to the system prompt; with `verify`
+//! the non-streamed response's language is detected (whatlang) and, on a
+//! mismatch, the response is generated once more with a firmer directive.
+//! Text too short or mixed to identify reliably counts as a match.
+
+use crate::system_prompt;
+use chatsafe_common::{Error as CommonError, Message, ResponseLanguage};
+use whatlang::Lang;
+
+/// ISO 639-1 codes of the languages whatlang detects
+const ISO_639_1: &[(&str, Lang)] = &[
+    ("af", Lang::Afr),
+    ("ak", Lang::Aka),
+    ("am", Lang::Amh),
+    ("ar", Lang::Ara),
+    ("az", Lang::Aze),
+    ("be", Lang::Bel),
+    ("bg", Lang::Bul),
+    ("bn", Lang::Ben),
+    ("ca", Lang::Cat),
+    ("cs", Lang::Ces),
+    ("da", Lang::Dan),
+    ("de", Lang::Deu),
+    ("el", Lang::Ell),
+    ("en", Lang::Eng),
+    ("eo", Lang::Epo),
+    ("es", Lang::Spa),
+    ("et", Lang::Est),
+    ("fa", Lang::Pes),
+    ("fi", Lang::Fin),
+    ("fr", Lang::Fra),
+    ("gu", Lang::Guj),
+    ("he", Lang::Heb),
+    ("hi", Lang::Hin),
+    ("hr", Lang::Hrv),
+    ("hu", Lang::Hun),
+    ("hy", Lang::Hye),
+    ("id", Lang::Ind),
+    ("it", Lang::Ita),
+    ("ja", Lang::Jpn),
+    ("jv", Lang::Jav),
+    ("ka", Lang::Kat),
+    ("km", Lang::Khm),
+    ("kn", Lang::Kan),
+    ("ko", Lang::Kor),
+    ("la", Lang::Lat),
+    ("lt", Lang::Lit),
+    ("lv", Lang::Lav),
+    ("mk", Lang::Mkd),
+    ("ml", Lang::Mal),
+    ("mr", Lang::Mar),
+    ("my", Lang::Mya),
+    ("nb", Lang::Nob),
+    ("ne", Lang::Nep),
+    ("nl", Lang::Nld),
+    ("no", Lang::Nob),
+    ("or", Lang::Ori),
+    ("pa", Lang::Pan),
+    ("pl", Lang::Pol),
+    ("pt", Lang::Por),
+    ("ro", Lang::Ron),
+    ("ru", Lang::Rus),
+    ("si", Lang::Sin),
+    ("sk", Lang::Slk),
+    ("sl", Lang::Slv),
+    ("sn", Lang::Sna),
+    ("sr", Lang::Srp),
+    ("sv", Lang::Swe),
+    ("ta", Lang::Tam),
+    ("te", Lang::Tel),
+    ("th", Lang::Tha),
+    ("tk", Lang::Tuk),
+    ("tl", Lang::Tgl),
+    ("tr", Lang::Tur),
+    ("uk", Lang::Ukr),
+    ("ur", Lang::Urd),
+    ("uz", Lang::Uzb),
+    ("vi", Lang::Vie),
+    ("yi", Lang::Yid),
+    ("zh", Lang::Cmn),
+    ("zu", Lang::Zul),
+];
+
+/// The language a request's response must be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TargetLanguage {
+    lang: Lang,
+    verify: bool,
+}
+
+impl TargetLanguage {
+    /// Resolve `language.code`: ISO 639-1 (`de`), ISO 639-3 (`deu`) or a
+    /// locale tag (`de-DE`)
+    pub(crate) fn new(language: &ResponseLanguage) -> Result<Self, CommonError> {
+        let primary = language
+            .code
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let lang = ISO_639_1
+            .iter()
+            .find(|(code, _)| *code == primary)
+            .map(|(_, lang)| *lang)
+            .or_else(|| Lang::from_code(primary))
+            .ok_or_else(|| {
+                CommonError::BadRequest(format!(
+                    "Unsupported language {:?}; use an ISO 639-1 or 639-3 code",
+                    language.code
+                ))
+            })?;
+        Ok(Self {
+            lang,
+            verify: language.verify,
+        })
+    }
+
+    /// Whether the response should be checked and regenerated on a mismatch
+    pub(crate) fn verify(&self) -> bool {
+        self.verify
+    }
+
+    /// ISO 639-3 code of the language
+    pub(crate) fn code(&self) -> &'static str {
+        self.lang.code()
+    }
+
+    /// Add the directive to the system prompt
+    pub(crate) fn apply(&self, messages: &mut Vec<Message>, default_prompt: &str) {
+        system_prompt::append(messages, &self.directive(), default_prompt);
+    }
+
+    /// `messages` with a firmer directive, for regenerating a response in
+    /// the wrong language
+    pub(crate) fn stricter(&self, messages: &[Message], default_prompt: &str) -> Vec<Message> {
+        let mut messages = messages.to_vec();
+        system_prompt::append(
+            &mut messages,
+            &format!(
+                "Your previous answer was not in {}. Write the entire answer in {}, \
+                 translating anything you would otherwise write in another language.",
+                self.lang.eng_name(),
+                self.lang.eng_name()
+            ),
+            default_prompt,
+        );
+        messages
+    }
+
+    /// Whether `text` is in the language, or too ambiguous to tell
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        match whatlang::detect(text) {
+            Some(info) if info.is_reliable() => info.lang() == self.lang,
+            _ => true,
+        }
+    }
+
+    fn directive(&self) -> String {
+        let name = self.lang.eng_name();
+        let native = self.lang.name();
+        if name == native {
+            format!(
+                "Always respond in {}, whatever language the user writes in.",
+                name
+            )
+        } else {
+            format!(
+                "Always respond in {} ({}), whatever language the user writes in.",
+                name, native
+            )
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Degraded startup when the config or registry does not parse
+//!
+//! A typo in `chatsafe.json` or in the user registry (`models.registry_file`)
+//! used to stop the server before it bound a port, so clients only saw
+//! "connection refused". Instead the server starts on built-in defaults with
+//! no model loaded, `/readyz` reports the parse errors, and
+//! `POST /admin/reload` re-reads both files and restarts the process once
+//! they parse.
+
+use crate::{create_error_response, AppState};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chatsafe_common::{Error as CommonError, RequestId, Result};
+use chatsafe_config::{AppConfig, ConfigLoader, ModelRegistry};
+use chatsafe_runtime::Runtime;
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Constants
+/// Time for the reload response to reach the client before the restart
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// Configuration to start with, and what failed to parse on the way
+pub(crate) struct Startup {
+    pub(crate) config: AppConfig,
+    pub(crate) registry: ModelRegistry,
+    /// Empty unless running in safe mode
+    pub(crate) errors: Vec<String>,
+}
+
+/// Load the config and registry, falling back to defaults for either one
+/// that fails
+pub(crate) fn load(config_path: Option<&PathBuf>) -> Result<Startup> {
+    let mut errors = Vec::new();
+    let config = ConfigLoader::load(config_path).unwrap_or_else(|e| {
+        errors.push(format!("config: {}", e));
+        AppConfig::default()
+    });
+    let mut registry = match load_registry(&config) {
+        Ok(registry) => registry,
+        Err(e) => {
+            errors.push(format!("registry: {}", e));
+            ModelRegistry::load_defaults()?
+        }
+    };
+    registry.set_model_dir(config.model_dir());
+
+    for problem in &errors {
+        error!("Starting in safe mode, {}", problem);
+    }
+    Ok(Startup {
+        config,
+        registry,
+        errors,
+    })
+}
+
+/// `models.registry_file` when set, otherwise the built-in registry
+fn load_registry(config: &AppConfig) -> Result<ModelRegistry> {
+    match &config.models.registry_file {
+        Some(path) => ModelRegistry::load_from_file(path),
+        None => ModelRegistry::load_defaults(),
+    }
+}
+
+/// `GET /readyz`: 200 once a model is loaded, 503 with the reason otherwise
+pub(crate) async fn readyz(State(state): State<AppState>) -> Response {
+    if !state.startup_errors.is_empty() {
+        let body = json!({
+            "ready": false,
+            "safe_mode": true,
+            "errors": *state.startup_errors
+        });
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    }
+
+    match state.model_handle.read().await.as_ref() {
+        Some(handle) => Json(json!({
+            "ready": true,
+            "safe_mode": false,
+            "model": handle.model_id.to_string()
+        }))
+        .into_response(),
+        None => {
+            let body = json!({ "ready": false, "safe_mode": false });
+            (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+        }
+    }
+}
+
+/// `POST /admin/reload`: leave safe mode once the files parse
+///
+/// Everything built from the config (listeners, backends, limits) is
+/// rebuilt by restarting the process with the same arguments.
+pub(crate) async fn reload(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    if state.startup_errors.is_empty() {
+        let error = CommonError::BadRequest(
+            "Not in safe mode; restart the server to apply config changes".into(),
+        );
+        return create_error_response(&error, &request_id, StatusCode::CONFLICT);
+    }
+
+    let errors = match load(None) {
+        Ok(startup) => startup.errors,
+        Err(e) => vec![format!("registry: {}", e)],
+    };
+    if !errors.is_empty() {
+        let error = CommonError::ConfigError(errors.join("; "));
+        return create_error_response(&error, &request_id, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    info!("Config and registry parse again, restarting");
+    tokio::spawn(async move {
+        tokio::time::sleep(RESTART_DELAY).await;
+        shutdown_backends(&state).await;
+        let e = restart();
+        error!("Restart failed: {}", e);
+    });
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "reloaded": true, "restarting": true })),
+    )
+        .into_response()
+}
+
+/// Stop managed backend processes; `exec` skips the destructors that
+/// would otherwise kill them, and the new process needs their ports
+async fn shutdown_backends(state: &AppState) {
+    if let Err(e) = state.runtime.shutdown().await {
+        warn!("Backend shutdown failed: {}", e);
+    }
+    if let Some(embedder) = &state.embedder {
+        if let Err(e) = embedder.write().await.shutdown().await {
+            warn!("Embedding backend shutdown failed: {}", e);
+        }
+    }
+    if let Some(guardrail) = &state.guardrail {
+        if let Err(e) = guardrail.shutdown().await {
+            warn!("Guard backend shutdown failed: {}", e);
+        }
+    }
+}
+
+/// Replace this process with a fresh copy; only returns on failure
+#[cfg(unix)]
+fn restart() -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return e,
+    };
+    warn!("Re-executing {}", exe.display());
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec()
+}
+
+#[cfg(not(unix))]
+fn restart() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "in-place restart needs a Unix platform; restart the server by hand",
+    )
+}
@@ -0,0 +1,217 @@
+//! HTTP accept loop tuned from `ServerConfig`
+//!
+//! `axum::serve` has no connection limit or keep-alive settings, so
+//! connections are accepted here and handed to hyper directly. At most
+//! `max_connections` are open at once across all listeners; further clients
+//! wait in the listen backlog until one closes instead of each getting a
+//! task and buffers.
+//!
+//! Each configured listener (TCP over IPv4 or IPv6, or a Unix socket) may
+//! require its own bearer token, e.g. an open loopback port beside a
+//! token-protected one for other local users.
+//!
+//! When told to stop, a listener closes, idle connections are shut and
+//! in-flight requests get up to `DRAIN_TIMEOUT` to finish.
+
+use crate::create_error_response;
+use axum::{
+    extract::{connect_info::IntoMakeServiceWithConnectInfo, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    serve::Listener,
+    Router,
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_config::ServerConfig;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tower::Service;
+use tracing::{debug, info, warn};
+
+// Constants
+/// Longest in-flight requests may run once the server is stopping
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Connection settings taken from `ServerConfig`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectionSettings {
+    /// `None` leaves the number of open connections unbounded
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) keep_alive: bool,
+    /// Longest a connection may sit between requests; `None` never closes it
+    pub(crate) keep_alive_timeout: Option<Duration>,
+    pub(crate) tcp_nodelay: bool,
+}
+
+impl ConnectionSettings {
+    pub(crate) fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            max_connections: (config.max_connections > 0).then_some(config.max_connections),
+            keep_alive: config.keep_alive,
+            keep_alive_timeout: (config.keep_alive_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.keep_alive_timeout_secs)),
+            tcp_nodelay: config.tcp_nodelay,
+        }
+    }
+
+    /// Connection slots shared by every listener
+    pub(crate) fn connection_slots(&self) -> Option<Arc<Semaphore>> {
+        self.max_connections.map(|n| Arc::new(Semaphore::new(n)))
+    }
+}
+
+/// Listener kinds the API serves on
+pub(crate) trait ApiListener: Listener {
+    /// Apply socket options and return the client address used for rate limiting
+    fn prepare(io: &Self::Io, addr: &Self::Addr, settings: &ConnectionSettings) -> SocketAddr;
+}
+
+impl ApiListener for TcpListener {
+    fn prepare(io: &Self::Io, addr: &Self::Addr, settings: &ConnectionSettings) -> SocketAddr {
+        if let Err(e) = io.set_nodelay(settings.tcp_nodelay) {
+            debug!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+        }
+        *addr
+    }
+}
+
+#[cfg(unix)]
+impl ApiListener for tokio::net::UnixListener {
+    /// Socket clients have no IP, so they share one loopback rate-limit bucket
+    fn prepare(_io: &Self::Io, _addr: &Self::Addr, _settings: &ConnectionSettings) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+    }
+}
+
+/// Bind a Unix socket readable only by this user, replacing a stale one
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Require `Authorization: Bearer <token>` on every route when a token is set
+pub(crate) fn require_token(app: Router, token: Option<String>) -> Router {
+    match token {
+        Some(token) => app.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            check_token,
+        )),
+        None => app,
+    }
+}
+
+async fn check_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes())) {
+        return next.run(request).await;
+    }
+    let mut response = create_error_response(
+        &CommonError::Unauthorized,
+        &RequestId::new(),
+        StatusCode::UNAUTHORIZED,
+    );
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Compare without stopping at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Serve `app` on `listener` until `stop` completes, then drain its connections
+pub(crate) async fn serve<L: ApiListener>(
+    mut listener: L,
+    app: Router,
+    settings: ConnectionSettings,
+    slots: Option<Arc<Semaphore>>,
+    stop: impl Future<Output = ()>,
+) {
+    let mut make_service: IntoMakeServiceWithConnectInfo<Router, SocketAddr> =
+        app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .keep_alive(settings.keep_alive)
+        .timer(TokioTimer::new())
+        // hyper starts this timer whenever it waits for the next request's
+        // headers, so it doubles as the idle keep-alive timeout
+        .header_read_timeout(settings.keep_alive_timeout);
+    let builder = Arc::new(builder);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(stop);
+
+    loop {
+        let accept = async {
+            // Take a slot before accepting so excess clients stay in the backlog
+            let permit = match &slots {
+                Some(slots) => Some(Arc::clone(slots).acquire_owned().await.ok()?),
+                None => None,
+            };
+            // Accept errors are retried inside the listener
+            Some((permit, listener.accept().await))
+        };
+        let (permit, (io, addr)) = tokio::select! {
+            accepted = accept => match accepted {
+                Some(accepted) => accepted,
+                None => break,
+            },
+            () = &mut stop => break,
+        };
+        let remote_addr = L::prepare(&io, &addr, &settings);
+
+        let service = match make_service.call(remote_addr).await {
+            Ok(service) => TowerToHyperService::new(service),
+            Err(infallible) => match infallible {},
+        };
+        let builder = Arc::clone(&builder);
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("Connection from {} ended with error: {}", remote_addr, e);
+            }
+        });
+    }
+
+    // Stop accepting before waiting for the open connections
+    drop(listener);
+    let open = graceful.count();
+    if open > 0 {
+        info!("Draining {} open connections", open);
+    }
+    if tokio::time::timeout(DRAIN_TIMEOUT, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Requests still running after {:?}; stopping anyway",
+            DRAIN_TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! OpenAI-compatible text-to-speech
+//!
+//! `POST /v1/audio/speech` speaks `input` with one of the registry's piper
+//! voices (`capability: speech`) and streams the audio back as piper
+//! produces it, so playback can start before the whole text is spoken.
+
+use crate::{create_error_response, AppState, RateLimitGuard};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_runtime::AudioFormat;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::info;
+
+// Constants
+/// Longest input accepted, matching OpenAI's limit
+const MAX_INPUT_CHARS: usize = 4096;
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 4.0;
+const SAMPLE_RATE_HEADER: &str = "x-chatsafe-sample-rate";
+/// Model names OpenAI clients send; any configured voice serves them
+const OPENAI_MODEL_ALIASES: &[&str] = &["tts-1", "tts-1-hd"];
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SpeechRequest {
+    /// `tts-1`, or a voice ID when `voice` is left out
+    model: Option<String>,
+    input: String,
+    /// Voice ID from the registry; the first voice when omitted
+    voice: Option<String>,
+    /// `wav` (default) or `pcm`
+    response_format: Option<String>,
+    speed: Option<f32>,
+}
+
+/// Speak the input and stream the audio
+pub(crate) async fn create_speech(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    Json(request): Json<SpeechRequest>,
+) -> Result<Response, Response> {
+    let ip = addr.ip();
+    let fail = |e: CommonError| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        create_error_response(&e, &request_id, status)
+    };
+
+    if let Err(e) = state.rate_limiter.check_rate_limit(ip).await {
+        state.metrics.record_error(Some(&request_id), &e).await;
+        state.metrics.record_rate_limit(ip.to_string()).await;
+        return Err(fail(e));
+    }
+    // Held by the body until the audio has been sent
+    let rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
+
+    let result = speak(&state, request).await;
+    let (speech, format) = match result {
+        Ok(speech) => speech,
+        Err(e) => {
+            state.metrics.record_error(Some(&request_id), &e).await;
+            return Err(fail(e));
+        }
+    };
+
+    let content_type = match format {
+        AudioFormat::Wav => "audio/wav",
+        AudioFormat::Pcm => "audio/pcm",
+    };
+    let audio = speech.audio.map(move |chunk| {
+        let _held = &rate_guard;
+        chunk
+    });
+    let mut response = Response::new(Body::from_stream(audio));
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(SAMPLE_RATE_HEADER, HeaderValue::from(speech.sample_rate));
+    Ok(response)
+}
+
+async fn speak(
+    state: &AppState,
+    request: SpeechRequest,
+) -> Result<(chatsafe_runtime::Speech, AudioFormat), CommonError> {
+    let synthesizer = state.synthesizer.as_ref().ok_or_else(|| {
+        CommonError::ServiceUnavailable(
+            "No speech voices configured; add a registry model with capability: speech".into(),
+        )
+    })?;
+
+    let input = request.input.trim();
+    if input.is_empty() {
+        return Err(CommonError::BadRequest("Input is empty".into()));
+    }
+    let chars = input.chars().count();
+    if chars > MAX_INPUT_CHARS {
+        return Err(CommonError::BadRequest(format!(
+            "Input is {} characters; at most {} are allowed",
+            chars, MAX_INPUT_CHARS
+        )));
+    }
+    let speed = request.speed.unwrap_or(1.0);
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        return Err(CommonError::BadRequest(format!(
+            "speed must be between {} and {}",
+            MIN_SPEED, MAX_SPEED
+        )));
+    }
+    let format = match request.response_format.as_deref() {
+        None | Some("wav") => AudioFormat::Wav,
+        Some("pcm") => AudioFormat::Pcm,
+        Some(other) => {
+            return Err(CommonError::BadRequest(format!(
+                "Unsupported response_format {:?}; use wav or pcm",
+                other
+            )))
+        }
+    };
+
+    // A model naming a voice selects it, as for chat models
+    let model = request
+        .model
+        .filter(|model| !OPENAI_MODEL_ALIASES.contains(&model.as_str()));
+    let voice = request.voice.or(model);
+
+    let speech = synthesizer
+        .synthesize(voice.as_deref(), input, speed, format)
+        .await?;
+    info!("Speaking {} characters with voice {}", chars, speech.voice);
+    Ok((speech, format))
+}
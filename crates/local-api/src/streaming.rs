@@ -1,7 +1,8 @@
 use axum::response::sse::{Event, Sse};
 use chatsafe_common::{
-    ChatCompletionChunk, DeltaContent, Error as CommonError, ObservableMetrics, RequestId,
-    StreamChoice, StreamFrame,
+    ChatCompletionChunk, ChoiceLogprobs, DeltaContent, Error as CommonError, ObservableMetrics,
+    QueueStatus, RequestId, ResponseTimings, StreamChoice, StreamFrame, TokenLogprob, ToolCall,
+    ToolCallDelta,
 };
 use futures::stream::Stream;
 use futures::StreamExt;
@@ -10,17 +11,23 @@ use std::convert::Infallible;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
 use tracing::error;
 
+use crate::content_log::ExcerptBuffer;
 use crate::rate_limiter::RateLimiter;
 
 // Constants
 const BUFFER_SIZE: usize = 32; // Maximum chunks to buffer for backpressure
 const CHUNK_TIMEOUT: Duration = Duration::from_secs(30); // Timeout per chunk
+/// Longest the final chunk waits for backend timings, which normally arrive first
+const TIMINGS_WAIT: Duration = Duration::from_millis(500);
 const CHUNK_OBJECT_TYPE: &str = "chat.completion.chunk";
 const DONE_MARKER: &str = "[DONE]";
+const EMPTY_CONTENT_VALUE: &str = "\"content\":\"\"";
 const ERROR_TYPE_RUNTIME: &str = "runtime_error";
-const ERROR_TYPE_STREAM: &str = "stream_error";
+const ERROR_TYPE_INTERNAL: &str = "internal_error";
+const PANIC_MESSAGE: &str = "Internal error while streaming the response";
 
 /// Create an SSE streaming response with observability and backpressure control
 ///
@@ -28,6 +35,7 @@ const ERROR_TYPE_STREAM: &str = "stream_error";
 /// and ensures proper cleanup of rate limits and request tracking.
 pub fn streaming_response_with_observability(
     stream: std::pin::Pin<Box<dyn Stream<Item = Result<StreamFrame, CommonError>> + Send>>,
+    timings: oneshot::Receiver<ResponseTimings>,
     model_id: String,
     metrics: Arc<ObservableMetrics>,
     rate_limiter: RateLimiter,
@@ -37,19 +45,27 @@ pub fn streaming_response_with_observability(
     // Use bounded channel for backpressure
     let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(BUFFER_SIZE);
 
-    // Spawn producer task with automatic cleanup
-    tokio::spawn(async move {
-        produce_stream_events(
-            stream,
-            model_id,
-            metrics,
-            tx,
-            rate_limiter,
-            client_ip,
-            request_id,
-        )
-        .await;
-    });
+    // Owned by the producer so cleanup happens whenever it exits
+    let cleanup = CleanupGuard::new(rate_limiter, client_ip, metrics.clone(), request_id.clone());
+
+    // Spawn producer task with automatic cleanup; a panic still ends the
+    // stream with an error event
+    let panic_tx = tx.clone();
+    crate::supervisor::spawn_with_fallback(
+        metrics.clone(),
+        request_id.clone(),
+        "stream producer",
+        produce_stream_events(stream, timings, model_id, metrics, tx, cleanup, request_id),
+        async move {
+            send_error_event(
+                &panic_tx,
+                PANIC_MESSAGE.to_string(),
+                ERROR_TYPE_INTERNAL,
+                None,
+            )
+            .await;
+        },
+    );
 
     // Consumer stream that yields from the bounded channel
     let response_stream = async_stream::stream! {
@@ -64,21 +80,13 @@ pub fn streaming_response_with_observability(
 /// Produce SSE events from the generation stream
 async fn produce_stream_events(
     mut stream: std::pin::Pin<Box<dyn Stream<Item = Result<StreamFrame, CommonError>> + Send>>,
+    timings: oneshot::Receiver<ResponseTimings>,
     model_id: String,
     metrics: Arc<ObservableMetrics>,
     tx: tokio::sync::mpsc::Sender<Result<Event, Infallible>>,
-    rate_limiter: RateLimiter,
-    client_ip: IpAddr,
+    _cleanup: CleanupGuard,
     request_id: RequestId,
 ) {
-    // Ensure cleanup happens when function exits
-    let _cleanup = CleanupGuard::new(
-        rate_limiter.clone(),
-        client_ip,
-        metrics.clone(),
-        request_id.clone(),
-    );
-
     let request_id_str = Arc::new(request_id.to_string());
     let model_id = Arc::new(model_id);
     let created = get_unix_timestamp();
@@ -94,6 +102,11 @@ async fn produce_stream_events(
         metrics: &metrics,
         first_token_recorded: &mut first_token_recorded,
         stream_start,
+        tracked_id: &request_id,
+        delta_encoder: DeltaEncoder::new(&request_id_str, &model_id, created),
+        excerpt: ExcerptBuffer::default(),
+        timings: Some(timings),
+        pending_logprobs: Vec::new(),
     };
 
     while let Some(frame_result) = tokio::time::timeout(CHUNK_TIMEOUT, stream.next())
@@ -118,6 +131,61 @@ struct FrameContext<'a> {
     metrics: &'a Arc<ObservableMetrics>,
     first_token_recorded: &'a mut bool,
     stream_start: std::time::Instant,
+    tracked_id: &'a RequestId,
+    delta_encoder: Option<DeltaEncoder>,
+    excerpt: ExcerptBuffer,
+    /// Backend timings for the final chunk
+    timings: Option<oneshot::Receiver<ResponseTimings>>,
+    /// Token log probabilities waiting for the next chunk
+    pending_logprobs: Vec<TokenLogprob>,
+}
+
+/// Delta chunk serializer for the per-token hot path
+///
+/// Everything in a delta chunk except the content is fixed for the life of a
+/// stream, so the surrounding JSON is serialized once and each token only
+/// escapes its content into a reused buffer.
+pub(crate) struct DeltaEncoder {
+    prefix: String,
+    suffix: String,
+    buf: Vec<u8>,
+}
+
+impl DeltaEncoder {
+    /// Build the skeleton from a real chunk so the output always matches
+    /// what serde would produce for the full `ChatCompletionChunk`
+    pub(crate) fn new(
+        request_id: &Arc<String>,
+        model_id: &Arc<String>,
+        created: i64,
+    ) -> Option<Self> {
+        let chunk = create_chunk(
+            request_id,
+            model_id,
+            created,
+            None,
+            Some(String::new()),
+            None,
+        );
+        let json = serde_json::to_string(&chunk).ok()?;
+        let value_end = json.rfind(EMPTY_CONTENT_VALUE)? + EMPTY_CONTENT_VALUE.len();
+        let value_start = value_end - 2; // the empty string literal
+
+        Some(Self {
+            prefix: json[..value_start].to_string(),
+            suffix: json[value_end..].to_string(),
+            buf: Vec::with_capacity(json.len() * 2),
+        })
+    }
+
+    /// Serialize a delta chunk carrying `content`
+    pub(crate) fn encode(&mut self, content: &str) -> Option<&str> {
+        self.buf.clear();
+        self.buf.extend_from_slice(self.prefix.as_bytes());
+        serde_json::to_writer(&mut self.buf, content).ok()?;
+        self.buf.extend_from_slice(self.suffix.as_bytes());
+        std::str::from_utf8(&self.buf).ok()
+    }
 }
 
 /// Process a single stream frame and send appropriate SSE event
@@ -135,31 +203,96 @@ async fn process_stream_frame(
             if !*ctx.first_token_recorded {
                 *ctx.first_token_recorded = true;
                 let latency_ms = ctx.stream_start.elapsed().as_millis() as u64;
-                ctx.metrics.record_first_token_latency(latency_ms).await;
+                ctx.metrics
+                    .record_first_token_latency(ctx.tracked_id, latency_ms)
+                    .await;
             }
 
             // Track chunk sent
             ctx.metrics.record_chunk().await;
+            ctx.excerpt.push(&content);
 
+            if !ctx.pending_logprobs.is_empty() {
+                let mut chunk = create_chunk(
+                    ctx.request_id,
+                    ctx.model_id,
+                    ctx.created,
+                    None,
+                    Some(content),
+                    None,
+                );
+                chunk.choices[0].logprobs = take_logprobs(&mut ctx.pending_logprobs);
+                return send_chunk_event(ctx.tx, chunk).await;
+            }
+            if let Some(json) = ctx.delta_encoder.as_mut().and_then(|e| e.encode(&content)) {
+                return ctx.tx.send(Ok(Event::default().data(json))).await.is_ok();
+            }
             send_delta_chunk(ctx.tx, ctx.request_id, ctx.model_id, ctx.created, content).await
         }
-        Ok(StreamFrame::Done { finish_reason, .. }) => {
+        Ok(StreamFrame::Logprobs { content }) => {
+            ctx.pending_logprobs.extend(content);
+            true
+        }
+        Ok(StreamFrame::Queued { position, eta_ms }) => {
+            // No choices, like a usage chunk, so OpenAI clients skip it
+            let mut chunk =
+                create_chunk(ctx.request_id, ctx.model_id, ctx.created, None, None, None);
+            chunk.choices.clear();
+            chunk.queue = Some(QueueStatus { position, eta_ms });
+            send_chunk_event(ctx.tx, chunk).await
+        }
+        Ok(StreamFrame::ToolCalls { tool_calls }) => {
+            send_tool_calls_chunk(
+                ctx.tx,
+                ctx.request_id,
+                ctx.model_id,
+                ctx.created,
+                tool_calls,
+            )
+            .await
+        }
+        Ok(StreamFrame::Done {
+            finish_reason,
+            usage,
+        }) => {
+            ctx.excerpt.log("response");
+            ctx.metrics
+                .record_tokens(
+                    ctx.tracked_id,
+                    usage.prompt_tokens as u64,
+                    usage.completion_tokens as u64,
+                )
+                .await;
+            let timings = match ctx.timings.take() {
+                Some(rx) => tokio::time::timeout(TIMINGS_WAIT, rx)
+                    .await
+                    .ok()
+                    .and_then(Result::ok),
+                None => None,
+            };
             send_done_chunk(
                 ctx.tx,
                 ctx.request_id,
                 ctx.model_id,
                 ctx.created,
                 finish_reason,
+                timings,
+                take_logprobs(&mut ctx.pending_logprobs),
             )
             .await;
             false // Stop streaming
         }
-        Ok(StreamFrame::Error { message }) => {
-            send_error_event(ctx.tx, message, ERROR_TYPE_RUNTIME).await;
-            false // Stop streaming
+        Ok(StreamFrame::Error {
+            message,
+            partial_content_length,
+        }) => {
+            let sent =
+                send_error_event(ctx.tx, message, ERROR_TYPE_RUNTIME, partial_content_length).await;
+            // Interrupted generations are followed by a final Done frame
+            sent && partial_content_length.is_some()
         }
         Err(e) => {
-            send_error_event(ctx.tx, e.to_string(), ERROR_TYPE_STREAM).await;
+            send_error_event(ctx.tx, e.to_string(), e.error_type(), None).await;
             false // Stop streaming
         }
     }
@@ -191,6 +324,26 @@ async fn send_delta_chunk(
     send_chunk_event(tx, chunk).await
 }
 
+/// Send the response's tool calls, each whole, in one chunk
+async fn send_tool_calls_chunk(
+    tx: &tokio::sync::mpsc::Sender<Result<Event, Infallible>>,
+    request_id: &Arc<String>,
+    model_id: &Arc<String>,
+    created: i64,
+    tool_calls: Vec<ToolCall>,
+) -> bool {
+    let mut chunk = create_chunk(request_id, model_id, created, None, None, None);
+    chunk.choices[0].delta.tool_calls = Some(
+        tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCallDelta { index, call })
+            .collect(),
+    );
+
+    send_chunk_event(tx, chunk).await
+}
+
 /// Send the final chunk with finish reason and DONE marker
 async fn send_done_chunk(
     tx: &tokio::sync::mpsc::Sender<Result<Event, Infallible>>,
@@ -198,9 +351,11 @@ async fn send_done_chunk(
     model_id: &Arc<String>,
     created: i64,
     finish_reason: chatsafe_common::FinishReason,
+    timings: Option<ResponseTimings>,
+    logprobs: Option<ChoiceLogprobs>,
 ) -> bool {
-    // Send final chunk with finish reason
-    let chunk = create_chunk(
+    // Send final chunk with finish reason and timings
+    let mut chunk = create_chunk(
         request_id,
         model_id,
         created,
@@ -208,6 +363,8 @@ async fn send_done_chunk(
         None,
         Some(finish_reason),
     );
+    chunk.chatsafe = timings;
+    chunk.choices[0].logprobs = logprobs;
 
     if !send_chunk_event(tx, chunk).await {
         return false;
@@ -235,10 +392,27 @@ fn create_chunk(
         model: model_id.to_string(),
         choices: vec![StreamChoice {
             index: 0,
-            delta: DeltaContent { role, content },
+            delta: DeltaContent {
+                role,
+                content,
+                tool_calls: None,
+            },
+            logprobs: None,
             finish_reason,
         }],
+        queue: None,
+        chatsafe: None,
+    }
+}
+
+/// Log probabilities for a chunk, leaving none pending
+fn take_logprobs(pending: &mut Vec<TokenLogprob>) -> Option<ChoiceLogprobs> {
+    if pending.is_empty() {
+        return None;
     }
+    Some(ChoiceLogprobs {
+        content: std::mem::take(pending),
+    })
 }
 
 /// Send a chunk as an SSE event
@@ -260,13 +434,17 @@ async fn send_error_event(
     tx: &tokio::sync::mpsc::Sender<Result<Event, Infallible>>,
     message: String,
     error_type: &str,
+    partial_content_length: Option<usize>,
 ) -> bool {
-    let error_data = json!({
+    let mut error_data = json!({
         "error": {
             "message": message,
             "type": error_type
         }
     });
+    if let Some(length) = partial_content_length {
+        error_data["error"]["partial_content_length"] = json!(length);
+    }
     tx.send(Ok(Event::default().data(error_data.to_string())))
         .await
         .is_ok()
@@ -313,9 +491,14 @@ impl Drop for CleanupGuard {
         let req_id = self.request_id.clone();
 
         // Spawn cleanup task
-        tokio::spawn(async move {
-            limiter.release_request(ip).await;
-            metrics.complete_request(&req_id).await;
-        });
+        crate::supervisor::spawn(
+            metrics.clone(),
+            req_id.clone(),
+            "stream cleanup",
+            async move {
+                limiter.release_request(ip).await;
+                crate::warn_if_slow(metrics.complete_request(&req_id).await);
+            },
+        );
     }
 }
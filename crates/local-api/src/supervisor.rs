@@ -0,0 +1,148 @@
+//! Supervised per-request work
+//!
+//! Work a request spawns (stream production, metrics completion) goes
+//! through here instead of a bare `tokio::spawn`, so a panic is logged with
+//! the request ID and counted rather than vanishing with the detached task.
+//! The `supervise_request` middleware does the same for handlers themselves,
+//! answering with a 500 instead of dropping the connection.
+//!
+//! Every request also gets a tracing span carrying its ID, model, stream
+//! flag and a hash of the client IP. Spawned work inherits the span, so
+//! `RUST_LOG=debug` output from concurrent requests can be told apart.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use chatsafe_common::{Error as CommonError, ObservableMetrics, RequestId};
+use futures::FutureExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::any::Any;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, LazyLock};
+use tracing::{error, field, info_span, Instrument};
+
+// Constants
+const HANDLER_PANIC_MESSAGE: &str = "Unexpected server error while handling the request";
+const CLIENT_HASH_HEX_CHARS: usize = 12;
+
+/// Random per process, so a logged hash can't be matched to an address by
+/// hashing candidate IPs, and hashes from different runs don't line up
+static CLIENT_HASH_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| {
+    let mut key = [0u8; 32];
+    key[..16].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key[16..].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key
+});
+
+/// Middleware running a handler inside its request span, turning a panic
+/// into a 500 `ErrorResponse`
+///
+/// Assigns the request ID here so the error response carries the same ID
+/// the handler would have used; handlers read it from the extensions and
+/// fill in the span's `model` and `stream` fields once the body is parsed.
+pub(crate) async fn supervise_request(
+    State(metrics): State<Arc<ObservableMetrics>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = RequestId::new();
+    request.extensions_mut().insert(request_id.clone());
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        model = field::Empty,
+        stream = field::Empty,
+        client = field::Empty,
+    );
+    if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        span.record("client", client_hash(addr.ip()).as_str());
+    }
+
+    async move {
+        match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+            Ok(response) => response,
+            Err(panic) => {
+                error!("Handler panicked: {}", panic_message(panic.as_ref()));
+                metrics.record_panicked_request().await;
+                crate::create_error_response(
+                    &CommonError::Internal(HANDLER_PANIC_MESSAGE.to_string()),
+                    &request_id,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Short keyed hash of a client IP, so logs can group requests by client
+/// without recording the address
+pub(crate) fn client_hash(ip: IpAddr) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&*CLIENT_HASH_KEY).expect("HMAC accepts any key length");
+    mac.update(ip.to_string().as_bytes());
+    hex::encode(mac.finalize().into_bytes())[..CLIENT_HASH_HEX_CHARS].to_string()
+}
+
+/// Spawn request-scoped work
+pub(crate) fn spawn<F>(
+    metrics: Arc<ObservableMetrics>,
+    request_id: RequestId,
+    task: &'static str,
+    work: F,
+) where
+    F: Future<Output = ()> + Send + 'static,
+{
+    spawn_with_fallback(metrics, request_id, task, work, async {});
+}
+
+/// Spawn request-scoped work, running `fallback` if it panics
+///
+/// The fallback is where a stream sends its terminal error frame, so the
+/// client is told the response ended instead of seeing it stop silently.
+pub(crate) fn spawn_with_fallback<F, P>(
+    metrics: Arc<ObservableMetrics>,
+    request_id: RequestId,
+    task: &'static str,
+    work: F,
+    fallback: P,
+) where
+    F: Future<Output = ()> + Send + 'static,
+    P: Future<Output = ()> + Send + 'static,
+{
+    // Inherit the request span so the task's logs stay attributable
+    tokio::spawn(
+        async move {
+            let Err(panic) = AssertUnwindSafe(work).catch_unwind().await else {
+                return;
+            };
+            error!(
+                request_id = %request_id,
+                task,
+                "Request task panicked: {}",
+                panic_message(panic.as_ref())
+            );
+            metrics.record_panicked_request().await;
+            fallback.await;
+        }
+        .in_current_span(),
+    );
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
@@ -0,0 +1,409 @@
+//! Parameter sweeps for tuning local settings
+//!
+//! `POST /v1/experiments/sweep` runs one conversation across every
+//! combination of the given temperatures, top_p values and models, and
+//! returns each output with its timings. Models can be any served model,
+//! by ID or alias. Runs go one at a time and wait for regular traffic to
+//! drain first, so a sweep never competes with chat requests for the
+//! backend's slots. Each run counts against the caller's rate limit and is
+//! recorded in the metrics like a chat request. Only one sweep runs at a
+//! time.
+//!
+//! With a `webhook` URL the sweep runs in the background and its runs are
+//! delivered to the webhook as a `job.completed` event (see `webhooks`).
+
+use crate::webhooks::JobEvent;
+use crate::{supervisor, AppState, RateLimitGuard};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chatsafe_common::{
+    ChatCompletionRequest, Error as CommonError, FinishReason, GenerationMetadata, Message,
+    RequestId, StreamFrame, Usage,
+};
+use chatsafe_runtime::ModelHandle;
+use futures::StreamExt;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::info;
+
+// Constants
+const MAX_SWEEP_RUNS: usize = 32;
+const IDLE_POLL_MS: u64 = 250;
+/// Longest a run waits for other requests before going ahead anyway
+const MAX_IDLE_WAIT_SECS: u64 = 30;
+
+static SWEEP_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SweepRequest {
+    messages: Vec<Message>,
+    /// Values to try; empty means the model default
+    #[serde(default)]
+    temperature: Vec<f32>,
+    #[serde(default)]
+    top_p: Vec<f32>,
+    /// Served model IDs or aliases to try; empty means the default model
+    #[serde(default)]
+    models: Vec<String>,
+    max_tokens: Option<usize>,
+    /// Run in the background and POST the outcome here
+    webhook: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SweepResponse {
+    runs: Vec<SweepRun>,
+}
+
+/// Outcome of one grid point
+#[derive(Debug, Serialize)]
+struct SweepRun {
+    model: String,
+    temperature: f32,
+    top_p: f32,
+    output: String,
+    finish_reason: Option<FinishReason>,
+    usage: Usage,
+    /// Wall time of the run, excluding the wait for idle
+    total_ms: u64,
+    /// Backend timings and token rates
+    metadata: Option<GenerationMetadata>,
+    error: Option<String>,
+}
+
+/// One combination of the grid, before defaults are applied
+pub(crate) struct GridPoint<'a> {
+    pub(crate) model: &'a str,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_p: Option<f32>,
+}
+
+/// Run the sweep grid and return every output
+pub(crate) async fn run_sweep(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    Json(request): Json<SweepRequest>,
+) -> Result<Response, Response> {
+    let fail = |e: CommonError| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        crate::create_error_response(&e, &request_id, status)
+    };
+
+    let webhook = request
+        .webhook
+        .as_deref()
+        .map(|url| state.webhooks.check_url(url))
+        .transpose()
+        .map_err(fail)?;
+    let Ok(running) = SWEEP_LOCK.try_lock() else {
+        return Err(fail(CommonError::ServiceUnavailable(
+            "A sweep is already running".into(),
+        )));
+    };
+    let models = served_models(&state, &request.models).await.map_err(fail)?;
+
+    let grid = grid(&models, &request.temperature, &request.top_p);
+    if grid.len() > MAX_SWEEP_RUNS {
+        return Err(fail(CommonError::BadRequest(format!(
+            "Sweep has {} runs; at most {} are allowed",
+            grid.len(),
+            MAX_SWEEP_RUNS
+        ))));
+    }
+    for point in &grid {
+        run_request(&request, point).validate().map_err(fail)?;
+    }
+
+    let job_id = request_id.to_string();
+    if webhook.is_some() {
+        info!(
+            "Running sweep {} of {} runs in the background",
+            job_id,
+            grid.len()
+        );
+        let response = json!({ "job_id": job_id, "status": "running" });
+        let metrics = Arc::clone(&state.metrics);
+        supervisor::spawn(metrics, request_id.clone(), "sweep", async move {
+            let _running = running;
+            let _ = run_job(&state, addr.ip(), &request, &models, job_id, webhook).await;
+        });
+        return Ok((StatusCode::ACCEPTED, Json(response)).into_response());
+    }
+
+    info!("Running sweep of {} runs", grid.len());
+    let runs = run_job(&state, addr.ip(), &request, &models, job_id, None)
+        .await
+        .map_err(fail)?;
+    Ok(Json(SweepResponse { runs }).into_response())
+}
+
+/// Model IDs for the requested names, which must all be served
+async fn served_models(state: &AppState, requested: &[String]) -> Result<Vec<String>, CommonError> {
+    if requested.is_empty() {
+        let default = state
+            .model_handle
+            .read()
+            .await
+            .clone()
+            .ok_or(CommonError::RuntimeNotReady)?;
+        return Ok(vec![default.model_id.to_string()]);
+    }
+    let served = state.runtime.handles().await;
+    requested
+        .iter()
+        .map(|name| {
+            let model_id = state.aliases.resolve(name);
+            if served.iter().any(|h| *h.model_id == *model_id) {
+                Ok(model_id.to_string())
+            } else {
+                let serving: Vec<&str> = served.iter().map(|h| &*h.model_id).collect();
+                Err(CommonError::BadRequest(format!(
+                    "Model {} is not loaded; add it to models.serve or use one of: {}",
+                    name,
+                    serving.join(", ")
+                )))
+            }
+        })
+        .collect()
+}
+
+/// The handle serving `model_id` now; models can be unloaded mid-sweep
+async fn serving(state: &AppState, model_id: &str) -> Result<ModelHandle, CommonError> {
+    state
+        .runtime
+        .handles()
+        .await
+        .into_iter()
+        .find(|h| *h.model_id == *model_id)
+        .ok_or_else(|| CommonError::ServiceUnavailable(format!("Model {model_id} is not loaded")))
+}
+
+/// Run the grid and tell the webhooks how it went
+async fn run_job(
+    state: &AppState,
+    ip: IpAddr,
+    request: &SweepRequest,
+    models: &[String],
+    job_id: String,
+    webhook: Option<Url>,
+) -> Result<Vec<SweepRun>, CommonError> {
+    let started = Instant::now();
+    let result = run_grid(state, ip, request, models).await;
+    let event = match &result {
+        Ok(runs) => JobEvent::completed(
+            job_id,
+            "sweep",
+            started.elapsed(),
+            summary(runs),
+            json!({ "runs": runs }),
+        ),
+        Err(e) => JobEvent::failed(job_id, "sweep", started.elapsed(), e.to_string()),
+    };
+    state.webhooks.notify(event, webhook);
+    result
+}
+
+async fn run_grid(
+    state: &AppState,
+    ip: IpAddr,
+    request: &SweepRequest,
+    models: &[String],
+) -> Result<Vec<SweepRun>, CommonError> {
+    let grid = grid(models, &request.temperature, &request.top_p);
+    let mut runs = Vec::with_capacity(grid.len());
+    for point in &grid {
+        wait_for_idle(state).await;
+        let params = state.registry.apply_overrides(
+            point.model,
+            point.temperature,
+            request.max_tokens,
+            point.top_p,
+            None,
+            None,
+        )?;
+        runs.push(run_point(state, ip, &request.messages, point, params).await);
+    }
+    Ok(runs)
+}
+
+/// Totals over a sweep's runs, for webhooks
+fn summary(runs: &[SweepRun]) -> serde_json::Value {
+    json!({
+        "runs": runs.len(),
+        "failed": runs.iter().filter(|run| run.error.is_some()).count(),
+        "prompt_tokens": runs.iter().map(|run| run.usage.prompt_tokens).sum::<usize>(),
+        "completion_tokens": runs.iter().map(|run| run.usage.completion_tokens).sum::<usize>(),
+        "total_ms": runs.iter().map(|run| run.total_ms).sum::<u64>()
+    })
+}
+
+/// Every combination of the values, with `None` standing for the default
+pub(crate) fn grid<'a>(
+    models: &'a [String],
+    temperatures: &[f32],
+    top_ps: &[f32],
+) -> Vec<GridPoint<'a>> {
+    let or_default = |values: &[f32]| -> Vec<Option<f32>> {
+        if values.is_empty() {
+            vec![None]
+        } else {
+            values.iter().copied().map(Some).collect()
+        }
+    };
+    let temperatures = or_default(temperatures);
+    let top_ps = or_default(top_ps);
+
+    let mut points = Vec::new();
+    for model in models {
+        for &temperature in &temperatures {
+            for &top_p in &top_ps {
+                points.push(GridPoint {
+                    model,
+                    temperature,
+                    top_p,
+                });
+            }
+        }
+    }
+    points
+}
+
+/// The chat request one grid point amounts to, for validation
+fn run_request(request: &SweepRequest, point: &GridPoint) -> ChatCompletionRequest {
+    ChatCompletionRequest {
+        model: Some(point.model.to_string()),
+        messages: request.messages.clone(),
+        temperature: point.temperature,
+        max_tokens: request.max_tokens,
+        stream: Some(false),
+        top_p: point.top_p,
+        ..Default::default()
+    }
+}
+
+/// Wait until no chat requests are in flight, up to `MAX_IDLE_WAIT_SECS`
+async fn wait_for_idle(state: &AppState) {
+    let give_up = Instant::now() + Duration::from_secs(MAX_IDLE_WAIT_SECS);
+    while state.metrics.active_requests().await > 0 && Instant::now() < give_up {
+        tokio::time::sleep(Duration::from_millis(IDLE_POLL_MS)).await;
+    }
+}
+
+async fn run_point(
+    state: &AppState,
+    ip: IpAddr,
+    messages: &[Message],
+    point: &GridPoint<'_>,
+    mut params: chatsafe_common::GenerationParams,
+) -> SweepRun {
+    let request_id = RequestId::new();
+    params.request_id = request_id.to_string();
+    let tracked_request_id = state
+        .metrics
+        .start_request(request_id.clone(), point.model.to_string(), false)
+        .await;
+    let mut run = SweepRun {
+        model: point.model.to_string(),
+        temperature: params.temperature,
+        top_p: params.top_p,
+        output: String::new(),
+        finish_reason: None,
+        usage: Usage::default(),
+        total_ms: 0,
+        metadata: None,
+        error: None,
+    };
+
+    if let Err(e) = state.rate_limiter.check_rate_limit(ip).await {
+        state.metrics.record_rate_limit(ip.to_string()).await;
+        return finish_run(state, run, &request_id, &tracked_request_id, e).await;
+    }
+    let _rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
+    let handle = match serving(state, point.model).await {
+        Ok(handle) => handle,
+        Err(e) => return finish_run(state, run, &request_id, &tracked_request_id, e).await,
+    };
+    let _permit = match state
+        .chat_slots
+        .for_model(&handle.model_id)
+        .acquire(Instant::now() + state.request_timeout, params.max_tokens)
+        .await
+    {
+        Ok(permit) => permit,
+        Err(e) => return finish_run(state, run, &request_id, &tracked_request_id, e).await,
+    };
+    state
+        .metrics
+        .record_generation_started(&tracked_request_id)
+        .await;
+    let started = Instant::now();
+    let cache_prompt = params.cache_prompt;
+    match state
+        .runtime
+        .generate(&handle, messages.to_vec(), params)
+        .await
+    {
+        Ok(mut generation) => {
+            while let Some(frame) = generation.stream.next().await {
+                match frame {
+                    Ok(StreamFrame::Delta { content }) => run.output.push_str(&content),
+                    Ok(StreamFrame::Done {
+                        finish_reason,
+                        usage,
+                    }) => {
+                        state
+                            .metrics
+                            .record_tokens(
+                                &tracked_request_id,
+                                usage.prompt_tokens as u64,
+                                usage.completion_tokens as u64,
+                            )
+                            .await;
+                        run.finish_reason = Some(finish_reason);
+                        run.usage = usage;
+                    }
+                    Ok(StreamFrame::Error { message, .. }) => run.error = Some(message),
+                    Err(e) => run.error = Some(e.to_string()),
+                    _ => {}
+                }
+            }
+            run.metadata = generation.metadata.try_recv().ok();
+            if let Some(metadata) = &run.metadata {
+                crate::record_generation_metadata(&state.metrics, metadata, cache_prompt).await;
+            }
+        }
+        Err(e) => run.error = Some(e.to_string()),
+    }
+    run.total_ms = started.elapsed().as_millis() as u64;
+    if let Some(message) = &run.error {
+        let e = CommonError::RuntimeError(message.clone());
+        state.metrics.record_error(Some(&request_id), &e).await;
+    }
+    state.metrics.complete_request(&tracked_request_id).await;
+    run
+}
+
+/// End a run that failed before generating
+async fn finish_run(
+    state: &AppState,
+    mut run: SweepRun,
+    request_id: &RequestId,
+    tracked_request_id: &RequestId,
+    e: CommonError,
+) -> SweepRun {
+    state.metrics.record_error(Some(request_id), &e).await;
+    state.metrics.complete_request(tracked_request_id).await;
+    run.error = Some(e.to_string());
+    run
+}
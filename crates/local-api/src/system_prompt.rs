@@ -0,0 +1,43 @@
+//! Lines added to the system prompt
+//!
+//! The date context, content profiles and response languages add their
+//! instructions to the conversation's leading system message. Without one,
+//! a system message is added that starts with the template's default system
+//! prompt, which the template would otherwise have used.
+
+use crate::AppState;
+use chatsafe_common::{Message, Role};
+use chatsafe_runtime::TemplateEngine;
+use std::collections::HashMap;
+
+/// Append to the leading system message, or add one that starts with
+/// `default_prompt`, the template's default system prompt
+pub(crate) fn append(messages: &mut Vec<Message>, line: &str, default_prompt: &str) {
+    match messages.first_mut() {
+        Some(first) if first.role == Role::System => {
+            first.content.push_str("\n\n");
+            first.content.push_str(line);
+        }
+        _ if default_prompt.is_empty() => messages.insert(0, Message::new(Role::System, line)),
+        _ => messages.insert(
+            0,
+            Message::new(Role::System, format!("{}\n\n{}", default_prompt, line)),
+        ),
+    }
+}
+
+/// The model template's default system prompt with `variables` filled in
+pub(crate) fn template_default(
+    state: &AppState,
+    model_id: &str,
+    variables: &HashMap<String, String>,
+) -> String {
+    state
+        .registry
+        .get_model_template(model_id)
+        .map(|template| {
+            TemplateEngine::render_variables(&template.default_system_prompt, variables, template)
+                .into_owned()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,178 @@
+//! OpenAI-compatible speech-to-text
+//!
+//! `POST /v1/audio/transcriptions` takes the same `multipart/form-data`
+//! upload as OpenAI's endpoint and hands the audio to the whisper-server of
+//! the registry's `transcribe` model, which starts on the first request.
+//! Transcriptions run one at a time.
+
+use crate::{create_error_response, AppState, RateLimitGuard};
+use axum::{
+    body::Bytes,
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        ConnectInfo, Multipart, State,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chatsafe_common::{Error as CommonError, RequestId};
+use chatsafe_runtime::{TranscriptFormat, TranscriptionRequest};
+use serde_json::json;
+use std::net::SocketAddr;
+use tracing::info;
+
+// Constants
+/// Largest accepted upload, matching OpenAI's limit
+pub(crate) const MAX_AUDIO_BYTES: usize = 25 * 1024 * 1024;
+/// Model name OpenAI clients send; served by whichever model is configured
+const OPENAI_MODEL_ALIAS: &str = "whisper-1";
+const SUBTITLES_VTT_CONTENT_TYPE: &str = "text/vtt; charset=utf-8";
+
+/// One field of a `multipart/form-data` body
+#[derive(Debug)]
+pub(crate) struct FormPart {
+    pub(crate) name: String,
+    pub(crate) file_name: Option<String>,
+    pub(crate) data: Bytes,
+}
+
+/// Transcribe an uploaded audio file
+pub(crate) async fn create_transcription(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(request_id): Extension<RequestId>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, Response> {
+    let ip = addr.ip();
+    let fail = |e: CommonError| {
+        let status =
+            StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        create_error_response(&e, &request_id, status)
+    };
+
+    if let Err(e) = state.rate_limiter.check_rate_limit(ip).await {
+        state.metrics.record_error(Some(&request_id), &e).await;
+        state.metrics.record_rate_limit(ip.to_string()).await;
+        return Err(fail(e));
+    }
+    let _rate_guard = RateLimitGuard::new(state.rate_limiter.clone(), ip);
+
+    let parts = match multipart {
+        Ok(multipart) => read_form(multipart).await,
+        Err(_) => {
+            let e = CommonError::BadRequest("Expected a multipart/form-data upload".into());
+            state.metrics.record_error(Some(&request_id), &e).await;
+            return Err(fail(e));
+        }
+    };
+    let result = match parts {
+        Ok(parts) => transcribe(&state, &parts).await,
+        // Over MAX_AUDIO_BYTES: answered like any other oversized body
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => return Err(e.into_response()),
+        Err(e) => Err(CommonError::BadRequest(format!(
+            "Invalid multipart body: {}",
+            e.body_text()
+        ))),
+    };
+    if let Err(e) = &result {
+        state.metrics.record_error(Some(&request_id), e).await;
+    }
+    result.map_err(fail)
+}
+
+async fn transcribe(state: &AppState, parts: &[FormPart]) -> Result<Response, CommonError> {
+    let transcriber = state.transcriber.as_ref().ok_or_else(|| {
+        CommonError::ServiceUnavailable(
+            "No transcription model configured; add a registry model with capability: transcribe"
+                .into(),
+        )
+    })?;
+
+    let field = |name: &str| -> Result<Option<String>, CommonError> {
+        parts
+            .iter()
+            .find(|part| part.name == name)
+            .map(|part| {
+                String::from_utf8(part.data.to_vec()).map_err(|_| {
+                    CommonError::BadRequest(format!("Field {} is not valid UTF-8", name))
+                })
+            })
+            .transpose()
+    };
+
+    let file = parts
+        .iter()
+        .find(|part| part.name == "file")
+        .ok_or_else(|| CommonError::BadRequest("Missing file field".into()))?;
+    if file.data.is_empty() {
+        return Err(CommonError::BadRequest("Audio file is empty".into()));
+    }
+    let temperature = field("temperature")?
+        .map(|t| {
+            t.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|t| (0.0..=1.0).contains(t))
+                .ok_or_else(|| CommonError::BadRequest(format!("Invalid temperature {:?}", t)))
+        })
+        .transpose()?;
+    let format = match field("response_format")? {
+        None => TranscriptFormat::Json,
+        Some(name) => TranscriptFormat::parse(&name).ok_or_else(|| {
+            CommonError::BadRequest(format!(
+                "Unsupported response_format {:?}; use json, text, srt, vtt or verbose_json",
+                name
+            ))
+        })?,
+    };
+
+    let mut transcriber = transcriber.lock().await;
+    if let Some(model) = field("model")? {
+        if model != OPENAI_MODEL_ALIAS && model != transcriber.model_id() {
+            return Err(CommonError::ModelNotFound(model));
+        }
+    }
+
+    info!(
+        "Transcribing {} bytes with {}",
+        file.data.len(),
+        transcriber.model_id()
+    );
+    let transcription = transcriber
+        .transcribe(TranscriptionRequest {
+            audio: file.data.to_vec(),
+            file_name: file.file_name.clone().unwrap_or_else(|| "audio".into()),
+            language: field("language")?,
+            prompt: field("prompt")?,
+            temperature,
+            format,
+        })
+        .await?;
+
+    Ok(match (format, transcription.verbose) {
+        (TranscriptFormat::Json, _) => Json(json!({ "text": transcription.text })).into_response(),
+        (TranscriptFormat::VerboseJson, Some(verbose)) => Json(verbose).into_response(),
+        (TranscriptFormat::Vtt, _) => (
+            [(header::CONTENT_TYPE, SUBTITLES_VTT_CONTENT_TYPE)],
+            transcription.text,
+        )
+            .into_response(),
+        _ => transcription.text.into_response(),
+    })
+}
+
+/// Read every field of the upload into memory
+pub(crate) async fn read_form(mut multipart: Multipart) -> Result<Vec<FormPart>, MultipartError> {
+    let mut parts = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let file_name = field.file_name().map(str::to_string);
+        parts.push(FormPart {
+            name,
+            file_name,
+            data: field.bytes().await?,
+        });
+    }
+    Ok(parts)
+}
@@ -0,0 +1,193 @@
+//! Notifications for long-running jobs
+//!
+//! A sweep sent with a `webhook` URL runs in the background: the request
+//! returns `202` with its job ID at once, and the outcome is POSTed to that
+//! URL when the job ends. Every finished job, background or not, is also
+//! POSTed to each `server.webhooks` entry, so scripts don't have to poll;
+//! that includes chat completions answered with `202` (see `deferred`).
+//!
+//! Only loopback URLs are accepted unless `server.allow_remote_webhooks` is
+//! set, since a notification can carry generated text. Redirects are not
+//! followed, so a loopback URL cannot forward the text elsewhere. Delivery is
+//! retried a few times; failures are logged and otherwise ignored.
+
+use chatsafe_common::{Error as CommonError, Result};
+use reqwest::Url;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+// Constants
+const DELIVERY_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of a job as POSTed to webhooks
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct JobEvent {
+    /// `job.completed` or `job.failed`
+    pub(crate) event: &'static str,
+    pub(crate) job_id: String,
+    /// What kind of job ran, e.g. `sweep`
+    pub(crate) kind: &'static str,
+    /// Unix seconds
+    pub(crate) finished_at: u64,
+    pub(crate) duration_ms: u64,
+    /// Counts and totals describing the job
+    pub(crate) summary: serde_json::Value,
+    /// The job's full result, as the synchronous endpoint would return it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+impl JobEvent {
+    pub(crate) fn completed(
+        job_id: String,
+        kind: &'static str,
+        duration: Duration,
+        summary: serde_json::Value,
+        result: serde_json::Value,
+    ) -> Self {
+        Self {
+            summary,
+            result: Some(result),
+            ..Self::new("job.completed", job_id, kind, duration)
+        }
+    }
+
+    pub(crate) fn failed(
+        job_id: String,
+        kind: &'static str,
+        duration: Duration,
+        error: String,
+    ) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new("job.failed", job_id, kind, duration)
+        }
+    }
+
+    fn new(event: &'static str, job_id: String, kind: &'static str, duration: Duration) -> Self {
+        Self {
+            event,
+            job_id,
+            kind,
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_ms: duration.as_millis() as u64,
+            summary: serde_json::json!({}),
+            result: None,
+            error: None,
+        }
+    }
+}
+
+/// Sends job events to the configured and per-job webhooks
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+    client: reqwest::Client,
+    urls: Vec<Url>,
+    allow_remote: bool,
+}
+
+impl Notifier {
+    /// Check the `server.webhooks` entries
+    pub(crate) fn new(urls: &[String], allow_remote: bool) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| CommonError::ConfigError(format!("Webhook client: {}", e)))?;
+        let urls = urls
+            .iter()
+            .map(|url| {
+                check_url(url, allow_remote)
+                    .map_err(|e| CommonError::ConfigError(format!("server.webhooks: {}", e)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            client,
+            urls,
+            allow_remote,
+        })
+    }
+
+    /// Parse a per-job webhook URL
+    pub(crate) fn check_url(&self, url: &str) -> Result<Url> {
+        check_url(url, self.allow_remote)
+    }
+
+    /// Deliver `event` to every configured webhook and `extra`, in the background
+    pub(crate) fn notify(&self, event: JobEvent, extra: Option<Url>) {
+        for url in self.urls.iter().cloned().chain(extra) {
+            let client = self.client.clone();
+            let event = event.clone();
+            tokio::spawn(async move { deliver(&client, url, &event).await });
+        }
+    }
+}
+
+/// Parse a webhook URL, refusing other hosts unless they are allowed
+fn check_url(url: &str, allow_remote: bool) -> Result<Url> {
+    let parsed = Url::parse(url)
+        .map_err(|e| CommonError::BadRequest(format!("Invalid webhook URL {}: {}", url, e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(CommonError::BadRequest(format!(
+            "Webhook URL {} must use http or https",
+            url
+        )));
+    }
+    if !allow_remote && !is_loopback(&parsed) {
+        return Err(CommonError::BadRequest(format!(
+            "Webhook URL {} is not on this machine; set server.allow_remote_webhooks to allow it",
+            url
+        )));
+    }
+    Ok(parsed)
+}
+
+async fn deliver(client: &reqwest::Client, url: Url, event: &JobEvent) {
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        match client.post(url.clone()).json(event).send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!(
+                    "Delivered {} for job {} to {}",
+                    event.event, event.job_id, url
+                );
+                return;
+            }
+            Ok(response) => warn!(
+                "Webhook {} answered {} for job {} (attempt {}/{})",
+                url,
+                response.status(),
+                event.job_id,
+                attempt,
+                DELIVERY_ATTEMPTS
+            ),
+            Err(e) => warn!(
+                "Webhook {} failed for job {} (attempt {}/{}): {}",
+                url, event.job_id, attempt, DELIVERY_ATTEMPTS, e
+            ),
+        }
+        if attempt < DELIVERY_ATTEMPTS {
+            tokio::time::sleep(RETRY_DELAY * attempt).await;
+        }
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
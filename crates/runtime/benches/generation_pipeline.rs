@@ -0,0 +1,97 @@
+//! Streaming generation pipeline benchmarks
+//!
+//! Feeds a recorded-shape llama-server SSE stream through the adapter's
+//! parser and frame processing, split into network-sized chunks.
+
+use bytes::Bytes;
+use chatsafe_common::StreamBoundary;
+use chatsafe_config::ModelRegistry;
+use chatsafe_runtime::sse::{self, SseParser};
+use chatsafe_runtime::{LlamaAdapter, StreamProcessState};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::convert::Infallible;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+const TOKENS: usize = 512;
+const CHUNK_SIZES: [usize; 3] = [64, 512, 4096];
+
+/// SSE body with one event per token and a final stop chunk
+fn sse_body(tokens: usize) -> Vec<u8> {
+    let mut body = String::new();
+    for i in 0..tokens {
+        let content = if i % 7 == 0 { " héllo" } else { " word" };
+        body.push_str(&format!(
+            "data: {{\"content\":\"{}\",\"stop\":false,\"id_slot\":0}}\n\n",
+            content
+        ));
+    }
+    body.push_str(
+        "data: {\"content\":\"\",\"stop\":true,\"id_slot\":0,\"tokens_evaluated\":42,\
+         \"timings\":{\"prompt_n\":42,\"prompt_ms\":12.5,\"predicted_n\":512,\
+         \"predicted_ms\":4000.0,\"predicted_per_second\":128.0}}\n\n",
+    );
+    body.into_bytes()
+}
+
+fn chunked(body: &[u8], size: usize) -> Vec<Bytes> {
+    body.chunks(size).map(Bytes::copy_from_slice).collect()
+}
+
+fn bench_sse_parser(c: &mut Criterion) {
+    let body = sse_body(TOKENS);
+    let mut group = c.benchmark_group("sse_parser");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    for size in CHUNK_SIZES {
+        let chunks = chunked(&body, size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &chunks, |b, chunks| {
+            b.iter(|| {
+                let mut parser = SseParser::new();
+                let mut payloads = 0;
+                for chunk in chunks {
+                    parser.push(chunk);
+                    while let Some(event) = parser.next_event() {
+                        payloads += sse::data_payloads(&event).count();
+                    }
+                }
+                payloads
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_stream_pipeline(c: &mut Criterion) {
+    let registry = ModelRegistry::load_defaults().expect("default registry");
+    let template = Arc::new(registry.get_template("llama3").expect("llama3").clone());
+    let stop_sequences = Arc::new(template.stop_tokens.clone());
+    let eos_token = Arc::new("<|eot_id|>".to_string());
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
+
+    let body = sse_body(TOKENS);
+    let mut group = c.benchmark_group("stream_pipeline");
+    group.throughput(Throughput::Elements(TOKENS as u64));
+
+    for size in CHUNK_SIZES {
+        let chunks = chunked(&body, size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &chunks, |b, chunks| {
+            b.to_async(&runtime).iter(|| async {
+                let stream = futures::stream::iter(chunks.iter().cloned().map(Ok::<_, Infallible>));
+                let state = StreamProcessState::new(
+                    Arc::clone(&template),
+                    Arc::clone(&stop_sequences),
+                    Arc::clone(&eos_token),
+                    StreamBoundary::Token,
+                );
+                LlamaAdapter::process_sse_stream(stream, state, 0, Arc::new(AtomicBool::new(false)))
+                    .await
+                    .expect("pipeline")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sse_parser, bench_stream_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,147 @@
+//! Template engine benchmarks
+//!
+//! Long multi-turn fixtures for prompt formatting and the cleaning paths.
+//! `process_stream_chunk` runs once per streamed token, so the streaming
+//! benches feed a whole response token by token.
+
+use chatsafe_common::{Message, Role};
+use chatsafe_config::{ModelRegistry, TemplateConfig};
+use chatsafe_runtime::{StreamState, TemplateEngine};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+const TEMPLATES: [&str; 3] = ["llama3", "chatml", "alpaca"];
+const TURN_COUNTS: [usize; 3] = [4, 32, 128];
+const RESPONSE_SENTENCES: usize = 200;
+
+const PARAGRAPH: &str = "The quick brown fox jumps over the lazy dog while the model \
+    explains, step by step, how the answer was derived and which caveats apply. ";
+
+fn template(id: &str) -> TemplateConfig {
+    ModelRegistry::load_defaults()
+        .expect("default registry")
+        .get_template(id)
+        .expect("template")
+        .clone()
+}
+
+/// Conversation alternating user/assistant turns after a system prompt
+fn conversation(turns: usize) -> Vec<Message> {
+    let mut messages = vec![Message::new(Role::System, "You are a helpful assistant.")];
+    for i in 0..turns {
+        let role = if i % 2 == 0 {
+            Role::User
+        } else {
+            Role::Assistant
+        };
+        messages.push(Message::new(role, PARAGRAPH.repeat(1 + i % 4)));
+    }
+    messages
+}
+
+/// Model output with echoed markers and role prefixes sprinkled in
+fn polluted_response() -> String {
+    let mut response = String::new();
+    for i in 0..RESPONSE_SENTENCES {
+        match i % 50 {
+            10 => response.push_str("Assistant: "),
+            25 => response.push_str("<|im_end|>"),
+            40 => response.push_str("\n### Response:\n"),
+            _ => {}
+        }
+        response.push_str(PARAGRAPH);
+        if i % 5 == 4 {
+            response.push('\n');
+        }
+    }
+    response
+}
+
+/// Split text into token-sized pieces on char boundaries
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for ch in text.chars() {
+        current.push(ch);
+        if ch == ' ' || current.len() >= 6 {
+            tokens.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn bench_format_prompt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("format_prompt");
+    for id in TEMPLATES {
+        let template = template(id);
+        for turns in TURN_COUNTS {
+            let messages = conversation(turns);
+            group.bench_with_input(BenchmarkId::new(id, turns), &messages, |b, messages| {
+                b.iter(|| TemplateEngine::format_prompt(black_box(messages), &template))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_clean_response(c: &mut Criterion) {
+    let response = polluted_response();
+    let mut group = c.benchmark_group("clean_response");
+    group.throughput(Throughput::Bytes(response.len() as u64));
+
+    for id in TEMPLATES {
+        let template = template(id);
+        let stop_sequences = template.stop_tokens.clone();
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                TemplateEngine::clean_response(
+                    black_box(&response),
+                    &template,
+                    &stop_sequences,
+                    "<|eot_id|>",
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_process_stream_chunk(c: &mut Criterion) {
+    let mut text = PARAGRAPH.repeat(RESPONSE_SENTENCES / 4);
+    text.push_str("<|eot_id|>");
+    let tokens = tokens(&text);
+
+    let mut group = c.benchmark_group("process_stream_chunk");
+    group.throughput(Throughput::Elements(tokens.len() as u64));
+
+    for id in TEMPLATES {
+        let template = template(id);
+        let stop_sequences = template.stop_tokens.clone();
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                let mut state = StreamState::new(&stop_sequences, "<|eot_id|>");
+                for token in &tokens {
+                    black_box(TemplateEngine::process_stream_chunk(
+                        token,
+                        &template,
+                        &stop_sequences,
+                        "<|eot_id|>",
+                        &mut state,
+                    ));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_format_prompt,
+    bench_clean_response,
+    bench_process_stream_chunk
+);
+criterion_main!(benches);
@@ -0,0 +1,140 @@
+//! Safety classification with a Llama Guard style model
+//!
+//! The guard model runs on its own llama-server. Each check sends the
+//! conversation in Llama Guard 3's prompt format to `/completion` and reads
+//! back `safe`, or `unsafe` followed by a line of violated categories
+//! (`S1,S10`). Only user and assistant turns are shown to the model, with
+//! template markers and the prompt's `<BEGIN …>`/`<END …>` delimiters taken
+//! out so the checked text cannot rewrite the classifier's instructions.
+
+use crate::TemplateEngine;
+use chatsafe_common::{Error, Message, Result, Role};
+
+// Constants
+const CATEGORIES: &[(&str, &str)] = &[
+    ("S1", "Violent Crimes."),
+    ("S2", "Non-Violent Crimes."),
+    ("S3", "Sex Crimes."),
+    ("S4", "Child Sexual Exploitation."),
+    ("S5", "Defamation."),
+    ("S6", "Specialized Advice."),
+    ("S7", "Privacy."),
+    ("S8", "Intellectual Property."),
+    ("S9", "Indiscriminate Weapons."),
+    ("S10", "Hate."),
+    ("S11", "Suicide & Self-Harm."),
+    ("S12", "Sexual Content."),
+    ("S13", "Elections."),
+    ("S14", "Code Interpreter Abuse."),
+];
+/// Openings of the prompt's section delimiters, lowercase
+const DELIMITER_OPENINGS: &[&str] = &["<begin", "<end"];
+/// Tokens a verdict needs: `unsafe`, a newline and a few categories
+pub(crate) const VERDICT_MAX_TOKENS: usize = 24;
+
+/// Whose turn a check is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardTarget {
+    /// The conversation's last user message
+    Prompt,
+    /// The last assistant message
+    Response,
+}
+
+impl GuardTarget {
+    /// What is checked, e.g. for error messages
+    pub fn noun(self) -> &'static str {
+        match self {
+            Self::Prompt => "prompt",
+            Self::Response => "response",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Prompt => "User",
+            Self::Response => "Agent",
+        }
+    }
+}
+
+/// The guard model's answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classification {
+    pub safe: bool,
+    /// Violated categories, e.g. `S1`; empty when safe
+    pub categories: Vec<String>,
+}
+
+/// Llama Guard 3 prompt asking about the last `target` turn of `messages`
+pub fn prompt(messages: &[Message], target: GuardTarget) -> String {
+    let role = target.label();
+    let mut text = format!(
+        "<|begin_of_text|><|start_header_id|>user<|end_header_id|>\n\n\
+         Task: Check if there is unsafe content in '{}' messages in conversations \
+         according our safety policy with the below categories.\n\n\
+         <BEGIN UNSAFE CONTENT CATEGORIES>\n",
+        role
+    );
+    for (id, name) in CATEGORIES {
+        text.push_str(&format!("{}: {}\n", id, name));
+    }
+    text.push_str("<END UNSAFE CONTENT CATEGORIES>\n\n<BEGIN CONVERSATION>\n\n");
+    for message in messages {
+        let speaker = match message.role {
+            Role::User => GuardTarget::Prompt.label(),
+            Role::Assistant => GuardTarget::Response.label(),
+            _ => continue,
+        };
+        text.push_str(&format!("{}: {}\n\n", speaker, quote(&message.content)));
+    }
+    text.push_str(&format!(
+        "<END CONVERSATION>\n\n\
+         Provide your safety assessment for ONLY THE LAST {} message in the above conversation:\n \
+         - First line must read 'safe' or 'unsafe'.\n \
+         - If unsafe, a second line must include a comma-separated list of violated categories.\
+         <|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n",
+        role
+    ));
+    text
+}
+
+/// A turn's text as it may appear inside the conversation section
+fn quote(content: &str) -> String {
+    let mut text = TemplateEngine::strip_template_markers(content);
+    // Dropping a `<` can line up another delimiter, so repeat until none is left
+    while let Some(at) = DELIMITER_OPENINGS
+        .iter()
+        .filter_map(|opening| text.to_ascii_lowercase().find(opening))
+        .min()
+    {
+        text.remove(at);
+    }
+    text.trim().to_string()
+}
+
+/// Read the guard model's reply
+pub fn parse(reply: &str) -> Result<Classification> {
+    let mut lines = reply.lines().map(str::trim).filter(|line| !line.is_empty());
+    match lines.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("safe") => Ok(Classification {
+            safe: true,
+            categories: Vec::new(),
+        }),
+        Some("unsafe") => Ok(Classification {
+            safe: false,
+            categories: lines
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|category| !category.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }),
+        _ => Err(Error::RuntimeError(format!(
+            "Guard model gave no verdict: {:?}",
+            chatsafe_common::text::truncate_bytes(reply, 80)
+        ))),
+    }
+}
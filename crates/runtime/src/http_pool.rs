@@ -0,0 +1,105 @@
+//! Shared HTTP clients for talking to llama-server
+//!
+//! Both clients are built once per adapter so keep-alive connections to the
+//! backend are reused across requests instead of reconnecting every time.
+//! reqwest does not expose its pool internals, so the counters here track
+//! what goes through it: requests sent and how many needed a failed connect.
+//! Generation timeouts come from `RuntimeConfig`; probes keep short fixed ones.
+
+use chatsafe_common::{BackendPoolStats, Error, Result};
+use chatsafe_config::RuntimeConfig;
+use reqwest::{Client, RequestBuilder, Response};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Constants
+const HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
+const HEALTH_CHECK_CONNECT_TIMEOUT_MS: u64 = 500;
+const POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+const POOL_MAX_IDLE_PER_HOST: usize = 8;
+
+#[derive(Default)]
+struct PoolCounters {
+    requests: AtomicU64,
+    in_flight: AtomicU64,
+    connect_errors: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+/// Generation and probe clients sharing one set of counters
+#[derive(Clone)]
+pub(crate) struct BackendClients {
+    generation: Client,
+    probe: Client,
+    counters: Arc<PoolCounters>,
+}
+
+impl BackendClients {
+    pub(crate) fn new(config: &RuntimeConfig) -> Result<Self> {
+        let generation = Client::builder()
+            .timeout(Duration::from_secs(config.generation_timeout_secs))
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .build()
+            .map_err(|e| Error::RuntimeError(format!("Failed to create HTTP client: {}", e)))?;
+
+        let probe = Client::builder()
+            .timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS))
+            .connect_timeout(Duration::from_millis(HEALTH_CHECK_CONNECT_TIMEOUT_MS))
+            .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+            .build()
+            .map_err(|e| {
+                Error::RuntimeError(format!("Failed to create health check client: {}", e))
+            })?;
+
+        Ok(Self {
+            generation,
+            probe,
+            counters: Arc::new(PoolCounters::default()),
+        })
+    }
+
+    /// Client with the long generation timeout
+    pub(crate) fn generation(&self) -> &Client {
+        &self.generation
+    }
+
+    /// Client with short timeouts for health and metadata probes
+    pub(crate) fn probe(&self) -> &Client {
+        &self.probe
+    }
+
+    /// Send a request built from either client, counting the outcome
+    pub(crate) async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let counters = &self.counters;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = scopeguard::guard((), |_| {
+            counters.in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        let result = request.send().await;
+        if let Err(e) = &result {
+            if e.is_connect() {
+                counters.connect_errors.fetch_add(1, Ordering::Relaxed);
+            } else if e.is_timeout() {
+                counters.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    pub(crate) fn stats(&self) -> BackendPoolStats {
+        BackendPoolStats {
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            awaiting_response: self.counters.in_flight.load(Ordering::Relaxed),
+            connect_errors: self.counters.connect_errors.load(Ordering::Relaxed),
+            timeouts: self.counters.timeouts.load(Ordering::Relaxed),
+            max_idle_per_host: POOL_MAX_IDLE_PER_HOST,
+            idle_timeout_secs: POOL_IDLE_TIMEOUT_SECS,
+            instances: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! llama-server instances serving one model, and routing between them
+//!
+//! Each instance has its own process, cached health and in-flight count.
+//! A generation gets every instance as an ordered list of routes: healthy
+//! ones first in balancing order, unhealthy ones last, so a connect failure
+//! can fail over before any tokens are produced. An instance that refused a
+//! connection is probed in the background until it answers again.
+
+use crate::http_pool::BackendClients;
+use crate::process_manager::{ExitWatch, OutputTail, ProcessManager};
+use crate::resource_sampler::ResourceSampler;
+use chatsafe_config::{InstanceConfig, LoadBalancing};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+// Constants
+const HEALTH_CACHE_TTL_MS: u64 = 2000;
+const REPROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Last live probe of an instance, reused for `HEALTH_CACHE_TTL_MS`
+#[derive(Default)]
+struct HealthCache {
+    checked_at: Option<Instant>,
+    last_success: Option<SystemTime>,
+}
+
+/// Counters shared between an instance and the generations routed to it
+#[derive(Default)]
+pub(crate) struct InstanceLoad {
+    in_flight: AtomicUsize,
+    healthy: AtomicBool,
+    /// A background task is probing the instance back into rotation
+    reprobing: AtomicBool,
+    oom_kills: AtomicU64,
+}
+
+impl InstanceLoad {
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_unhealthy(&self) {
+        self.healthy.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn oom_kills(&self) -> u64 {
+        self.oom_kills.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_oom_kill(&self) {
+        self.oom_kills.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a generation against this instance until the guard drops
+    pub(crate) fn acquire(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.clone())
+    }
+}
+
+pub(crate) struct InFlightGuard(Arc<InstanceLoad>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// One llama-server process (or external endpoint)
+pub(crate) struct Instance {
+    pub(crate) config: InstanceConfig,
+    pub(crate) url: String,
+    pub(crate) process: ProcessManager,
+    pub(crate) resources: ResourceSampler,
+    load: Arc<InstanceLoad>,
+    health: Mutex<HealthCache>,
+}
+
+impl Instance {
+    pub(crate) fn new(config: InstanceConfig) -> Self {
+        Self {
+            url: config.server_url(),
+            process: ProcessManager::new(format!("llama-server:{}", config.port)),
+            config,
+            resources: ResourceSampler::default(),
+            load: Arc::new(InstanceLoad::default()),
+            health: Mutex::new(HealthCache::default()),
+        }
+    }
+
+    pub(crate) fn load(&self) -> &Arc<InstanceLoad> {
+        &self.load
+    }
+
+    /// Health from the cache, probing if it is older than the TTL
+    ///
+    /// Holding the lock across the probe collapses concurrent checks into one.
+    pub(crate) async fn health(&self, clients: &BackendClients) -> (bool, Option<SystemTime>) {
+        let mut cache = self.health.lock().await;
+        let fresh = cache
+            .checked_at
+            .is_some_and(|at| at.elapsed() < Duration::from_millis(HEALTH_CACHE_TTL_MS));
+        if !fresh {
+            self.probe_locked(clients, &mut cache).await;
+        }
+        (self.load.is_healthy(), cache.last_success)
+    }
+
+    /// Probe `/health` now, bypassing the cache
+    pub(crate) async fn probe(&self, clients: &BackendClients) -> bool {
+        let mut cache = self.health.lock().await;
+        self.probe_locked(clients, &mut cache).await;
+        self.load.is_healthy()
+    }
+
+    async fn probe_locked(&self, clients: &BackendClients, cache: &mut HealthCache) {
+        let healthy = probe_health(clients, &self.url).await;
+        self.load.healthy.store(healthy, Ordering::Relaxed);
+        cache.checked_at = Some(Instant::now());
+        if healthy {
+            cache.last_success = Some(SystemTime::now());
+        }
+    }
+
+    /// Forget the cached probe so the next health check goes to the server
+    pub(crate) fn invalidate_health(&mut self) {
+        self.health.get_mut().checked_at = None;
+    }
+
+    fn route(&self) -> InstanceRoute {
+        InstanceRoute {
+            url: self.url.clone(),
+            load: self.load.clone(),
+            server_exited: self.process.exit_flag(),
+            exit: self.process.exit_watch(),
+            output: self.process.output(),
+        }
+    }
+}
+
+/// Whether the server at `url` answers `/health`
+async fn probe_health(clients: &BackendClients, url: &str) -> bool {
+    let url = format!("{}/health", url);
+    match clients.send(clients.probe().get(&url)).await {
+        Ok(response) => response.status().is_success(),
+        Err(_) => false,
+    }
+}
+
+/// Where a generation may be sent
+#[derive(Clone)]
+pub(crate) struct InstanceRoute {
+    pub(crate) url: String,
+    pub(crate) load: Arc<InstanceLoad>,
+    pub(crate) server_exited: Arc<AtomicBool>,
+    pub(crate) exit: ExitWatch,
+    pub(crate) output: OutputTail,
+}
+
+impl InstanceRoute {
+    /// Take the instance out of rotation after a refused connection
+    pub(crate) fn mark_unreachable(&self, clients: &BackendClients) {
+        self.reprobe_every(clients, REPROBE_INTERVAL);
+    }
+
+    /// Mark the instance unhealthy and probe it every `interval` until it
+    /// answers or is dropped; one task per instance however often it fails
+    pub(crate) fn reprobe_every(&self, clients: &BackendClients, interval: Duration) {
+        self.load.mark_unhealthy();
+        if self.load.reprobing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let load: Weak<InstanceLoad> = Arc::downgrade(&self.load);
+        let clients = clients.clone();
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(load) = load.upgrade() else {
+                    return;
+                };
+                if load.is_healthy() || probe_health(&clients, &url).await {
+                    load.healthy.store(true, Ordering::Relaxed);
+                    load.reprobing.store(false, Ordering::Relaxed);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Try the healthy instance at `url` first; false if there is none, or
+/// all its `slots` are busy and a pinned request would only queue there
+pub(crate) fn prefer(routes: &mut Vec<InstanceRoute>, url: &str, slots: usize) -> bool {
+    let Some(index) = routes.iter().position(|route| {
+        route.url == url && route.load.is_healthy() && route.load.in_flight() < slots
+    }) else {
+        return false;
+    };
+    let route = routes.remove(index);
+    routes.insert(0, route);
+    true
+}
+
+/// Orders instances for each generation
+pub(crate) struct Balancer {
+    strategy: LoadBalancing,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub(crate) fn new(strategy: LoadBalancing) -> Self {
+        Self {
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Every instance as a route, preferred first
+    pub(crate) fn routes(&self, instances: &[Instance]) -> Vec<InstanceRoute> {
+        let count = instances.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count.max(1);
+        let mut order: Vec<usize> = (0..count).map(|i| (start + i) % count).collect();
+
+        // Stable sorts keep the round-robin rotation among equals
+        if self.strategy == LoadBalancing::LeastBusy {
+            order.sort_by_key(|&i| instances[i].load.in_flight());
+        }
+        order.sort_by_key(|&i| !instances[i].load.is_healthy());
+
+        order.into_iter().map(|i| instances[i].route()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(count: u16) -> Vec<Instance> {
+        (0..count)
+            .map(|i| {
+                let instance = Instance::new(InstanceConfig {
+                    port: 9000 + i,
+                    main_gpu: None,
+                    base_url: None,
+                });
+                instance.load.healthy.store(true, Ordering::Relaxed);
+                instance
+            })
+            .collect()
+    }
+
+    fn first_port(routes: &[InstanceRoute]) -> String {
+        routes[0].url.rsplit(':').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_round_robin_rotates_and_skips_unhealthy() {
+        let instances = instances(3);
+        let balancer = Balancer::new(LoadBalancing::RoundRobin);
+
+        let firsts: Vec<_> = (0..3)
+            .map(|_| first_port(&balancer.routes(&instances)))
+            .collect();
+        assert_eq!(firsts, ["9000", "9001", "9002"]);
+
+        instances[0].load.mark_unhealthy();
+        let routes = balancer.routes(&instances);
+        assert_eq!(routes.len(), 3);
+        assert_eq!(first_port(&routes), "9001");
+        // Unhealthy instances stay available as a last resort
+        assert!(routes[2].url.ends_with(":9000"));
+    }
+
+    #[test]
+    fn test_least_busy_prefers_idle_instance() {
+        let instances = instances(3);
+        let balancer = Balancer::new(LoadBalancing::LeastBusy);
+
+        let busy = [instances[0].load.acquire(), instances[1].load.acquire()];
+        for _ in 0..3 {
+            assert_eq!(first_port(&balancer.routes(&instances)), "9002");
+        }
+        drop(busy);
+        assert_eq!(instances[0].load.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_prefer_skips_instance_with_every_slot_busy() {
+        let instances = instances(2);
+        let balancer = Balancer::new(LoadBalancing::RoundRobin);
+        let url = instances[1].url.clone();
+
+        let mut routes = balancer.routes(&instances);
+        assert!(prefer(&mut routes, &url, 2));
+        assert_eq!(first_port(&routes), "9001");
+
+        let busy = [instances[1].load.acquire(), instances[1].load.acquire()];
+        let mut routes = balancer.routes(&instances);
+        assert!(!prefer(&mut routes, &url, 2));
+        drop(busy);
+        assert!(prefer(&mut routes, &url, 2));
+    }
+}
@@ -1,24 +1,64 @@
+pub mod guard;
+mod http_pool;
+mod instance_pool;
 mod llama_adapter;
+mod model_set;
+mod piper_adapter;
+pub mod postprocess;
 mod process_manager;
+mod resource_sampler;
+pub mod response_format;
 mod runtime;
+pub mod sanitize;
+#[cfg(feature = "images")]
+mod sd_adapter;
+mod slot_affinity;
+pub mod sse;
 pub mod template_engine;
+pub mod tool_calls;
+mod whisper_adapter;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod pollution_tests;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
-pub use llama_adapter::LlamaAdapter;
+pub use guard::{Classification, GuardTarget};
+pub use llama_adapter::{Embeddings, LlamaAdapter, StreamProcessState};
+pub use model_set::ModelSet;
+pub use piper_adapter::{AudioFormat, AudioStream, PiperAdapter, Speech};
 pub use runtime::{ModelRuntime, RuntimeHandle};
-pub use template_engine::{CleanedResponse, StreamChunkResult, TemplateEngine};
+#[cfg(feature = "images")]
+pub use sd_adapter::{ImageRequest, SdAdapter};
+pub use template_engine::{
+    CleanedResponse, PromptOptions, StopMatcher, StreamChunkResult, StreamState, TemplateEngine,
+};
+pub use whisper_adapter::{TranscriptFormat, Transcription, TranscriptionRequest, WhisperAdapter};
 
 use async_trait::async_trait;
-use chatsafe_common::{GenerationParams, Message, Result, StreamFrame};
+use chatsafe_common::{
+    BackendPoolStats, GenerationMetadata, GenerationParams, Message, ProcessResources, Result,
+    StreamFrame,
+};
 use futures::Stream;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::SystemTime;
+use tokio::sync::oneshot;
+
+/// Stream of generation frames
+pub type FrameStream = Pin<Box<dyn Stream<Item = Result<StreamFrame>> + Send>>;
+
+/// A running generation: frames plus metadata sent once the backend finishes
+///
+/// The metadata sender is dropped without a value if generation fails early.
+pub struct Generation {
+    pub stream: FrameStream,
+    pub metadata: oneshot::Receiver<GenerationMetadata>,
+}
 
 /// Handle to a loaded model
 #[derive(Debug, Clone, PartialEq)]
@@ -35,6 +75,38 @@ pub struct RuntimeHealth {
     pub model_loaded: Option<ModelHandle>,
     pub active_requests: usize,
     pub uptime_seconds: u64,
+    /// When llama-server last answered a health probe successfully
+    pub last_success: Option<SystemTime>,
+    /// Latest CPU/memory sample of each spawned backend process
+    pub resources: Vec<ProcessResources>,
+}
+
+/// A LoRA adapter of a served model and the scale it is applied with
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LoraState {
+    /// Position in the registry's `loras`, which llama-server uses as its ID
+    pub id: usize,
+    pub path: String,
+    /// 0 when the adapter is disabled
+    pub scale: f32,
+}
+
+/// Troubleshooting snapshot of the runtime's backends
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct RuntimeDiagnostics {
+    pub instances: Vec<InstanceDiagnostics>,
+}
+
+/// One backend process or endpoint
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InstanceDiagnostics {
+    pub url: String,
+    /// Whether the runtime spawned this backend
+    pub managed: bool,
+    pub healthy: bool,
+    pub in_flight: usize,
+    /// Last lines of stdout/stderr, oldest first (empty when not managed)
+    pub recent_output: Vec<String>,
 }
 
 /// Trait for model runtime implementations
@@ -46,23 +118,53 @@ pub trait Runtime: Send + Sync {
     /// Get current loaded model handle
     async fn get_handle(&self) -> Option<ModelHandle>;
 
+    /// Handles of every loaded model, the default one first
+    async fn handles(&self) -> Vec<ModelHandle> {
+        self.get_handle().await.into_iter().collect()
+    }
+
     /// Generate completion with streaming
     async fn generate(
         &self,
         handle: &ModelHandle,
         messages: Vec<Message>,
         params: GenerationParams,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamFrame>> + Send>>>;
+    ) -> Result<Generation>;
 
     /// Cancel a generation request
     async fn cancel(&self, request_id: &str) -> Result<()>;
 
+    /// Erase all backend KV/prompt caches, returning the number of slots cleared
+    async fn flush_caches(&self) -> Result<usize>;
+
+    /// Backend build identifier, if the backend reports one
+    async fn backend_version(&self) -> Option<String>;
+
+    /// Per-backend state and recent process output for troubleshooting
+    fn diagnostics(&self) -> RuntimeDiagnostics;
+
+    /// Counters for the runtime's HTTP connection pool, if it has one
+    fn pool_stats(&self) -> Option<BackendPoolStats>;
+
+    /// LoRA adapters of a served model with their current scales
+    async fn lora_adapters(&self, model_id: &str) -> Result<Vec<LoraState>>;
+
+    /// Change the scale of one of a served model's LoRA adapters; 0 disables it
+    async fn set_lora_scale(&self, model_id: &str, id: usize, scale: f32)
+        -> Result<Vec<LoraState>>;
+
     /// Get runtime health status
     async fn health(&self) -> Result<RuntimeHealth>;
 
+    /// Health of each model's backend by model ID, the default one first
+    async fn model_health(&self) -> Vec<(String, RuntimeHealth)>;
+
     /// Unload current model
     async fn unload(&mut self) -> Result<()>;
 
+    /// Stop serving one model, stopping its backend to free memory
+    async fn unload_model(&mut self, model_id: &str) -> Result<()>;
+
     /// Shutdown runtime completely
     async fn shutdown(&mut self) -> Result<()>;
 }
@@ -79,7 +181,7 @@ pub trait RuntimeExt: Runtime {
     ) -> Result<String> {
         use futures::StreamExt;
 
-        let mut stream = self.generate(handle, messages, params).await?;
+        let mut stream = self.generate(handle, messages, params).await?.stream;
         let mut content = String::new();
 
         while let Some(frame) = stream.next().await {
@@ -87,7 +189,7 @@ pub trait RuntimeExt: Runtime {
                 StreamFrame::Delta { content: delta } => {
                     content.push_str(&delta);
                 }
-                StreamFrame::Error { message } => {
+                StreamFrame::Error { message, .. } => {
                     return Err(chatsafe_common::Error::RuntimeError(message));
                 }
                 _ => {}
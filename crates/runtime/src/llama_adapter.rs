@@ -1,49 +1,81 @@
-use crate::{template_engine::TemplateEngine, ModelHandle, Runtime, RuntimeHealth};
+use crate::guard::{self, Classification, GuardTarget};
+use crate::http_pool::BackendClients;
+use crate::instance_pool::{self, Balancer, Instance, InstanceLoad, InstanceRoute};
+use crate::postprocess;
+use crate::process_manager::{ExitCause, ExitWatch, OutputTail};
+use crate::response_format::OutputConstraint;
+use crate::slot_affinity::{PinGuard, SlotAffinity, SlotPin};
+use crate::sse::{self, SseParser, Utf8Decoder};
+use crate::template_engine::{PromptOptions, StreamChunkResult, StreamState, TemplateEngine};
+use crate::tool_calls;
+use crate::{
+    Generation, InstanceDiagnostics, LoraState, ModelHandle, Runtime, RuntimeDiagnostics,
+    RuntimeHealth,
+};
 use async_trait::async_trait;
 use chatsafe_common::{
-    Error, FinishReason, GenerationParams, Message, Result, Role, StreamFrame, Usage,
+    text, Error, FinishMode, FinishReason, GenerationMetadata, GenerationParams, Message,
+    RawPrompt, Result, Role, SamplerSettings, StreamBoundary, StreamFrame, TokenLogprob, Tool,
+    TopLogprob, Usage,
+};
+use chatsafe_config::{
+    Capability, HardwareProfile, InstanceConfig, ModelConfig, PostProcessor, RuntimeConfig,
+    ServerTuning, TemplateConfig,
 };
-use chatsafe_config::{ModelConfig, RuntimeConfig, TemplateConfig};
 use futures::Stream;
-use reqwest::Client;
 use serde::Deserialize;
 use std::path::PathBuf;
-use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use tokio::process::Command;
 use tokio::sync::{oneshot, RwLock};
 use tokio::time::{sleep, timeout, Duration};
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
 // Constants
-const HTTP_TIMEOUT_SECS: u64 = 300;
-const HTTP_CONNECT_TIMEOUT_SECS: u64 = 5;
-const HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
-const HEALTH_CHECK_CONNECT_TIMEOUT_MS: u64 = 500;
 const PROCESS_KILL_WAIT_MS: u64 = 200;
 const SERVER_READY_MAX_ATTEMPTS: u32 = 60;
 const SERVER_READY_CHECK_INTERVAL_MS: u64 = 500;
 const PROCESS_START_WAIT_MS: u64 = 100;
 const MODEL_LOAD_TIMEOUT_SECS: u64 = 30;
-const DEFAULT_PARALLEL_REQUESTS: &str = "4";
 const DEFAULT_N_PREDICT: &str = "-1";
 const LLAMA_SERVER_BINARY: &str = "./llama.cpp/build/bin/llama-server";
-const TOKEN_ESTIMATION_DIVISOR: usize = 4;
+const MAX_LOGGED_CHUNK_BYTES: usize = 200;
 const KILL_SIGNAL: &str = "-9";
-const ROLE_POLLUTION_FALLBACK: &str = "I understand you'd like me to respond, but I should avoid role-playing conversations. How can I help you directly?";
+const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+/// Where llama-server puts each image of a multimodal prompt
+const MEDIA_MARKER: &str = "<__media__>";
+const OOM_GUIDANCE: &str = "llama-server ran out of memory; try a smaller quantization of the model (e.g. Q4_K_M) or fewer GPU layers (`gpu_layers` in the model registry)";
 
 /// Adapter for llama.cpp server
 pub struct LlamaAdapter {
     model_path: PathBuf,
+    /// Multimodal projector of a vision model
+    mmproj_path: Option<PathBuf>,
+    /// LoRA adapter files, in registry order
+    lora_paths: Vec<PathBuf>,
+    /// Current scale of each adapter, kept across server restarts
+    lora_scales: std::sync::Mutex<Vec<f32>>,
+    /// Held from reading the scales until the changed ones are stored, so
+    /// concurrent updates don't undo each other
+    lora_update: tokio::sync::Mutex<()>,
+    /// `--slot-save-path`, without which llama-server refuses to erase slots
+    slot_save_path: Option<PathBuf>,
     model_config: ModelConfig,
     template_config: TemplateConfig,
     runtime_config: RuntimeConfig,
-    process_manager: crate::process_manager::ProcessManager,
-    server_url: String,
+    /// Context size passed to llama-server, below `ctx_window` after an
+    /// adaptive retry
+    ctx_size: usize,
+    instances: Vec<Instance>,
+    balancer: Balancer,
+    /// Which slot each conversation's prompt is cached in
+    affinity: SlotAffinity,
     current_handle: Option<ModelHandle>,
     start_time: SystemTime,
     active_requests: Arc<RwLock<std::collections::HashMap<String, oneshot::Sender<()>>>>,
+    clients: BackendClients,
 }
 
 impl LlamaAdapter {
@@ -53,63 +85,175 @@ impl LlamaAdapter {
         template_config: TemplateConfig,
         runtime_config: RuntimeConfig,
     ) -> Result<Self> {
-        let server_url = format!("http://127.0.0.1:{}", runtime_config.llama_server_port);
+        let clients = BackendClients::new(&runtime_config)?;
+        let instances = runtime_config
+            .resolved_instances()
+            .into_iter()
+            .map(Instance::new)
+            .collect();
 
         Ok(Self {
             model_path,
+            mmproj_path: None,
+            lora_paths: Vec::new(),
+            lora_scales: std::sync::Mutex::new(Vec::new()),
+            lora_update: tokio::sync::Mutex::new(()),
+            slot_save_path: None,
+            ctx_size: model_config.ctx_window,
             model_config,
             template_config,
+            balancer: Balancer::new(runtime_config.load_balancing),
+            affinity: SlotAffinity::default(),
             runtime_config,
-            process_manager: crate::process_manager::ProcessManager::new(
-                "llama-server".to_string(),
-            ),
-            server_url,
+            instances,
             current_handle: None,
             start_time: SystemTime::now(),
             active_requests: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            clients,
         })
     }
 
-    /// Create a default HTTP client with standard timeouts
-    fn create_default_client() -> Result<Client> {
-        Client::builder()
-            .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
-            .connect_timeout(Duration::from_secs(HTTP_CONNECT_TIMEOUT_SECS))
-            .build()
-            .map_err(|e| Error::RuntimeError(format!("Failed to create HTTP client: {}", e)))
+    /// Load a multimodal projector alongside the model
+    pub fn with_mmproj(mut self, path: Option<PathBuf>) -> Self {
+        self.mmproj_path = path;
+        self
+    }
+
+    /// Apply the registry's LoRA adapters, one path per entry of `loras`
+    pub fn with_loras(mut self, paths: Vec<PathBuf>) -> Self {
+        let scales = self.model_config.loras.iter().map(|lora| lora.scale);
+        self.lora_scales = std::sync::Mutex::new(scales.take(paths.len()).collect());
+        self.lora_paths = paths;
+        self
+    }
+
+    /// Directory spawned servers may save slots to, which also lets
+    /// `cache: false` and `/admin/flush` erase them
+    pub fn with_slot_save_path(mut self, path: Option<PathBuf>) -> Self {
+        self.slot_save_path = path;
+        self
+    }
+
+    /// Directory passed as `--slot-save-path`
+    pub(crate) fn slot_save_path(&self) -> Option<&PathBuf> {
+        self.slot_save_path.as_ref()
     }
 
-    /// Create a health check client with short timeouts
-    fn create_health_check_client() -> Result<Client> {
-        Client::builder()
-            .timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS))
-            .connect_timeout(Duration::from_millis(HEALTH_CHECK_CONNECT_TIMEOUT_MS))
-            .build()
-            .map_err(|e| {
-                Error::RuntimeError(format!("Failed to create health check client: {}", e))
+    fn current_lora_scales(&self) -> Vec<f32> {
+        self.lora_scales
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn lora_states(&self) -> Vec<LoraState> {
+        self.lora_paths
+            .iter()
+            .zip(self.current_lora_scales())
+            .enumerate()
+            .map(|(id, (path, scale))| LoraState {
+                id,
+                path: path.display().to_string(),
+                scale,
             })
+            .collect()
+    }
+
+    /// Send the adapter scales to every instance's `/lora-adapters`
+    async fn apply_lora_scales(&self, scales: &[f32]) -> Result<()> {
+        let body: Vec<_> = scales
+            .iter()
+            .enumerate()
+            .map(|(id, scale)| serde_json::json!({ "id": id, "scale": scale }))
+            .collect();
+        for instance in &self.instances {
+            let url = format!("{}/lora-adapters", instance.url);
+            let response = self
+                .clients
+                .send(self.clients.generation().post(&url).json(&body))
+                .await
+                .map_err(|e| Error::RuntimeError(format!("Failed to set LoRA scales: {}", e)))?;
+            if !response.status().is_success() {
+                return Err(Error::RuntimeError(format!(
+                    "Failed to set LoRA scales on {}: {}",
+                    instance.url,
+                    response.status()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_serves(&self, model_id: &str) -> Result<()> {
+        if model_id != self.model_config.id {
+            return Err(Error::ModelNotFound(format!("{} is not served", model_id)));
+        }
+        Ok(())
+    }
+
+    /// ID of the registry model this adapter serves
+    pub fn model_id(&self) -> &str {
+        &self.model_config.id
     }
 
-    fn build_prompt(&self, messages: &[Message]) -> String {
-        TemplateEngine::format_prompt(messages, &self.template_config)
+    /// Backend settings this adapter was built with
+    pub(crate) fn runtime_config(&self) -> &RuntimeConfig {
+        &self.runtime_config
+    }
+
+    /// Whether generation `request_id` is running on this adapter
+    pub(crate) async fn is_generating(&self, request_id: &str) -> bool {
+        self.active_requests.read().await.contains_key(request_id)
+    }
+
+    fn build_prompt(&self, messages: &[Message], params: &GenerationParams) -> String {
+        let options = PromptOptions {
+            tools: params.offered_tools(),
+            tool_choice: params.tool_choice.as_ref(),
+            variables: Some(&params.prompt_variables),
+        };
+        TemplateEngine::format_prompt_with(messages, options, &self.template_config)
+    }
+
+    /// Put a media marker ahead of each message's text for every image it
+    /// carries, returning the images' base64 data in prompt order
+    fn take_images(&self, mut messages: Vec<Message>) -> Result<(Vec<Message>, Vec<String>)> {
+        let mut images = Vec::new();
+        for message in &mut messages {
+            // A marker typed by the user would shift every image after it
+            if message.content.contains(MEDIA_MARKER) {
+                message.content = message.content.replace(MEDIA_MARKER, "");
+            }
+            if message.images.is_empty() {
+                continue;
+            }
+            if !self.model_config.vision {
+                return Err(Error::BadRequest(format!(
+                    "Model {} does not accept images; use a registry model with \"vision\": true",
+                    self.model_config.id
+                )));
+            }
+            let markers = vec![MEDIA_MARKER; message.images.len()].join("\n");
+            message.content = format!("{}\n{}", markers, message.content);
+            images.extend(message.images.drain(..).map(|image| image.data));
+        }
+        Ok((messages, images))
     }
 
-    /// Clean up any existing llama-server process
-    async fn cleanup_existing_process(&mut self) -> Result<()> {
+    /// Clean up any existing llama-server process for an instance
+    async fn cleanup_existing_process(&mut self, index: usize) -> Result<()> {
         // Use ProcessManager to clean up any tracked process
-        self.process_manager.cleanup().await?;
+        self.instances[index].process.cleanup().await?;
 
-        // Also check for orphaned processes on our port using lsof
-        self.kill_orphaned_processes().await?;
+        // Also check for orphaned processes on its port using lsof
+        Self::kill_orphaned_processes(self.instances[index].config.port).await?;
 
         Ok(())
     }
 
-    /// Kill any orphaned llama-server processes on our port
-    async fn kill_orphaned_processes(&self) -> Result<()> {
-        let port = self.runtime_config.llama_server_port;
-
-        // Use lsof to find processes listening on our port
+    /// Kill any orphaned llama-server processes on a port
+    async fn kill_orphaned_processes(port: u16) -> Result<()> {
+        // Use lsof to find processes listening on the port
         let output = Command::new("lsof")
             .args(["-ti", &format!(":{}", port)])
             .output()
@@ -141,197 +285,259 @@ impl LlamaAdapter {
         Ok(())
     }
 
-    /// Check if the port is available
-    async fn is_port_available(&self) -> bool {
-        let port = self.runtime_config.llama_server_port;
-
+    /// Check if a port is available
+    async fn is_port_available(port: u16) -> bool {
         // Try to connect to the port
         tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
             .await
             .is_err()
     }
 
-    /// Wait for the server to become ready
-    async fn wait_for_ready(&mut self) -> Result<()> {
+    /// Wait for an instance to become ready
+    async fn wait_for_ready(&mut self, index: usize) -> Result<()> {
         for attempts in 1..=SERVER_READY_MAX_ATTEMPTS {
+            let instance = &mut self.instances[index];
+
             // Check if the process is still alive
-            if !self.process_manager.is_running() {
-                return Err(Error::RuntimeError(
-                    "llama-server process died unexpectedly".to_string(),
+            if self.runtime_config.manage_process && !instance.process.is_running() {
+                let (cause, output) = Self::diagnose_exit(
+                    &instance.process.exit_watch(),
+                    &instance.process.output(),
+                    instance.load(),
+                )
+                .await;
+                return Err(Self::exit_error(
+                    format!(
+                        "llama-server on port {} died unexpectedly",
+                        instance.config.port
+                    ),
+                    cause,
+                    &output,
                 ));
             }
 
-            // Try health check
-            if let Ok(health) = self.health().await {
-                if health.is_healthy {
-                    info!("Server ready after {} attempts", attempts);
-                    return Ok(());
-                }
+            // Probe directly: a cached result would delay readiness by its TTL
+            if instance.probe(&self.clients).await {
+                info!(
+                    "Server at {} ready after {} attempts",
+                    instance.url, attempts
+                );
+                return Ok(());
             }
 
             sleep(Duration::from_millis(SERVER_READY_CHECK_INTERVAL_MS)).await;
         }
 
         Err(Error::RuntimeError(format!(
-            "Server failed to become ready after {} attempts",
-            SERVER_READY_MAX_ATTEMPTS
+            "Server at {} failed to become ready after {} attempts",
+            self.instances[index].url, SERVER_READY_MAX_ATTEMPTS
         )))
     }
 
+    /// Wait for every instance, in order
+    async fn wait_for_all_ready(&mut self) -> Result<()> {
+        for index in 0..self.instances.len() {
+            self.wait_for_ready(index).await?;
+        }
+        Ok(())
+    }
+
     /// Build the llama-server command with all arguments
-    fn build_server_command(&self) -> Command {
-        let mut cmd = Command::new(LLAMA_SERVER_BINARY);
+    fn build_server_command(&self, instance: &InstanceConfig) -> Command {
+        let mut cmd = match &self.runtime_config.working_dir {
+            Some(dir) => {
+                // Resolve the binary before changing directory, or the
+                // relative path would be looked up from `dir`
+                let binary = std::env::current_dir()
+                    .map(|cwd| cwd.join(LLAMA_SERVER_BINARY))
+                    .unwrap_or_else(|_| PathBuf::from(LLAMA_SERVER_BINARY));
+                let mut cmd = Command::new(binary);
+                cmd.current_dir(dir);
+                cmd
+            }
+            None => Command::new(LLAMA_SERVER_BINARY),
+        };
+        let tuning = ServerTuning::new(
+            &self.model_config.resources,
+            HardwareProfile::detect().filter(|_| self.runtime_config.hardware_tuning),
+        );
+        cmd.envs(&self.model_config.env);
         cmd.arg("--model")
             .arg(&self.model_path)
             .arg("--ctx-size")
-            .arg(self.model_config.ctx_window.to_string())
+            .arg(self.ctx_size.to_string())
             .arg("--n-gpu-layers")
-            .arg(self.model_config.resources.gpu_layers.to_string())
+            .arg(tuning.gpu_layers.to_string())
             .arg("--host")
             .arg("127.0.0.1")
             .arg("--port")
-            .arg(self.runtime_config.llama_server_port.to_string())
+            .arg(instance.port.to_string())
             .arg("--threads")
-            .arg(self.model_config.resources.threads.to_string())
+            .arg(tuning.threads.to_string())
             .arg("--n-predict")
             .arg(DEFAULT_N_PREDICT)
             .arg("--parallel")
-            .arg(DEFAULT_PARALLEL_REQUESTS)
+            .arg(self.runtime_config.parallel_slots.max(1).to_string())
             .arg("--cont-batching")
             .arg("--flash-attn")
-            .arg("on");
-        cmd
-    }
-
-    /// Handle cleanup after startup failure
-    async fn cleanup_after_failure(&mut self, context: &str) {
-        if let Err(e) = self.cleanup_existing_process().await {
-            warn!("Failed to cleanup after {}: {}", context, e);
+            .arg(tuning.flash_attn);
+        if self.model_config.capability == Capability::Embed {
+            cmd.arg("--embedding");
         }
-    }
-
-    /// Process SSE chunk and extract content
-    fn parse_sse_chunk(data: &str) -> Result<StreamChunk> {
-        serde_json::from_str::<StreamChunk>(data).map_err(|e| {
-            Error::RuntimeError(format!(
-                "Failed to parse SSE chunk: {}. Data: {:?}",
-                e, data
-            ))
-        })
-    }
-
-    /// Clean streaming content by removing markers
-    fn clean_streaming_content(content: &str) -> String {
-        let markers = [
-            "<|eot_id|>",
-            "<|end_of_text|>",
-            "<|start_header_id|>",
-            "<|end_header_id|>",
-        ];
-        let mut cleaned = content.to_string();
-        for marker in &markers {
-            cleaned = cleaned.replace(marker, "");
+        if let Some(mmproj) = &self.mmproj_path {
+            cmd.arg("--mmproj").arg(mmproj);
         }
-        cleaned
-    }
-
-    /// Check for role pollution in accumulated content
-    fn has_role_pollution(content: &str) -> bool {
-        content.contains("AI:") && content.contains("You:")
-    }
-
-    /// Estimate token count from text
-    fn estimate_tokens(text: &str) -> usize {
-        text.len() / TOKEN_ESTIMATION_DIVISOR
-    }
-}
-
-/// SSE stream chunk structure
-#[derive(Deserialize, Debug)]
-struct StreamChunk {
-    content: String,
-    stop: bool,
-}
-
-/// Completion request for llama.cpp server
-#[derive(serde::Serialize)]
-struct CompletionRequest {
-    prompt: String,
-    n_predict: usize,
-    temperature: f32,
-    top_p: f32,
-    top_k: i32,
-    repeat_penalty: f32,
-    stop: Vec<String>,
-    stream: bool,
-}
-
-#[async_trait]
-impl Runtime for LlamaAdapter {
-    async fn load(&mut self, model_id: &str) -> Result<ModelHandle> {
-        if model_id != self.model_config.id {
-            return Err(Error::InvalidModel(format!(
-                "This adapter is configured for model {}, not {}",
-                self.model_config.id, model_id
-            )));
+        if let Some(dir) = &self.slot_save_path {
+            cmd.arg("--slot-save-path").arg(dir);
         }
+        // Disabled adapters are still loaded, at scale 0, so IDs stay stable
+        for (path, scale) in self.lora_paths.iter().zip(self.current_lora_scales()) {
+            cmd.arg("--lora-scaled").arg(path).arg(scale.to_string());
+        }
+        if let Some(gpu) = instance.main_gpu {
+            cmd.arg("--split-mode")
+                .arg("none")
+                .arg("--main-gpu")
+                .arg(gpu.to_string());
+        }
+        cmd
+    }
 
-        info!(
-            "Loading model: {} from {}",
-            model_id,
-            self.model_path.display()
-        );
-
+    /// Spawn one instance's llama-server
+    async fn start_instance(&mut self, index: usize) -> Result<()> {
         // Clean up any existing process first
-        if let Err(e) = self.cleanup_existing_process().await {
+        if let Err(e) = self.cleanup_existing_process(index).await {
             warn!("Error during cleanup: {}", e);
         }
 
+        let port = self.instances[index].config.port;
+
         // Check if port is available before spawning
-        if !self.is_port_available().await {
+        if !Self::is_port_available(port).await {
             return Err(Error::RuntimeError(format!(
                 "Port {} is already in use. Another llama-server instance may be running.",
-                self.runtime_config.llama_server_port
+                port
             )));
         }
 
+        if let Some(dir) = &self.slot_save_path {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                Error::RuntimeError(format!(
+                    "Failed to create slot directory {}: {}",
+                    dir.display(),
+                    e
+                ))
+            })?;
+        }
+
         // Start llama.cpp server using ProcessManager
-        let cmd = self.build_server_command();
+        let cmd = self.build_server_command(&self.instances[index].config);
+        let instance = &mut self.instances[index];
+        let process = &mut instance.process;
 
         // Spawn with proper stdout/stderr draining
-        self.process_manager
+        process
             .spawn(cmd)
             .await
             .map_err(|e| Error::RuntimeError(format!("Failed to start llama.cpp server: {}", e)))?;
 
         // Check if process started successfully
         sleep(Duration::from_millis(PROCESS_START_WAIT_MS)).await;
-        if !self.process_manager.is_running() {
-            return Err(Error::RuntimeError(format!(
-                "llama-server exited immediately. Check if binary exists at {}",
-                LLAMA_SERVER_BINARY
-            )));
+        if !process.is_running() {
+            let (cause, output) =
+                Self::diagnose_exit(&process.exit_watch(), &process.output(), instance.load())
+                    .await;
+            return Err(Self::exit_error(
+                format!(
+                    "llama-server exited immediately. Check if binary exists at {}",
+                    LLAMA_SERVER_BINARY
+                ),
+                cause,
+                &output,
+            ));
+        }
+
+        if let Some(pid) = process.pid() {
+            let exited = process.exit_flag();
+            self.instances[index].resources.start(pid, exited);
+        }
+
+        info!("llama-server process started on port {}", port);
+        Ok(())
+    }
+
+    /// Why an instance's process went away, counting out-of-memory kills
+    async fn diagnose_exit(
+        exit: &ExitWatch,
+        output: &OutputTail,
+        load: &InstanceLoad,
+    ) -> (ExitCause, Vec<String>) {
+        let lines = output.lines_after_exit().await;
+        let cause = ExitCause::classify(exit.status(), &lines);
+        if cause == ExitCause::OutOfMemory {
+            load.record_oom_kill();
+        }
+        (cause, lines)
+    }
+
+    /// Error for a process that died before becoming ready
+    fn exit_error(context: String, cause: ExitCause, output: &[String]) -> Error {
+        let tail = Self::format_output_tail(output);
+        match cause {
+            ExitCause::OutOfMemory => {
+                Error::BackendOutOfMemory(format!("{}: {}{}", context, OOM_GUIDANCE, tail))
+            }
+            _ => Error::ModelLoadFailed(format!("{}{}", context, tail)),
+        }
+    }
+
+    /// Append a child's last output lines to an error message
+    fn format_output_tail(lines: &[String]) -> String {
+        if lines.is_empty() {
+            return String::new();
+        }
+        format!("\nLast llama-server output:\n{}", lines.join("\n"))
+    }
+
+    /// Handle cleanup after startup failure
+    async fn cleanup_after_failure(&mut self, context: &str) {
+        // A user-managed server is never ours to kill
+        if !self.runtime_config.manage_process {
+            return;
         }
+        for index in 0..self.instances.len() {
+            if let Err(e) = self.cleanup_existing_process(index).await {
+                warn!("Failed to cleanup after {}: {}", context, e);
+            }
+        }
+    }
 
-        info!("llama-server process started successfully");
+    /// Spawn every instance at `ctx_size` and wait until all are ready
+    async fn start_all(&mut self) -> Result<()> {
+        for index in 0..self.instances.len() {
+            if let Err(e) = self.start_instance(index).await {
+                self.cleanup_after_failure("startup failure").await;
+                return Err(e);
+            }
+        }
 
-        // Wait for server to be ready with timeout
+        // Wait for every server to be ready with timeout
         let wait_result = timeout(
             Duration::from_secs(MODEL_LOAD_TIMEOUT_SECS),
-            self.wait_for_ready(),
+            self.wait_for_all_ready(),
         )
         .await;
 
         match wait_result {
             Ok(Ok(())) => {
-                info!("Model loaded successfully");
-                let handle = ModelHandle {
-                    model_id: Arc::from(model_id),
-                    loaded_at: SystemTime::now(),
-                    context_size: self.model_config.ctx_window,
-                };
-                self.current_handle = Some(handle.clone());
-                Ok(handle)
+                for index in 0..self.instances.len() {
+                    let url = self.instances[index].url.clone();
+                    if let Err(e) = self.verify_loaded_model(&url, &self.model_config.id).await {
+                        self.cleanup_after_failure("model mismatch").await;
+                        return Err(e);
+                    }
+                }
+                Ok(())
             }
             Ok(Err(e)) => {
                 self.cleanup_after_failure("startup failure").await;
@@ -346,136 +552,1626 @@ impl Runtime for LlamaAdapter {
         }
     }
 
-    async fn get_handle(&self) -> Option<ModelHandle> {
-        self.current_handle.clone()
+    /// Half the current context for an adaptive retry, if allowed and above the floor
+    fn reduced_context_size(&self) -> Option<usize> {
+        let reduced = self.ctx_size / 2;
+        (self.runtime_config.adaptive_context && reduced >= self.runtime_config.min_ctx_window)
+            .then_some(reduced)
     }
 
-    async fn generate(
-        &self,
-        handle: &ModelHandle,
-        messages: Vec<Message>,
-        params: GenerationParams,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamFrame>> + Send>>> {
-        // Verify handle matches
-        if self.current_handle.as_ref() != Some(handle) {
-            return Err(Error::InvalidModel(
-                "Model handle does not match loaded model".into(),
-            ));
-        }
-
-        let prompt = self.build_prompt(&messages);
-        let request_id = params.request_id.clone();
-
-        let request = CompletionRequest {
-            prompt,
-            n_predict: params.max_tokens,
-            temperature: params.temperature,
-            top_p: params.top_p,
-            top_k: params.top_k,
-            repeat_penalty: params.repeat_penalty,
-            stop: params.stop_sequences.clone(),
-            stream: true,
+    fn set_loaded(&mut self, model_id: &str) -> ModelHandle {
+        let handle = ModelHandle {
+            model_id: Arc::from(model_id),
+            loaded_at: SystemTime::now(),
+            context_size: self.ctx_size,
         };
+        self.current_handle = Some(handle.clone());
+        // Fresh servers have empty slots
+        self.affinity.clear();
+        handle
+    }
 
-        let url = format!("{}/completion", self.server_url);
-        // Use Arc for values moved into async block
-        let template = Arc::new(self.template_config.clone());
-        let stop_sequences = Arc::new(self.model_config.stop_sequences.clone());
-        let eos_token = Arc::new(self.model_config.eos_token.clone());
-        let model_id = Arc::new(self.model_config.id.clone());
-        let request_id_arc = Arc::new(request_id.clone());
+    /// Attach to user-managed llama-servers instead of spawning them
+    async fn attach(&mut self, model_id: &str) -> Result<ModelHandle> {
+        for index in 0..self.instances.len() {
+            let url = self.instances[index].url.clone();
+            info!("Attaching to external llama-server at {}", url);
 
-        // Setup cancellation
-        let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
-        let active_reqs = self.active_requests.clone();
+            timeout(
+                Duration::from_secs(MODEL_LOAD_TIMEOUT_SECS),
+                self.wait_for_ready(index),
+            )
+            .await
+            .map_err(|_| {
+                Error::RuntimeError(format!(
+                    "External llama-server at {} did not become ready",
+                    url
+                ))
+            })??;
 
-        // Register active request
-        {
-            let mut reqs = active_reqs.write().await;
-            reqs.insert(request_id_arc.to_string(), cancel_tx);
+            self.verify_loaded_model(&url, model_id).await?;
         }
 
-        let stream = Self::create_generation_stream(StreamParams {
-            request,
-            url,
-            request_id: request_id_arc,
-            model_id,
-            template,
-            stop_sequences,
-            eos_token,
-            active_reqs,
-            cancel_rx,
-        });
-
-        Ok(Box::pin(stream))
+        info!("Attached to external llama-server");
+        Ok(self.set_loaded(model_id))
     }
 
-    async fn cancel(&self, request_id: &str) -> Result<()> {
-        let mut reqs = self.active_requests.write().await;
-        if let Some(cancel_tx) = reqs.remove(request_id) {
-            let _ = cancel_tx.send(());
-            info!("Cancelled request: {}", request_id);
-        } else {
-            warn!("No active request found for cancellation: {}", request_id);
+    /// Check `/props` reports the configured model
+    ///
+    /// Catches a stale llama-server (e.g. from another project) answering on
+    /// the configured port instead of the one chatsafe expects.
+    async fn verify_loaded_model(&self, server_url: &str, model_id: &str) -> Result<()> {
+        let url = format!("{}/props", server_url);
+        let props: serde_json::Value = self
+            .clients
+            .send(self.clients.probe().get(&url))
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Failed to read /props: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Invalid /props response: {}", e)))?;
+
+        let managed = self.runtime_config.manage_process;
+        match Self::loaded_model_matches(&props, &self.model_path, managed) {
+            Some(true) => Ok(()),
+            Some(false) if managed => Err(Error::InvalidModel(format!(
+                "llama-server at {} has {} loaded, expected {}; another llama-server may be answering on its port",
+                server_url,
+                Self::props_model_path(&props).unwrap_or_default(),
+                self.model_path.display()
+            ))),
+            Some(false) => Err(Error::InvalidModel(format!(
+                "External llama-server at {} has {} loaded, expected {}",
+                server_url,
+                Self::props_model_path(&props).unwrap_or_default(),
+                self.model_path.display()
+            ))),
+            None => {
+                warn!(
+                    "llama-server at {} did not report its model; assuming {}",
+                    server_url, model_id
+                );
+                Ok(())
+            }
         }
-        Ok(())
     }
 
-    async fn health(&self) -> Result<RuntimeHealth> {
-        let url = format!("{}/health", self.server_url);
-        let health_client = Self::create_health_check_client()?;
-
-        let is_healthy = match health_client.get(&url).send().await {
-            Ok(response) => response.status().is_success(),
-            Err(_) => false,
+    /// Reject a prompt with more tokens than the context (or configured cap)
+    ///
+    /// Tokens are counted by the backend's own `/tokenize`, so the limit
+    /// means the same for every script. Every token covers at least one
+    /// byte, so prompts with fewer bytes than the limit skip the round trip,
+    /// as do those `fit_context` already counted; if the backend can't
+    /// count, the prompt is let through.
+    async fn check_prompt_length(&self, prompt: &RawPrompt, counted: Option<usize>) -> Result<()> {
+        let limit = self.prompt_limit();
+        let (tokens, chars) = match prompt {
+            RawPrompt::Tokens(tokens) => (tokens.len(), None),
+            RawPrompt::Text(text) if text.len() <= limit => return Ok(()),
+            RawPrompt::Text(text) => {
+                let counted = match counted {
+                    Some(tokens) => Some(tokens),
+                    None => self.count_tokens(text).await,
+                };
+                let Some(tokens) = counted else {
+                    debug!("Could not count prompt tokens; skipping length check");
+                    return Ok(());
+                };
+                (tokens, Some(text.chars().count()))
+            }
         };
-
-        let uptime = self.start_time.elapsed().unwrap_or_default().as_secs();
-
-        let active_count = self.active_requests.read().await.len();
-
-        Ok(RuntimeHealth {
-            is_healthy,
-            model_loaded: self.current_handle.clone(),
-            active_requests: active_count,
-            uptime_seconds: uptime,
-        })
+        if tokens <= limit {
+            return Ok(());
+        }
+        let size = match chars {
+            Some(chars) => format!("{} tokens ({} chars)", tokens, chars),
+            None => format!("{} tokens", tokens),
+        };
+        Err(Error::ValidationFailed(format!(
+            "Prompt is {}, over the {}-token limit of {}",
+            size, limit, self.model_config.id
+        )))
     }
 
-    async fn unload(&mut self) -> Result<()> {
-        self.current_handle = None;
-        // Server stays running, just mark as unloaded
-        Ok(())
+    /// Most tokens a prompt may have: the context, or the configured cap
+    fn prompt_limit(&self) -> usize {
+        self.runtime_config
+            .max_prompt_tokens
+            .map_or(self.ctx_size, |cap| cap.min(self.ctx_size))
     }
 
-    async fn shutdown(&mut self) -> Result<()> {
-        // Use ProcessManager for proper cleanup
-        self.process_manager.terminate().await?;
+    /// Drop the oldest turns until the prompt leaves room for `max_tokens`
+    ///
+    /// System messages and the last user turn, with anything after it, are
+    /// always kept, and the history is only cut before a user turn. Whole
+    /// turns are dropped, as few as possible, found by binary search over
+    /// `/tokenize` counts. A prompt that cannot be counted is left as it
+    /// is; one still too long once only the kept messages remain is left to
+    /// `check_prompt_length`.
+    async fn fit_context(
+        &self,
+        messages: Vec<Message>,
+        params: &GenerationParams,
+    ) -> FittedContext {
+        let budget = self
+            .ctx_size
+            .saturating_sub(params.max_tokens)
+            .min(self.prompt_limit());
+        let cuts = history_cuts(&messages);
+        let untouched = |messages, counted| FittedContext {
+            messages,
+            dropped: 0,
+            counted,
+        };
+        if !self.runtime_config.truncate_history || budget == 0 || cuts.len() < 2 {
+            return untouched(messages, None);
+        }
+        let first = cuts[0];
+        let keep = |cut: usize| {
+            let mut kept = messages[..first].to_vec();
+            kept.extend_from_slice(&messages[cut..]);
+            kept
+        };
+        // Whether the prompt kept from `cut` fits, with its count if one was needed
+        let fits = |cut: usize| {
+            let prompt = self.build_prompt(&keep(cut), params);
+            async move {
+                // Every token covers at least one byte
+                if prompt.len() <= budget {
+                    return Some((true, None));
+                }
+                let tokens = self.count_tokens(&prompt).await?;
+                Some((tokens <= budget, Some((prompt, tokens))))
+            }
+        };
+        match fits(first).await {
+            Some((false, _)) => {}
+            Some((true, counted)) => return untouched(messages, counted),
+            None => return untouched(messages, None),
+        }
 
-        self.current_handle = None;
+        // Fewest cuts that fit, or all of them
+        let (mut low, mut high) = (1, cuts.len() - 1);
+        let mut counted = None;
+        while low < high {
+            let mid = (low + high) / 2;
+            match fits(cuts[mid]).await {
+                Some((true, count)) => {
+                    high = mid;
+                    counted = count;
+                }
+                Some((false, _)) => low = mid + 1,
+                None => return untouched(messages, None),
+            }
+        }
+        let cut = cuts[low];
+        info!(
+            "Dropped the {} oldest messages so the prompt fits {} tokens",
+            cut - first,
+            budget
+        );
+        FittedContext {
+            messages: keep(cut),
+            dropped: cut - first,
+            counted,
+        }
+    }
 
-        // Double-check port is released
-        sleep(Duration::from_millis(PROCESS_START_WAIT_MS)).await;
-        if !self.is_port_available().await {
-            warn!(
-                "Port {} still in use after shutdown",
-                self.runtime_config.llama_server_port
-            );
+    /// Token count of `text` from llama-server's `/tokenize`
+    async fn count_tokens(&self, text: &str) -> Option<usize> {
+        #[derive(Deserialize)]
+        struct TokenizeResponse {
+            tokens: Vec<serde_json::Value>,
         }
 
-        Ok(())
+        let route = self.balancer.routes(&self.instances).into_iter().next()?;
+        let url = format!("{}/tokenize", route.url);
+        let request = self
+            .clients
+            .probe()
+            .post(&url)
+            .json(&serde_json::json!({ "content": text }));
+        let response: TokenizeResponse =
+            self.clients.send(request).await.ok()?.json().await.ok()?;
+        Some(response.tokens.len())
     }
-}
 
-struct StreamProcessState {
-    accumulated: String,
-    token_count: usize,
-    fallback_sent: bool,
-}
+    /// Embed each input with a server started in embedding mode
+    pub async fn embed(&self, inputs: &[String]) -> Result<Embeddings> {
+        #[derive(Deserialize)]
+        struct EmbeddingData {
+            index: usize,
+            embedding: Vec<f32>,
+        }
+        #[derive(Deserialize, Default)]
+        struct EmbeddingUsage {
+            prompt_tokens: usize,
+        }
+        #[derive(Deserialize)]
+        struct EmbeddingsResponse {
+            data: Vec<EmbeddingData>,
+            #[serde(default)]
+            usage: EmbeddingUsage,
+        }
+
+        if self.current_handle.is_none() {
+            return Err(Error::InvalidModel(format!(
+                "Embedding model {} is not loaded",
+                self.model_config.id
+            )));
+        }
+        let route = self
+            .balancer
+            .routes(&self.instances)
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::ServiceUnavailable("No embedding server".into()))?;
+        let _in_flight = route.load.acquire();
+        let request = self
+            .clients
+            .generation()
+            .post(format!("{}{}", route.url, EMBEDDINGS_PATH))
+            .json(&serde_json::json!({ "input": inputs }));
+        let response = self
+            .clients
+            .send(request)
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Embedding request failed: {}", e)))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| v.pointer("/error/message")?.as_str().map(str::to_string))
+                .unwrap_or_else(|| format!("llama-server returned {}", status));
+            // Oversized inputs come back as client errors
+            return Err(if status.is_client_error() {
+                Error::BadRequest(message)
+            } else {
+                Error::RuntimeError(message)
+            });
+        }
+        let mut response: EmbeddingsResponse = response
+            .json()
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Invalid embeddings response: {}", e)))?;
+        if response.data.len() != inputs.len() {
+            return Err(Error::RuntimeError(format!(
+                "llama-server returned {} embeddings for {} inputs",
+                response.data.len(),
+                inputs.len()
+            )));
+        }
+        response.data.sort_by_key(|data| data.index);
+        Ok(Embeddings {
+            vectors: response.data.into_iter().map(|d| d.embedding).collect(),
+            prompt_tokens: response.usage.prompt_tokens,
+        })
+    }
+
+    /// Ask a guard model whether the last `target` turn of `messages` is safe
+    pub async fn classify(
+        &self,
+        messages: &[Message],
+        target: GuardTarget,
+    ) -> Result<Classification> {
+        #[derive(Deserialize)]
+        struct CompletionResponse {
+            content: String,
+        }
+
+        if self.current_handle.is_none() {
+            return Err(Error::InvalidModel(format!(
+                "Guard model {} is not loaded",
+                self.model_config.id
+            )));
+        }
+        let route = self
+            .balancer
+            .routes(&self.instances)
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::ServiceUnavailable("No guard server".into()))?;
+        let _in_flight = route.load.acquire();
+        let request = self
+            .clients
+            .generation()
+            .post(format!("{}/completion", route.url))
+            .json(&serde_json::json!({
+                "prompt": guard::prompt(messages, target),
+                "n_predict": guard::VERDICT_MAX_TOKENS,
+                "temperature": 0.0,
+                "cache_prompt": false
+            }));
+        let response = self
+            .clients
+            .send(request)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::RuntimeError(format!("Guard request failed: {}", e)))?;
+        let response: CompletionResponse = response
+            .json()
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Invalid guard response: {}", e)))?;
+        guard::parse(&response.content)
+    }
+
+    /// Model file llama-server reports in `/props`
+    fn props_model_path(props: &serde_json::Value) -> Option<&str> {
+        props
+            .get("model_path")
+            .or_else(|| props.pointer("/default_generation_settings/model"))
+            .and_then(|v| v.as_str())
+    }
+
+    /// Whether `/props` names the expected model file
+    ///
+    /// A spawned server reports the exact path it was given; an external one
+    /// may see the file under a different directory, so only the file name
+    /// is compared.
+    fn loaded_model_matches(
+        props: &serde_json::Value,
+        expected: &std::path::Path,
+        exact: bool,
+    ) -> Option<bool> {
+        let reported = std::path::Path::new(Self::props_model_path(props)?);
+        Some(if exact {
+            reported == expected
+        } else {
+            reported.file_name() == expected.file_name()
+        })
+    }
+
+    /// Process SSE chunk and extract content
+    fn parse_sse_chunk(data: &[u8]) -> Result<StreamChunk> {
+        serde_json::from_slice::<StreamChunk>(data).map_err(|e| {
+            Error::RuntimeError(format!(
+                "Failed to parse SSE chunk: {}. Data: {:?}",
+                e,
+                text::truncate_bytes(&String::from_utf8_lossy(data), MAX_LOGGED_CHUNK_BYTES)
+            ))
+        })
+    }
+}
+
+/// One vector per input of an embedding request, in input order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    /// Tokens across all inputs; 0 when llama-server does not say
+    pub prompt_tokens: usize,
+}
+
+/// SSE stream chunk structure
+#[derive(Deserialize, Debug, Default)]
+struct StreamChunk {
+    /// Raw bytes: a multibyte character may be split across chunks
+    #[serde(deserialize_with = "deserialize_content_bytes")]
+    content: Vec<u8>,
+    stop: bool,
+    /// Set on the final chunk when `n_predict` ran out
+    #[serde(default)]
+    stopped_limit: bool,
+    /// Only present on the final chunk
+    #[serde(default)]
+    id_slot: Option<i64>,
+    #[serde(default)]
+    tokens_evaluated: Option<usize>,
+    #[serde(default)]
+    timings: Option<LlamaTimings>,
+    /// Sampled tokens of this chunk, when `n_probs` was sent
+    #[serde(default)]
+    completion_probabilities: Vec<LlamaTokenProbs>,
+}
+
+/// A sampled token and its most likely alternatives
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct LlamaTokenProbs {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+    top_logprobs: Vec<LlamaTopProb>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct LlamaTopProb {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+}
+
+impl LlamaTokenProbs {
+    /// OpenAI's form, keeping the `top` most likely alternatives
+    fn to_logprob(&self, top: usize) -> TokenLogprob {
+        TokenLogprob {
+            token: self.token.clone(),
+            logprob: self.logprob,
+            bytes: token_bytes(&self.token, &self.bytes),
+            top_logprobs: self
+                .top_logprobs
+                .iter()
+                .take(top)
+                .map(|alt| TopLogprob {
+                    token: alt.token.clone(),
+                    logprob: alt.logprob,
+                    bytes: token_bytes(&alt.token, &alt.bytes),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Older llama-server builds send no `bytes`
+fn token_bytes(token: &str, bytes: &[u8]) -> Vec<u8> {
+    if bytes.is_empty() {
+        token.as_bytes().to_vec()
+    } else {
+        bytes.to_vec()
+    }
+}
+
+/// Read a JSON string without requiring it to be valid UTF-8 on its own
+fn deserialize_content_bytes<'de, D>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct BytesVisitor;
+
+    impl serde::de::Visitor<'_> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a string")
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> std::result::Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+    }
+
+    deserializer.deserialize_bytes(BytesVisitor)
+}
+
+/// Timing block llama-server attaches to the final chunk
+#[derive(Deserialize, Debug)]
+struct LlamaTimings {
+    prompt_n: usize,
+    prompt_ms: f64,
+    predicted_n: usize,
+    predicted_ms: f64,
+    predicted_per_second: f64,
+}
+
+impl StreamChunk {
+    /// Extract generation metadata from the final chunk
+    fn metadata(&self) -> GenerationMetadata {
+        let timings = self.timings.as_ref();
+        GenerationMetadata {
+            slot_id: self.id_slot,
+            prompt_tokens: self.tokens_evaluated,
+            // Only tokens the backend had to process show up in prompt_n
+            cached_prompt_tokens: self
+                .tokens_evaluated
+                .zip(timings)
+                .map(|(evaluated, t)| evaluated.saturating_sub(t.prompt_n)),
+            completion_tokens: timings.map(|t| t.predicted_n),
+            prompt_ms: timings.map(|t| t.prompt_ms),
+            completion_ms: timings.map(|t| t.predicted_ms),
+            tokens_per_second: timings.map(|t| t.predicted_per_second),
+            role_pollution: false,
+            slot_affinity: false,
+            truncated_messages: 0,
+        }
+    }
+}
+
+/// Messages kept by `fit_context`
+struct FittedContext {
+    messages: Vec<Message>,
+    /// How many of the oldest messages were dropped
+    dropped: usize,
+    /// A prompt built from the kept messages, with its token count
+    counted: Option<(String, usize)>,
+}
+
+/// Where the history of `messages` may be cut: the first message after
+/// the leading system messages, then each later user message up to and
+/// including the last, so the kept history always opens on a user turn
+/// and tool results stay with the call. Empty without a user message.
+fn history_cuts(messages: &[Message]) -> Vec<usize> {
+    let Some(last_user) = messages.iter().rposition(|m| m.role == Role::User) else {
+        return Vec::new();
+    };
+    let first = messages
+        .iter()
+        .position(|m| m.role != Role::System)
+        .unwrap_or(messages.len())
+        .min(last_user);
+    std::iter::once(first)
+        .chain((first + 1..=last_user).filter(|&i| messages[i].role == Role::User))
+        .collect()
+}
+
+/// `prompt` as llama-server takes it
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum CompletionPrompt {
+    Raw(RawPrompt),
+    /// Text with one `MEDIA_MARKER` per image, images as base64
+    Multimodal {
+        prompt_string: String,
+        multimodal_data: Vec<String>,
+    },
+}
+
+impl CompletionPrompt {
+    fn estimated_tokens(&self) -> usize {
+        match self {
+            CompletionPrompt::Raw(prompt) => prompt.estimated_tokens(),
+            CompletionPrompt::Multimodal { prompt_string, .. } => {
+                chatsafe_common::text::estimate_tokens(prompt_string)
+            }
+        }
+    }
+}
+
+impl From<RawPrompt> for CompletionPrompt {
+    fn from(prompt: RawPrompt) -> Self {
+        CompletionPrompt::Raw(prompt)
+    }
+}
+
+/// Completion request for llama.cpp server
+#[derive(serde::Serialize)]
+struct CompletionRequest {
+    prompt: CompletionPrompt,
+    n_predict: usize,
+    temperature: f32,
+    top_p: f32,
+    top_k: i32,
+    repeat_penalty: f32,
+    #[serde(flatten)]
+    sampler: SamplerSettings,
+    stop: Vec<String>,
+    stream: bool,
+    cache_prompt: bool,
+    /// Slot holding the conversation's cached prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_slot: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grammar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_schema: Option<serde_json::Value>,
+    /// Alternatives per token to report probabilities for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n_probs: Option<usize>,
+}
+
+#[async_trait]
+impl Runtime for LlamaAdapter {
+    async fn load(&mut self, model_id: &str) -> Result<ModelHandle> {
+        if model_id != self.model_config.id {
+            return Err(Error::InvalidModel(format!(
+                "This adapter is configured for model {}, not {}",
+                self.model_config.id, model_id
+            )));
+        }
+
+        info!(
+            "Loading model: {} from {}",
+            model_id,
+            self.model_path.display()
+        );
+
+        if !self.runtime_config.manage_process {
+            return self.attach(model_id).await;
+        }
+
+        self.ctx_size = self.model_config.ctx_window;
+        loop {
+            match self.start_all().await {
+                Ok(()) => break,
+                Err(Error::BackendOutOfMemory(reason)) => {
+                    let Some(reduced) = self.reduced_context_size() else {
+                        return Err(Error::BackendOutOfMemory(reason));
+                    };
+                    warn!(
+                        "Out of memory with a {}-token context, retrying with {}: {}",
+                        self.ctx_size, reduced, reason
+                    );
+                    self.ctx_size = reduced;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.ctx_size < self.model_config.ctx_window {
+            warn!(
+                "Model {} loaded with a {}-token context instead of the configured {}",
+                model_id, self.ctx_size, self.model_config.ctx_window
+            );
+        }
+        info!("Model loaded successfully");
+        Ok(self.set_loaded(model_id))
+    }
+
+    async fn get_handle(&self) -> Option<ModelHandle> {
+        self.current_handle.clone()
+    }
+
+    async fn generate(
+        &self,
+        handle: &ModelHandle,
+        messages: Vec<Message>,
+        params: GenerationParams,
+    ) -> Result<Generation> {
+        // Verify handle matches
+        if self.current_handle.as_ref() != Some(handle) {
+            return Err(Error::InvalidModel(
+                "Model handle does not match loaded model".into(),
+            ));
+        }
+
+        params.check_deadline("before generation started")?;
+
+        // Raw prompts and uncached requests leave nothing to come back to
+        let conversation = (params.cache_prompt && params.prompt_override.is_none())
+            .then(|| SlotAffinity::fingerprint(&messages))
+            .flatten();
+
+        let (prompt, images, truncated_messages, counted) = match params.prompt_override.clone() {
+            Some(prompt) => (prompt, Vec::new(), 0, None),
+            None => {
+                let fitted = self.fit_context(messages, &params).await;
+                let (messages, images) = self.take_images(fitted.messages)?;
+                let prompt = self.build_prompt(&messages, &params);
+                // Image markers change the prompt after it was counted
+                let counted = fitted
+                    .counted
+                    .filter(|(counted, _)| *counted == prompt)
+                    .map(|(_, tokens)| tokens);
+                (RawPrompt::Text(prompt), images, fitted.dropped, counted)
+            }
+        };
+        self.check_prompt_length(&prompt, counted).await?;
+        let prompt = match prompt {
+            RawPrompt::Text(prompt_string) if !images.is_empty() => CompletionPrompt::Multimodal {
+                prompt_string,
+                multimodal_data: images,
+            },
+            prompt => CompletionPrompt::Raw(prompt),
+        };
+        let request_id = params.request_id.clone();
+        let constraint = OutputConstraint::new(params.response_format.as_ref())?;
+
+        let mut routes = self.balancer.routes(&self.instances);
+        let slots = self.runtime_config.parallel_slots.max(1);
+        let pin = conversation
+            .and_then(|conversation| self.affinity.claim(conversation))
+            .filter(|guard| instance_pool::prefer(&mut routes, &guard.pin.url, slots));
+
+        let request = CompletionRequest {
+            prompt,
+            n_predict: params.max_tokens,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            repeat_penalty: params.repeat_penalty,
+            sampler: params.sampler,
+            stop: params.stop_sequences.clone(),
+            stream: true,
+            cache_prompt: params.cache_prompt,
+            id_slot: pin.as_ref().map(|guard| guard.pin.slot),
+            grammar: params
+                .grammar
+                .clone()
+                .or_else(|| constraint.as_ref().and_then(|c| c.grammar.clone())),
+            json_schema: constraint.as_ref().and_then(|c| c.json_schema.clone()),
+            // At least one, or llama-server reports nothing at all
+            n_probs: params.top_logprobs.map(|top| top.max(1)),
+        };
+
+        let mut processors = self.model_config.postprocess.clone();
+        if params.sanitize_markup && !processors.contains(&PostProcessor::SanitizeMarkup) {
+            processors.push(PostProcessor::SanitizeMarkup);
+        }
+
+        // Use Arc for values moved into async block
+        let state = StreamProcessState::new(
+            Arc::new(self.template_config.clone()),
+            Arc::new(params.stop_sequences.clone()),
+            Arc::new(self.model_config.eos_token.clone()),
+            params.stream_boundary,
+        )
+        .with_finish(params.finish)
+        .with_postprocess(Arc::new(processors))
+        .with_tools(Arc::new(params.offered_tools().to_vec()))
+        .with_output_check(constraint)
+        .with_logprobs(params.top_logprobs);
+        let model_id = Arc::new(self.model_config.id.clone());
+        let request_id_arc = Arc::new(request_id.clone());
+
+        // Setup cancellation
+        let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+        let active_reqs = self.active_requests.clone();
+
+        // Register active request
+        {
+            let mut reqs = active_reqs.write().await;
+            reqs.insert(request_id_arc.to_string(), cancel_tx);
+        }
+
+        let (metadata_tx, metadata) = oneshot::channel();
+        let stream = Self::create_generation_stream(StreamParams {
+            request,
+            routes,
+            affinity: self.affinity.clone(),
+            conversation,
+            pin,
+            truncated_messages,
+            request_id: request_id_arc,
+            model_id,
+            state,
+            active_reqs,
+            cancel_rx,
+            deadline: params.deadline,
+            metadata_tx,
+            clients: self.clients.clone(),
+        });
+
+        Ok(Generation {
+            stream: Box::pin(stream),
+            metadata,
+        })
+    }
+
+    async fn cancel(&self, request_id: &str) -> Result<()> {
+        let mut reqs = self.active_requests.write().await;
+        if let Some(cancel_tx) = reqs.remove(request_id) {
+            let _ = cancel_tx.send(());
+            info!("Cancelled request: {}", request_id);
+        } else {
+            warn!("No active request found for cancellation: {}", request_id);
+        }
+        Ok(())
+    }
+
+    async fn flush_caches(&self) -> Result<usize> {
+        if self.current_handle.is_none() {
+            return Ok(0);
+        }
+
+        for instance in &self.instances {
+            for slot_id in 0..self.runtime_config.parallel_slots.max(1) {
+                Self::erase_slot(&self.clients, &instance.url, slot_id as i64).await?;
+            }
+        }
+        self.affinity.clear();
+        let erased = self.runtime_config.total_slots();
+        info!("Erased KV cache for {} slots", erased);
+        Ok(erased)
+    }
+
+    async fn backend_version(&self) -> Option<String> {
+        // All instances run the same binary, so any healthy one will do
+        let route = self.balancer.routes(&self.instances).into_iter().next()?;
+        let url = format!("{}/props", route.url);
+        let props: serde_json::Value = self
+            .clients
+            .send(self.clients.probe().get(&url))
+            .await
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+        props
+            .get("build_info")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    }
+
+    fn diagnostics(&self) -> RuntimeDiagnostics {
+        RuntimeDiagnostics {
+            instances: self
+                .instances
+                .iter()
+                .map(|instance| InstanceDiagnostics {
+                    url: instance.url.clone(),
+                    managed: self.runtime_config.manage_process,
+                    healthy: instance.load().is_healthy(),
+                    in_flight: instance.load().in_flight(),
+                    recent_output: instance.process.output().lines(),
+                })
+                .collect(),
+        }
+    }
+
+    fn pool_stats(&self) -> Option<chatsafe_common::BackendPoolStats> {
+        let mut stats = self.clients.stats();
+        stats.instances = self
+            .instances
+            .iter()
+            .map(|instance| chatsafe_common::BackendInstanceStats {
+                url: instance.url.clone(),
+                healthy: instance.load().is_healthy(),
+                in_flight: instance.load().in_flight(),
+                oom_kills: instance.load().oom_kills(),
+                resources: instance.resources.latest(),
+            })
+            .collect();
+        Some(stats)
+    }
+
+    async fn lora_adapters(&self, model_id: &str) -> Result<Vec<LoraState>> {
+        self.check_serves(model_id)?;
+        Ok(self.lora_states())
+    }
+
+    async fn set_lora_scale(
+        &self,
+        model_id: &str,
+        id: usize,
+        scale: f32,
+    ) -> Result<Vec<LoraState>> {
+        self.check_serves(model_id)?;
+        if id >= self.lora_paths.len() {
+            return Err(Error::BadRequest(format!(
+                "Model {} has no LoRA adapter {}",
+                model_id, id
+            )));
+        }
+        if !scale.is_finite() {
+            return Err(Error::BadRequest("LoRA scale must be a number".into()));
+        }
+        let _update = self.lora_update.lock().await;
+        let mut scales = self.current_lora_scales();
+        scales[id] = scale;
+        // A stopped server picks the scales up when it next starts
+        if self.current_handle.is_some() {
+            self.apply_lora_scales(&scales).await?;
+        }
+        *self.lora_scales.lock().unwrap_or_else(|e| e.into_inner()) = scales;
+        info!("LoRA adapter {} of {} set to scale {}", id, model_id, scale);
+        Ok(self.lora_states())
+    }
+
+    async fn health(&self) -> Result<RuntimeHealth> {
+        // Serving is possible while any instance is up
+        let mut is_healthy = false;
+        let mut last_success = None;
+        for instance in &self.instances {
+            let (healthy, success) = instance.health(&self.clients).await;
+            is_healthy |= healthy;
+            last_success = last_success.max(success);
+        }
+
+        let uptime = self.start_time.elapsed().unwrap_or_default().as_secs();
+
+        let active_count = self.active_requests.read().await.len();
+
+        Ok(RuntimeHealth {
+            is_healthy,
+            model_loaded: self.current_handle.clone(),
+            active_requests: active_count,
+            uptime_seconds: uptime,
+            last_success,
+            resources: self
+                .instances
+                .iter()
+                .filter_map(|instance| instance.resources.latest())
+                .collect(),
+        })
+    }
+
+    async fn model_health(&self) -> Vec<(String, RuntimeHealth)> {
+        self.health()
+            .await
+            .map(|health| (self.model_id().to_string(), health))
+            .into_iter()
+            .collect()
+    }
+
+    async fn unload(&mut self) -> Result<()> {
+        self.current_handle = None;
+        self.instances
+            .iter_mut()
+            .for_each(Instance::invalidate_health);
+        // Server stays running, just mark as unloaded
+        Ok(())
+    }
+
+    async fn unload_model(&mut self, model_id: &str) -> Result<()> {
+        if model_id != self.model_config.id {
+            return Err(Error::ModelNotFound(format!("{} is not served", model_id)));
+        }
+        self.shutdown().await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        self.current_handle = None;
+        self.instances
+            .iter_mut()
+            .for_each(Instance::invalidate_health);
+
+        // Detach only: the external server keeps running
+        if !self.runtime_config.manage_process {
+            return Ok(());
+        }
+
+        // Use ProcessManager for proper cleanup
+        for instance in &mut self.instances {
+            instance.process.terminate().await?;
+        }
+
+        // Double-check ports are released
+        sleep(Duration::from_millis(PROCESS_START_WAIT_MS)).await;
+        for instance in &self.instances {
+            if !Self::is_port_available(instance.config.port).await {
+                warn!("Port {} still in use after shutdown", instance.config.port);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Per-generation state turning llama-server chunks into frames
+pub struct StreamProcessState {
+    template: Arc<TemplateConfig>,
+    stop_sequences: Arc<Vec<String>>,
+    eos_token: Arc<String>,
+    decoder: Utf8Decoder,
+    cleaner: StreamState,
+    token_count: usize,
+    metadata: GenerationMetadata,
+    finish: FinishMode,
+    stopped_limit: bool,
+    postprocess: Arc<Vec<PostProcessor>>,
+    /// Tools whose calls are parsed out of the response
+    tools: Arc<Vec<Tool>>,
+    /// `response_format` the finished response must satisfy
+    output_check: Option<OutputConstraint>,
+    /// Alternatives per token to report, when log probabilities were asked for
+    top_logprobs: Option<usize>,
+}
+
+impl StreamProcessState {
+    pub fn new(
+        template: Arc<TemplateConfig>,
+        stop_sequences: Arc<Vec<String>>,
+        eos_token: Arc<String>,
+        boundary: StreamBoundary,
+    ) -> Self {
+        let cleaner = StreamState::new(&stop_sequences, &eos_token).with_boundary(boundary);
+        Self {
+            template,
+            stop_sequences,
+            eos_token,
+            decoder: Utf8Decoder::default(),
+            cleaner,
+            token_count: 0,
+            metadata: GenerationMetadata::default(),
+            finish: FinishMode::Exact,
+            stopped_limit: false,
+            postprocess: Arc::new(Vec::new()),
+            tools: Arc::new(Vec::new()),
+            output_check: None,
+            top_logprobs: None,
+        }
+    }
+
+    /// Set how a response cut off by the token limit ends
+    pub fn with_finish(mut self, finish: FinishMode) -> Self {
+        self.finish = finish;
+        self
+    }
+
+    /// Set the processors run over the complete response
+    pub fn with_postprocess(mut self, processors: Arc<Vec<PostProcessor>>) -> Self {
+        self.postprocess = processors;
+        self
+    }
+
+    /// Set the tools the model was offered
+    pub fn with_tools(mut self, tools: Arc<Vec<Tool>>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Set the format the finished response is checked against
+    pub fn with_output_check(mut self, constraint: Option<OutputConstraint>) -> Self {
+        self.output_check = constraint;
+        self
+    }
+
+    /// Report token log probabilities with `top` alternatives each
+    pub fn with_logprobs(mut self, top: Option<usize>) -> Self {
+        self.top_logprobs = top;
+        self
+    }
+
+    fn handle_chunk(&mut self, chunk: &StreamChunk, frames: &mut Vec<StreamFrame>) -> bool {
+        let first_new = frames.len();
+        if !chunk.content.is_empty() {
+            self.token_count += 1;
+            let text = self.decoder.decode(&chunk.content);
+            self.push_text(&text, frames);
+        }
+        self.push_logprobs(chunk, first_new, frames);
+
+        if chunk.stop {
+            self.metadata = chunk.metadata();
+            self.stopped_limit = chunk.stopped_limit;
+
+            // Release text held back for a split character, stop sequence or boundary
+            let tail = self.decoder.finish();
+            self.push_text(&tail, frames);
+            if let Some(content) = self.cleaner.finish() {
+                frames.push(StreamFrame::Delta { content });
+            }
+            self.metadata.role_pollution = self.cleaner.fallback_sent();
+            return true;
+        }
+
+        false
+    }
+
+    /// Put the chunk's log probabilities ahead of the text it produced, which
+    /// may also be held back; a token that completed a stop sequence is left
+    /// out with its text
+    fn push_logprobs(&self, chunk: &StreamChunk, at: usize, frames: &mut Vec<StreamFrame>) {
+        let Some(top) = self.top_logprobs else {
+            return;
+        };
+        if chunk.completion_probabilities.is_empty() || self.cleaner.is_finished() {
+            return;
+        }
+        let content = chunk
+            .completion_probabilities
+            .iter()
+            .map(|probs| probs.to_logprob(top))
+            .collect();
+        frames.insert(at, StreamFrame::Logprobs { content });
+    }
+
+    fn push_text(&mut self, text: &str, frames: &mut Vec<StreamFrame>) {
+        if text.is_empty() {
+            return;
+        }
+
+        // After a stop sequence the cleaner ignores further content; keep
+        // reading until the backend's stop chunk for its metadata
+        match TemplateEngine::process_stream_chunk(
+            text,
+            &self.template,
+            &self.stop_sequences,
+            &self.eos_token,
+            &mut self.cleaner,
+        ) {
+            StreamChunkResult::Partial { content } => {
+                frames.push(StreamFrame::Delta { content });
+            }
+            StreamChunkResult::Complete { .. } | StreamChunkResult::Buffering => {}
+        }
+    }
+}
+
+/// Parameters for stream generation
+struct StreamParams {
+    request: CompletionRequest,
+    /// Instances to try, preferred first
+    routes: Vec<InstanceRoute>,
+    affinity: SlotAffinity,
+    /// Fingerprint of the conversation, when its slot is worth remembering
+    conversation: Option<u64>,
+    /// Slot the request is pinned to, on the first route
+    pin: Option<PinGuard>,
+    /// Oldest messages left out to fit the context window
+    truncated_messages: usize,
+    request_id: Arc<String>,
+    model_id: Arc<String>,
+    state: StreamProcessState,
+    active_reqs: Arc<RwLock<std::collections::HashMap<String, oneshot::Sender<()>>>>,
+    cancel_rx: oneshot::Receiver<()>,
+    deadline: Option<Instant>,
+    metadata_tx: oneshot::Sender<GenerationMetadata>,
+    clients: BackendClients,
+}
+
+impl LlamaAdapter {
+    /// Create the generation stream
+    fn create_generation_stream(
+        params: StreamParams,
+    ) -> impl Stream<Item = Result<StreamFrame>> + Send {
+        async_stream::stream! {
+            // Track cleanup
+            let _cleanup = scopeguard::guard(params.active_reqs.clone(), |reqs| {
+                let id = params.request_id.to_string();
+                tokio::spawn(async move {
+                    let mut r = reqs.write().await;
+                    r.remove(&id);
+                });
+            });
+
+            // Send start frame immediately
+            yield Ok(StreamFrame::Start {
+                id: params.request_id.to_string(),
+                model: params.model_id.to_string(),
+                role: Role::Assistant,
+            });
+
+            let cache_prompt = params.request.cache_prompt;
+            // Held until generation ends, so no other pinned request shares the slot
+            let pin = params.pin;
+
+            // Process the streaming response
+            let backend_call = Self::process_stream_response(
+                &params.clients,
+                params.request,
+                params.routes,
+                params.state,
+                params.cancel_rx,
+            );
+
+            // Dropping the call on expiry closes the connection, which stops llama-server
+            let result = match params.deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), backend_call)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(Error::DeadlineExceeded("generation did not finish in time".into()))
+                    }),
+                None => backend_call.await,
+            };
+
+            match result {
+                Ok((frames, mut metadata, served_by)) => {
+                    metadata.slot_affinity = pin
+                        .as_ref()
+                        .is_some_and(|guard| served_by.as_deref() == Some(guard.pin.url.as_str()));
+                    drop(pin);
+                    metadata.truncated_messages = params.truncated_messages;
+                    let served = metadata
+                        .slot_id
+                        .zip(served_by)
+                        .map(|(slot, url)| SlotPin { url, slot });
+
+                    if let Some(conversation) = params.conversation {
+                        match served.clone() {
+                            Some(pin) => params.affinity.record(conversation, pin),
+                            None => params.affinity.forget(conversation),
+                        }
+                    }
+                    // Opted-out prompts must not stay resident in the slot's
+                    // KV cache; a response whose prompt could not be erased
+                    // is not delivered
+                    if let Some(served) = served.filter(|_| !cache_prompt) {
+                        params.affinity.forget_slot(&served);
+                        if let Err(e) =
+                            Self::erase_slot(&params.clients, &served.url, served.slot).await
+                        {
+                            warn!("{}", e);
+                            yield Ok(StreamFrame::Error {
+                                message: format!("{}; the prompt may still be cached", e),
+                                partial_content_length: None,
+                            });
+                            return;
+                        }
+                    }
+
+                    // Receiver may be gone if the API does not need metadata
+                    let _ = params.metadata_tx.send(metadata);
+
+                    // Yield all frames from the processing
+                    for frame in frames {
+                        yield Ok(frame);
+                    }
+                }
+                // Typed, so the API answers with the error's own status
+                Err(e) => yield Err(e),
+            }
+        }
+    }
+
+    /// Clear a llama-server slot's KV cache
+    async fn erase_slot(clients: &BackendClients, server_url: &str, slot_id: i64) -> Result<()> {
+        let url = format!("{}/slots/{}?action=erase", server_url, slot_id);
+        let response = clients
+            .send(clients.generation().post(&url))
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Failed to erase slot {}: {}", slot_id, e)))?;
+
+        if !response.status().is_success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to erase slot {}: {}",
+                slot_id,
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Process the streaming response from llama.cpp server
+    ///
+    /// Instances that refuse the connection are marked unhealthy and the next
+    /// route is tried; nothing has been generated at that point, so failover
+    /// is invisible to the client. Also returns the URL of the instance that
+    /// served the request.
+    async fn process_stream_response(
+        clients: &BackendClients,
+        mut request: CompletionRequest,
+        routes: Vec<InstanceRoute>,
+        state: StreamProcessState,
+        mut cancel_rx: oneshot::Receiver<()>,
+    ) -> Result<(Vec<StreamFrame>, GenerationMetadata, Option<String>)> {
+        let serialize = |request: &CompletionRequest| {
+            serde_json::to_string(request)
+                .map_err(|e| Error::RuntimeError(format!("Failed to serialize request: {}", e)))
+        };
+        let request_json = serialize(&request)?;
+        // A pinned slot only exists on the first route
+        let failover_json = match request.id_slot.take() {
+            Some(_) => Some(serialize(&request)?),
+            None => None,
+        };
+
+        let mut last_error = None;
+        for (attempt, route) in routes.into_iter().enumerate() {
+            let body = match &failover_json {
+                Some(json) if attempt > 0 => json,
+                _ => &request_json,
+            };
+            let _in_flight = route.load.acquire();
+            let url = format!("{}/completion", route.url);
+
+            // Make request with cancellation support
+            let response_future = clients.send(
+                clients
+                    .generation()
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .header("Accept", "text/event-stream")
+                    .body(body.clone()),
+            );
+
+            // Race between response and cancellation
+            let response = tokio::select! {
+                resp = response_future => resp,
+                _ = &mut cancel_rx => {
+                    return Ok((
+                        vec![StreamFrame::Error {
+                            message: "Request cancelled".to_string(),
+                            partial_content_length: None,
+                        }],
+                        GenerationMetadata::default(),
+                        None,
+                    ));
+                }
+            };
+
+            let response = match response {
+                Ok(response) => response,
+                Err(e) if e.is_connect() => {
+                    warn!(
+                        "llama-server at {} unreachable, failing over: {}",
+                        route.url, e
+                    );
+                    route.mark_unreachable(clients);
+                    last_error = Some(e);
+                    continue;
+                }
+                Err(e) => return Err(Error::RuntimeError(format!("Request failed: {}", e))),
+            };
+
+            if !response.status().is_success() {
+                return Ok((
+                    vec![StreamFrame::Error {
+                        message: format!("Server error: {}", response.status()),
+                        partial_content_length: None,
+                    }],
+                    GenerationMetadata::default(),
+                    None,
+                ));
+            }
+
+            // Process SSE stream
+            let server_exited = route.server_exited.clone();
+            let (mut frames, metadata) = Self::process_sse_stream(
+                response.bytes_stream(),
+                state,
+                request.prompt.estimated_tokens(),
+                route.server_exited,
+            )
+            .await?;
+
+            // Server output stays in the logs; it never goes to the client
+            if server_exited.load(Ordering::SeqCst) {
+                let (cause, output) =
+                    Self::diagnose_exit(&route.exit, &route.output, &route.load).await;
+                warn!(
+                    "llama-server at {} exited during generation ({:?}){}",
+                    route.url,
+                    cause,
+                    Self::format_output_tail(&output)
+                );
+                if cause == ExitCause::OutOfMemory {
+                    if let Some(StreamFrame::Error { message, .. }) = frames
+                        .iter_mut()
+                        .rev()
+                        .find(|frame| matches!(frame, StreamFrame::Error { .. }))
+                    {
+                        message.push_str(": ");
+                        message.push_str(OOM_GUIDANCE);
+                    }
+                }
+            }
+            return Ok((frames, metadata, Some(route.url)));
+        }
+
+        Err(Error::RuntimeError(match last_error {
+            Some(e) => format!("Request failed: {}", e),
+            None => "No llama-server instance configured".to_string(),
+        }))
+    }
+
+    /// Process an SSE byte stream from llama-server into frames
+    ///
+    /// Public so the criterion harness can drive the pipeline without a backend.
+    pub async fn process_sse_stream<S, E>(
+        bytes_stream: S,
+        mut state: StreamProcessState,
+        prompt_tokens: usize,
+        server_exited: Arc<AtomicBool>,
+    ) -> Result<(Vec<StreamFrame>, GenerationMetadata)>
+    where
+        S: futures::Stream<Item = std::result::Result<bytes::Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        use futures::StreamExt;
+
+        let mut frames = Vec::new();
+        let mut bytes_stream = std::pin::pin!(bytes_stream);
+        let mut parser = SseParser::new();
+        let mut dropped_frames = 0;
+        let mut stream_complete = false;
+        let mut interruption = None;
+
+        while let Some(chunk_result) = bytes_stream.next().await {
+            let bytes = match chunk_result {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    interruption = Some(e.to_string());
+                    break;
+                }
+            };
+
+            parser.push(&bytes);
+
+            // Process complete SSE events
+            while let Some(event) = parser.next_event() {
+                for data in sse::data_payloads(&event) {
+                    match Self::parse_sse_chunk(data) {
+                        Ok(chunk) => {
+                            if state.handle_chunk(&chunk, &mut frames) {
+                                stream_complete = true;
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            // Log the malformed frame but continue processing
+                            warn!("Dropped malformed SSE frame: {}", e);
+                            dropped_frames += 1;
+                        }
+                    }
+                }
+
+                if stream_complete {
+                    break;
+                }
+            }
+
+            if stream_complete {
+                break;
+            }
+        }
+
+        // Log warning if any frames were dropped
+        if dropped_frames > 0 {
+            warn!(
+                "Dropped {} malformed SSE frames during streaming",
+                dropped_frames
+            );
+        }
+
+        let usage = Usage {
+            prompt_tokens,
+            completion_tokens: state.token_count,
+            total_tokens: prompt_tokens + state.token_count,
+        };
+
+        // Salvage what was streamed if the backend went away before stopping
+        if !stream_complete {
+            let reason = interruption.unwrap_or_else(|| "connection closed".to_string());
+            frames.push(Self::interruption_frame(
+                &reason,
+                server_exited.load(Ordering::SeqCst),
+                &frames,
+            ));
+            frames.push(StreamFrame::Done {
+                finish_reason: FinishReason::Error,
+                usage,
+            });
+            return Ok((frames, state.metadata));
+        }
+
+        let finish_reason = if state.stopped_limit {
+            if state.finish == FinishMode::Sentence {
+                Self::trim_to_sentence(&mut frames);
+            }
+            FinishReason::Length
+        } else if Self::extract_tool_calls(&mut frames, &state.tools) {
+            FinishReason::ToolCalls
+        } else {
+            FinishReason::Stop
+        };
+        if !state.postprocess.is_empty() {
+            Self::postprocess_deltas(&mut frames, &state.postprocess);
+        }
+
+        // A response cut off by the limit is reported as such, not as invalid
+        let finish_reason = match &state.output_check {
+            Some(constraint) if matches!(finish_reason, FinishReason::Stop) => {
+                match constraint.check(&Self::delta_text(&frames)) {
+                    Ok(()) => finish_reason,
+                    Err(problem) => {
+                        warn!("Response failed response_format check: {}", problem);
+                        FinishReason::InvalidFormat
+                    }
+                }
+            }
+            _ => finish_reason,
+        };
+
+        // Send done frame with usage stats
+        frames.push(StreamFrame::Done {
+            finish_reason,
+            usage,
+        });
+
+        Ok((frames, state.metadata))
+    }
+
+    /// Drop delta text after the last complete sentence
+    ///
+    /// Left alone if there is no complete sentence, since trimming would
+    /// leave nothing.
+    fn trim_to_sentence(frames: &mut Vec<StreamFrame>) {
+        if let Some(end) = text::last_sentence_end(&Self::delta_text(frames)) {
+            Self::truncate_deltas(frames, end);
+        }
+    }
+
+    /// Replace the delta text with a `ToolCalls` frame if it calls `tools`
+    ///
+    /// Runs before post-processing so rewrites can't break the call JSON;
+    /// text around the calls stays as one delta.
+    fn extract_tool_calls(frames: &mut Vec<StreamFrame>, tools: &[Tool]) -> bool {
+        let Some(reply) = tool_calls::parse(&Self::delta_text(frames), tools) else {
+            return false;
+        };
+        frames.retain(|frame| !matches!(frame, StreamFrame::Delta { .. }));
+        if !reply.content.is_empty() {
+            frames.push(StreamFrame::Delta {
+                content: reply.content,
+            });
+        }
+        frames.push(StreamFrame::ToolCalls {
+            tool_calls: reply.tool_calls,
+        });
+        true
+    }
+
+    /// Rewrite the delta text with the model's post-processors
+    ///
+    /// Deltas up to the first changed byte are kept as they were, so
+    /// streaming granularity survives; the rest becomes one final delta.
+    fn postprocess_deltas(frames: &mut Vec<StreamFrame>, processors: &[PostProcessor]) {
+        let content = Self::delta_text(frames);
+        let processed = postprocess::apply(processors, &content);
+        if processed == content {
+            return;
+        }
+
+        let mut common = content
+            .char_indices()
+            .zip(processed.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or_else(|| content.len().min(processed.len()));
+        while !processed.is_char_boundary(common) {
+            common -= 1;
+        }
+        Self::truncate_deltas(frames, common);
+        if common < processed.len() {
+            frames.push(StreamFrame::Delta {
+                content: processed[common..].to_string(),
+            });
+        }
+    }
+
+    fn delta_text(frames: &[StreamFrame]) -> String {
+        frames
+            .iter()
+            .filter_map(|frame| match frame {
+                StreamFrame::Delta { content } => Some(content.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Cut the delta text at byte `end`, dropping deltas past it
+    fn truncate_deltas(frames: &mut Vec<StreamFrame>, end: usize) {
+        let mut seen = 0;
+        frames.retain_mut(|frame| {
+            let StreamFrame::Delta { content } = frame else {
+                return true;
+            };
+            let start = seen;
+            seen += content.len();
+            if start >= end {
+                return false;
+            }
+            if seen > end {
+                content.truncate(end - start);
+            }
+            true
+        });
+    }
+
+    /// Build the error frame for a generation cut off before its stop chunk
+    fn interruption_frame(
+        reason: &str,
+        server_exited: bool,
+        frames: &[StreamFrame],
+    ) -> StreamFrame {
+        let partial_content_length = frames
+            .iter()
+            .map(|frame| match frame {
+                StreamFrame::Delta { content } => content.chars().count(),
+                _ => 0,
+            })
+            .sum::<usize>();
+
+        let message = if server_exited {
+            format!(
+                "llama-server exited mid-generation after {} characters",
+                partial_content_length
+            )
+        } else {
+            format!(
+                "Backend stream interrupted after {} characters: {}",
+                partial_content_length, reason
+            )
+        };
+        warn!("{}", message);
+
+        StreamFrame::Error {
+            message,
+            partial_content_length: Some(partial_content_length),
+        }
+    }
+}
 
 #[cfg(test)]
 mod llama_stream_tests {
     use super::*;
+    use crate::template_engine::ROLE_POLLUTION_FALLBACK;
 
     fn test_template() -> TemplateConfig {
         TemplateConfig {
@@ -487,6 +2183,9 @@ mod llama_stream_tests {
             user_suffix: "<|eot_id|>".to_string(),
             assistant_prefix: "<|start_header_id|>assistant<|end_header_id|>\n\n".to_string(),
             assistant_suffix: "<|eot_id|>".to_string(),
+            tool_prefix: String::new(),
+            tool_suffix: String::new(),
+            stop_tokens: Vec::new(),
             default_system_prompt: "You are helpful.".to_string(),
         }
     }
@@ -497,30 +2196,39 @@ mod llama_stream_tests {
         let stop_sequences = vec!["<|eot_id|>".to_string()];
         let eos_token = "<|end_of_text|>";
 
-        let mut state = StreamProcessState::new();
+        let mut state = StreamProcessState::new(
+            Arc::new(template),
+            Arc::new(stop_sequences),
+            Arc::new(eos_token.to_string()),
+            StreamBoundary::Token,
+        );
         let mut frames = Vec::new();
 
         let chunks = vec![
             StreamChunk {
-                content: "AI: Hello there".to_string(),
+                content: "AI: Hello there".as_bytes().to_vec(),
                 stop: false,
+                ..Default::default()
             },
             StreamChunk {
-                content: "\nYou: Hi".to_string(),
+                content: "\nYou: Hi".as_bytes().to_vec(),
                 stop: false,
+                ..Default::default()
             },
             StreamChunk {
-                content: "\nAI: Still here".to_string(),
+                content: "\nAI: Still here".as_bytes().to_vec(),
                 stop: false,
+                ..Default::default()
             },
             StreamChunk {
-                content: String::new(),
+                content: Vec::new(),
                 stop: true,
+                ..Default::default()
             },
         ];
 
         for chunk in chunks {
-            if state.handle_chunk(&chunk, &mut frames, &template, &stop_sequences, eos_token) {
+            if state.handle_chunk(&chunk, &mut frames) {
                 break;
             }
         }
@@ -536,267 +2244,1013 @@ mod llama_stream_tests {
             .count();
 
         assert_eq!(fallback_count, 1, "Fallback should be emitted exactly once");
+        assert!(state.metadata.role_pollution);
     }
-}
 
-impl StreamProcessState {
-    fn new() -> Self {
-        Self {
-            accumulated: String::new(),
-            token_count: 0,
-            fallback_sent: false,
+    #[test]
+    fn interruption_frame_reports_partial_length() {
+        let frames = vec![
+            StreamFrame::Delta {
+                content: "Héllo".to_string(),
+            },
+            StreamFrame::Delta {
+                content: " world".to_string(),
+            },
+        ];
+
+        match LlamaAdapter::interruption_frame("connection reset", true, &frames) {
+            StreamFrame::Error {
+                message,
+                partial_content_length,
+            } => {
+                assert_eq!(partial_content_length, Some(11));
+                assert!(message.contains("exited mid-generation"));
+            }
+            other => panic!("Expected error frame, got {:?}", other),
+        }
+
+        match LlamaAdapter::interruption_frame("connection reset", false, &frames) {
+            StreamFrame::Error { message, .. } => {
+                assert!(message.contains("connection reset"));
+            }
+            other => panic!("Expected error frame, got {:?}", other),
         }
     }
 
-    fn handle_chunk(
-        &mut self,
-        chunk: &StreamChunk,
-        frames: &mut Vec<StreamFrame>,
-        template: &TemplateConfig,
-        stop_sequences: &[String],
-        eos_token: &str,
-    ) -> bool {
-        if !chunk.content.is_empty() {
-            self.token_count += 1;
+    #[test]
+    fn final_chunk_metadata_reports_cache_reuse() {
+        let data = r#"{"content":"","stop":true,"id_slot":1,"tokens_evaluated":120,
+            "timings":{"prompt_n":20,"prompt_ms":35.5,"predicted_n":64,
+            "predicted_ms":1600.0,"predicted_per_second":40.0}}"#;
+        let chunk = LlamaAdapter::parse_sse_chunk(data.as_bytes()).expect("valid chunk");
+        let metadata = chunk.metadata();
+
+        assert_eq!(metadata.slot_id, Some(1));
+        assert_eq!(metadata.prompt_tokens, Some(120));
+        assert_eq!(metadata.cached_prompt_tokens, Some(100));
+        assert_eq!(metadata.completion_tokens, Some(64));
+        assert_eq!(metadata.tokens_per_second, Some(40.0));
+
+        let partial = LlamaAdapter::parse_sse_chunk(br#"{"content":"hi","stop":false}"#)
+            .expect("valid chunk");
+        assert_eq!(partial.metadata(), GenerationMetadata::default());
+    }
 
-            if !self.fallback_sent {
-                self.accumulated.push_str(&chunk.content);
+    #[tokio::test]
+    async fn multibyte_characters_split_across_chunks_are_reassembled() {
+        let events: Vec<&[u8]> = vec![
+            b"data: {\"content\":\"caf\xc3\",\"stop\":false}\n\n",
+            b"data: {\"content\":\"\xa9 ok\",\"stop\":false}\n\n",
+            b"data: {\"content\":\"\",\"stop\":true}\n\n",
+        ];
+        let stream = futures::stream::iter(
+            events
+                .into_iter()
+                .map(|e| Ok::<_, std::convert::Infallible>(bytes::Bytes::from_static(e))),
+        );
+        let state = StreamProcessState::new(
+            Arc::new(test_template()),
+            Arc::new(vec!["<|eot_id|>".to_string()]),
+            Arc::new("<|end_of_text|>".to_string()),
+            StreamBoundary::Token,
+        );
 
-                if LlamaAdapter::has_role_pollution(&self.accumulated) {
-                    frames.push(StreamFrame::Delta {
-                        content: ROLE_POLLUTION_FALLBACK.to_string(),
-                    });
-                    self.fallback_sent = true;
-                    self.accumulated.clear();
-                } else {
-                    let cleaned = LlamaAdapter::clean_streaming_content(&chunk.content);
-                    if !cleaned.trim().is_empty() {
-                        frames.push(StreamFrame::Delta { content: cleaned });
-                    }
-                }
-            }
-        }
+        let (frames, _) =
+            LlamaAdapter::process_sse_stream(stream, state, 0, Arc::new(AtomicBool::new(false)))
+                .await
+                .expect("stream processed");
 
-        if chunk.stop {
-            let final_cleaned = TemplateEngine::clean_response(
-                &self.accumulated,
-                template,
-                stop_sequences,
-                eos_token,
+        let text: String = frames
+            .iter()
+            .filter_map(|frame| match frame {
+                StreamFrame::Delta { content } => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "café ok");
+    }
+
+    #[tokio::test]
+    async fn responses_breaking_the_format_are_flagged() {
+        let constraint =
+            OutputConstraint::new(Some(&chatsafe_common::ResponseFormat::JsonObject)).unwrap();
+        for (content, expected) in [
+            (r#"{\"ok\": true}"#, "stop"),
+            (r#"Sure! {\"ok\": true}"#, "invalid_format"),
+        ] {
+            let events = vec![
+                format!("data: {{\"content\":\"{}\",\"stop\":false}}\n\n", content),
+                "data: {\"content\":\"\",\"stop\":true}\n\n".to_string(),
+            ];
+            let stream = futures::stream::iter(
+                events
+                    .into_iter()
+                    .map(|e| Ok::<_, std::convert::Infallible>(bytes::Bytes::from(e))),
             );
+            let state = StreamProcessState::new(
+                Arc::new(test_template()),
+                Arc::new(vec!["<|eot_id|>".to_string()]),
+                Arc::new("<|end_of_text|>".to_string()),
+                StreamBoundary::Token,
+            )
+            .with_output_check(constraint.clone());
 
-            if final_cleaned.stopped_at.is_some() {
-                frames.push(StreamFrame::Delta {
-                    content: "\n".to_string(),
-                });
-            }
+            let (frames, _) = LlamaAdapter::process_sse_stream(
+                stream,
+                state,
+                0,
+                Arc::new(AtomicBool::new(false)),
+            )
+            .await
+            .expect("stream processed");
 
-            return true;
+            let Some(StreamFrame::Done { finish_reason, .. }) = frames.last() else {
+                panic!("expected Done: {:?}", frames);
+            };
+            assert_eq!(serde_json::to_value(finish_reason).unwrap(), expected);
         }
+    }
 
-        false
+    #[tokio::test]
+    async fn tool_call_replies_become_tool_call_frames() {
+        let events: Vec<&[u8]> = vec![
+            b"data: {\"content\":\"<tool_call>\\n{\\\"name\\\": \\\"get_weather\\\", \",\"stop\":false}\n\n",
+            b"data: {\"content\":\"\\\"arguments\\\": {\\\"city\\\": \\\"Paris\\\"}}\\n</tool_call>\",\"stop\":false}\n\n",
+            b"data: {\"content\":\"\",\"stop\":true}\n\n",
+        ];
+        let stream = futures::stream::iter(
+            events
+                .into_iter()
+                .map(|e| Ok::<_, std::convert::Infallible>(bytes::Bytes::from_static(e))),
+        );
+        let tool: Tool = serde_json::from_value(serde_json::json!({
+            "type": "function",
+            "function": { "name": "get_weather", "parameters": { "type": "object" } }
+        }))
+        .unwrap();
+        let state = StreamProcessState::new(
+            Arc::new(test_template()),
+            Arc::new(vec!["<|eot_id|>".to_string()]),
+            Arc::new("<|end_of_text|>".to_string()),
+            StreamBoundary::Token,
+        )
+        .with_tools(Arc::new(vec![tool]));
+
+        let (frames, _) =
+            LlamaAdapter::process_sse_stream(stream, state, 0, Arc::new(AtomicBool::new(false)))
+                .await
+                .expect("stream processed");
+
+        assert!(!frames
+            .iter()
+            .any(|frame| matches!(frame, StreamFrame::Delta { .. })));
+        let Some(StreamFrame::ToolCalls { tool_calls }) = frames.iter().rev().nth(1) else {
+            panic!("expected tool calls before Done: {:?}", frames);
+        };
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert!(matches!(
+            frames.last(),
+            Some(StreamFrame::Done {
+                finish_reason: FinishReason::ToolCalls,
+                ..
+            })
+        ));
     }
-}
 
-/// Parameters for stream generation
-struct StreamParams {
-    request: CompletionRequest,
-    url: String,
-    request_id: Arc<String>,
-    model_id: Arc<String>,
-    template: Arc<TemplateConfig>,
-    stop_sequences: Arc<Vec<String>>,
-    eos_token: Arc<String>,
-    active_reqs: Arc<RwLock<std::collections::HashMap<String, oneshot::Sender<()>>>>,
-    cancel_rx: oneshot::Receiver<()>,
-}
+    #[tokio::test]
+    async fn token_logprobs_precede_their_text() {
+        let events: Vec<&[u8]> = vec![
+            br#"data: {"content":"Hi","stop":false,"completion_probabilities":[{"id":1,"token":"Hi","bytes":[72,105],"logprob":-0.25,"top_logprobs":[{"id":1,"token":"Hi","bytes":[72,105],"logprob":-0.25},{"id":2,"token":"Hey","bytes":[72,101,121],"logprob":-1.5}]}]}"#,
+            b"\n\n",
+            br#"data: {"content":"<|eot_id|>","stop":false,"completion_probabilities":[{"id":3,"token":"<|eot_id|>","logprob":-0.01,"top_logprobs":[]}]}"#,
+            b"\n\n",
+            b"data: {\"content\":\"\",\"stop\":true}\n\n",
+        ];
+        let stream = futures::stream::iter(
+            events
+                .into_iter()
+                .map(|e| Ok::<_, std::convert::Infallible>(bytes::Bytes::from_static(e))),
+        );
+        let state = StreamProcessState::new(
+            Arc::new(test_template()),
+            Arc::new(vec!["<|eot_id|>".to_string()]),
+            Arc::new("<|end_of_text|>".to_string()),
+            StreamBoundary::Token,
+        )
+        .with_logprobs(Some(1));
 
-impl LlamaAdapter {
-    /// Create the generation stream
-    fn create_generation_stream(
-        params: StreamParams,
-    ) -> impl Stream<Item = Result<StreamFrame>> + Send {
-        async_stream::stream! {
-            // Track cleanup
-            let _cleanup = scopeguard::guard(params.active_reqs.clone(), |reqs| {
-                let id = params.request_id.to_string();
-                tokio::spawn(async move {
-                    let mut r = reqs.write().await;
-                    r.remove(&id);
-                });
-            });
+        let (frames, _) =
+            LlamaAdapter::process_sse_stream(stream, state, 0, Arc::new(AtomicBool::new(false)))
+                .await
+                .expect("stream processed");
 
-            // Send start frame immediately
-            yield Ok(StreamFrame::Start {
-                id: params.request_id.to_string(),
-                model: params.model_id.to_string(),
-                role: Role::Assistant,
-            });
+        // The stop token's probabilities go with it
+        let logprobs: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| match frame {
+                StreamFrame::Logprobs { content } => Some(content),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(logprobs.len(), 1);
+        let token = &logprobs[0][0];
+        assert_eq!(token.token, "Hi");
+        assert_eq!(token.logprob, -0.25);
+        assert_eq!(token.bytes, b"Hi");
+        assert_eq!(token.top_logprobs.len(), 1);
+
+        let logprobs_at = frames
+            .iter()
+            .position(|f| matches!(f, StreamFrame::Logprobs { .. }));
+        let text_at = frames
+            .iter()
+            .position(|f| matches!(f, StreamFrame::Delta { content } if content == "Hi"));
+        assert!(logprobs_at < text_at, "{:?}", frames);
+    }
 
-            // Process the streaming response
-            match Self::process_stream_response(
-                params.request,
-                params.url,
-                params.template,
-                params.stop_sequences,
-                params.eos_token,
-                params.cancel_rx,
-            ).await {
-                Ok(result) => {
-                    // Yield all frames from the processing
-                    for frame in result {
-                        yield Ok(frame);
-                    }
-                }
-                Err(e) => {
-                    yield Ok(StreamFrame::Error {
-                        message: e.to_string(),
-                    });
-                }
+    /// Minimal llama-server stand-in answering every request with `body`,
+    /// returning its base URL and the request line of each request served
+    async fn mock_llama_server(body: &'static str) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = served.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let read = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..read]);
+                let line = request.lines().next().unwrap_or_default();
+                log.lock().unwrap().push(line.to_string());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
             }
-        }
+        });
+        (base_url, served)
     }
 
-    /// Process the streaming response from llama.cpp server
-    async fn process_stream_response(
-        request: CompletionRequest,
-        url: String,
-        template: Arc<TemplateConfig>,
-        stop_sequences: Arc<Vec<String>>,
-        eos_token: Arc<String>,
-        mut cancel_rx: oneshot::Receiver<()>,
-    ) -> Result<Vec<StreamFrame>> {
-        let mut frames = Vec::new();
+    fn test_adapter(base_url: String, model_path: &str, manage_process: bool) -> LlamaAdapter {
+        let registry = chatsafe_config::ModelRegistry::load_defaults().unwrap();
+        let model = registry.get_default_model().unwrap().clone();
+        LlamaAdapter::new(
+            PathBuf::from(model_path),
+            model,
+            test_template(),
+            RuntimeConfig {
+                llama_server_port: 0,
+                threads: 1,
+                gpu_layers: None,
+                generation_timeout_secs: 300,
+                connect_timeout_secs: 5,
+                base_url: Some(base_url),
+                manage_process,
+                instances: Vec::new(),
+                load_balancing: chatsafe_config::LoadBalancing::RoundRobin,
+                working_dir: None,
+                adaptive_context: false,
+                min_ctx_window: 2048,
+                max_prompt_tokens: None,
+                transcription_port: 8091,
+                embedding_port: 8092,
+                guard_port: 8093,
+                parallel_slots: 4,
+                hardware_tuning: false,
+                truncate_history: true,
+            },
+        )
+        .unwrap()
+    }
 
-        // Build streaming request
-        let client = Self::create_default_client()?;
+    #[tokio::test]
+    async fn test_health_probes_are_cached() {
+        let (base_url, probes) = mock_llama_server("ok").await;
+        let adapter = test_adapter(base_url, "model.gguf", true);
 
-        let request_json = serde_json::to_string(&request)
-            .map_err(|e| Error::RuntimeError(format!("Failed to serialize request: {}", e)))?;
+        let first = adapter.health().await.unwrap();
+        assert!(first.is_healthy);
+        assert!(first.last_success.is_some());
 
-        // Make request with cancellation support
-        let response_future = client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .header("Accept", "text/event-stream")
-            .body(request_json)
-            .send();
-
-        // Race between response and cancellation
-        let response = tokio::select! {
-            resp = response_future => resp,
-            _ = &mut cancel_rx => {
-                return Ok(vec![StreamFrame::Error {
-                    message: "Request cancelled".to_string(),
-                }]);
-            }
-        };
+        let second = adapter.health().await.unwrap();
+        assert_eq!(second.last_success, first.last_success);
+        assert_eq!(probes.lock().unwrap().len(), 1);
 
-        let response =
-            response.map_err(|e| Error::RuntimeError(format!("Request failed: {}", e)))?;
+        let stats = adapter.pool_stats().unwrap();
+        assert_eq!(stats.requests, 1);
+        assert_eq!(stats.awaiting_response, 0);
+    }
 
-        if !response.status().is_success() {
-            return Ok(vec![StreamFrame::Error {
-                message: format!("Server error: {}", response.status()),
-            }]);
-        }
-
-        // Process SSE stream
-        frames.extend(
-            Self::process_sse_stream(
-                response,
-                template,
-                stop_sequences,
-                eos_token,
-                request.prompt,
-            )
-            .await?,
+    #[tokio::test]
+    async fn test_attach_validates_loaded_model() {
+        let props = r#"{"model_path":"/srv/models/llama-3.2-3b.gguf"}"#;
+        let (base_url, _) = mock_llama_server(props).await;
+        let model_id = chatsafe_config::ModelRegistry::load_defaults()
+            .unwrap()
+            .get_default_model()
+            .unwrap()
+            .id
+            .clone();
+
+        let mut adapter = test_adapter(base_url.clone(), "/home/me/models/other.gguf", false);
+        let err = adapter.load(&model_id).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidModel(_)));
+        assert!(adapter.get_handle().await.is_none());
+
+        // Same file under a different directory is accepted
+        let mut adapter =
+            test_adapter(base_url.clone(), "/home/me/models/llama-3.2-3b.gguf", false);
+        let handle = adapter.load(&model_id).await.unwrap();
+        assert_eq!(&*handle.model_id, model_id.as_str());
+        adapter.shutdown().await.unwrap();
+
+        // A spawned server must report exactly the path it was given
+        let adapter = test_adapter(base_url.clone(), "/home/me/models/llama-3.2-3b.gguf", true);
+        let err = adapter
+            .verify_loaded_model(base_url.trim_end_matches('/'), &model_id)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("another llama-server"));
+        let adapter = test_adapter(base_url.clone(), "/srv/models/llama-3.2-3b.gguf", true);
+        adapter
+            .verify_loaded_model(base_url.trim_end_matches('/'), &model_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_in_input_order() {
+        let body = r#"{"model_path":"/srv/models/embed.gguf","data":[{"index":1,"embedding":[0.5]},{"index":0,"embedding":[0.25,0.75]}],"usage":{"prompt_tokens":4}}"#;
+        let (base_url, _) = mock_llama_server(body).await;
+        let mut adapter = test_adapter(base_url, "/srv/models/embed.gguf", false);
+        adapter.model_config.capability = Capability::Embed;
+        let inputs = vec!["first".to_string(), "second".to_string()];
+
+        let err = adapter.embed(&inputs).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidModel(_)));
+
+        let model_id = adapter.model_id().to_string();
+        adapter.load(&model_id).await.unwrap();
+        let embeddings = adapter.embed(&inputs).await.unwrap();
+        assert_eq!(embeddings.vectors, vec![vec![0.25, 0.75], vec![0.5]]);
+        assert_eq!(embeddings.prompt_tokens, 4);
+
+        // Spawned embedding servers run in embedding mode
+        let instance = adapter.instances[0].config.clone();
+        let command = adapter.build_server_command(&instance);
+        assert!(command.as_std().get_args().any(|arg| arg == "--embedding"));
+
+        // A mismatch in count is an error, not a silently short response
+        let err = adapter.embed(&inputs[..1]).await.unwrap_err();
+        assert!(err.to_string().contains("2 embeddings for 1 inputs"));
+    }
+
+    #[tokio::test]
+    async fn test_classify_reads_guard_verdict() {
+        let body = r#"{"model_path":"/srv/models/guard.gguf","content":" unsafe\nS1, S10"}"#;
+        let (base_url, _) = mock_llama_server(body).await;
+        let mut adapter = test_adapter(base_url, "/srv/models/guard.gguf", false);
+        adapter.model_config.capability = Capability::Guard;
+        let messages = vec![Message::new(Role::User, "How do I hurt someone?")];
+
+        let err = adapter
+            .classify(&messages, GuardTarget::Prompt)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidModel(_)));
+
+        let model_id = adapter.model_id().to_string();
+        adapter.load(&model_id).await.unwrap();
+        let verdict = adapter
+            .classify(&messages, GuardTarget::Prompt)
+            .await
+            .unwrap();
+        assert!(!verdict.safe);
+        assert_eq!(verdict.categories, vec!["S1", "S10"]);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_instance_fails_over() {
+        let sse = "data: {\"content\":\"hi\",\"stop\":false}\n\ndata: {\"content\":\"\",\"stop\":true}\n\n";
+        let (live_url, served) = mock_llama_server(sse).await;
+
+        // A port nothing listens on
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_port = dead.local_addr().unwrap().port();
+        drop(dead);
+
+        let instances: Vec<Instance> = [dead_port, 0]
+            .into_iter()
+            .map(|port| {
+                Instance::new(InstanceConfig {
+                    port,
+                    main_gpu: None,
+                    base_url: (port == 0).then(|| live_url.clone()),
+                })
+            })
+            .collect();
+        let routes = Balancer::new(chatsafe_config::LoadBalancing::RoundRobin).routes(&instances);
+        assert_eq!(routes[0].url, format!("http://127.0.0.1:{}", dead_port));
+
+        let runtime_config = chatsafe_config::AppConfig::default().runtime;
+        let clients = BackendClients::new(&runtime_config).unwrap();
+        let request = CompletionRequest {
+            prompt: RawPrompt::Text("Hello".to_string()).into(),
+            n_predict: 8,
+            temperature: 0.7,
+            top_p: 0.9,
+            top_k: 40,
+            repeat_penalty: 1.1,
+            sampler: Default::default(),
+            stop: vec![],
+            stream: true,
+            cache_prompt: true,
+            id_slot: None,
+            grammar: None,
+            json_schema: None,
+            n_probs: None,
+        };
+        let state = StreamProcessState::new(
+            Arc::new(test_template()),
+            Arc::new(vec![]),
+            Arc::new("<|end_of_text|>".to_string()),
+            StreamBoundary::Token,
         );
+        let (_cancel_tx, cancel_rx) = oneshot::channel();
 
-        Ok(frames)
+        let (frames, _, served_by) =
+            LlamaAdapter::process_stream_response(&clients, request, routes, state, cancel_rx)
+                .await
+                .unwrap();
+
+        assert_eq!(served_by.as_deref(), Some(live_url.trim_end_matches('/')));
+        assert_eq!(served.lock().unwrap().len(), 1);
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, StreamFrame::Delta { content } if content == "hi")));
+        assert!(!instances[0].load().is_healthy());
+        assert_eq!(instances[1].load().in_flight(), 0);
     }
 
-    /// Process SSE event stream
-    async fn process_sse_stream(
-        response: reqwest::Response,
-        template: Arc<TemplateConfig>,
-        stop_sequences: Arc<Vec<String>>,
-        eos_token: Arc<String>,
-        prompt: String,
-    ) -> Result<Vec<StreamFrame>> {
+    #[tokio::test]
+    async fn test_unreachable_instance_is_probed_back_into_rotation() {
+        let (url, probes) = mock_llama_server("ok").await;
+        let instances = vec![Instance::new(InstanceConfig {
+            port: 0,
+            main_gpu: None,
+            base_url: Some(url),
+        })];
+        let routes = Balancer::new(chatsafe_config::LoadBalancing::RoundRobin).routes(&instances);
+        let runtime_config = chatsafe_config::AppConfig::default().runtime;
+        let clients = BackendClients::new(&runtime_config).unwrap();
+
+        // Repeated failures share one probing task
+        routes[0].reprobe_every(&clients, Duration::from_millis(20));
+        routes[0].reprobe_every(&clients, Duration::from_millis(20));
+        assert!(!instances[0].load().is_healthy());
+
+        for _ in 0..100 {
+            if instances[0].load().is_healthy() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(instances[0].load().is_healthy());
+        assert_eq!(*probes.lock().unwrap(), ["GET /health HTTP/1.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_up_turn_returns_to_its_slot() {
         use futures::StreamExt;
 
-        let mut frames = Vec::new();
-        let mut bytes_stream = response.bytes_stream();
-        let mut buffer = Vec::new();
-        let mut dropped_frames = 0;
-        let mut state = StreamProcessState::new();
-        let mut stream_complete = false;
+        let sse = "data: {\"content\":\"hi\",\"stop\":false}\n\ndata: {\"content\":\"\",\"stop\":true,\"id_slot\":1}\n\n";
+        let (url, _) = mock_llama_server(sse).await;
+        let mut adapter = test_adapter(url, "model.gguf", false);
+        let handle = adapter.set_loaded(&adapter.model_config.id.clone());
+        assert!(adapter.instances[0].probe(&adapter.clients).await);
+
+        let mut messages = vec![Message::new(Role::User, "Hello")];
+        for follow_up in [false, true] {
+            let Generation { stream, metadata } = adapter
+                .generate(&handle, messages.clone(), GenerationParams::default())
+                .await
+                .unwrap();
+            stream.collect::<Vec<_>>().await;
+            let metadata = metadata.await.unwrap();
+            assert_eq!(metadata.slot_id, Some(1));
+            assert_eq!(metadata.slot_affinity, follow_up);
+            messages.push(Message {
+                role: Role::User,
+                content: "And then?".to_string(),
+                ..messages[0].clone()
+            });
+        }
 
-        while let Some(chunk_result) = bytes_stream.next().await {
-            let bytes =
-                chunk_result.map_err(|e| Error::RuntimeError(format!("Stream error: {}", e)))?;
-
-            buffer.extend_from_slice(&bytes);
-
-            // Process SSE lines
-            while let Some(newline_pos) = buffer.windows(2).position(|w| w == b"\n\n") {
-                let event_bytes = buffer.drain(..newline_pos + 2).collect::<Vec<_>>();
-                let event = String::from_utf8_lossy(&event_bytes);
-
-                // Parse SSE event
-                for line in event.lines() {
-                    if let Some(data) = line.strip_prefix("data: ") {
-                        match Self::parse_sse_chunk(data) {
-                            Ok(chunk) => {
-                                if state.handle_chunk(
-                                    &chunk,
-                                    &mut frames,
-                                    template.as_ref(),
-                                    stop_sequences.as_ref(),
-                                    &eos_token,
-                                ) {
-                                    stream_complete = true;
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                // Log the malformed frame but continue processing
-                                warn!("Dropped malformed SSE frame: {}", e);
-                                dropped_frames += 1;
-                            }
+        // Erased slots hold no conversation
+        adapter.affinity.clear();
+        let Generation { stream, metadata } = adapter
+            .generate(&handle, messages, GenerationParams::default())
+            .await
+            .unwrap();
+        stream.collect::<Vec<_>>().await;
+        assert!(!metadata.await.unwrap().slot_affinity);
+    }
+
+    #[tokio::test]
+    async fn test_history_dropped_to_fit_context() {
+        use futures::StreamExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Counts one token per byte and keeps the completion prompts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let seen = prompts.clone();
+        let tokenized = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let counted = tokenized.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let body = loop {
+                    let read = socket.read(&mut buf).await.unwrap_or(0);
+                    request.extend_from_slice(&buf[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| {
+                                l.to_ascii_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length || read == 0 {
+                            break (head.to_string(), body.to_string());
                         }
                     }
-                }
-
-                if stream_complete {
-                    break;
-                }
+                    if read == 0 {
+                        break (text, String::new());
+                    }
+                };
+                let (head, body) = body;
+                let json: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+                let reply = if head.starts_with("POST /tokenize") {
+                    let content = json["content"].as_str().unwrap_or("").to_string();
+                    let tokens = content.len();
+                    counted.lock().unwrap().push(content);
+                    serde_json::json!({ "tokens": vec![0; tokens] }).to_string()
+                } else {
+                    seen.lock()
+                        .unwrap()
+                        .push(json["prompt"].as_str().unwrap_or("").to_string());
+                    "data: {\"content\":\"ok\",\"stop\":false}\n\ndata: {\"content\":\"\",\"stop\":true}\n\n".to_string()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    reply.len(),
+                    reply
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
             }
+        });
 
-            if stream_complete {
-                break;
-            }
+        let mut adapter = test_adapter(url, "model.gguf", false);
+        adapter.ctx_size = 500;
+        let handle = adapter.set_loaded(&adapter.model_config.id.clone());
+        let message = |role, content: String| Message::new(role, content);
+        let mut messages = vec![message(Role::System, "Be brief.".to_string())];
+        for turn in 0..6 {
+            messages.push(message(
+                Role::User,
+                format!("question {} {}", turn, "x".repeat(40)),
+            ));
+            messages.push(message(
+                Role::Assistant,
+                format!("answer {} {}", turn, "y".repeat(40)),
+            ));
         }
+        messages.push(message(Role::User, "last question".to_string()));
+        let params = GenerationParams {
+            max_tokens: 100,
+            ..Default::default()
+        };
 
-        // Log warning if any frames were dropped
-        if dropped_frames > 0 {
-            warn!(
-                "Dropped {} malformed SSE frames during streaming",
-                dropped_frames
-            );
+        let Generation { stream, metadata } = adapter
+            .generate(&handle, messages.clone(), params.clone())
+            .await
+            .unwrap();
+        stream.collect::<Vec<_>>().await;
+        let dropped = metadata.await.unwrap().truncated_messages;
+        assert!(dropped > 0 && dropped < 12, "dropped {}", dropped);
+
+        let prompt = prompts.lock().unwrap().last().cloned().unwrap();
+        assert!(prompt.len() <= 400, "{} bytes", prompt.len());
+        assert!(prompt.contains("Be brief.") && prompt.contains("last question"));
+        assert!(prompt.contains("answer 5") && !prompt.contains("question 0"));
+        // The kept history opens on a user turn, counted at most once
+        assert!(!prompt.contains("answer 0"));
+        let count = tokenized
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|p| **p == prompt)
+            .count();
+        assert!(count <= 1, "counted {} times", count);
+        // As few as possible: keeping one more turn would not fit
+        let mut longer = messages[..1].to_vec();
+        longer.extend_from_slice(&messages[1 + dropped - 2..]);
+        assert!(adapter.build_prompt(&longer, &params).len() > 400);
+
+        // Nothing is dropped with truncation off
+        adapter.runtime_config.truncate_history = false;
+        let fitted = adapter.fit_context(messages.clone(), &params).await;
+        assert_eq!((fitted.messages.len(), fitted.dropped), (messages.len(), 0));
+    }
+
+    #[test]
+    fn test_history_cuts_keep_tool_results_with_their_call() {
+        let message = |role| Message::new(role, "");
+        let messages = [
+            message(Role::System),
+            message(Role::User),
+            message(Role::Assistant),
+            message(Role::Tool),
+            message(Role::Assistant),
+            message(Role::User),
+            message(Role::Assistant),
+        ];
+        assert_eq!(history_cuts(&messages), [1, 5]);
+        assert!(history_cuts(&messages[..1]).is_empty());
+        assert_eq!(history_cuts(&messages[..2]), [1]);
+    }
+
+    #[test]
+    fn test_adaptive_context_halves_down_to_floor() {
+        let mut adapter = test_adapter("http://127.0.0.1:1".to_string(), "model.gguf", true);
+        adapter.ctx_size = 8192;
+        assert_eq!(adapter.reduced_context_size(), None);
+
+        adapter.runtime_config.adaptive_context = true;
+        let mut sizes = Vec::new();
+        while let Some(reduced) = adapter.reduced_context_size() {
+            adapter.ctx_size = reduced;
+            sizes.push(reduced);
         }
+        assert_eq!(sizes, [4096, 2048]);
+
+        let instance = adapter.instances[0].config.clone();
+        let cmd = adapter.build_server_command(&instance);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        let ctx_flag = args.iter().position(|arg| *arg == "--ctx-size").unwrap();
+        assert_eq!(args[ctx_flag + 1], "2048");
+        assert_eq!(adapter.set_loaded("test").context_size, 2048);
+    }
 
-        // Send done frame with usage stats
-        frames.push(StreamFrame::Done {
-            finish_reason: FinishReason::Stop,
-            usage: Usage {
-                prompt_tokens: Self::estimate_tokens(&prompt),
-                completion_tokens: state.token_count,
-                total_tokens: Self::estimate_tokens(&prompt) + state.token_count,
-            },
+    #[test]
+    fn test_server_command_applies_env_and_working_dir() {
+        let mut adapter = test_adapter("http://127.0.0.1:1".to_string(), "model.gguf", true);
+        adapter
+            .model_config
+            .env
+            .insert("CUDA_VISIBLE_DEVICES".to_string(), "1".to_string());
+        adapter.runtime_config.working_dir = Some(PathBuf::from("/tmp"));
+
+        let instance = adapter.instances[0].config.clone();
+        let cmd = adapter.build_server_command(&instance);
+        let cmd = cmd.as_std();
+
+        assert_eq!(cmd.get_current_dir(), Some(std::path::Path::new("/tmp")));
+        assert!(std::path::Path::new(cmd.get_program()).is_absolute());
+        let env: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(
+            env,
+            vec![(
+                std::ffi::OsStr::new("CUDA_VISIBLE_DEVICES"),
+                Some(std::ffi::OsStr::new("1"))
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slots_erased_after_opt_out_and_flush() {
+        use futures::StreamExt;
+
+        let sse = "data: {\"content\":\"hi\",\"stop\":false}\n\ndata: {\"content\":\"\",\"stop\":true,\"id_slot\":2}\n\n";
+        let (url, served) = mock_llama_server(sse).await;
+        let adapter = test_adapter(url, "model.gguf", true)
+            .with_slot_save_path(Some(PathBuf::from("/data/chatsafe/slots")));
+
+        // Erasing only works on servers started with a slot directory
+        let instance = adapter.instances[0].config.clone();
+        let cmd = adapter.build_server_command(&instance);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        let flag = args
+            .iter()
+            .position(|arg| *arg == "--slot-save-path")
+            .unwrap();
+        assert_eq!(args[flag + 1], "/data/chatsafe/slots");
+
+        let mut adapter = adapter;
+        let handle = adapter.set_loaded(&adapter.model_config.id.clone());
+        let params = GenerationParams {
+            cache_prompt: false,
+            ..Default::default()
+        };
+        let message = Message::new(Role::User, "Forget this");
+        let Generation { stream, .. } = adapter
+            .generate(&handle, vec![message], params)
+            .await
+            .unwrap();
+        let frames: Vec<_> = stream.collect().await;
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, Ok(StreamFrame::Delta { content }) if content == "hi")));
+        assert_eq!(
+            served.lock().unwrap().last().unwrap(),
+            "POST /slots/2?action=erase HTTP/1.1"
+        );
+
+        served.lock().unwrap().clear();
+        assert_eq!(adapter.flush_caches().await.unwrap(), 4);
+        let erased: Vec<String> = (0..4)
+            .map(|slot| format!("POST /slots/{}?action=erase HTTP/1.1", slot))
+            .collect();
+        assert_eq!(*served.lock().unwrap(), erased);
+    }
+
+    #[tokio::test]
+    async fn test_lora_adapters_and_scales() {
+        let mut adapter = test_adapter("http://127.0.0.1:1".to_string(), "model.gguf", true);
+        adapter.model_config.loras = vec![chatsafe_config::LoraAdapter {
+            path: "style.gguf".to_string(),
+            scale: 0.8,
+        }];
+        let adapter = adapter.with_loras(vec![PathBuf::from("/models/style.gguf")]);
+        let model_id = adapter.model_id().to_string();
+
+        let instance = adapter.instances[0].config.clone();
+        let cmd = adapter.build_server_command(&instance);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        let lora = args.iter().position(|arg| *arg == "--lora-scaled").unwrap();
+        assert_eq!(args[lora + 1], "/models/style.gguf");
+        assert_eq!(args[lora + 2], "0.8");
+
+        // Not loaded: the scale is kept for the next start
+        let loras = adapter.set_lora_scale(&model_id, 0, 0.0).await.unwrap();
+        assert_eq!(loras[0].scale, 0.0);
+        let cmd = adapter.build_server_command(&instance);
+        assert!(cmd.as_std().get_args().any(|arg| arg == "0"));
+        assert!(adapter.set_lora_scale(&model_id, 1, 1.0).await.is_err());
+        assert!(adapter.lora_adapters("other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lora_scales_both_kept() {
+        let (base_url, served) = mock_llama_server("{}").await;
+        let mut adapter = test_adapter(base_url, "model.gguf", false);
+        adapter.model_config.loras = ["style.gguf", "tone.gguf"]
+            .into_iter()
+            .map(|path| chatsafe_config::LoraAdapter {
+                path: path.to_string(),
+                scale: 1.0,
+            })
+            .collect();
+        let mut adapter = adapter.with_loras(vec![
+            PathBuf::from("/models/style.gguf"),
+            PathBuf::from("/models/tone.gguf"),
+        ]);
+        let model_id = adapter.model_id().to_string();
+        adapter.set_loaded(&model_id);
+
+        let (first, second) = tokio::join!(
+            adapter.set_lora_scale(&model_id, 0, 0.25),
+            adapter.set_lora_scale(&model_id, 1, 0.5),
+        );
+        first.unwrap();
+        second.unwrap();
+        let scales: Vec<_> = adapter.lora_states().iter().map(|l| l.scale).collect();
+        assert_eq!(scales, [0.25, 0.5]);
+        assert_eq!(served.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sentence_finish_trims_partial_sentence_at_limit() {
+        let sse = "data: {\"content\":\"Done. Then\",\"stop\":false}\n\n\
+                   data: {\"content\":\" more\",\"stop\":false}\n\n\
+                   data: {\"content\":\"\",\"stop\":true,\"stopped_limit\":true}\n\n";
+        let (base_url, _) = mock_llama_server(sse).await;
+        let instance = Instance::new(InstanceConfig {
+            port: 0,
+            main_gpu: None,
+            base_url: Some(base_url),
         });
+        let clients = BackendClients::new(&chatsafe_config::AppConfig::default().runtime).unwrap();
+
+        for (finish, expected) in [
+            (FinishMode::Exact, "Done. Then more"),
+            (FinishMode::Sentence, "Done."),
+        ] {
+            let request = CompletionRequest {
+                prompt: RawPrompt::Text("Hello".to_string()).into(),
+                n_predict: 3,
+                temperature: 0.7,
+                top_p: 0.9,
+                top_k: 40,
+                repeat_penalty: 1.1,
+                sampler: Default::default(),
+                stop: vec![],
+                stream: true,
+                cache_prompt: true,
+                id_slot: None,
+                grammar: None,
+                json_schema: None,
+                n_probs: None,
+            };
+            let state = StreamProcessState::new(
+                Arc::new(test_template()),
+                Arc::new(vec![]),
+                Arc::new("<|end_of_text|>".to_string()),
+                StreamBoundary::Token,
+            )
+            .with_finish(finish);
+            let routes = Balancer::new(chatsafe_config::LoadBalancing::RoundRobin)
+                .routes(std::slice::from_ref(&instance));
+            let (_cancel_tx, cancel_rx) = oneshot::channel();
+
+            let (frames, _, _) =
+                LlamaAdapter::process_stream_response(&clients, request, routes, state, cancel_rx)
+                    .await
+                    .unwrap();
+
+            let text: String = frames
+                .iter()
+                .filter_map(|frame| match frame {
+                    StreamFrame::Delta { content } => Some(content.as_str()),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(text, expected);
+            assert!(matches!(
+                frames.last(),
+                Some(StreamFrame::Done {
+                    finish_reason: FinishReason::Length,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_length_checked_in_backend_tokens() {
+        let (base_url, served) = mock_llama_server("{\"tokens\":[1,2,3,4,5,6]}").await;
+        let mut adapter = test_adapter(base_url, "model.gguf", false);
+        adapter.ctx_size = 8;
+
+        // Short enough in bytes to skip the backend
+        adapter
+            .check_prompt_length(&RawPrompt::Text("héllo".to_string()), None)
+            .await
+            .unwrap();
+        assert!(served.lock().unwrap().is_empty());
+
+        adapter
+            .check_prompt_length(&RawPrompt::Text("ten bytes!".to_string()), None)
+            .await
+            .unwrap();
+        assert_eq!(served.lock().unwrap().len(), 1);
+
+        adapter.runtime_config.max_prompt_tokens = Some(4);
+        let err = adapter
+            .check_prompt_length(&RawPrompt::Text("ten bytes!".to_string()), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ValidationFailed(_)));
+        assert!(err.to_string().contains("6 tokens (10 chars)"));
+
+        // A count already taken is not asked for again
+        let err = adapter
+            .check_prompt_length(&RawPrompt::Text("ten bytes!".to_string()), Some(9))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("9 tokens (10 chars)"));
+        assert_eq!(served.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_images_become_media_markers() {
+        let mut adapter = test_adapter("http://127.0.0.1:9".to_string(), "model.gguf", false);
+        let messages: Vec<Message> = serde_json::from_value(serde_json::json!([
+            { "role": "user", "content": [
+                { "type": "text", "text": "What is this? <__media__>" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+            ] }
+        ]))
+        .unwrap();
+
+        let err = adapter.take_images(messages.clone()).unwrap_err();
+        assert!(matches!(err, Error::BadRequest(_)));
+
+        adapter.model_config.vision = true;
+        let (messages, images) = adapter.take_images(messages).unwrap();
+        assert_eq!(images, vec!["AAAA".to_string()]);
+        assert_eq!(messages[0].content, "<__media__>\nWhat is this? ");
+        assert!(messages[0].images.is_empty());
+
+        let prompt = CompletionPrompt::Multimodal {
+            prompt_string: messages[0].content.clone(),
+            multimodal_data: images,
+        };
+        assert_eq!(
+            serde_json::to_value(&prompt).unwrap(),
+            serde_json::json!({
+                "prompt_string": "<__media__>\nWhat is this? ",
+                "multimodal_data": ["AAAA"]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_model_set_routes_by_model() {
+        let (default_url, _) = mock_llama_server("ok").await;
+        let (other_url, _) = mock_llama_server("ok").await;
+        let default = test_adapter(default_url, "model.gguf", true);
+        let default_id = default.model_id().to_string();
+        let mut other = test_adapter(other_url, "other.gguf", true);
+        other.model_config.id = "other-model".to_string();
+
+        let registry = chatsafe_config::ModelRegistry::load_defaults().unwrap();
+        let duplicate = test_adapter("http://127.0.0.1:9".to_string(), "model.gguf", true);
+        let err = crate::ModelSet::new(
+            test_adapter("http://127.0.0.1:9".to_string(), "model.gguf", true),
+            registry.clone(),
+        )
+        .with_model(duplicate)
+        .err()
+        .unwrap();
+        assert!(matches!(err, Error::ConfigError(_)));
+
+        let mut models = crate::ModelSet::new(default, registry)
+            .with_model(other)
+            .unwrap();
+        assert!(models.serves("other-model"));
+        assert!(models.handles().await.is_empty());
+
+        let health = models.model_health().await;
+        let ids: Vec<&str> = health.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, [default_id.as_str(), "other-model"]);
+        assert!(health.iter().all(|(_, health)| health.is_healthy));
+
+        // Models outside the registry cannot replace the default
+        let err = models.load("unknown-model").await.unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)));
+        assert!(models.serves(&default_id));
+        let err = models.unload_model("unknown-model").await.unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)));
+    }
 
-        Ok(frames)
+    #[tokio::test]
+    async fn test_model_set_switches_default() {
+        let (base_url, _) = mock_llama_server(r#"{"model_path":"/srv/models/other.gguf"}"#).await;
+        let registry = chatsafe_config::ModelRegistry::load_defaults().unwrap();
+        let mut data: serde_json::Value =
+            serde_json::from_str(&registry.export().unwrap()).unwrap();
+        let mut other = data["models"][0].clone();
+        other["id"] = "other-model".into();
+        other["path"] = "other.gguf".into();
+        other["default"] = false.into();
+        let mut third = other.clone();
+        third["id"] = "third-model".into();
+        third["path"] = "third.gguf".into();
+        let models = data["models"].as_array_mut().unwrap();
+        models.push(other);
+        models.push(third);
+        let registry = chatsafe_config::ModelRegistry::load_from_json(&data.to_string()).unwrap();
+
+        let default = test_adapter(base_url, "/home/me/models/other.gguf", false);
+        let default_id = default.model_id().to_string();
+        let mut models = crate::ModelSet::new(default, registry);
+        models.load(&default_id).await.unwrap();
+
+        // The server now reports other.gguf, which other-model expects
+        let handle = models.load("other-model").await.unwrap();
+        assert_eq!(&*handle.model_id, "other-model");
+        assert_eq!(models.get_handle().await, Some(handle.clone()));
+        assert!(!models.serves(&default_id));
+
+        // A failed switch brings back the previous model
+        let err = models.load("third-model").await.unwrap_err();
+        assert!(matches!(err, Error::InvalidModel(_)));
+        let restored = models.get_handle().await.unwrap();
+        assert_eq!(&*restored.model_id, "other-model");
+
+        models.unload_model("other-model").await.unwrap();
+        assert!(models.get_handle().await.is_none());
     }
 }
@@ -0,0 +1,301 @@
+//! Several chat models served at once
+//!
+//! The default model runs on the configured instances and every
+//! `models.serve` entry on a llama-server of its own. A handle names the
+//! model it was loaded for, so each generation goes to that model's adapter;
+//! whole-runtime operations (flush, unload, shutdown) reach every model.
+//!
+//! Loading a registry chat model that is not in the set switches the default
+//! model: its server is stopped and one for the new model started on the same
+//! instances. If the new model fails to load, the old one is loaded again.
+//!
+//! `RuntimeHandle::load` starts servers on adapters it owns and only swaps
+//! them into the set once they are ready, so other models keep generating
+//! while one loads.
+
+use crate::{
+    Generation, LlamaAdapter, LoraState, ModelHandle, ModelRuntime, Runtime, RuntimeDiagnostics,
+    RuntimeHealth,
+};
+use async_trait::async_trait;
+use chatsafe_common::{BackendPoolStats, Error, GenerationParams, Message, Result};
+use chatsafe_config::ModelRegistry;
+use tracing::{info, warn};
+
+/// The default chat model plus any served next to it
+pub struct ModelSet {
+    /// The default model first
+    adapters: Vec<LlamaAdapter>,
+    /// Where a switched-to default model is looked up
+    registry: ModelRegistry,
+}
+
+impl ModelSet {
+    pub fn new(default: LlamaAdapter, registry: ModelRegistry) -> Self {
+        Self {
+            adapters: vec![default],
+            registry,
+        }
+    }
+
+    /// Serve another model next to the ones already in the set
+    pub fn with_model(mut self, adapter: LlamaAdapter) -> Result<Self> {
+        if self.serves(adapter.model_id()) {
+            return Err(Error::ConfigError(format!(
+                "Model {} is already served",
+                adapter.model_id()
+            )));
+        }
+        self.adapters.push(adapter);
+        Ok(self)
+    }
+
+    /// Whether `model_id` is one of the set's models, loaded or not
+    pub fn serves(&self, model_id: &str) -> bool {
+        self.adapters
+            .iter()
+            .any(|adapter| adapter.model_id() == model_id)
+    }
+
+    fn default_adapter(&self) -> &LlamaAdapter {
+        &self.adapters[0]
+    }
+
+    fn adapter(&self, model_id: &str) -> Result<&LlamaAdapter> {
+        self.adapters
+            .iter()
+            .find(|adapter| adapter.model_id() == model_id)
+            .ok_or_else(|| Error::ModelNotFound(format!("{} is not served", model_id)))
+    }
+
+    fn adapter_mut(&mut self, model_id: &str) -> Option<&mut LlamaAdapter> {
+        self.adapters
+            .iter_mut()
+            .find(|adapter| adapter.model_id() == model_id)
+    }
+
+    /// A new, unstarted adapter for `model_id` on the instances of
+    /// `instances_of`, the model whose servers it takes over
+    fn fresh_adapter(&self, model_id: &str, instances_of: &str) -> Result<LlamaAdapter> {
+        let current = self.adapter(instances_of)?;
+        Ok(
+            ModelRuntime::chat_adapter(&self.registry, model_id, current.runtime_config().clone())?
+                .with_slot_save_path(current.slot_save_path().cloned()),
+        )
+    }
+
+    /// What loading `model_id` takes
+    pub(crate) async fn plan_load(&self, model_id: &str) -> Result<LoadPlan> {
+        if !self.serves(model_id) {
+            let default_id = self.default_adapter().model_id();
+            return Ok(LoadPlan::Switch {
+                replacement: Box::new(self.fresh_adapter(model_id, default_id)?),
+                placeholder: Box::new(self.fresh_adapter(default_id, default_id)?),
+            });
+        }
+        match self.adapter(model_id)?.get_handle().await {
+            Some(handle) => Ok(LoadPlan::Loaded(handle)),
+            None => Ok(LoadPlan::Start(Box::new(
+                self.fresh_adapter(model_id, model_id)?,
+            ))),
+        }
+    }
+
+    /// Put `adapter` in place of the set's adapter for the same model,
+    /// returning that one
+    pub(crate) fn replace(&mut self, adapter: LlamaAdapter) -> Option<LlamaAdapter> {
+        let current = self.adapter_mut(adapter.model_id())?;
+        Some(std::mem::replace(current, adapter))
+    }
+
+    /// Put `adapter` in place of the default model's, returning that one
+    pub(crate) fn replace_default(&mut self, adapter: LlamaAdapter) -> LlamaAdapter {
+        std::mem::replace(&mut self.adapters[0], adapter)
+    }
+
+    /// Replace the default model with `model_id` on the same instances
+    async fn switch_default(&mut self, model_id: &str) -> Result<ModelHandle> {
+        let default_id = self.default_adapter().model_id().to_string();
+        let mut replacement = self.fresh_adapter(model_id, &default_id)?;
+        let handle = switch(&mut self.adapters[0], &mut replacement, model_id).await?;
+        self.adapters[0] = replacement;
+        Ok(handle)
+    }
+}
+
+/// How `RuntimeHandle::load` gets a model running
+pub(crate) enum LoadPlan {
+    /// Already running
+    Loaded(ModelHandle),
+    /// A served model that is not running, with a new adapter to start
+    Start(Box<LlamaAdapter>),
+    /// Not in the set: the default model's instances go to `replacement`,
+    /// while `placeholder`, an unstarted adapter for the default model,
+    /// stands in for it
+    Switch {
+        replacement: Box<LlamaAdapter>,
+        placeholder: Box<LlamaAdapter>,
+    },
+}
+
+/// Stop `previous` and start `model_id` on `replacement`, which uses the same
+/// instances; if that fails, `previous` is loaded again when it was loaded
+pub(crate) async fn switch(
+    previous: &mut LlamaAdapter,
+    replacement: &mut LlamaAdapter,
+    model_id: &str,
+) -> Result<ModelHandle> {
+    let previous_id = previous.model_id().to_string();
+    info!(
+        "Switching the default model from {} to {}",
+        previous_id, model_id
+    );
+    let was_loaded = previous.get_handle().await.is_some();
+    previous.shutdown().await?;
+
+    match replacement.load(model_id).await {
+        Ok(handle) => Ok(handle),
+        Err(e) => {
+            warn!(
+                "Loading {} failed, going back to {}: {}",
+                model_id, previous_id, e
+            );
+            if was_loaded {
+                if let Err(reload) = previous.load(&previous_id).await {
+                    warn!("Reloading {} failed too: {}", previous_id, reload);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+#[async_trait]
+impl Runtime for ModelSet {
+    async fn load(&mut self, model_id: &str) -> Result<ModelHandle> {
+        match self.adapter_mut(model_id) {
+            Some(adapter) => adapter.load(model_id).await,
+            None => self.switch_default(model_id).await,
+        }
+    }
+
+    async fn get_handle(&self) -> Option<ModelHandle> {
+        self.default_adapter().get_handle().await
+    }
+
+    async fn handles(&self) -> Vec<ModelHandle> {
+        let mut handles = Vec::with_capacity(self.adapters.len());
+        for adapter in &self.adapters {
+            handles.extend(adapter.get_handle().await);
+        }
+        handles
+    }
+
+    async fn generate(
+        &self,
+        handle: &ModelHandle,
+        messages: Vec<Message>,
+        params: GenerationParams,
+    ) -> Result<Generation> {
+        self.adapter(&handle.model_id)?
+            .generate(handle, messages, params)
+            .await
+    }
+
+    async fn cancel(&self, request_id: &str) -> Result<()> {
+        for adapter in &self.adapters {
+            if adapter.is_generating(request_id).await {
+                return adapter.cancel(request_id).await;
+            }
+        }
+        // Let the default adapter report the unknown request
+        self.default_adapter().cancel(request_id).await
+    }
+
+    async fn flush_caches(&self) -> Result<usize> {
+        let mut slots = 0;
+        for adapter in &self.adapters {
+            slots += adapter.flush_caches().await?;
+        }
+        Ok(slots)
+    }
+
+    async fn backend_version(&self) -> Option<String> {
+        self.default_adapter().backend_version().await
+    }
+
+    fn diagnostics(&self) -> RuntimeDiagnostics {
+        RuntimeDiagnostics {
+            instances: self
+                .adapters
+                .iter()
+                .flat_map(|adapter| adapter.diagnostics().instances)
+                .collect(),
+        }
+    }
+
+    fn pool_stats(&self) -> Option<BackendPoolStats> {
+        // Only the default model's pool; the others' instances are appended
+        let mut stats = self.default_adapter().pool_stats()?;
+        for adapter in &self.adapters[1..] {
+            if let Some(other) = adapter.pool_stats() {
+                stats.instances.extend(other.instances);
+            }
+        }
+        Some(stats)
+    }
+
+    async fn lora_adapters(&self, model_id: &str) -> Result<Vec<LoraState>> {
+        self.adapter(model_id)?.lora_adapters(model_id).await
+    }
+
+    async fn set_lora_scale(
+        &self,
+        model_id: &str,
+        id: usize,
+        scale: f32,
+    ) -> Result<Vec<LoraState>> {
+        self.adapter(model_id)?
+            .set_lora_scale(model_id, id, scale)
+            .await
+    }
+
+    /// The default model's health, which decides whether the server can
+    /// answer; see `model_health` for the others
+    async fn health(&self) -> Result<RuntimeHealth> {
+        self.default_adapter().health().await
+    }
+
+    async fn model_health(&self) -> Vec<(String, RuntimeHealth)> {
+        let mut health = Vec::with_capacity(self.adapters.len());
+        for adapter in &self.adapters {
+            health.extend(adapter.model_health().await);
+        }
+        health
+    }
+
+    async fn unload(&mut self) -> Result<()> {
+        for adapter in &mut self.adapters {
+            adapter.unload().await?;
+        }
+        Ok(())
+    }
+
+    async fn unload_model(&mut self, model_id: &str) -> Result<()> {
+        match self.adapter_mut(model_id) {
+            Some(adapter) => adapter.unload_model(model_id).await,
+            None => Err(Error::ModelNotFound(format!("{} is not served", model_id))),
+        }
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        // Stop every server even if one fails, then report the first error
+        let mut result = Ok(());
+        for adapter in &mut self.adapters {
+            if let Err(e) = adapter.shutdown().await {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,352 @@
+//! Text-to-speech through the piper CLI
+//!
+//! Each registry model with `capability: speech` is one piper voice (an
+//! `.onnx` file next to its `.onnx.json` config). Piper synthesizes one
+//! input per run, so every request spawns its own process and streams the
+//! raw samples from its stdout while it is still speaking.
+
+use chatsafe_common::{Error, Result};
+use chatsafe_config::{ModelConfig, ModelRegistry, RuntimeConfig};
+use futures::Stream;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+// Constants
+const PIPER_BINARY: &str = "./piper/piper";
+const DEFAULT_SAMPLE_RATE: u32 = 22050;
+const READ_CHUNK_BYTES: usize = 8192;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Audio bytes as piper produces them
+pub type AudioStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>;
+
+/// Container for synthesized audio
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// RIFF/WAVE with an open-ended length, playable while streaming
+    #[default]
+    Wav,
+    /// Headerless 16-bit little-endian mono samples
+    Pcm,
+}
+
+/// One piper voice from the registry
+#[derive(Debug, Clone)]
+struct Voice {
+    model_path: PathBuf,
+    sample_rate: u32,
+    env: HashMap<String, String>,
+}
+
+/// Synthesized speech, streaming while piper runs
+pub struct Speech {
+    pub voice: String,
+    pub sample_rate: u32,
+    pub audio: AudioStream,
+}
+
+/// The registry's speech voices and how to run piper for them
+pub struct PiperAdapter {
+    binary: PathBuf,
+    working_dir: Option<PathBuf>,
+    voices: BTreeMap<String, Voice>,
+}
+
+impl PiperAdapter {
+    /// Adapter for every `speech` model in the registry, if there are any
+    pub fn from_registry(
+        registry: &ModelRegistry,
+        runtime_config: &RuntimeConfig,
+    ) -> Result<Option<Self>> {
+        let mut voices = BTreeMap::new();
+        for model in registry.get_speech_models() {
+            let model_path = registry.get_model_path(&model.id)?;
+            voices.insert(model.id.clone(), Voice::new(model_path, model));
+        }
+        if voices.is_empty() {
+            return Ok(None);
+        }
+
+        // Resolve the binary before any directory change, as for llama-server
+        let binary = match &runtime_config.working_dir {
+            Some(_) => std::env::current_dir()
+                .map(|cwd| cwd.join(PIPER_BINARY))
+                .unwrap_or_else(|_| PathBuf::from(PIPER_BINARY)),
+            None => PathBuf::from(PIPER_BINARY),
+        };
+        Ok(Some(Self {
+            binary,
+            working_dir: runtime_config.working_dir.clone(),
+            voices,
+        }))
+    }
+
+    /// Voice IDs, sorted; the first is used when a request names none
+    pub fn voices(&self) -> impl Iterator<Item = &str> {
+        self.voices.keys().map(String::as_str)
+    }
+
+    /// Speak `text`, returning once piper has produced its first audio
+    ///
+    /// `speed` scales the speaking rate (1.0 is the voice's natural pace).
+    pub async fn synthesize(
+        &self,
+        voice: Option<&str>,
+        text: &str,
+        speed: f32,
+        format: AudioFormat,
+    ) -> Result<Speech> {
+        let (voice_id, voice) = match voice {
+            Some(id) => self
+                .voices
+                .get_key_value(id)
+                .ok_or_else(|| Error::ModelNotFound(format!("voice {}", id)))?,
+            None => self
+                .voices
+                .iter()
+                .next()
+                .ok_or_else(|| Error::ModelNotFound("no speech voices configured".into()))?,
+        };
+
+        let mut cmd = Command::new(&self.binary);
+        if let Some(dir) = &self.working_dir {
+            cmd.current_dir(dir);
+        }
+        cmd.envs(&voice.env)
+            .arg("--model")
+            .arg(&voice.model_path)
+            .arg("--output_raw")
+            .arg("--length_scale")
+            .arg((1.0 / speed).to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = cmd.spawn().map_err(|e| {
+            Error::RuntimeError(format!(
+                "Failed to start piper at {}: {}",
+                self.binary.display(),
+                e
+            ))
+        })?;
+
+        let (Some(mut stdin), Some(mut stdout), Some(mut stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(Error::RuntimeError("piper stdio was not captured".into()));
+        };
+        // Piper reads a line per utterance; write from a task so a long
+        // input can't deadlock against unread audio
+        let input = text.replace(['\r', '\n'], " ");
+        tokio::spawn(async move {
+            if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                debug!("Failed to write piper input: {}", e);
+            }
+            // Dropping stdin closes it, which tells piper to finish
+        });
+        let log = tokio::spawn(async move {
+            let mut log = String::new();
+            let _ = stderr.read_to_string(&mut log).await;
+            log
+        });
+
+        // Wait for the first audio so startup failures become errors
+        let mut first = vec![0; READ_CHUNK_BYTES];
+        let read = stdout
+            .read(&mut first)
+            .await
+            .map_err(|e| Error::RuntimeError(format!("Failed to read piper output: {}", e)))?;
+        if read == 0 {
+            let status = child.wait().await.ok();
+            let log = log.await.unwrap_or_default();
+            return Err(Error::RuntimeError(format!(
+                "piper produced no audio (exit {:?}): {}",
+                status.and_then(|s| s.code()),
+                log.trim()
+            )));
+        }
+        first.truncate(read);
+
+        let header = match format {
+            AudioFormat::Wav => wav_header(voice.sample_rate),
+            AudioFormat::Pcm => Vec::new(),
+        };
+        let audio = async_stream::stream! {
+            let mut chunk = header;
+            chunk.extend_from_slice(&first);
+            yield Ok(chunk);
+
+            let mut buf = vec![0; READ_CHUNK_BYTES];
+            loop {
+                match stdout.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => yield Ok(buf[..n].to_vec()),
+                    Err(e) => {
+                        yield Err(Error::RuntimeError(format!("Failed to read piper output: {}", e)));
+                        break;
+                    }
+                }
+            }
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    warn!("piper exited with {}: {}", status, log.await.unwrap_or_default().trim());
+                }
+                Err(e) => warn!("Failed to wait for piper: {}", e),
+                Ok(_) => {}
+            }
+        };
+
+        Ok(Speech {
+            voice: voice_id.clone(),
+            sample_rate: voice.sample_rate,
+            audio: Box::pin(audio),
+        })
+    }
+}
+
+impl Voice {
+    fn new(model_path: PathBuf, model: &ModelConfig) -> Self {
+        Self {
+            sample_rate: voice_sample_rate(&model_path),
+            model_path,
+            env: model.env.clone(),
+        }
+    }
+}
+
+/// Sample rate from the voice's `.onnx.json`, which piper reads itself
+fn voice_sample_rate(model_path: &std::path::Path) -> u32 {
+    let mut config_path = model_path.as_os_str().to_owned();
+    config_path.push(".json");
+    std::fs::read_to_string(&config_path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        .and_then(|config| config.pointer("/audio/sample_rate")?.as_u64())
+        .and_then(|rate| u32::try_from(rate).ok())
+        .unwrap_or(DEFAULT_SAMPLE_RATE)
+}
+
+/// 44-byte WAVE header for mono 16-bit audio of unknown length
+///
+/// The size fields are left at their maximum, which players read as
+/// "until the stream ends".
+fn wav_header(sample_rate: u32) -> Vec<u8> {
+    let block_align = BITS_PER_SAMPLE / 8;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // mono
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn test_adapter(binary: &str, voice_path: PathBuf) -> PiperAdapter {
+        let mut voices = BTreeMap::new();
+        voices.insert(
+            "amy".to_string(),
+            Voice {
+                sample_rate: voice_sample_rate(&voice_path),
+                model_path: voice_path,
+                env: Default::default(),
+            },
+        );
+        PiperAdapter {
+            binary: PathBuf::from(binary),
+            working_dir: None,
+            voices,
+        }
+    }
+
+    #[test]
+    fn test_wav_header_layout() {
+        let header = wav_header(16000);
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[..4], b"RIFF");
+        assert_eq!(&header[24..28], &16000u32.to_le_bytes());
+        assert_eq!(&header[28..32], &32000u32.to_le_bytes());
+        assert_eq!(&header[36..40], b"data");
+    }
+
+    #[test]
+    fn test_sample_rate_from_voice_config() {
+        let dir = std::env::temp_dir().join(format!("chatsafe-piper-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let voice = dir.join("amy.onnx");
+        assert_eq!(voice_sample_rate(&voice), DEFAULT_SAMPLE_RATE);
+
+        std::fs::write(
+            dir.join("amy.onnx.json"),
+            r#"{"audio":{"sample_rate":16000}}"#,
+        )
+        .unwrap();
+        assert_eq!(voice_sample_rate(&voice), 16000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Executable stand-in for piper running `script`, ignoring its arguments
+    #[cfg(unix)]
+    fn fake_piper(dir: &std::path::Path, name: &str, script: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_synthesize_streams_process_output() {
+        let dir = std::env::temp_dir().join(format!("chatsafe-piper-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Echoing the input back stands in for audio samples
+        let adapter = test_adapter(&fake_piper(&dir, "echo", "cat"), dir.join("amy.onnx"));
+        let speech = adapter
+            .synthesize(None, "hello\nworld", 1.0, AudioFormat::Wav)
+            .await
+            .unwrap();
+        assert_eq!(speech.voice, "amy");
+        let audio: Vec<u8> = speech.audio.map(|chunk| chunk.unwrap()).concat().await;
+        assert_eq!(&audio[..44], wav_header(DEFAULT_SAMPLE_RATE).as_slice());
+        assert_eq!(&audio[44..], b"hello world");
+
+        let err = adapter
+            .synthesize(Some("brian"), "hi", 1.0, AudioFormat::Pcm)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::ModelNotFound(_)));
+
+        let adapter = test_adapter(
+            &fake_piper(&dir, "broken", "echo 'voice not found' >&2; exit 1"),
+            dir.join("amy.onnx"),
+        );
+        let err = adapter
+            .synthesize(None, "hi", 1.0, AudioFormat::Pcm)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("voice not found"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod pollution_tests {
-    use crate::template_engine::{StreamChunkResult, TemplateEngine};
+    use crate::template_engine::{StreamChunkResult, StreamState, TemplateEngine};
     use chatsafe_common::{Message, Role};
     use chatsafe_config::TemplateConfig;
 
@@ -14,6 +14,9 @@ mod pollution_tests {
             user_suffix: "<|eot_id|>".to_string(),
             assistant_prefix: "<|start_header_id|>assistant<|end_header_id|>\n\n".to_string(),
             assistant_suffix: "<|eot_id|>".to_string(),
+            tool_prefix: String::new(),
+            tool_suffix: String::new(),
+            stop_tokens: Vec::new(),
             default_system_prompt: "You are helpful.".to_string(),
         }
     }
@@ -100,7 +103,7 @@ mod pollution_tests {
         let template = llama3_template();
         let stop_sequences = vec!["<|eot_id|>".to_string()];
         let eos_token = "<|end_of_text|>";
-        let mut buffer = String::new();
+        let mut state = StreamState::new(&stop_sequences, eos_token);
 
         // Stream chunk with single role marker - should clean but not replace
         let chunk = "AI: This is a response\nContinuing without role marker";
@@ -109,7 +112,7 @@ mod pollution_tests {
             &template,
             &stop_sequences,
             eos_token,
-            &mut buffer,
+            &mut state,
         );
 
         match result {
@@ -126,7 +129,7 @@ mod pollution_tests {
         let template = llama3_template();
         let stop_sequences = vec!["<|eot_id|>".to_string()];
         let eos_token = "<|end_of_text|>";
-        let mut buffer = String::new();
+        let mut state = StreamState::new(&stop_sequences, eos_token);
 
         // Stream chunks that build up to stop sequence
         let chunk1 = "Hello world";
@@ -135,7 +138,7 @@ mod pollution_tests {
             &template,
             &stop_sequences,
             eos_token,
-            &mut buffer,
+            &mut state,
         );
 
         assert!(matches!(result1, StreamChunkResult::Partial { .. }));
@@ -146,7 +149,7 @@ mod pollution_tests {
             &template,
             &stop_sequences,
             eos_token,
-            &mut buffer,
+            &mut state,
         );
 
         match result2 {
@@ -166,18 +169,9 @@ mod pollution_tests {
     fn test_multi_turn_prompt_formatting() {
         let template = llama3_template();
         let messages = vec![
-            Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            },
-            Message {
-                role: Role::Assistant,
-                content: "Hi there!".to_string(),
-            },
-            Message {
-                role: Role::User,
-                content: "How are you?".to_string(),
-            },
+            Message::new(Role::User, "Hello"),
+            Message::new(Role::Assistant, "Hi there!"),
+            Message::new(Role::User, "How are you?"),
         ];
 
         let prompt = TemplateEngine::format_prompt(&messages, &template);
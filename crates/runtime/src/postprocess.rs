@@ -0,0 +1,125 @@
+//! Final-response processors configured per model
+//!
+//! Run once the whole response is known, on both the streaming and the
+//! non-streaming path. The adapter buffers a generation's frames before
+//! sending them, so a processor may rewrite any part of the text, not just
+//! append to it.
+
+use crate::sanitize;
+use chatsafe_config::PostProcessor;
+
+/// Run `processors` over `text` in order
+pub fn apply(processors: &[PostProcessor], text: &str) -> String {
+    let mut text = text.to_string();
+    for processor in processors {
+        text = match processor {
+            PostProcessor::CloseCodeFences => close_code_fences(&text),
+            PostProcessor::NormalizeLists => normalize_list_numbering(&text),
+            PostProcessor::SanitizeMarkup => sanitize::sanitize_markup(&text),
+        };
+    }
+    text
+}
+
+/// Opening fence of a line, e.g. "```" or "~~~~", if it is one
+pub(crate) fn fence(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.len() - trimmed.trim_start_matches(marker).len();
+    (len >= 3).then(|| &trimmed[..len])
+}
+
+/// Close a code block left open when generation stopped inside it
+pub fn close_code_fences(text: &str) -> String {
+    let mut open: Option<&str> = None;
+    for line in text.lines() {
+        let Some(marker) = fence(line) else {
+            continue;
+        };
+        match open {
+            None => open = Some(marker),
+            // A closing fence uses the same character, at least as long
+            Some(opener)
+                if marker.starts_with(opener)
+                    && line.trim().trim_start_matches(&marker[..1]).is_empty() =>
+            {
+                open = None
+            }
+            Some(_) => {}
+        }
+    }
+
+    let Some(opener) = open else {
+        return text.to_string();
+    };
+    let separator = if text.ends_with('\n') { "" } else { "\n" };
+    format!("{}{}{}", text, separator, opener)
+}
+
+/// Split "  3. item" into its indent, number and the rest from the delimiter
+fn ordered_item(line: &str) -> Option<(usize, u64, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    let tail = &rest[digits..];
+    let mut chars = tail.chars();
+    let delimiter_ok = matches!(chars.next(), Some('.') | Some(')'));
+    let spaced = matches!(chars.next(), Some(' ') | Some('\t') | None);
+    if !(delimiter_ok && spaced) {
+        return None;
+    }
+    Some((indent, rest[..digits].parse().ok()?, tail))
+}
+
+/// Renumber ordered lists sequentially from their first number
+///
+/// Models often repeat "1." or skip numbers. Blank lines and more deeply
+/// indented lines don't end a list; code blocks are left untouched.
+pub fn normalize_list_numbering(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    // (indent, next number) per open list, innermost last
+    let mut lists: Vec<(usize, u64)> = Vec::new();
+    let mut code_fence: Option<String> = None;
+
+    for line in text.split_inclusive('\n') {
+        if let Some(marker) = fence(line) {
+            match &code_fence {
+                None => code_fence = Some(marker.to_string()),
+                Some(opener) if marker.starts_with(opener.as_str()) => code_fence = None,
+                Some(_) => {}
+            }
+            result.push_str(line);
+            continue;
+        }
+        if code_fence.is_some() || line.trim().is_empty() {
+            result.push_str(line);
+            continue;
+        }
+
+        match ordered_item(line) {
+            Some((indent, number, tail)) => {
+                lists.retain(|(list_indent, _)| *list_indent <= indent);
+                let next = match lists.last_mut() {
+                    Some((list_indent, next)) if *list_indent == indent => next,
+                    _ => {
+                        lists.push((indent, number));
+                        &mut lists.last_mut().expect("just pushed").1
+                    }
+                };
+                result.push_str(&line[..indent]);
+                result.push_str(&next.to_string());
+                result.push_str(tail);
+                *next += 1;
+            }
+            None => {
+                let indent = line.len() - line.trim_start().len();
+                lists.retain(|(list_indent, _)| *list_indent < indent);
+                result.push_str(line);
+            }
+        }
+    }
+    result
+}
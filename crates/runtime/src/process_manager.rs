@@ -2,9 +2,14 @@
 //!
 //! This module provides a robust process manager that ensures child processes
 //! are properly cleaned up and their output streams are drained to prevent deadlock.
+//! The last lines of output are kept so startup failures can say why, and
+//! the exit status is kept so a kill by the OOM killer can be told apart.
 
 use chatsafe_common::Result;
-use std::process::Stdio;
+use std::collections::VecDeque;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
@@ -16,6 +21,152 @@ const GRACEFUL_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
 const FORCEFUL_KILL_TIMEOUT_SECS: u64 = 2;
 const LOG_PREFIX_STDOUT: &str = "stdout";
 const LOG_PREFIX_STDERR: &str = "stderr";
+const OUTPUT_TAIL_LINES: usize = 50;
+const OUTPUT_DRAIN_WAIT_MS: u64 = 500;
+const OUTPUT_DRAIN_POLL_MS: u64 = 10;
+/// SIGKILL, sent by both the Linux OOM killer and macOS jetsam
+#[cfg(unix)]
+const SIGKILL: i32 = 9;
+/// Lowercase fragments llama.cpp and its GPU backends log on allocation failure
+const OOM_MARKERS: &[&str] = &[
+    "out of memory",
+    "outofmemory",
+    "failed to allocate",
+    "unable to allocate",
+    "cudamalloc failed",
+    "insufficient memory",
+];
+
+/// Most recent stdout/stderr lines of a child, oldest first
+#[derive(Clone, Default)]
+pub struct OutputTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    /// Output streams still being drained
+    open_streams: Arc<AtomicUsize>,
+}
+
+impl OutputTail {
+    fn push(&self, stream: &str, line: &str) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == OUTPUT_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(format!("{}: {}", stream, line));
+    }
+
+    /// Snapshot of the buffered lines
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+
+    /// Lines once both streams have closed, or after a short wait
+    ///
+    /// A child that just exited may still have output in its pipes.
+    pub async fn lines_after_exit(&self) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(OUTPUT_DRAIN_WAIT_MS);
+        while self.open_streams.load(Ordering::SeqCst) > 0 && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(OUTPUT_DRAIN_POLL_MS)).await;
+        }
+        self.lines()
+    }
+}
+
+/// How a child process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCause {
+    /// Killed for using too much memory, or logged an allocation failure
+    OutOfMemory,
+    /// Killed by another signal
+    Signal(i32),
+    /// Exited on its own with this code
+    Code(i32),
+    /// Status no longer available (e.g. reaped elsewhere)
+    Unknown,
+}
+
+impl ExitCause {
+    /// Classify an exit from its status and last output lines
+    ///
+    /// chatsafe only ever SIGKILLs a child after taking it out of its
+    /// `ExitWatch`, so a SIGKILL seen here came from the kernel (or jetsam).
+    pub fn classify(status: Option<ExitStatus>, output: &[String]) -> Self {
+        let logged_oom = output.iter().any(|line| {
+            let line = line.to_lowercase();
+            OOM_MARKERS.iter().any(|marker| line.contains(marker))
+        });
+        if logged_oom {
+            return ExitCause::OutOfMemory;
+        }
+
+        let Some(status) = status else {
+            return ExitCause::Unknown;
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return if signal == SIGKILL {
+                    ExitCause::OutOfMemory
+                } else {
+                    ExitCause::Signal(signal)
+                };
+            }
+        }
+        status.code().map_or(ExitCause::Unknown, ExitCause::Code)
+    }
+}
+
+/// Shared handle to a child that can be polled without `&mut ProcessManager`
+///
+/// In-flight streams hold one so they can read the exit status of a
+/// backend that died under them.
+#[derive(Clone, Default)]
+pub struct ExitWatch {
+    child: Arc<Mutex<Option<Child>>>,
+    status: Arc<Mutex<Option<ExitStatus>>>,
+}
+
+impl ExitWatch {
+    fn new(child: Child) -> Self {
+        Self {
+            child: Arc::new(Mutex::new(Some(child))),
+            status: Arc::default(),
+        }
+    }
+
+    fn child(&self) -> MutexGuard<'_, Option<Child>> {
+        self.child.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take the child out, e.g. to terminate it; its exit is then not recorded
+    fn take(&self) -> Option<Child> {
+        self.child().take()
+    }
+
+    /// Reap the child if it has exited; `Ok(true)` while it still runs
+    fn poll(&self) -> std::io::Result<bool> {
+        let mut slot = self.child();
+        let Some(child) = slot.as_mut() else {
+            return Ok(false);
+        };
+        match child.try_wait()? {
+            Some(status) => {
+                *slot = None;
+                *self.status.lock().unwrap_or_else(|e| e.into_inner()) = Some(status);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Exit status, once the child has exited by itself
+    pub fn status(&self) -> Option<ExitStatus> {
+        let _ = self.poll();
+        *self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
 
 /// Process manager that handles spawning, monitoring, and cleanup of child processes
 ///
@@ -24,14 +175,44 @@ const LOG_PREFIX_STDERR: &str = "stderr";
 /// - Graceful shutdown is attempted before forceful kill
 /// - Processes are cleaned up on drop
 pub struct ProcessManager {
-    child: Option<Child>,
+    watch: ExitWatch,
     name: String,
+    exited: Arc<AtomicBool>,
+    output: OutputTail,
 }
 
 impl ProcessManager {
     /// Create a new process manager with the given name for logging
     pub fn new(name: String) -> Self {
-        Self { child: None, name }
+        Self {
+            watch: ExitWatch::default(),
+            name,
+            exited: Arc::new(AtomicBool::new(false)),
+            output: OutputTail::default(),
+        }
+    }
+
+    /// PID of the current child, if one is running
+    pub fn pid(&self) -> Option<u32> {
+        self.watch.child().as_ref().and_then(|child| child.id())
+    }
+
+    /// Exit watch of the current (or last) child
+    pub fn exit_watch(&self) -> ExitWatch {
+        self.watch.clone()
+    }
+
+    /// Recent output of the current (or last) child
+    pub fn output(&self) -> OutputTail {
+        self.output.clone()
+    }
+
+    /// Flag that flips to `true` once the current child closes its stdout
+    ///
+    /// Unlike `is_running`, this can be checked without `&mut self`, so
+    /// in-flight streams can tell a dead backend from a network hiccup.
+    pub fn exit_flag(&self) -> Arc<AtomicBool> {
+        self.exited.clone()
     }
 
     /// Spawn a new process with proper stdout/stderr handling
@@ -51,55 +232,56 @@ impl ProcessManager {
             .kill_on_drop(true); // Ensure process is killed if handle is dropped
 
         let mut child = command.spawn()?;
+        self.exited = Arc::new(AtomicBool::new(false));
+        self.output = OutputTail::default();
 
         // Spawn tasks to drain stdout and stderr to prevent blocking
         if let Some(stdout) = child.stdout.take() {
             let name = self.name.clone();
+            let exited = self.exited.clone();
+            let output = self.output.clone();
+            output.open_streams.fetch_add(1, Ordering::SeqCst);
             tokio::spawn(async move {
                 let reader = BufReader::new(stdout);
                 let mut lines = reader.lines();
                 while let Ok(Some(line)) = lines.next_line().await {
                     info!("{} {}: {}", name, LOG_PREFIX_STDOUT, line);
+                    output.push(LOG_PREFIX_STDOUT, &line);
                 }
+                // stdout only closes when the process goes away
+                exited.store(true, Ordering::SeqCst);
+                output.open_streams.fetch_sub(1, Ordering::SeqCst);
             });
         }
 
         if let Some(stderr) = child.stderr.take() {
             let name = self.name.clone();
+            let output = self.output.clone();
+            output.open_streams.fetch_add(1, Ordering::SeqCst);
             tokio::spawn(async move {
                 let reader = BufReader::new(stderr);
                 let mut lines = reader.lines();
                 while let Ok(Some(line)) = lines.next_line().await {
                     // Use warn for stderr as it often contains important diagnostics
                     warn!("{} {}: {}", name, LOG_PREFIX_STDERR, line);
+                    output.push(LOG_PREFIX_STDERR, &line);
                 }
+                output.open_streams.fetch_sub(1, Ordering::SeqCst);
             });
         }
 
-        self.child = Some(child);
+        self.watch = ExitWatch::new(child);
         Ok(())
     }
 
     /// Check if process is still running
     pub fn is_running(&mut self) -> bool {
-        if let Some(ref mut child) = self.child {
-            match child.try_wait() {
-                Ok(Some(_)) => {
-                    // Process has exited
-                    self.child = None;
-                    false
-                }
-                Ok(None) => {
-                    // Still running
-                    true
-                }
-                Err(e) => {
-                    warn!("Error checking {} process status: {}", self.name, e);
-                    false
-                }
+        match self.watch.poll() {
+            Ok(running) => running,
+            Err(e) => {
+                warn!("Error checking {} process status: {}", self.name, e);
+                false
             }
-        } else {
-            false
         }
     }
 
@@ -108,7 +290,7 @@ impl ProcessManager {
     /// Attempts graceful shutdown with SIGTERM first (on Unix),
     /// then falls back to forceful kill if needed.
     pub async fn terminate(&mut self) -> Result<()> {
-        if let Some(mut child) = self.child.take() {
+        if let Some(mut child) = self.watch.take() {
             info!("Terminating {} process", self.name);
 
             // Try graceful shutdown first
@@ -190,7 +372,7 @@ impl ProcessManager {
         if self.is_running() {
             self.terminate().await?;
         }
-        self.child = None;
+        self.watch.take();
         Ok(())
     }
 }
@@ -199,7 +381,7 @@ impl Drop for ProcessManager {
     fn drop(&mut self) {
         // Try to kill process on drop
         // Note: We can't await in drop, so we spawn a detached task
-        if let Some(mut child) = self.child.take() {
+        if let Some(mut child) = self.watch.take() {
             let name = self.name.clone();
 
             // Use spawn_blocking for the kill operation in drop context
@@ -255,6 +437,25 @@ mod tests {
         // but at least we verify no panic occurred
     }
 
+    #[tokio::test]
+    async fn test_exit_flag_set_when_process_exits() {
+        let mut pm = ProcessManager::new("test_exit".to_string());
+        let flag = pm.exit_flag();
+        assert!(!flag.load(Ordering::SeqCst));
+
+        let cmd = Command::new("true");
+        pm.spawn(cmd).await.expect("Failed to spawn process");
+        let flag = pm.exit_flag();
+
+        for _ in 0..50 {
+            if flag.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(flag.load(Ordering::SeqCst));
+    }
+
     #[tokio::test]
     async fn test_multiple_spawn() {
         let mut pm = ProcessManager::new("test_multi".to_string());
@@ -277,4 +478,55 @@ mod tests {
         pm.cleanup().await.expect("Failed to cleanup");
         assert!(!pm.is_running());
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigkill_classified_as_out_of_memory() {
+        let mut pm = ProcessManager::new("test_oom".to_string());
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("kill -9 $$");
+        pm.spawn(cmd).await.expect("Failed to spawn process");
+
+        let watch = pm.exit_watch();
+        let lines = pm.output().lines_after_exit().await;
+        let mut status = None;
+        for _ in 0..50 {
+            status = watch.status();
+            if status.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(ExitCause::classify(status, &lines), ExitCause::OutOfMemory);
+        assert!(!pm.is_running());
+
+        let exit = Command::new("sh")
+            .arg("-c")
+            .arg("exit 3")
+            .status()
+            .await
+            .unwrap();
+        assert_eq!(ExitCause::classify(Some(exit), &[]), ExitCause::Code(3));
+        let logged = ["stderr: ggml_cuda: cudaMalloc failed: out of memory".to_string()];
+        assert_eq!(
+            ExitCause::classify(Some(exit), &logged),
+            ExitCause::OutOfMemory
+        );
+    }
+
+    #[tokio::test]
+    async fn test_output_tail_keeps_last_lines() {
+        let mut pm = ProcessManager::new("test_tail".to_string());
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("for i in $(seq 1 60); do echo line $i; done; echo 'model not found' >&2");
+        pm.spawn(cmd).await.expect("Failed to spawn process");
+
+        let lines = pm.output().lines_after_exit().await;
+        assert_eq!(lines.len(), OUTPUT_TAIL_LINES);
+        assert!(lines.contains(&"stderr: model not found".to_string()));
+        assert!(lines.contains(&"stdout: line 60".to_string()));
+        assert!(!lines.contains(&"stdout: line 1".to_string()));
+    }
 }
@@ -0,0 +1,157 @@
+//! Background CPU/memory sampling of llama-server child processes
+//!
+//! Linux reads `/proc/<pid>/stat` and `/proc/<pid>/status`; macOS asks `ps`.
+//! Samples are taken every few seconds and only the latest one is kept, so
+//! slow generations can be lined up against swapping or CPU saturation.
+
+use chatsafe_common::ProcessResources;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::debug;
+
+// Constants
+const SAMPLE_INTERVAL_SECS: u64 = 5;
+
+/// Raw reading of one process
+struct Reading {
+    /// Total CPU time consumed so far, where the platform reports it
+    cpu_seconds: Option<f64>,
+    /// Instantaneous CPU usage, where the platform reports that instead
+    cpu_percent: Option<f64>,
+    rss_bytes: u64,
+    threads: Option<u64>,
+}
+
+/// Latest resource sample of a child process
+#[derive(Clone, Default)]
+pub(crate) struct ResourceSampler {
+    latest: Arc<Mutex<Option<ProcessResources>>>,
+}
+
+impl ResourceSampler {
+    pub(crate) fn latest(&self) -> Option<ProcessResources> {
+        self.latest
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Sample `pid` until `exited` flips, replacing any previous sampling
+    pub(crate) fn start(&mut self, pid: u32, exited: Arc<AtomicBool>) {
+        // A fresh slot detaches any task still sampling the previous child
+        self.latest = Arc::new(Mutex::new(None));
+        let latest = self.latest.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(SAMPLE_INTERVAL_SECS));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut previous: Option<(Instant, f64)> = None;
+
+            loop {
+                ticker.tick().await;
+                if exited.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Some(reading) = read_process(pid).await else {
+                    debug!("Stopped sampling pid {}: process not readable", pid);
+                    break;
+                };
+
+                let now = Instant::now();
+                let cpu_percent = reading.cpu_percent.or_else(|| {
+                    let cpu = reading.cpu_seconds?;
+                    let (then, cpu_then) = previous.replace((now, cpu))?;
+                    let wall = now.duration_since(then).as_secs_f64();
+                    (wall > 0.0).then(|| (cpu - cpu_then) / wall * 100.0)
+                });
+
+                let sample = ProcessResources {
+                    pid,
+                    cpu_percent,
+                    rss_bytes: reading.rss_bytes,
+                    threads: reading.threads,
+                    sampled_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                };
+                *latest.lock().unwrap_or_else(|e| e.into_inner()) = Some(sample);
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn read_process(pid: u32) -> Option<Reading> {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    let stat = tokio::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .await
+        .ok()?;
+    let status = tokio::fs::read_to_string(format!("/proc/{}/status", pid))
+        .await
+        .ok()?;
+
+    // The command name may contain spaces, so count fields after its ')'
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    let ticks_per_second = sysconf(SysconfVar::CLK_TCK).ok().flatten()? as f64;
+
+    let status_value = |key: &str| -> Option<u64> {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    };
+
+    Some(Reading {
+        cpu_seconds: Some((utime + stime) / ticks_per_second),
+        cpu_percent: None,
+        rss_bytes: status_value("VmRSS:")? * 1024,
+        threads: status_value("Threads:"),
+    })
+}
+
+#[cfg(target_os = "macos")]
+async fn read_process(pid: u32) -> Option<Reading> {
+    let output = tokio::process::Command::new("ps")
+        .args(["-o", "%cpu=,rss=", "-p", &pid.to_string()])
+        .output()
+        .await
+        .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut values = text.split_whitespace();
+    let cpu_percent: f64 = values.next()?.parse().ok()?;
+    let rss_kb: u64 = values.next()?.parse().ok()?;
+
+    Some(Reading {
+        cpu_seconds: None,
+        cpu_percent: Some(cpu_percent),
+        rss_bytes: rss_kb * 1024,
+        threads: None,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+async fn read_process(_pid: u32) -> Option<Reading> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reads_own_process() {
+        let reading = read_process(std::process::id()).await.unwrap();
+        assert!(reading.rss_bytes > 0);
+        assert!(reading.threads.unwrap() >= 1);
+        assert!(reading.cpu_seconds.unwrap() >= 0.0);
+    }
+}
@@ -0,0 +1,90 @@
+//! JSON output constrained by grammar
+//!
+//! `json_object` sends llama-server a GBNF grammar for a JSON object (the one
+//! llama.cpp ships as `grammars/json.gbnf`); `json_schema` sends the schema
+//! as `json_schema`, which llama-server converts to GBNF itself. Sampling
+//! then cannot leave the grammar, but generation can still stop early, so
+//! the finished text is parsed and checked against the schema as well.
+
+use chatsafe_common::{Error, ResponseFormat, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// GBNF for a single JSON object
+pub const JSON_OBJECT_GRAMMAR: &str = r#"root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4})
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+/// Backend constraint and output check for one request
+#[derive(Clone)]
+pub struct OutputConstraint {
+    /// Sent as llama-server's `grammar`
+    pub grammar: Option<String>,
+    /// Sent as llama-server's `json_schema`
+    pub json_schema: Option<Value>,
+    validator: Option<Arc<Validator>>,
+}
+
+impl OutputConstraint {
+    /// Constraint for `format`, or `None` for free text
+    ///
+    /// Fails with `BadRequest` when the schema itself is invalid.
+    pub fn new(format: Option<&ResponseFormat>) -> Result<Option<Self>> {
+        let schema = match format {
+            None | Some(ResponseFormat::Text) => return Ok(None),
+            Some(ResponseFormat::JsonObject) => None,
+            Some(ResponseFormat::JsonSchema { json_schema }) => json_schema.schema.clone(),
+        };
+        let Some(schema) = schema else {
+            return Ok(Some(Self {
+                grammar: Some(JSON_OBJECT_GRAMMAR.to_string()),
+                json_schema: None,
+                validator: None,
+            }));
+        };
+
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| Error::BadRequest(format!("Invalid response_format schema: {}", e)))?;
+        Ok(Some(Self {
+            grammar: None,
+            json_schema: Some(schema),
+            validator: Some(Arc::new(validator)),
+        }))
+    }
+
+    /// Check the complete output, describing the first problem found
+    pub fn check(&self, text: &str) -> std::result::Result<(), String> {
+        let value: Value =
+            serde_json::from_str(text.trim()).map_err(|e| format!("not valid JSON: {}", e))?;
+        match &self.validator {
+            Some(validator) => validator
+                .validate(&value)
+                .map_err(|e| format!("does not match the schema: {}", e)),
+            None if value.is_object() => Ok(()),
+            None => Err("not a JSON object".to_string()),
+        }
+    }
+}
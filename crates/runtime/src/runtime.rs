@@ -1,28 +1,65 @@
-use crate::{ModelHandle, Runtime, RuntimeHealth};
-use chatsafe_common::{GenerationParams, Message, Result, StreamFrame};
-use chatsafe_config::{AppConfig, ModelRegistry};
-use futures::Stream;
-use std::pin::Pin;
+use crate::model_set::{self, LoadPlan};
+use crate::{
+    Generation, LoraState, ModelHandle, ModelSet, Runtime, RuntimeDiagnostics, RuntimeHealth,
+};
+use chatsafe_common::{BackendPoolStats, Error, GenerationParams, Message, Result};
+use chatsafe_config::{
+    AppConfig, Capability, InstanceConfig, ModelRegistry, RuntimeConfig, TemplateConfig,
+};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 /// Handle to interact with the runtime
 #[derive(Clone)]
 pub struct RuntimeHandle {
-    inner: Arc<RwLock<Box<dyn Runtime>>>,
+    inner: Arc<RwLock<ModelSet>>,
+    /// Held by one load at a time
+    loading: Arc<Mutex<()>>,
 }
 
 impl RuntimeHandle {
     /// Create a new runtime handle
-    pub fn new(runtime: Box<dyn Runtime>) -> Self {
+    pub fn new(models: ModelSet) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(runtime)),
+            inner: Arc::new(RwLock::new(models)),
+            loading: Arc::default(),
         }
     }
 
     /// Load a model
+    ///
+    /// The server is started on an adapter outside the set, which is only
+    /// locked to swap it in, so other models keep serving meanwhile.
     pub async fn load(&self, model_id: &str) -> Result<ModelHandle> {
-        self.inner.write().await.load(model_id).await
+        let _loading = self.loading.lock().await;
+        let plan = self.inner.read().await.plan_load(model_id).await?;
+        match plan {
+            LoadPlan::Loaded(handle) => Ok(handle),
+            LoadPlan::Start(mut adapter) => {
+                let handle = adapter.load(model_id).await?;
+                self.inner.write().await.replace(*adapter);
+                Ok(handle)
+            }
+            LoadPlan::Switch {
+                mut replacement,
+                placeholder,
+            } => {
+                // Requests for the default model fail fast while it is stopped
+                let mut previous = self.inner.write().await.replace_default(*placeholder);
+                let result = model_set::switch(&mut previous, &mut replacement, model_id).await;
+                let mut models = self.inner.write().await;
+                match result {
+                    Ok(handle) => {
+                        models.replace_default(*replacement);
+                        Ok(handle)
+                    }
+                    Err(e) => {
+                        models.replace_default(previous);
+                        Err(e)
+                    }
+                }
+            }
+        }
     }
 
     /// Get current model handle
@@ -30,18 +67,28 @@ impl RuntimeHandle {
         self.inner.read().await.get_handle().await
     }
 
+    /// Handles of every loaded model, the default one first
+    pub async fn handles(&self) -> Vec<ModelHandle> {
+        self.inner.read().await.handles().await
+    }
+
     /// Generate completion
     pub async fn generate(
         &self,
         handle: &ModelHandle,
         messages: Vec<Message>,
         params: GenerationParams,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamFrame>> + Send>>> {
-        self.inner
-            .read()
-            .await
-            .generate(handle, messages, params)
-            .await
+    ) -> Result<Generation> {
+        // Waiting behind a model load counts against the request deadline
+        let runtime = match params.remaining() {
+            Some(remaining) => tokio::time::timeout(remaining, self.inner.read())
+                .await
+                .map_err(|_| {
+                    Error::DeadlineExceeded("expired while waiting for the runtime".into())
+                })?,
+            None => self.inner.read().await,
+        };
+        runtime.generate(handle, messages, params).await
     }
 
     /// Cancel generation
@@ -49,16 +96,65 @@ impl RuntimeHandle {
         self.inner.read().await.cancel(request_id).await
     }
 
+    /// Erase backend caches
+    pub async fn flush_caches(&self) -> Result<usize> {
+        self.inner.read().await.flush_caches().await
+    }
+
+    /// Get backend version
+    pub async fn backend_version(&self) -> Option<String> {
+        self.inner.read().await.backend_version().await
+    }
+
+    /// Get backend diagnostics
+    pub async fn diagnostics(&self) -> RuntimeDiagnostics {
+        self.inner.read().await.diagnostics()
+    }
+
+    /// Get backend HTTP pool counters
+    pub async fn pool_stats(&self) -> Option<BackendPoolStats> {
+        self.inner.read().await.pool_stats()
+    }
+
+    /// Get a served model's LoRA adapters
+    pub async fn lora_adapters(&self, model_id: &str) -> Result<Vec<LoraState>> {
+        self.inner.read().await.lora_adapters(model_id).await
+    }
+
+    /// Change the scale of a served model's LoRA adapter
+    pub async fn set_lora_scale(
+        &self,
+        model_id: &str,
+        id: usize,
+        scale: f32,
+    ) -> Result<Vec<LoraState>> {
+        self.inner
+            .read()
+            .await
+            .set_lora_scale(model_id, id, scale)
+            .await
+    }
+
     /// Get runtime health
     pub async fn health(&self) -> Result<RuntimeHealth> {
         self.inner.read().await.health().await
     }
 
+    /// Get the health of each model's backend
+    pub async fn model_health(&self) -> Vec<(String, RuntimeHealth)> {
+        self.inner.read().await.model_health().await
+    }
+
     /// Unload model
     pub async fn unload(&self) -> Result<()> {
         self.inner.write().await.unload().await
     }
 
+    /// Unload one model and stop its backend
+    pub async fn unload_model(&self, model_id: &str) -> Result<()> {
+        self.inner.write().await.unload_model(model_id).await
+    }
+
     /// Shutdown runtime
     pub async fn shutdown(&self) -> Result<()> {
         self.inner.write().await.shutdown().await
@@ -70,20 +166,163 @@ pub struct ModelRuntime;
 
 impl ModelRuntime {
     /// Create a runtime based on configuration
+    ///
+    /// The default model runs on the configured instances and each
+    /// `models.serve` entry on its own llama-server; none is loaded yet.
     pub async fn create(config: &AppConfig, registry: &ModelRegistry) -> Result<RuntimeHandle> {
         // For now, we only support llama.cpp
-        let model_id = config.models.default_model.clone();
-        let model_path = registry.get_model_path(&model_id)?;
-        let model_config = registry.get_model(&model_id)?;
-        let template = registry.get_model_template(&model_id)?;
+        // The configured default may be an alias pinning a model version
+        let model_id = registry
+            .resolve_alias(&config.models.default_model)
+            .to_string();
+        let mut models = ModelSet::new(
+            Self::chat_adapter(registry, &model_id, config.runtime.clone())?
+                .with_slot_save_path(Some(config.slot_dir())),
+            registry.clone(),
+        );
+
+        let mut ports: Vec<u16> = config
+            .runtime
+            .resolved_instances()
+            .iter()
+            .map(|instance| instance.port)
+            .collect();
+        for served in &config.models.serve {
+            let served_id = registry.resolve_alias(&served.model).to_string();
+            if models.serves(&served_id) {
+                return Err(Error::ConfigError(format!(
+                    "models.serve lists {} more than once",
+                    served_id
+                )));
+            }
+            let port = served.instance.port;
+            if served.instance.base_url.is_none() && ports.contains(&port) {
+                return Err(Error::ConfigError(format!(
+                    "models.serve: port {} for {} is already in use by another model",
+                    port, served_id
+                )));
+            }
+            ports.push(port);
+
+            let mut runtime_config = config.runtime.clone();
+            runtime_config.base_url = None;
+            runtime_config.instances = vec![served.instance.clone()];
+            models = models.with_model(
+                Self::chat_adapter(registry, &served_id, runtime_config)?
+                    .with_slot_save_path(Some(config.slot_dir())),
+            )?;
+        }
+
+        Ok(RuntimeHandle::new(models))
+    }
+
+    /// llama-server adapter for one registry chat model
+    pub(crate) fn chat_adapter(
+        registry: &ModelRegistry,
+        model_id: &str,
+        runtime_config: RuntimeConfig,
+    ) -> Result<crate::LlamaAdapter> {
+        let model_config = registry.get_model(model_id)?;
+        if model_config.capability != Capability::Chat {
+            return Err(Error::ConfigError(format!(
+                "{} is not a chat model and cannot serve chat completions",
+                model_id
+            )));
+        }
+        Ok(crate::LlamaAdapter::new(
+            registry.get_model_path(model_id)?,
+            model_config.clone(),
+            registry.get_model_template(model_id)?.clone(),
+            runtime_config,
+        )?
+        .with_mmproj(registry.get_mmproj_path(model_id)?)
+        .with_loras(registry.get_lora_paths(model_id)?))
+    }
+
+    /// Create the speech-to-text backend, if the registry has a `transcribe` model
+    ///
+    /// The server itself starts on the first transcription.
+    pub fn create_transcriber(
+        config: &AppConfig,
+        registry: &ModelRegistry,
+    ) -> Result<Option<crate::WhisperAdapter>> {
+        let Some(model_config) = registry.get_transcription_model() else {
+            return Ok(None);
+        };
+        let model_path = registry.get_model_path(&model_config.id)?;
+        crate::WhisperAdapter::new(model_path, model_config.clone(), config.runtime.clone())
+            .map(Some)
+    }
 
-        let adapter = crate::LlamaAdapter::new(
+    /// Create the embedding backend, if the registry has an `embed` model
+    ///
+    /// It is a second llama-server, in embedding mode on `embedding_port`,
+    /// loaded on the first request.
+    pub fn create_embedder(
+        config: &AppConfig,
+        registry: &ModelRegistry,
+    ) -> Result<Option<crate::LlamaAdapter>> {
+        let Some(model_config) = registry.get_embedding_model() else {
+            return Ok(None);
+        };
+        let model_path = registry.get_model_path(&model_config.id)?;
+        let mut runtime_config = config.runtime.clone();
+        runtime_config.base_url = None;
+        runtime_config.instances = vec![InstanceConfig {
+            port: runtime_config.embedding_port,
+            main_gpu: None,
+            base_url: None,
+        }];
+        crate::LlamaAdapter::new(
             model_path,
             model_config.clone(),
-            template.clone(),
-            config.runtime.clone(),
-        )?;
+            TemplateConfig::default(),
+            runtime_config,
+        )
+        .map(Some)
+    }
+
+    /// Create the guardrail backend, if the registry has a `guard` model
+    ///
+    /// It is another llama-server, on `guard_port`, loaded on first use.
+    pub fn create_guard(
+        config: &AppConfig,
+        registry: &ModelRegistry,
+    ) -> Result<Option<crate::LlamaAdapter>> {
+        let Some(model_config) = registry.get_guard_model() else {
+            return Ok(None);
+        };
+        let model_path = registry.get_model_path(&model_config.id)?;
+        let mut runtime_config = config.runtime.clone();
+        runtime_config.base_url = None;
+        runtime_config.instances = vec![InstanceConfig {
+            port: runtime_config.guard_port,
+            main_gpu: None,
+            base_url: None,
+        }];
+        crate::LlamaAdapter::new(
+            model_path,
+            model_config.clone(),
+            TemplateConfig::default(),
+            runtime_config,
+        )
+        .map(Some)
+    }
 
-        Ok(RuntimeHandle::new(Box::new(adapter)))
+    /// Create the image generator, if the registry has an `image` model
+    #[cfg(feature = "images")]
+    pub fn create_image_generator(
+        config: &AppConfig,
+        registry: &ModelRegistry,
+    ) -> Result<Option<crate::SdAdapter>> {
+        let Some(model_config) = registry.get_image_model() else {
+            return Ok(None);
+        };
+        let model_path = registry.get_model_path(&model_config.id)?;
+        Ok(Some(crate::SdAdapter::new(
+            model_path,
+            model_config.clone(),
+            config.runtime.clone(),
+        )))
     }
 }
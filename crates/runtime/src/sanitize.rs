@@ -0,0 +1,320 @@
+//! Neutralizing markup that is dangerous when a client renders output as HTML
+//!
+//! Thin clients often pass model output through a markdown renderer that
+//! allows inline HTML. A prompt-injected or hallucinated `<script>` tag,
+//! event handler or `javascript:` link would then run in the client. The
+//! sanitizer escapes the `<` of every tag outside a small allowlist of
+//! formatting tags, and of allowed tags with handlers, dangerous schemes or
+//! entities in their attributes, so they render as text. Dangerous link
+//! targets become `#`. Tags are scanned across lines, since HTML lets
+//! attributes span them, and a `>` inside a quoted attribute value does not
+//! end them. Code blocks and code spans are left alone: renderers show them
+//! literally, and escaping would corrupt code examples. Backticks inside a
+//! tag are attribute text, as in CommonMark, so they can't hide the tag.
+
+use crate::postprocess::fence;
+
+/// Formatting tags left as HTML when their attributes are harmless
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "b",
+    "blockquote",
+    "br",
+    "cite",
+    "code",
+    "dd",
+    "del",
+    "details",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "summary",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// URL schemes that run code or smuggle content
+const DANGEROUS_SCHEMES: &[&str] = &["javascript:", "vbscript:", "data:"];
+
+/// Replacement for a dangerous link target
+const SAFE_TARGET: &str = "#";
+
+/// A run of text that is either prose or code
+enum Segment<'a> {
+    Prose(String),
+    Code(&'a str),
+}
+
+/// Where a left-to-right scan is relative to an HTML tag
+#[derive(Default)]
+struct TagState {
+    open: bool,
+    /// Quote of the attribute value being read
+    quote: Option<char>,
+    /// Just after `=`, where a quote starts a value
+    after_equals: bool,
+}
+
+impl TagState {
+    /// Advance over `c`, followed by `next`; true if `c` belongs to a tag
+    fn step(&mut self, c: char, next: Option<char>) -> bool {
+        if !self.open {
+            self.open = c == '<'
+                && next.is_some_and(|n| n.is_ascii_alphabetic() || matches!(n, '/' | '!' | '?'));
+            return self.open;
+        }
+        match self.quote {
+            Some(quote) if c == quote => self.quote = None,
+            Some(_) => {}
+            None if c == '>' => self.open = false,
+            None if matches!(c, '"' | '\'') && self.after_equals => self.quote = Some(c),
+            None => {}
+        }
+        self.after_equals =
+            self.quote.is_none() && (c == '=' || (self.after_equals && c.is_whitespace()));
+        true
+    }
+}
+
+/// Escape dangerous HTML and defuse dangerous links outside code
+pub fn sanitize_markup(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for segment in segments(text) {
+        match segment {
+            Segment::Code(code) => result.push_str(code),
+            Segment::Prose(prose) => {
+                // Tags may span lines, links and reference definitions don't
+                for line in escape_tags(&prose).split_inclusive('\n') {
+                    result.push_str(&defuse_links(line));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Split text into code and prose, joining prose across lines
+///
+/// Code spans and tags are matched left to right, whichever starts first.
+fn segments(text: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut code_fence: Option<String> = None;
+    let mut tag = TagState::default();
+
+    for line in text.split_inclusive('\n') {
+        if let Some(marker) = fence(line) {
+            match &code_fence {
+                None => code_fence = Some(marker.to_string()),
+                Some(opener) if marker.starts_with(opener.as_str()) => code_fence = None,
+                Some(_) => {}
+            }
+            segments.push(Segment::Code(line));
+            continue;
+        }
+        if code_fence.is_some() {
+            segments.push(Segment::Code(line));
+            continue;
+        }
+        let mut prose_start = 0;
+        let mut chars = line.char_indices().peekable();
+        while let Some((open, c)) = chars.next() {
+            let next = chars.peek().map(|&(_, next)| next);
+            if tag.step(c, next) || c != '`' {
+                continue;
+            }
+            // An unmatched backtick opens no code span
+            let Some(length) = line[open + 1..].find('`') else {
+                continue;
+            };
+            let close = open + 1 + length;
+            push_prose(&mut segments, &line[prose_start..open]);
+            segments.push(Segment::Code(&line[open..=close]));
+            prose_start = close + 1;
+            while chars.next_if(|&(i, _)| i <= close).is_some() {}
+        }
+        push_prose(&mut segments, &line[prose_start..]);
+    }
+    segments
+}
+
+/// Length of the tag starting at `tag`, through its `>` or to the end
+fn tag_len(tag: &str) -> usize {
+    let mut state = TagState::default();
+    let mut chars = tag.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        state.step(c, chars.peek().map(|&(_, next)| next));
+        if !state.open {
+            return i + c.len_utf8();
+        }
+    }
+    tag.len()
+}
+
+fn push_prose(segments: &mut Vec<Segment<'_>>, prose: &str) {
+    if let Some(Segment::Prose(last)) = segments.last_mut() {
+        last.push_str(prose);
+    } else {
+        segments.push(Segment::Prose(prose.to_string()));
+    }
+}
+
+/// Escape the `<` of disallowed or dangerous tags and autolinks
+fn escape_tags(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        if is_dangerous_tag(&tag[..tag_len(tag)]) {
+            result.push_str("&lt;");
+        } else {
+            result.push('<');
+        }
+        rest = &tag[1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn is_dangerous_tag(tag: &str) -> bool {
+    let inner = &tag[1..];
+    // Comments, doctypes and processing instructions
+    if inner.starts_with(['!', '?']) {
+        return true;
+    }
+    let inner = inner.strip_prefix('/').unwrap_or(inner);
+    let name: String = inner
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let after = &inner[name.len()..];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !(after.is_empty()
+            || after.starts_with(char::is_whitespace)
+            || after.starts_with(['/', '>', ':']))
+    {
+        // Not a tag, e.g. "a < b"
+        return false;
+    }
+    if after.starts_with(':') {
+        // An autolink, e.g. <https://example.com>
+        return is_dangerous_url(inner);
+    }
+    let normalized = normalize(tag);
+    !ALLOWED_TAGS.contains(&name.as_str())
+        || has_event_handler(&tag.to_ascii_lowercase())
+        || DANGEROUS_SCHEMES
+            .iter()
+            .any(|scheme| normalized.contains(scheme))
+        // Entities could spell out a scheme or hide a separator
+        || has_entity(&normalized)
+}
+
+/// Whether text has a numeric or named character reference, e.g. `&colon;`
+fn has_entity(text: &str) -> bool {
+    text.match_indices('&').any(|(i, _)| {
+        let reference = &text[i + 1..];
+        let name = reference
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .count();
+        reference.starts_with('#') || (name > 0 && reference[name..].starts_with(';'))
+    })
+}
+
+/// Whether a lowercased tag has an `on...=` attribute
+fn has_event_handler(tag: &str) -> bool {
+    tag.match_indices("on").any(|(i, _)| {
+        let separated = tag[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '/'));
+        let attribute = tag[i..]
+            .chars()
+            .take_while(char::is_ascii_alphabetic)
+            .count();
+        separated && attribute > 2 && tag[i + attribute..].trim_start().starts_with('=')
+    })
+}
+
+/// Replace dangerous targets of `[text](url)` links and images
+fn defuse_links(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("](") {
+        let target_start = start + 2;
+        result.push_str(&rest[..target_start]);
+        let target = &rest[target_start..];
+        let end = target.find(')').unwrap_or(target.len());
+        if is_dangerous_url(&target[..end]) {
+            result.push_str(SAFE_TARGET);
+        } else {
+            result.push_str(&target[..end]);
+        }
+        rest = &target[end..];
+    }
+    // Reference definitions: `[id]: url`
+    if let Some((label, url)) = rest.split_once("]:") {
+        if label.trim_start().starts_with('[') && is_dangerous_url(url) {
+            result.push_str(label);
+            result.push_str("]: ");
+            result.push_str(SAFE_TARGET);
+            result.push_str(if url.ends_with('\n') { "\n" } else { "" });
+            return result;
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn is_dangerous_url(url: &str) -> bool {
+    let normalized = normalize(url);
+    let normalized = normalized.trim_start_matches('<');
+    DANGEROUS_SCHEMES
+        .iter()
+        .any(|scheme| normalized.starts_with(scheme))
+        // Renderers decode entities in link targets
+        || normalized.contains("&#")
+        || normalized.contains("&colon;")
+}
+
+/// Lowercase without whitespace or control characters, which browsers
+/// ignore inside a scheme (`java\tscript:`)
+fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,189 @@
+//! Image generation through stable-diffusion.cpp's `sd` CLI
+//!
+//! The registry model with `capability: image` is run by `sd` through
+//! `ProcessManager`, one run per request, and the PNGs it writes to a
+//! scratch directory are read back. Runs are serialized by the caller since
+//! each one takes the whole GPU.
+
+use crate::process_manager::ProcessManager;
+use chatsafe_common::{Error, Result};
+use chatsafe_config::{ModelConfig, RuntimeConfig};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{info, warn};
+
+// Constants
+const SD_BINARY: &str = "./stable-diffusion.cpp/build/bin/sd";
+const EXIT_POLL_MS: u64 = 200;
+const OUTPUT_FILE: &str = "image.png";
+
+/// Prompt and options for one generation run
+#[derive(Debug, Clone)]
+pub struct ImageRequest {
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    /// Number of images, generated as one batch
+    pub count: usize,
+    pub width: u32,
+    pub height: u32,
+    pub steps: Option<u32>,
+    /// Fixed seed for reproducible images; random when `None`
+    pub seed: Option<i64>,
+}
+
+/// The registry's image model and the process running it
+pub struct SdAdapter {
+    model_id: String,
+    model_path: PathBuf,
+    model_config: ModelConfig,
+    runtime_config: RuntimeConfig,
+    binary: PathBuf,
+    process: ProcessManager,
+}
+
+impl SdAdapter {
+    pub fn new(
+        model_path: PathBuf,
+        model_config: ModelConfig,
+        runtime_config: RuntimeConfig,
+    ) -> Self {
+        // Resolve the binary before any directory change, as for llama-server
+        let binary = match &runtime_config.working_dir {
+            Some(_) => std::env::current_dir()
+                .map(|cwd| cwd.join(SD_BINARY))
+                .unwrap_or_else(|_| PathBuf::from(SD_BINARY)),
+            None => PathBuf::from(SD_BINARY),
+        };
+        Self {
+            model_id: model_config.id.clone(),
+            process: ProcessManager::new(format!("sd:{}", model_config.id)),
+            model_path,
+            model_config,
+            runtime_config,
+            binary,
+        }
+    }
+
+    /// ID of the registry model being served
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// Run `sd` and return the PNG bytes of every image it wrote
+    pub async fn generate(&mut self, request: &ImageRequest) -> Result<Vec<Vec<u8>>> {
+        let scratch = std::env::temp_dir().join(format!("chatsafe-sd-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&scratch)?;
+        let result = self.run(request, &scratch).await;
+        if let Err(e) = std::fs::remove_dir_all(&scratch) {
+            warn!("Failed to remove {}: {}", scratch.display(), e);
+        }
+        result
+    }
+
+    async fn run(&mut self, request: &ImageRequest, scratch: &Path) -> Result<Vec<Vec<u8>>> {
+        info!(
+            "Generating {} {}x{} image(s) with {}",
+            request.count, request.width, request.height, self.model_id
+        );
+        self.process
+            .spawn(self.build_command(request, &scratch.join(OUTPUT_FILE)))
+            .await
+            .map_err(|e| {
+                Error::RuntimeError(format!(
+                    "Failed to start sd at {}: {}",
+                    self.binary.display(),
+                    e
+                ))
+            })?;
+
+        let deadline =
+            Instant::now() + Duration::from_secs(self.runtime_config.generation_timeout_secs);
+        while self.process.is_running() {
+            if Instant::now() >= deadline {
+                self.process.terminate().await?;
+                return Err(Error::Timeout(self.runtime_config.generation_timeout_secs));
+            }
+            sleep(Duration::from_millis(EXIT_POLL_MS)).await;
+        }
+
+        let status = self.process.exit_watch().status();
+        if !status.is_some_and(|s| s.success()) {
+            let output = self.process.output().lines_after_exit().await;
+            return Err(Error::RuntimeError(format!(
+                "sd failed ({:?}):\n{}",
+                status.and_then(|s| s.code()),
+                output.join("\n")
+            )));
+        }
+        read_images(scratch)
+    }
+
+    fn build_command(&self, request: &ImageRequest, output: &Path) -> Command {
+        let mut cmd = Command::new(&self.binary);
+        if let Some(dir) = &self.runtime_config.working_dir {
+            cmd.current_dir(dir);
+        }
+        cmd.envs(&self.model_config.env);
+        cmd.arg("--model")
+            .arg(&self.model_path)
+            .arg("--prompt")
+            .arg(&request.prompt)
+            .arg("--output")
+            .arg(output)
+            .arg("--width")
+            .arg(request.width.to_string())
+            .arg("--height")
+            .arg(request.height.to_string())
+            .arg("--batch-count")
+            .arg(request.count.to_string())
+            .arg("--seed")
+            .arg(request.seed.unwrap_or(-1).to_string())
+            .arg("--threads")
+            .arg(self.model_config.resources.threads.to_string());
+        if let Some(negative) = &request.negative_prompt {
+            cmd.arg("--negative-prompt").arg(negative);
+        }
+        if let Some(steps) = request.steps {
+            cmd.arg("--steps").arg(steps.to_string());
+        }
+        cmd
+    }
+}
+
+/// PNGs in `dir`, in name order (`sd` numbers batch images after the first)
+fn read_images(dir: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        return Err(Error::RuntimeError(
+            "sd exited without writing an image".into(),
+        ));
+    }
+    paths
+        .iter()
+        .map(|path| std::fs::read(path).map_err(Error::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_images_in_batch_order() {
+        let dir = std::env::temp_dir().join(format!("chatsafe-sd-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(read_images(&dir).is_err());
+
+        std::fs::write(dir.join("image_2.png"), b"second").unwrap();
+        std::fs::write(dir.join("image.png"), b"first").unwrap();
+        std::fs::write(dir.join("sd.log"), b"ignored").unwrap();
+        let images = read_images(&dir).unwrap();
+        assert_eq!(images, vec![b"first".to_vec(), b"second".to_vec()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Routing conversations back to the slot holding their prompt cache
+//!
+//! llama-server keeps each slot's last prompt in its KV cache, so a follow-up
+//! turn only evaluates the new messages, but only if it lands on the same
+//! slot of the same instance. A conversation is recognized by a fingerprint
+//! of its opening (system messages and first user message), which stays the
+//! same as turns are appended. The table remembers which instance and slot
+//! last served each fingerprint. The next turn is sent there first with
+//! `id_slot`, unless another pinned request is using that slot or every
+//! slot of that instance is busy; then it is routed and llama-server picks
+//! a slot as usual.
+
+use chatsafe_common::{Message, Role};
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+// Constants
+/// Conversations remembered; the least recently used is forgotten first
+const MAX_CONVERSATIONS: usize = 1024;
+
+/// Instance and slot a conversation's prompt is cached in
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SlotPin {
+    pub(crate) url: String,
+    pub(crate) slot: i64,
+}
+
+#[derive(Default)]
+struct AffinityTable {
+    conversations: HashMap<u64, (SlotPin, u64)>,
+    /// Slots a pinned request is using right now
+    busy: HashSet<SlotPin>,
+    clock: u64,
+}
+
+/// Conversation fingerprint to slot map, shared by a model's generations
+#[derive(Clone, Default)]
+pub(crate) struct SlotAffinity {
+    table: Arc<Mutex<AffinityTable>>,
+}
+
+/// A claimed slot, free for other pinned requests again once dropped
+pub(crate) struct PinGuard {
+    pub(crate) pin: SlotPin,
+    table: Arc<Mutex<AffinityTable>>,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        lock(&self.table).busy.remove(&self.pin);
+    }
+}
+
+fn lock(table: &Mutex<AffinityTable>) -> std::sync::MutexGuard<'_, AffinityTable> {
+    table.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl SlotAffinity {
+    /// Fingerprint of a conversation's opening; `None` without a user message
+    pub(crate) fn fingerprint(messages: &[Message]) -> Option<u64> {
+        let first_user = messages.iter().position(|m| m.role == Role::User)?;
+        let mut hasher = DefaultHasher::new();
+        for message in &messages[..=first_user] {
+            format!("{:?}", message.role).hash(&mut hasher);
+            message.content.hash(&mut hasher);
+            for image in &message.images {
+                image.data.hash(&mut hasher);
+            }
+        }
+        Some(hasher.finish())
+    }
+
+    /// The slot that served `conversation` last, unless it is in use
+    pub(crate) fn claim(&self, conversation: u64) -> Option<PinGuard> {
+        let mut table = lock(&self.table);
+        let pin = table.conversations.get(&conversation)?.0.clone();
+        if !table.busy.insert(pin.clone()) {
+            return None;
+        }
+        Some(PinGuard {
+            pin,
+            table: self.table.clone(),
+        })
+    }
+
+    /// Remember that `conversation`'s prompt is now cached in `pin`, which
+    /// no longer holds any other conversation's
+    pub(crate) fn record(&self, conversation: u64, pin: SlotPin) {
+        let mut table = lock(&self.table);
+        table.clock += 1;
+        let now = table.clock;
+        table
+            .conversations
+            .retain(|&id, (held, _)| id == conversation || *held != pin);
+        table.conversations.insert(conversation, (pin, now));
+        if table.conversations.len() > MAX_CONVERSATIONS {
+            let oldest = table
+                .conversations
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                table.conversations.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget a conversation whose prompt was erased from its slot
+    pub(crate) fn forget(&self, conversation: u64) {
+        lock(&self.table).conversations.remove(&conversation);
+    }
+
+    /// Forget the conversation cached in a slot that was erased
+    pub(crate) fn forget_slot(&self, pin: &SlotPin) {
+        lock(&self.table)
+            .conversations
+            .retain(|_, (held, _)| held != pin);
+    }
+
+    /// Forget every conversation, after the slots were erased or restarted
+    pub(crate) fn clear(&self) {
+        lock(&self.table).conversations.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(port: u16, slot: i64) -> SlotPin {
+        SlotPin {
+            url: format!("http://127.0.0.1:{}", port),
+            slot,
+        }
+    }
+
+    fn message(role: Role, content: &str) -> Message {
+        Message::new(role, content)
+    }
+
+    #[test]
+    fn test_fingerprint_survives_appended_turns() {
+        let opening = vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "What is Rust?"),
+        ];
+        let mut follow_up = opening.clone();
+        follow_up.push(message(Role::Assistant, "A language."));
+        follow_up.push(message(Role::User, "Who made it?"));
+
+        let key = SlotAffinity::fingerprint(&opening).unwrap();
+        assert_eq!(SlotAffinity::fingerprint(&follow_up), Some(key));
+        assert_ne!(
+            SlotAffinity::fingerprint(&[message(Role::User, "What is Go?")]),
+            Some(key)
+        );
+        assert_eq!(
+            SlotAffinity::fingerprint(&[message(Role::System, "Hi")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_claim_skips_busy_slot_and_slot_reuse_evicts() {
+        let affinity = SlotAffinity::default();
+        assert!(affinity.claim(1).is_none());
+
+        affinity.record(1, pin(9000, 2));
+        let guard = affinity.claim(1).unwrap();
+        assert_eq!(guard.pin, pin(9000, 2));
+        // A second turn sent before the first finished is not pinned
+        assert!(affinity.claim(1).is_none());
+        drop(guard);
+        assert!(affinity.claim(1).is_some());
+
+        // Another conversation took over the slot
+        affinity.record(2, pin(9000, 2));
+        assert!(affinity.claim(1).is_none());
+        assert!(affinity.claim(2).is_some());
+
+        affinity.forget(2);
+        assert!(affinity.claim(2).is_none());
+    }
+}
@@ -0,0 +1,196 @@
+//! Incremental server-sent events parser
+//!
+//! Bytes from the backend are appended to one growing `BytesMut` with CRLF
+//! line endings turned into LF; complete events are split off the front
+//! without copying and `data:` payloads are
+//! handed out as slices borrowed from the event. `Utf8Decoder` reassembles
+//! characters the backend split across events.
+
+use bytes::{Bytes, BytesMut};
+
+// Constants
+const EVENT_TERMINATOR: &[u8] = b"\n\n";
+const DATA_FIELD: &[u8] = b"data:";
+const INITIAL_CAPACITY: usize = 8 * 1024;
+
+/// Splits a byte stream into SSE events
+pub struct SseParser {
+    buffer: BytesMut,
+    /// Bytes already searched for a terminator, so each byte is scanned once
+    scanned: usize,
+    /// The last push ended in `\r`, which is dropped if `\n` comes next
+    pending_cr: bool,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(INITIAL_CAPACITY),
+            scanned: 0,
+            pending_cr: false,
+        }
+    }
+
+    /// Append bytes received from the backend, normalizing `\r\n` to `\n`
+    pub fn push(&mut self, bytes: &[u8]) {
+        let mut rest = bytes;
+        if std::mem::take(&mut self.pending_cr) && rest.first() != Some(&b'\n') {
+            self.buffer.extend_from_slice(b"\r");
+        }
+        while let Some(pos) = rest.iter().position(|&b| b == b'\r') {
+            self.buffer.extend_from_slice(&rest[..pos]);
+            match rest.get(pos + 1) {
+                Some(b'\n') => {}
+                Some(_) => self.buffer.extend_from_slice(b"\r"),
+                None => self.pending_cr = true,
+            }
+            rest = &rest[pos + 1..];
+        }
+        self.buffer.extend_from_slice(rest);
+    }
+
+    /// Take the next complete event, including its terminating blank line
+    pub fn next_event(&mut self) -> Option<Bytes> {
+        // Step back one byte in case a terminator straddles two pushes
+        let start = self.scanned.saturating_sub(EVENT_TERMINATOR.len() - 1);
+
+        match self.buffer[start..]
+            .windows(EVENT_TERMINATOR.len())
+            .position(|window| window == EVENT_TERMINATOR)
+        {
+            Some(pos) => {
+                self.scanned = 0;
+                Some(
+                    self.buffer
+                        .split_to(start + pos + EVENT_TERMINATOR.len())
+                        .freeze(),
+                )
+            }
+            None => {
+                self.scanned = self.buffer.len();
+                None
+            }
+        }
+    }
+}
+
+impl Default for SseParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The `data:` payloads of one event, borrowed from it
+pub fn data_payloads(event: &[u8]) -> impl Iterator<Item = &[u8]> {
+    event.split(|&b| b == b'\n').filter_map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let value = line.strip_prefix(DATA_FIELD)?;
+        Some(value.strip_prefix(b" ").unwrap_or(value))
+    })
+}
+
+/// Incremental UTF-8 decoder for streamed content
+///
+/// llama-server may split a multibyte character across chunks; the
+/// incomplete tail is kept until the rest arrives. Invalid bytes become
+/// U+FFFD as with `String::from_utf8_lossy`.
+#[derive(Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Decode as much of `bytes` (after any held-back tail) as is complete
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut decoded = String::with_capacity(self.pending.len());
+
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(text) => {
+                    decoded.push_str(text);
+                    self.pending.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    decoded.push_str(&String::from_utf8_lossy(&self.pending[..valid]));
+                    match e.error_len() {
+                        Some(invalid) => {
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + invalid);
+                        }
+                        None => {
+                            // Incomplete sequence at the end: wait for more
+                            self.pending.drain(..valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        decoded
+    }
+
+    /// Flush a tail that will never be completed
+    pub fn finish(&mut self) -> String {
+        let tail = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_across_pushes() {
+        let mut parser = SseParser::new();
+        parser.push(b"data: {\"a\":1}\n");
+        assert!(parser.next_event().is_none());
+        parser.push(b"\ndata: {\"b\"");
+        let event = parser.next_event().unwrap();
+        assert_eq!(event.as_ref(), b"data: {\"a\":1}\n\n");
+        assert!(parser.next_event().is_none());
+        parser.push(b":2}\n\n");
+        let event = parser.next_event().unwrap();
+        assert_eq!(
+            data_payloads(&event).collect::<Vec<_>>(),
+            vec![b"{\"b\":2}"]
+        );
+    }
+
+    #[test]
+    fn test_crlf_events_split_across_pushes() {
+        let mut parser = SseParser::new();
+        parser.push(b"data: {\"a\":1}\r\n\r");
+        assert!(parser.next_event().is_none());
+        parser.push(b"\ndata: a\rb\r");
+        let event = parser.next_event().unwrap();
+        assert_eq!(event.as_ref(), b"data: {\"a\":1}\n\n");
+        parser.push(b"\n\r\n");
+        let event = parser.next_event().unwrap();
+        assert_eq!(data_payloads(&event).collect::<Vec<_>>(), vec![b"a\rb"]);
+    }
+
+    #[test]
+    fn test_data_payloads_skip_other_fields() {
+        let event = b"event: message\r\ndata:{\"x\":1}\r\n: comment\n\n";
+        let payloads: Vec<_> = data_payloads(event).collect();
+        assert_eq!(payloads, vec![b"{\"x\":1}".as_slice()]);
+    }
+
+    #[test]
+    fn test_utf8_decoder_holds_split_characters() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "a€b".as_bytes();
+
+        assert_eq!(decoder.decode(&bytes[..2]), "a");
+        assert_eq!(decoder.decode(&bytes[2..3]), "");
+        assert_eq!(decoder.decode(&bytes[3..]), "€b");
+        assert_eq!(decoder.decode(&[0xff, b'x']), "\u{fffd}x");
+        assert_eq!(decoder.decode(&[0xe2, 0x82]), "");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
+}
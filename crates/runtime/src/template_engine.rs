@@ -1,5 +1,11 @@
-use chatsafe_common::{Message, Role};
+use crate::tool_calls;
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, Anchored, Input, MatchKind, StartKind};
+use chatsafe_common::{Message, Role, StreamBoundary, Tool, ToolChoice};
 use chatsafe_config::TemplateConfig;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use unicode_segmentation::UnicodeSegmentation;
 
 // Constants for template markers
 const TEMPLATE_MARKERS: &[&str] = &[
@@ -24,27 +30,78 @@ const ROLE_PATTERNS: &[&str] = &[
     "### Response:",
 ];
 
+// Both must appear for output to count as a role-played dialogue
+const DIALOGUE_PATTERNS: &[&str] = &["AI:", "You:"];
+
+// Automata compiled once; the pattern sets are fixed and always valid
+static MARKER_MATCHER: LazyLock<AhoCorasick> =
+    LazyLock::new(|| AhoCorasick::new(TEMPLATE_MARKERS).expect("template markers compile"));
+static ROLE_MATCHER: LazyLock<AhoCorasick> = LazyLock::new(|| {
+    AhoCorasickBuilder::new()
+        .match_kind(MatchKind::LeftmostFirst)
+        .start_kind(StartKind::Both)
+        .build(ROLE_PATTERNS)
+        .expect("role patterns compile")
+});
+static DIALOGUE_MATCHER: LazyLock<AhoCorasick> =
+    LazyLock::new(|| AhoCorasick::new(DIALOGUE_PATTERNS).expect("dialogue patterns compile"));
+
+// Prompt variable delimiters
+const VARIABLE_OPEN: &str = "{{";
+const VARIABLE_CLOSE: &str = "}}";
+
 // Fallback messages
-const ROLE_POLLUTION_FALLBACK: &str = "I understand you'd like me to respond, but I should avoid role-playing conversations. How can I help you directly?";
+pub(crate) const ROLE_POLLUTION_FALLBACK: &str = "I understand you'd like me to respond, but I should avoid role-playing conversations. How can I help you directly?";
 const EMPTY_RESPONSE_FALLBACK: &str = "I'm here to help. What would you like to know?";
 
+/// Request-specific parts of a prompt
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PromptOptions<'a> {
+    /// Functions described at the end of the first system prompt
+    pub tools: &'a [Tool],
+    pub tool_choice: Option<&'a ToolChoice>,
+    /// Values for `{{name}}` placeholders in the template's default system prompt
+    pub variables: Option<&'a HashMap<String, String>>,
+}
+
 /// Template engine for formatting messages and cleaning responses
 pub struct TemplateEngine;
 
 impl TemplateEngine {
     /// Format messages into a prompt using the model template
     pub fn format_prompt(messages: &[Message], template: &TemplateConfig) -> String {
+        Self::format_prompt_with(messages, PromptOptions::default(), template)
+    }
+
+    /// Format messages with request-specific tools and variables
+    ///
+    /// Tools are described at the end of the first system prompt. Earlier
+    /// assistant tool calls are written back in the format the model is told
+    /// to use, so it sees its own calls in the history.
+    pub fn format_prompt_with(
+        messages: &[Message],
+        options: PromptOptions<'_>,
+        template: &TemplateConfig,
+    ) -> String {
         let mut prompt = String::with_capacity(1024); // Pre-allocate reasonable size
         let mut has_system = false;
+        let tool_section = (!options.tools.is_empty())
+            .then(|| tool_calls::instructions(options.tools, options.tool_choice));
 
         for message in messages {
             match message.role {
                 Role::System => {
+                    let content = match &tool_section {
+                        Some(section) if !has_system => {
+                            Cow::Owned(format!("{}\n\n{}", message.content, section))
+                        }
+                        _ => Cow::Borrowed(message.content.as_str()),
+                    };
                     has_system = true;
                     Self::write_message(
                         &mut prompt,
                         &template.system_prefix,
-                        &message.content,
+                        &content,
                         &template.system_suffix,
                     );
                 }
@@ -52,10 +109,24 @@ impl TemplateEngine {
                     // Add default system prompt if not provided
                     if !has_system {
                         has_system = true;
+                        let default_prompt = match options.variables {
+                            Some(variables) => Self::render_variables(
+                                &template.default_system_prompt,
+                                variables,
+                                template,
+                            ),
+                            None => Cow::Borrowed(template.default_system_prompt.as_str()),
+                        };
+                        let content = match &tool_section {
+                            Some(section) => {
+                                Cow::Owned(format!("{}\n\n{}", default_prompt, section))
+                            }
+                            None => default_prompt,
+                        };
                         Self::write_message(
                             &mut prompt,
                             &template.system_prefix,
-                            &template.default_system_prompt,
+                            &content,
                             &template.system_suffix,
                         );
                     }
@@ -68,13 +139,32 @@ impl TemplateEngine {
                     );
                 }
                 Role::Assistant => {
+                    let content = match message.tool_calls.as_deref() {
+                        Some(calls) if !calls.is_empty() => {
+                            let calls = tool_calls::render_calls(calls);
+                            if message.content.is_empty() {
+                                Cow::Owned(calls)
+                            } else {
+                                Cow::Owned(format!("{}\n{}", message.content, calls))
+                            }
+                        }
+                        _ => Cow::Borrowed(message.content.as_str()),
+                    };
                     Self::write_message(
                         &mut prompt,
                         &template.assistant_prefix,
-                        &message.content,
+                        &content,
                         &template.assistant_suffix,
                     );
                 }
+                Role::Tool => {
+                    let (prefix, suffix) = if template.tool_prefix.is_empty() {
+                        (&template.user_prefix, &template.user_suffix)
+                    } else {
+                        (&template.tool_prefix, &template.tool_suffix)
+                    };
+                    Self::write_message(&mut prompt, prefix, &message.content, suffix);
+                }
             }
         }
 
@@ -84,6 +174,74 @@ impl TemplateEngine {
         prompt
     }
 
+    /// Replace `{{name}}` placeholders with `variables`
+    ///
+    /// Placeholders without a value are left as written. Values are inserted
+    /// in one pass, so placeholders inside them are not expanded, and lose
+    /// control characters and anything that could end the system turn
+    /// early: template markers, the template's own prefixes and suffixes, and
+    /// its stop tokens.
+    pub fn render_variables<'t>(
+        text: &'t str,
+        variables: &HashMap<String, String>,
+        template: &TemplateConfig,
+    ) -> Cow<'t, str> {
+        if variables.is_empty() || !text.contains(VARIABLE_OPEN) {
+            return Cow::Borrowed(text);
+        }
+
+        let mut rendered = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(VARIABLE_OPEN) {
+            let after_open = &rest[start + VARIABLE_OPEN.len()..];
+            let Some(end) = after_open.find(VARIABLE_CLOSE) else {
+                break;
+            };
+            let placeholder_end = start + VARIABLE_OPEN.len() + end + VARIABLE_CLOSE.len();
+            rendered.push_str(&rest[..start]);
+            match variables.get(after_open[..end].trim()) {
+                Some(value) => rendered.push_str(&Self::escape_variable(value, template)),
+                None => rendered.push_str(&rest[start..placeholder_end]),
+            }
+            rest = &rest[placeholder_end..];
+        }
+        rendered.push_str(rest);
+        Cow::Owned(rendered)
+    }
+
+    fn escape_variable(value: &str, template: &TemplateConfig) -> String {
+        let mut escaped: String = value
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let markers: Vec<&str> = TEMPLATE_MARKERS
+            .iter()
+            .copied()
+            .chain([
+                template.system_prefix.trim(),
+                template.system_suffix.trim(),
+                template.user_prefix.trim(),
+                template.user_suffix.trim(),
+                template.assistant_prefix.trim(),
+                template.assistant_suffix.trim(),
+            ])
+            .chain(template.stop_tokens.iter().map(String::as_str))
+            .filter(|marker| !marker.is_empty())
+            .collect();
+        // Repeat so removing one marker can't join the halves of another
+        loop {
+            let before = escaped.len();
+            for marker in &markers {
+                if escaped.contains(marker) {
+                    escaped = escaped.replace(marker, "");
+                }
+            }
+            if escaped.len() == before {
+                return escaped;
+            }
+        }
+    }
+
     /// Helper to write a message with prefix and suffix
     fn write_message(prompt: &mut String, prefix: &str, content: &str, suffix: &str) {
         // Pre-calculate capacity for better performance
@@ -123,25 +281,17 @@ impl TemplateEngine {
         }
     }
 
-    /// Truncate text at the first stop sequence found
+    /// Truncate text at the earliest stop sequence
     fn truncate_at_stop_sequence(
         text: &mut String,
         stop_sequences: &[String],
         eos_token: &str,
     ) -> Option<String> {
-        // Check all stop sequences first, then eos_token - avoid creating string
-        for stop_seq in stop_sequences {
-            if let Some(pos) = text.find(stop_seq.as_str()) {
-                text.truncate(pos);
-                return Some(stop_seq.clone());
-            }
-        }
-        // Check eos_token without allocation
-        if let Some(pos) = text.find(eos_token) {
-            text.truncate(pos);
-            return Some(eos_token.to_string());
-        }
-        None
+        let matcher = StopMatcher::new(stop_sequences, eos_token);
+        let (pos, stop_seq) = matcher.find(text)?;
+        let stop_seq = stop_seq.to_string();
+        text.truncate(pos);
+        Some(stop_seq)
     }
 
     /// Remove template prefixes/suffixes if echoed by model
@@ -157,13 +307,10 @@ impl TemplateEngine {
         }
     }
 
-    /// Remove leaked template markers
+    /// Remove leaked template markers in a single pass
     fn remove_template_markers(text: &mut String) {
-        // Only do replacement if markers are actually present (optimization)
-        for marker in TEMPLATE_MARKERS {
-            if !marker.is_empty() && text.contains(marker) {
-                *text = text.replace(marker, "");
-            }
+        if MARKER_MATCHER.is_match(text.as_str()) {
+            *text = MARKER_MATCHER.replace_all(text, &[""; TEMPLATE_MARKERS.len()]);
         }
     }
 
@@ -197,26 +344,34 @@ impl TemplateEngine {
 
     /// Check if text contains dialogue pattern
     fn has_dialogue_pattern(text: &str) -> bool {
-        text.contains("AI:") && text.contains("You:")
+        let mut seen = [false; DIALOGUE_PATTERNS.len()];
+        for found in DIALOGUE_MATCHER.find_iter(text) {
+            seen[found.pattern().as_usize()] = true;
+            if seen.iter().all(|&s| s) {
+                return true;
+            }
+        }
+        false
     }
 
     /// Clean role markers from a single line
     fn clean_role_from_line(line: &str) -> Option<String> {
+        // Most lines contain no role pattern at all
+        if !ROLE_MATCHER.is_match(line) {
+            return Some(line.to_string());
+        }
+
         let trimmed = line.trim_start();
 
         // Check if line starts with any role pattern
-        for pattern in ROLE_PATTERNS {
-            if trimmed.starts_with(pattern) {
-                let remainder = trimmed.trim_start_matches(pattern).trim();
-
-                // If there's actual content after the role marker, return it
-                if !remainder.is_empty() {
-                    return Some(remainder.to_string());
-                } else {
-                    // Empty after role marker, skip this line
-                    return None;
-                }
-            }
+        let anchored = Input::new(trimmed).anchored(Anchored::Yes);
+        if let Some(found) = ROLE_MATCHER.find(anchored) {
+            let pattern = ROLE_PATTERNS[found.pattern().as_usize()];
+            let remainder = trimmed.trim_start_matches(pattern).trim();
+
+            // If there's actual content after the role marker, return it,
+            // otherwise skip this line
+            return (!remainder.is_empty()).then(|| remainder.to_string());
         }
 
         // Line doesn't start with role pattern, check for mid-line markers
@@ -238,54 +393,257 @@ impl TemplateEngine {
         stop_sequences: &[String],
         eos_token: &str,
     ) -> Option<String> {
-        for stop_seq in stop_sequences
-            .iter()
-            .chain(std::iter::once(&eos_token.to_string()))
-        {
-            if text.contains(stop_seq) {
-                return Some(stop_seq.clone());
-            }
-        }
-        None
+        StopMatcher::new(stop_sequences, eos_token)
+            .find(text)
+            .map(|(_, stop_seq)| stop_seq.to_string())
     }
 
     /// Process streaming chunk
     ///
-    /// The buffer maintains ALL accumulated content until a stop sequence is found.
-    /// Partial emissions are just for incremental display - the buffer keeps everything.
+    /// The state keeps ALL accumulated content; partial emissions are the new,
+    /// cleaned content that can no longer turn into a stop sequence or marker.
+    /// Text that might still be the start of one is held back until the next
+    /// chunk or `StreamState::finish`.
     pub fn process_stream_chunk(
         chunk: &str,
         template: &TemplateConfig,
         stop_sequences: &[String],
         eos_token: &str,
-        buffer: &mut String,
+        state: &mut StreamState,
     ) -> StreamChunkResult {
+        if state.finished {
+            return StreamChunkResult::Buffering;
+        }
+
         // Add new chunk to accumulated buffer
-        let start_len = buffer.len();
-        buffer.push_str(chunk);
+        let start_len = state.buffer.len();
+        state.buffer.push_str(chunk);
 
-        // Check for stop sequences in entire buffer
-        if let Some(stop_seq) = Self::contains_stop_sequence(buffer, stop_sequences, eos_token) {
+        // Earlier chunks were already checked, so only the new tail (plus
+        // enough overlap for a stop sequence split across chunks) is scanned
+        if let Some((pos, stop_seq)) = state.stop_matcher.find_from(&state.buffer, start_len) {
             // Found stop sequence - clean and finalize the ENTIRE accumulated response
-            let cleaned = Self::clean_response(buffer, template, stop_sequences, eos_token);
-            let result = StreamChunkResult::Complete {
+            let stopped_at = Some(stop_seq.to_string());
+            state.buffer.truncate(pos);
+            state.finished = true;
+            let cleaned = Self::clean_response(&state.buffer, template, stop_sequences, eos_token);
+            return StreamChunkResult::Complete {
                 content: cleaned.content,
-                stopped_at: Some(stop_seq),
+                stopped_at,
+            };
+        }
+
+        // Role-played dialogue replaces the rest of the response
+        if Self::has_dialogue_pattern(&state.buffer) {
+            state.finished = true;
+            state.fallback_sent = true;
+            return StreamChunkResult::Partial {
+                content: ROLE_POLLUTION_FALLBACK.to_string(),
             };
-            buffer.clear();
-            return result;
         }
 
-        // No stop sequence yet - emit only the new content if it's safe
-        let new_content = &buffer[start_len..];
+        let safe_end = state.safe_end();
+        match state.take_until(safe_end) {
+            Some(content) => StreamChunkResult::Partial { content },
+            None => StreamChunkResult::Buffering,
+        }
+    }
+
+    /// Whether `text` contains a chat template marker such as `<|im_start|>`
+    pub fn contains_template_markers(text: &str) -> bool {
+        MARKER_MATCHER.is_match(text)
+    }
+
+    /// `text` without chat template markers, removed until none are left so
+    /// a marker split by another one cannot reassemble
+    pub fn strip_template_markers(text: &str) -> String {
+        let mut text = text.to_string();
+        while MARKER_MATCHER.is_match(text.as_str()) {
+            Self::remove_template_markers(&mut text);
+        }
+        text
+    }
 
-        // For simplicity in streaming, just emit the new content after basic cleaning
-        if !new_content.is_empty() {
-            let cleaned = Self::remove_role_pollution(new_content);
-            StreamChunkResult::Partial { content: cleaned }
+    /// Clean a streamed segment: drop template markers and role prefixes at
+    /// line starts, leaving whitespace between tokens intact
+    fn clean_stream_segment(segment: &str, at_line_start: bool) -> String {
+        let text = if MARKER_MATCHER.is_match(segment) {
+            MARKER_MATCHER.replace_all(segment, &[""; TEMPLATE_MARKERS.len()])
         } else {
-            StreamChunkResult::Buffering
+            segment.to_string()
+        };
+
+        let mut cleaned = String::with_capacity(text.len());
+        for (i, line) in text.split_inclusive('\n').enumerate() {
+            let line_start = i > 0 || at_line_start;
+            let anchored = Input::new(line.trim_start()).anchored(Anchored::Yes);
+            match ROLE_MATCHER.find(anchored).filter(|_| line_start) {
+                Some(found) => {
+                    cleaned.push_str(line.trim_start()[found.end()..].trim_start_matches(' '))
+                }
+                None => cleaned.push_str(line),
+            }
+        }
+        cleaned
+    }
+}
+
+/// Per-stream state for `TemplateEngine::process_stream_chunk`
+pub struct StreamState {
+    buffer: String,
+    /// Bytes of `buffer` already emitted
+    emitted: usize,
+    stop_matcher: StopMatcher,
+    boundary: StreamBoundary,
+    fallback_sent: bool,
+    finished: bool,
+}
+
+impl StreamState {
+    pub fn new(stop_sequences: &[String], eos_token: &str) -> Self {
+        Self {
+            buffer: String::new(),
+            emitted: 0,
+            stop_matcher: StopMatcher::new(stop_sequences, eos_token),
+            boundary: StreamBoundary::Token,
+            fallback_sent: false,
+            finished: false,
+        }
+    }
+
+    /// Only release text at the given boundary
+    pub fn with_boundary(mut self, boundary: StreamBoundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    /// Raw text accumulated so far (up to the stop sequence, if one was hit)
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Whether role-play pollution replaced the response with the fallback
+    pub fn fallback_sent(&self) -> bool {
+        self.fallback_sent
+    }
+
+    /// Whether a stop sequence ended the response or the backend stopped
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Release held-back text once the backend has stopped
+    pub fn finish(&mut self) -> Option<String> {
+        self.finished = true;
+        if self.fallback_sent {
+            return None;
+        }
+        self.take_until(self.buffer.len())
+    }
+
+    /// End of the text that cannot be the start of a stop sequence or marker
+    fn safe_end(&self) -> usize {
+        let held_back = self
+            .stop_matcher
+            .patterns
+            .iter()
+            .map(String::as_str)
+            .chain(TEMPLATE_MARKERS.iter().copied())
+            .filter_map(|pattern| {
+                (1..pattern.len())
+                    .rev()
+                    .filter_map(|k| pattern.get(..k))
+                    .find(|prefix| self.buffer.ends_with(prefix))
+                    .map(str::len)
+            })
+            .max()
+            .unwrap_or(0);
+        let end = (self.buffer.len() - held_back).max(self.emitted);
+
+        // The next token may still extend the last grapheme or word
+        let pending = &self.buffer[self.emitted..end];
+        let boundary_end = match self.boundary {
+            StreamBoundary::Token => pending.len(),
+            StreamBoundary::Grapheme => pending
+                .grapheme_indices(true)
+                .next_back()
+                .map_or(0, |(start, _)| start),
+            StreamBoundary::Word => pending
+                .char_indices()
+                .rev()
+                .find(|(_, c)| c.is_whitespace())
+                .map_or(0, |(start, c)| start + c.len_utf8()),
+        };
+        self.emitted + boundary_end
+    }
+
+    /// Clean and emit `buffer[emitted..end]`, if it leaves anything
+    fn take_until(&mut self, end: usize) -> Option<String> {
+        if end <= self.emitted {
+            return None;
         }
+        let at_line_start = self.emitted == 0 || self.buffer[..self.emitted].ends_with('\n');
+        let cleaned =
+            TemplateEngine::clean_stream_segment(&self.buffer[self.emitted..end], at_line_start);
+        self.emitted = end;
+        (!cleaned.is_empty()).then_some(cleaned)
+    }
+}
+
+/// Stop sequences plus the EOS token, scanned for the earliest match
+///
+/// One automaton finds all of them in a single pass over each chunk.
+pub struct StopMatcher {
+    patterns: Vec<String>,
+    /// `None` when there is nothing to stop at
+    automaton: Option<AhoCorasick>,
+    max_len: usize,
+}
+
+impl StopMatcher {
+    pub fn new(stop_sequences: &[String], eos_token: &str) -> Self {
+        let patterns: Vec<String> = stop_sequences
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(eos_token))
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        let max_len = patterns.iter().map(String::len).max().unwrap_or(0);
+        // Stop sequences are capped in number and length, well within limits
+        let automaton = (!patterns.is_empty()).then(|| {
+            AhoCorasickBuilder::new()
+                .match_kind(MatchKind::LeftmostFirst)
+                .build(&patterns)
+                .expect("stop sequences compile")
+        });
+
+        Self {
+            patterns,
+            automaton,
+            max_len,
+        }
+    }
+
+    /// Earliest stop sequence in `text`, with its byte offset
+    ///
+    /// On a tie the sequence listed first wins.
+    pub fn find<'a>(&'a self, text: &str) -> Option<(usize, &'a str)> {
+        let found = self.automaton.as_ref()?.find(text)?;
+        Some((
+            found.start(),
+            self.patterns[found.pattern().as_usize()].as_str(),
+        ))
+    }
+
+    /// Like `find`, assuming `text[..new_start]` was already checked
+    pub fn find_from<'a>(&'a self, text: &str, new_start: usize) -> Option<(usize, &'a str)> {
+        let mut start = new_start.saturating_sub(self.max_len.saturating_sub(1));
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.find(&text[start..])
+            .map(|(pos, stop_seq)| (start + pos, stop_seq))
     }
 }
 
@@ -324,6 +682,9 @@ mod tests {
             user_suffix: "</|user|>".to_string(),
             assistant_prefix: "<|assistant|>".to_string(),
             assistant_suffix: "</|assistant|>".to_string(),
+            tool_prefix: String::new(),
+            tool_suffix: String::new(),
+            stop_tokens: Vec::new(),
             default_system_prompt: "You are helpful.".to_string(),
         }
     }
@@ -332,14 +693,8 @@ mod tests {
     fn test_format_prompt() {
         let template = test_template();
         let messages = vec![
-            Message {
-                role: Role::System,
-                content: "Be concise.".to_string(),
-            },
-            Message {
-                role: Role::User,
-                content: "Hello".to_string(),
-            },
+            Message::new(Role::System, "Be concise."),
+            Message::new(Role::User, "Hello"),
         ];
 
         let prompt = TemplateEngine::format_prompt(&messages, &template);
@@ -349,6 +704,25 @@ mod tests {
         assert!(prompt.ends_with("<|assistant|>"));
     }
 
+    #[test]
+    fn test_format_tool_result() {
+        let messages = vec![
+            Message::new(Role::User, "Weather?"),
+            Message::new(Role::Tool, "{\"temp\": 21}"),
+        ];
+
+        // Without tool markers the result is presented as a user turn
+        let mut template = test_template();
+        let prompt = TemplateEngine::format_prompt(&messages, &template);
+        assert!(prompt.contains("<|user|>{\"temp\": 21}</|user|>"));
+
+        template.tool_prefix = "<|tool|>".to_string();
+        template.tool_suffix = "</|tool|>".to_string();
+        let prompt = TemplateEngine::format_prompt(&messages, &template);
+        assert!(prompt.contains("<|tool|>{\"temp\": 21}</|tool|>"));
+        assert!(prompt.ends_with("<|assistant|>"));
+    }
+
     #[test]
     fn test_clean_response() {
         let template = test_template();
@@ -389,7 +763,7 @@ mod tests {
         let template = test_template();
         let stop_sequences = vec!["STOP".to_string()];
         let eos_token = "EOS";
-        let mut buffer = String::new();
+        let mut state = StreamState::new(&stop_sequences, eos_token);
 
         // Test partial chunk
         let result = TemplateEngine::process_stream_chunk(
@@ -397,25 +771,25 @@ mod tests {
             &template,
             &stop_sequences,
             eos_token,
-            &mut buffer,
+            &mut state,
         );
 
         match result {
             StreamChunkResult::Partial { content } => {
-                // remove_role_pollution trims the content
-                assert_eq!(content, "Hello");
+                // Whitespace between tokens is preserved while streaming
+                assert_eq!(content, "Hello ");
             }
             _ => panic!("Expected partial result"),
         }
 
         // Test stop sequence detection
-        buffer.clear();
+        state = StreamState::new(&stop_sequences, eos_token);
         let result = TemplateEngine::process_stream_chunk(
             "Hello STOP there",
             &template,
             &stop_sequences,
             eos_token,
-            &mut buffer,
+            &mut state,
         );
 
         match result {
@@ -429,4 +803,129 @@ mod tests {
             _ => panic!("Expected complete result"),
         }
     }
+
+    #[test]
+    fn test_stop_sequence_split_across_chunks() {
+        let template = test_template();
+        let stop_sequences = vec!["<|eot_id|>".to_string()];
+        let mut state = StreamState::new(&stop_sequences, "</s>");
+
+        // The possible start of a stop sequence is held back, never emitted
+        let result = TemplateEngine::process_stream_chunk(
+            "Done héllo <|eo",
+            &template,
+            &stop_sequences,
+            "</s>",
+            &mut state,
+        );
+        assert!(
+            matches!(result, StreamChunkResult::Partial { content } if content == "Done héllo ")
+        );
+        let result = TemplateEngine::process_stream_chunk(
+            "t_i",
+            &template,
+            &stop_sequences,
+            "</s>",
+            &mut state,
+        );
+        assert!(matches!(result, StreamChunkResult::Buffering));
+
+        let result = TemplateEngine::process_stream_chunk(
+            "d|> trailing",
+            &template,
+            &stop_sequences,
+            "</s>",
+            &mut state,
+        );
+        match result {
+            StreamChunkResult::Complete { stopped_at, .. } => {
+                assert_eq!(stopped_at, Some("<|eot_id|>".to_string()));
+            }
+            _ => panic!("Expected complete result"),
+        }
+        assert_eq!(state.finish(), None);
+    }
+
+    #[test]
+    fn test_stream_state_finish_releases_held_back_text() {
+        let template = test_template();
+        let stop_sequences = vec!["<|eot_id|>".to_string()];
+        let mut state = StreamState::new(&stop_sequences, "</s>");
+
+        let result = TemplateEngine::process_stream_chunk(
+            "Use a <",
+            &template,
+            &stop_sequences,
+            "</s>",
+            &mut state,
+        );
+        assert!(matches!(result, StreamChunkResult::Partial { content } if content == "Use a "));
+        assert_eq!(state.finish(), Some("<".to_string()));
+    }
+
+    #[test]
+    fn test_stop_matcher_finds_earliest() {
+        let matcher = StopMatcher::new(&["LATE".to_string(), "EARLY".to_string()], "");
+        assert_eq!(matcher.find("a EARLY b LATE"), Some((2, "EARLY")));
+        assert_eq!(matcher.find("nothing here"), None);
+        // On a tie the sequence listed first wins, even if it is shorter
+        let matcher = StopMatcher::new(&["<|e".to_string(), "<|end|>".to_string()], "");
+        assert_eq!(matcher.find("ok<|end|>"), Some((2, "<|e")));
+        let matcher = StopMatcher::new(&[], "");
+        assert_eq!(matcher.find("anything"), None);
+        let matcher = StopMatcher::new(&["LATE".to_string(), "EARLY".to_string()], "");
+
+        // Only the tail plus overlap is scanned
+        assert_eq!(matcher.find_from("EARLY and more", 10), None);
+        assert_eq!(matcher.find_from("text EARLY", 7), Some((5, "EARLY")));
+    }
+
+    #[test]
+    fn test_word_boundary_streaming() {
+        let template = test_template();
+        let stop_sequences = vec!["STOP".to_string()];
+        let mut state =
+            StreamState::new(&stop_sequences, "EOS").with_boundary(StreamBoundary::Word);
+
+        let push = |chunk: &str, state: &mut StreamState| {
+            TemplateEngine::process_stream_chunk(chunk, &template, &stop_sequences, "EOS", state)
+        };
+        assert!(matches!(
+            push("Hel", &mut state),
+            StreamChunkResult::Buffering
+        ));
+        assert!(
+            matches!(push("lo wor", &mut state), StreamChunkResult::Partial { content } if content == "Hello ")
+        );
+        assert_eq!(state.finish(), Some("wor".to_string()));
+    }
+
+    #[test]
+    fn test_grapheme_boundary_streaming() {
+        let template = test_template();
+        let stop_sequences = vec!["STOP".to_string()];
+        let mut state =
+            StreamState::new(&stop_sequences, "EOS").with_boundary(StreamBoundary::Grapheme);
+
+        // A combining accent may still follow the last character
+        let result = TemplateEngine::process_stream_chunk(
+            "cafe",
+            &template,
+            &stop_sequences,
+            "EOS",
+            &mut state,
+        );
+        assert!(matches!(result, StreamChunkResult::Partial { content } if content == "caf"));
+        let result = TemplateEngine::process_stream_chunk(
+            "\u{301} ok",
+            &template,
+            &stop_sequences,
+            "EOS",
+            &mut state,
+        );
+        assert!(
+            matches!(result, StreamChunkResult::Partial { content } if content == "e\u{301} o")
+        );
+        assert_eq!(state.finish(), Some("k".to_string()));
+    }
 }
@@ -0,0 +1,163 @@
+//! Function calling through the prompt
+//!
+//! llama-server's completion endpoint knows nothing about tools, so tool
+//! definitions are described in the system prompt and the model is asked to
+//! answer with `<tool_call>{"name": ..., "arguments": {...}}</tool_call>`
+//! blocks, the format Hermes- and Qwen-style fine-tunes are trained on. The
+//! complete reply is then parsed back into OpenAI `tool_calls`, whose
+//! `arguments` are always a JSON object. A bare JSON
+//! object (or array of them) with `name` and `arguments`, or Llama 3's
+//! `parameters`, is accepted too, with or without a code fence.
+
+use chatsafe_common::{Tool, ToolCall, ToolChoice, ToolChoiceMode};
+use serde_json::{json, Value};
+
+// Constants
+pub const CALL_OPEN: &str = "<tool_call>";
+pub const CALL_CLOSE: &str = "</tool_call>";
+const EMPTY_ARGUMENTS: &str = "{}";
+
+/// A reply split into plain text and tool calls
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedReply {
+    /// Text outside the call blocks, trimmed
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// System prompt section describing `tools` and how to call them
+pub fn instructions(tools: &[Tool], choice: Option<&ToolChoice>) -> String {
+    let mut text = String::from(
+        "# Tools\n\nYou may call one or more functions to help with the user's request. \
+         The functions, with their arguments as JSON Schema:\n<tools>\n",
+    );
+    for tool in tools {
+        text.push_str(&json!(tool).to_string());
+        text.push('\n');
+    }
+    text.push_str(
+        "</tools>\n\nTo call a function, reply with one JSON object per call, each inside tags:\n\
+         <tool_call>\n{\"name\": \"<function name>\", \"arguments\": {<arguments object>}}\n</tool_call>\n\
+         Results come back in the next message.",
+    );
+
+    match choice {
+        Some(ToolChoice::Function { function, .. }) => {
+            text.push_str(&format!(" You must call {} now.", function.name));
+        }
+        Some(ToolChoice::Mode(ToolChoiceMode::Required)) => {
+            text.push_str(" You must call at least one function now.");
+        }
+        _ => text.push_str(" If no function is needed, answer normally."),
+    }
+    text
+}
+
+/// Calls from an earlier assistant turn, written the way the model is told to make them
+pub fn render_calls(calls: &[ToolCall]) -> String {
+    calls
+        .iter()
+        .map(|call| {
+            let arguments: Value = serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            // Name first, as in the instructions
+            format!(
+                "{}\n{{\"name\": {}, \"arguments\": {}}}\n{}",
+                CALL_OPEN,
+                json!(call.function.name),
+                arguments,
+                CALL_CLOSE
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parse the model's reply into calls to `tools`
+///
+/// Returns `None` when the reply is plain text, or when any call is
+/// malformed or names a function that was not offered, so a confused model
+/// produces text instead of a broken call.
+pub fn parse(text: &str, tools: &[Tool]) -> Option<ParsedReply> {
+    if tools.is_empty() {
+        return None;
+    }
+
+    let (content, values) = if text.contains(CALL_OPEN) {
+        tagged_calls(text)?
+    } else {
+        (String::new(), bare_calls(text)?)
+    };
+
+    let tool_calls = values
+        .iter()
+        .map(|value| to_call(value, tools))
+        .collect::<Option<Vec<_>>>()?;
+    if tool_calls.is_empty() {
+        return None;
+    }
+    Some(ParsedReply {
+        content,
+        tool_calls,
+    })
+}
+
+/// `<tool_call>` blocks and the text around them; the last block may be
+/// unclosed when the model stops right after the JSON
+fn tagged_calls(text: &str) -> Option<(String, Vec<Value>)> {
+    let mut content = String::new();
+    let mut values = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(CALL_OPEN) {
+        content.push_str(&rest[..start]);
+        let body = &rest[start + CALL_OPEN.len()..];
+        let (json, after) = match body.find(CALL_CLOSE) {
+            Some(end) => (&body[..end], &body[end + CALL_CLOSE.len()..]),
+            None => (body, ""),
+        };
+        values.push(serde_json::from_str(strip_fence(json)).ok()?);
+        rest = after;
+    }
+    content.push_str(rest);
+    Some((content.trim().to_string(), values))
+}
+
+/// A reply that is nothing but a call object or an array of them
+fn bare_calls(text: &str) -> Option<Vec<Value>> {
+    let text = strip_fence(text);
+    if !text.starts_with('{') && !text.starts_with('[') {
+        return None;
+    }
+    match serde_json::from_str(text).ok()? {
+        Value::Array(values) => Some(values),
+        value => Some(vec![value]),
+    }
+}
+
+fn strip_fence(text: &str) -> &str {
+    let text = text.trim();
+    let Some(inner) = text.strip_prefix("```").and_then(|t| t.strip_suffix("```")) else {
+        return text;
+    };
+    inner.strip_prefix("json").unwrap_or(inner).trim()
+}
+
+fn to_call(value: &Value, tools: &[Tool]) -> Option<ToolCall> {
+    let name = value.get("name")?.as_str()?;
+    if !tools.iter().any(|tool| tool.function.name == name) {
+        return None;
+    }
+    let arguments = match value.get("arguments").or_else(|| value.get("parameters")) {
+        // Encoded as a string, the way OpenAI sends them
+        Some(Value::String(encoded)) => serde_json::from_str(encoded).ok()?,
+        Some(Value::Null) | None => {
+            return Some(ToolCall::function(name.to_string(), EMPTY_ARGUMENTS.into()))
+        }
+        Some(arguments) => arguments.clone(),
+    };
+    // Clients decode `arguments` as an object; anything else is a broken call
+    if !arguments.is_object() {
+        return None;
+    }
+    Some(ToolCall::function(name.to_string(), arguments.to_string()))
+}
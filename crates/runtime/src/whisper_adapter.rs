@@ -0,0 +1,451 @@
+//! Speech-to-text through whisper.cpp's `whisper-server`
+//!
+//! A registry model with `capability: transcribe` is served by its own
+//! whisper-server on `transcription_port`, spawned through `ProcessManager`
+//! on first use so servers that never transcribe don't pay for the model.
+
+use crate::process_manager::ProcessManager;
+use chatsafe_common::{Error, Result};
+use chatsafe_config::{ModelConfig, RuntimeConfig};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio::process::Command;
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+
+// Constants
+const WHISPER_SERVER_BINARY: &str = "./whisper.cpp/build/bin/whisper-server";
+const SERVER_READY_MAX_ATTEMPTS: u32 = 60;
+const SERVER_READY_CHECK_INTERVAL_MS: u64 = 500;
+const PROCESS_START_WAIT_MS: u64 = 100;
+const INFERENCE_PATH: &str = "inference";
+
+/// Audio and options for one transcription
+#[derive(Debug, Clone, Default)]
+pub struct TranscriptionRequest {
+    pub audio: Vec<u8>,
+    /// Original file name; whisper-server picks the decoder from its extension
+    pub file_name: String,
+    /// ISO-639-1 language code; `None` lets whisper detect it
+    pub language: Option<String>,
+    /// Text that primes the decoder, e.g. names and spellings
+    pub prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub format: TranscriptFormat,
+}
+
+/// Output formats, named as in OpenAI's API; whisper-server produces each
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TranscriptFormat {
+    #[default]
+    Json,
+    Text,
+    Srt,
+    Vtt,
+    VerboseJson,
+}
+
+impl TranscriptFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "text" => Some(Self::Text),
+            "srt" => Some(Self::Srt),
+            "vtt" => Some(Self::Vtt),
+            "verbose_json" => Some(Self::VerboseJson),
+            _ => None,
+        }
+    }
+
+    /// `response_format` sent to whisper-server; plain text is taken from
+    /// its JSON so it can be trimmed the same way
+    fn server_name(self) -> &'static str {
+        match self {
+            Self::Json | Self::Text => "json",
+            Self::Srt => "srt",
+            Self::Vtt => "vtt",
+            Self::VerboseJson => "verbose_json",
+        }
+    }
+}
+
+/// Transcribed text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transcription {
+    /// Plain text, or the subtitles for `srt` and `vtt`
+    pub text: String,
+    /// Language, duration and timed segments, for `verbose_json`
+    pub verbose: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct InferenceText {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct InferenceError {
+    error: String,
+}
+
+/// One whisper-server, spawned or attached on first use
+pub struct WhisperAdapter {
+    model_id: String,
+    model_path: PathBuf,
+    model_config: ModelConfig,
+    runtime_config: RuntimeConfig,
+    base_url: String,
+    client: reqwest::Client,
+    process: ProcessManager,
+    ready: bool,
+}
+
+impl WhisperAdapter {
+    pub fn new(
+        model_path: PathBuf,
+        model_config: ModelConfig,
+        runtime_config: RuntimeConfig,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(runtime_config.connect_timeout_secs))
+            .timeout(Duration::from_secs(runtime_config.generation_timeout_secs))
+            .build()
+            .map_err(|e| Error::RuntimeError(format!("Failed to build HTTP client: {}", e)))?;
+        let base_url = format!("http://127.0.0.1:{}/", runtime_config.transcription_port);
+
+        Ok(Self {
+            model_id: model_config.id.clone(),
+            model_path,
+            process: ProcessManager::new(format!("whisper-server:{}", model_config.id)),
+            model_config,
+            runtime_config,
+            base_url,
+            client,
+            ready: false,
+        })
+    }
+
+    /// ID of the registry model being served
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// Spawn (or attach to) whisper-server unless it is already up
+    pub async fn ensure_started(&mut self) -> Result<()> {
+        if self.ready && (!self.runtime_config.manage_process || self.process.is_running()) {
+            return Ok(());
+        }
+        self.ready = false;
+
+        if self.runtime_config.manage_process {
+            info!(
+                "Starting whisper-server for {} on port {}",
+                self.model_id, self.runtime_config.transcription_port
+            );
+            self.process.cleanup().await?;
+            self.process
+                .spawn(self.build_server_command())
+                .await
+                .map_err(|e| {
+                    Error::RuntimeError(format!("Failed to start whisper-server: {}", e))
+                })?;
+
+            sleep(Duration::from_millis(PROCESS_START_WAIT_MS)).await;
+            if !self.process.is_running() {
+                return Err(Error::RuntimeError(format!(
+                    "whisper-server exited immediately. Check if binary exists at {}{}",
+                    WHISPER_SERVER_BINARY,
+                    self.output_excerpt().await
+                )));
+            }
+        } else {
+            info!("Attaching to external whisper-server at {}", self.base_url);
+        }
+
+        self.wait_for_ready().await?;
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Transcribe one audio file, starting the server if needed
+    pub async fn transcribe(&mut self, request: TranscriptionRequest) -> Result<Transcription> {
+        self.ensure_started().await?;
+
+        let boundary = format!("chatsafe-{}", uuid::Uuid::new_v4().simple());
+        let body = multipart_body(&boundary, &request);
+        let response = self
+            .client
+            .post(format!("{}{}", self.base_url, INFERENCE_PATH))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| Error::RuntimeError(format!("whisper-server request failed: {}", e)))?;
+
+        let status = response.status();
+        let bytes = response.bytes().await.map_err(|e| {
+            Error::RuntimeError(format!("Failed to read whisper-server response: {}", e))
+        })?;
+        // whisper-server answers decode failures with 200 and an `error` body
+        if let Ok(InferenceError { error }) = serde_json::from_slice(&bytes) {
+            return Err(Error::BadRequest(format!(
+                "Transcription failed: {}",
+                error
+            )));
+        }
+        if !status.is_success() {
+            return Err(Error::RuntimeError(format!(
+                "whisper-server returned {}",
+                status
+            )));
+        }
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::RuntimeError(format!("Invalid whisper-server response: {}", e))
+        };
+        match request.format {
+            TranscriptFormat::Json | TranscriptFormat::Text => {
+                let InferenceText { text } =
+                    serde_json::from_slice(&bytes).map_err(|e| invalid(&e))?;
+                Ok(Transcription {
+                    text: text.trim().to_string(),
+                    verbose: None,
+                })
+            }
+            TranscriptFormat::Srt | TranscriptFormat::Vtt => Ok(Transcription {
+                text: String::from_utf8(bytes.to_vec()).map_err(|e| invalid(&e))?,
+                verbose: None,
+            }),
+            TranscriptFormat::VerboseJson => {
+                let verbose: serde_json::Value =
+                    serde_json::from_slice(&bytes).map_err(|e| invalid(&e))?;
+                let text = verbose["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string();
+                Ok(Transcription {
+                    text,
+                    verbose: Some(verbose),
+                })
+            }
+        }
+    }
+
+    /// Stop a spawned whisper-server; an external one keeps running
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.ready = false;
+        if self.runtime_config.manage_process {
+            self.process.terminate().await?;
+        }
+        Ok(())
+    }
+
+    fn build_server_command(&self) -> Command {
+        let mut cmd = match &self.runtime_config.working_dir {
+            Some(dir) => {
+                // Resolve the binary before changing directory, as for llama-server
+                let binary = std::env::current_dir()
+                    .map(|cwd| cwd.join(WHISPER_SERVER_BINARY))
+                    .unwrap_or_else(|_| PathBuf::from(WHISPER_SERVER_BINARY));
+                let mut cmd = Command::new(binary);
+                cmd.current_dir(dir);
+                cmd
+            }
+            None => Command::new(WHISPER_SERVER_BINARY),
+        };
+        cmd.envs(&self.model_config.env);
+        cmd.arg("--model")
+            .arg(&self.model_path)
+            .arg("--host")
+            .arg("127.0.0.1")
+            .arg("--port")
+            .arg(self.runtime_config.transcription_port.to_string())
+            .arg("--threads")
+            .arg(self.model_config.resources.threads.to_string());
+        cmd
+    }
+
+    async fn wait_for_ready(&mut self) -> Result<()> {
+        for attempts in 1..=SERVER_READY_MAX_ATTEMPTS {
+            if self.runtime_config.manage_process && !self.process.is_running() {
+                return Err(Error::RuntimeError(format!(
+                    "whisper-server on port {} died unexpectedly{}",
+                    self.runtime_config.transcription_port,
+                    self.output_excerpt().await
+                )));
+            }
+
+            match self.client.get(&self.base_url).send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!(
+                        "whisper-server at {} ready after {} attempts",
+                        self.base_url, attempts
+                    );
+                    return Ok(());
+                }
+                Ok(response) => warn!("whisper-server answered {}", response.status()),
+                Err(_) => {}
+            }
+            sleep(Duration::from_millis(SERVER_READY_CHECK_INTERVAL_MS)).await;
+        }
+
+        Err(Error::RuntimeError(format!(
+            "whisper-server at {} failed to become ready after {} attempts",
+            self.base_url, SERVER_READY_MAX_ATTEMPTS
+        )))
+    }
+
+    async fn output_excerpt(&self) -> String {
+        let lines = self.process.output().lines_after_exit().await;
+        if lines.is_empty() {
+            String::new()
+        } else {
+            format!("\nLast whisper-server output:\n{}", lines.join("\n"))
+        }
+    }
+}
+
+/// `multipart/form-data` body for whisper-server's `/inference`
+fn multipart_body(boundary: &str, request: &TranscriptionRequest) -> Vec<u8> {
+    let mut body = Vec::with_capacity(request.audio.len() + 512);
+    let mut field = |name: &str, value: &str| {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    };
+    field("response_format", request.format.server_name());
+    if let Some(language) = &request.language {
+        field("language", language);
+    }
+    if let Some(prompt) = &request.prompt {
+        field("prompt", prompt);
+    }
+    if let Some(temperature) = request.temperature {
+        field("temperature", &temperature.to_string());
+    }
+
+    let file_name = request.file_name.replace(['"', '\r', '\n'], "_");
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, file_name
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&request.audio);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_adapter(port: u16) -> WhisperAdapter {
+        let registry = chatsafe_config::ModelRegistry::load_defaults().unwrap();
+        let mut model = registry.get_default_model().unwrap().clone();
+        model.id = "whisper-test".into();
+        let mut runtime_config = chatsafe_config::AppConfig::default().runtime;
+        runtime_config.manage_process = false;
+        runtime_config.transcription_port = port;
+        WhisperAdapter::new(PathBuf::from("ggml-base.bin"), model, runtime_config).unwrap()
+    }
+
+    /// whisper-server stand-in answering every request with `body`
+    async fn mock_whisper_server(body: &'static str) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_multipart_body_has_fields_and_file() {
+        let request = TranscriptionRequest {
+            audio: b"RIFF....".to_vec(),
+            file_name: "note\".wav".into(),
+            language: Some("de".into()),
+            ..Default::default()
+        };
+        let body = String::from_utf8(multipart_body("b0", &request)).unwrap();
+        assert!(body.starts_with("--b0\r\n"));
+        assert!(body.contains("name=\"language\"\r\n\r\nde\r\n"));
+        assert!(!body.contains("name=\"prompt\""));
+        assert!(body.contains("filename=\"note_.wav\""));
+        assert!(body.ends_with("RIFF....\r\n--b0--\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_against_external_server() {
+        let port = mock_whisper_server(r#"{"text":" Hello there.\n"}"#).await;
+        let mut adapter = test_adapter(port);
+        let result = adapter
+            .transcribe(TranscriptionRequest {
+                audio: vec![0; 16],
+                file_name: "a.wav".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.text, "Hello there.");
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_passes_subtitles_through() {
+        let srt = "1\n00:00:00,000 --> 00:00:01,500\n Hello there.\n\n";
+        let port = mock_whisper_server(srt).await;
+        let mut adapter = test_adapter(port);
+        let request = TranscriptionRequest {
+            format: TranscriptFormat::Srt,
+            ..Default::default()
+        };
+        let result = adapter.transcribe(request.clone()).await.unwrap();
+        assert_eq!(result.text, srt);
+        assert!(String::from_utf8(multipart_body("b0", &request))
+            .unwrap()
+            .contains("name=\"response_format\"\r\n\r\nsrt\r\n"));
+
+        let port = mock_whisper_server(r#"{"language":"en","text":" Hi.","segments":[]}"#).await;
+        let mut adapter = test_adapter(port);
+        let result = adapter
+            .transcribe(TranscriptionRequest {
+                format: TranscriptFormat::VerboseJson,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.text, "Hi.");
+        assert_eq!(result.verbose.unwrap()["language"], "en");
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_surfaces_decoder_errors() {
+        let port = mock_whisper_server(r#"{"error":"failed to read audio data"}"#).await;
+        let mut adapter = test_adapter(port);
+        let err = adapter
+            .transcribe(TranscriptionRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest(_)));
+    }
+}